@@ -1,131 +1,274 @@
 // client/src/circuit.rs
 
 use tokio::net::TcpStream;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use crate::transports::quic::QuicTransport;
-use crate::transports::r#trait::PluggableTransport;
-use crate::transports::tcp::{send_message, receive_message};
-use common::protocol::PhantomBandMessage;
-use common::crypto;
-use bincode;
-use log::{info, error};
+use transports::noise::{LinkCredentials, LinkSession};
+use common::protocol::{self, Capabilities, LinkParameters, PhantomBandMessage, RelayCell, RelayDescriptor, TransportKind};
+use common::crypto::Fingerprint;
+use common::crypto::ntor::NtorClient;
+use common::crypto::onion::{ClientOnion, HopLayer, OnionPayload};
+use common::error::{CloseReason, PhantomBandError};
+use log::{info, warn};
+use std::net::SocketAddr;
+use std::time::Duration;
 
+/// How long we wait for a relay to accept our TCP connection.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long one hop may take to join the circuit, including the link to
+/// the first hop.
+const HOP_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Default)]
 pub struct Circuit {
+    /// Our id for the circuit on the link to the first hop; zero until built.
     pub id: u64,
-    pub relay_key: Option<[u8; 32]>,
-    // TODO: Add circuit-related fields
+    /// When set, the first hop is rejected unless it proves this identity.
+    pub pinned_relay: Option<Fingerprint>,
+    /// Identities of the hops the circuit reaches, nearest first. After a
+    /// failed build, the hops it reached before failing.
+    pub path: Vec<Fingerprint>,
+    /// Link protocol version and capabilities agreed with the first hop.
+    pub link: Option<LinkParameters>,
+    /// When set, a first hop that cannot do the hybrid post-quantum handshake
+    /// is refused instead of falling back to X25519 alone.
+    pub require_pq: bool,
+    /// Our onion layers, one per hop.
+    onion: ClientOnion,
+    /// The link to the first hop, once open.
+    session: Option<LinkSession>,
 }
 
 impl Circuit {
     pub fn new() -> Self {
-        Circuit { id: 0, relay_key: None }
+        Circuit::default()
     }
 
-    pub fn build(&mut self) -> Result<(), String> {
-        info!("Building circuit...");
-        // TODO: Implement circuit building logic
+    /// Builds a circuit through `path`, nearest hop first: creates it at the
+    /// first relay, then extends it one hop at a time. An error names the
+    /// hop that failed, and the circuit is torn down.
+    pub async fn build(&mut self, path: &[RelayDescriptor]) -> Result<(), PhantomBandError> {
+        if self.session.is_some() {
+            return Err(PhantomBandError::Internal("Circuit is already built".to_string()));
+        }
+        if path.is_empty() {
+            return Err(PhantomBandError::Policy("Circuit path is empty".to_string()));
+        }
+        for (i, relay) in path.iter().enumerate() {
+            if path[..i].iter().any(|earlier| earlier.fingerprint() == relay.fingerprint()) {
+                return Err(PhantomBandError::Policy(format!("Relay {} appears twice in the circuit path", relay.fingerprint())));
+            }
+        }
+        info!("Building {}-hop circuit...", path.len());
+        self.path.clear();
+
+        for (hop, relay) in path.iter().enumerate() {
+            let added = tokio::time::timeout(HOP_TIMEOUT, self.add_hop(relay)).await
+                .unwrap_or_else(|_| Err(PhantomBandError::Timeout(format!("Adding relay {}", relay.fingerprint()))));
+            if let Err(e) = added {
+                warn!("Circuit failed at hop {} of {} ({}): {}", hop + 1, path.len(), relay.fingerprint(), e);
+                self.teardown(&e).await;
+                return Err(e.context(&format!("Hop {} of {} ({})", hop + 1, path.len(), relay.fingerprint())));
+            }
+            info!("Circuit {} reached hop {} of {} ({})", self.id, hop + 1, path.len(), relay.fingerprint());
+        }
         Ok(())
     }
 
-    pub async fn send(&self, data: &[u8]) -> Result<(), String> {
-        info!("Sending data through circuit...");
-        // TODO: Implement data sending logic
-        Ok(())
+    /// Sends a relay cell to the last hop.
+    pub async fn send(&mut self, cell: &RelayCell) -> Result<(), PhantomBandError> {
+        let hop = self.last_hop()?;
+        let result = self.send_cell(cell, hop).await;
+        self.fail_on_error(result).await
+    }
+
+    /// Waits for the next relay cell from the last hop.
+    pub async fn receive(&mut self) -> Result<RelayCell, PhantomBandError> {
+        let result = self.receive_from_last_hop().await;
+        self.fail_on_error(result).await
     }
 
-    pub async fn connect_to_relay(&mut self, relay_address: &str) -> Result<(), String> {
+    /// Destroys the circuit and closes the link to the first hop.
+    pub async fn close(&mut self) -> Result<(), PhantomBandError> {
+        let Some(mut session) = self.session.take() else {
+            return Ok(());
+        };
+        self.onion = ClientOnion::new();
+        self.path.clear();
+        session.send_message(&PhantomBandMessage::Destroy { circuit_id: self.id, reason: CloseReason::Requested }).await?;
+        session.send_message(&PhantomBandMessage::Disconnect { reason: CloseReason::Requested }).await
+    }
+
+    async fn add_hop(&mut self, relay: &RelayDescriptor) -> Result<(), PhantomBandError> {
+        if self.session.is_none() {
+            self.create(relay).await
+        } else {
+            self.extend(relay).await
+        }
+    }
+
+    /// Opens a Noise link to the first hop, authenticating it by the link key
+    /// and identity in its verified descriptor, and creates the circuit there.
+    async fn create(&mut self, relay: &RelayDescriptor) -> Result<(), PhantomBandError> {
+        let relay_address = tcp_address(relay)?;
         info!("Attempting to connect to relay at: {}", relay_address);
-        // For now, we'll use a direct TCP connection for message exchange demonstration
-        // In a real scenario, the QuicTransport would handle the underlying connection.
-        match TcpStream::connect(relay_address).await {
-            Ok(mut stream) => {
-                info!("Successfully connected to relay at: {}", relay_address);
-
-                // 1. Send ConnectRequest
-                let client_id = "test_client_id".to_string();
-                let client_public_key = crypto::generate_keypair();
-                let connect_request = PhantomBandMessage::ConnectRequest {
-                    client_id,
-                    public_key: client_public_key,
-                };
-                let serialized_request = bincode::serialize(&connect_request)
-                    .map_err(|e| format!("Failed to serialize ConnectRequest: {}", e))?;
-                let encrypted_request = crypto::encrypt(&serialized_request, &client_public_key)
-                    .map_err(|e| format!("Failed to encrypt ConnectRequest: {}", e))?;
-                send_message(&mut stream, &encrypted_request).await?;
-                info!("Sent ConnectRequest: {:?}", connect_request);
-
-                // 2. Receive ConnectResponse
-                let encrypted_response = receive_message(&mut stream).await?;
-                let decrypted_response = crypto::decrypt(&encrypted_response, &client_public_key)
-                    .map_err(|e| format!("Failed to decrypt ConnectResponse: {}", e))?;
-                let connect_response: PhantomBandMessage = bincode::deserialize(&decrypted_response)
-                    .map_err(|e| format!("Failed to deserialize ConnectResponse: {}", e))?;
-                info!("Received ConnectResponse: {:?}", connect_response);
-
-                if let PhantomBandMessage::ConnectResponse { relay_id: _, public_key, success, message: _ } = connect_response {
-                    if success {
-                        self.relay_key = Some(public_key);
-                        info!("Relay public key received and stored.");
-                    } else {
-                        return Err("Relay connection failed.".to_string());
-                    }
-                } else {
-                    return Err("Unexpected response type for ConnectResponse.".to_string());
-                }
-
-                // 3. Send CircuitCreate message
-                let circuit_id = 12345; // Dummy circuit ID
-                let circuit_create = PhantomBandMessage::CircuitCreate {
-                    circuit_id,
-                    public_key: client_public_key,
-                };
-                let serialized_circuit_create = bincode::serialize(&circuit_create)
-                    .map_err(|e| format!("Failed to serialize CircuitCreate: {}", e))?;
-                let encrypted_circuit_create = crypto::encrypt(&serialized_circuit_create, self.relay_key.as_ref().unwrap())
-                    .map_err(|e| format!("Failed to encrypt CircuitCreate: {}", e))?;
-                send_message(&mut stream, &encrypted_circuit_create).await?;
-                info!("Sent CircuitCreate: {:?}", circuit_create);
-
-                // 4. Receive CircuitCreated response
-                let encrypted_circuit_created = receive_message(&mut stream).await?;
-                let decrypted_circuit_created = crypto::decrypt(&encrypted_circuit_created, self.relay_key.as_ref().unwrap())
-                    .map_err(|e| format!("Failed to decrypt CircuitCreated: {}", e))?;
-                let circuit_created: PhantomBandMessage = bincode::deserialize(&decrypted_circuit_created)
-                    .map_err(|e| format!("Failed to deserialize CircuitCreated: {}", e))?;
-                info!("Received CircuitCreated: {:?}", circuit_created);
-
-                if let PhantomBandMessage::CircuitCreated { circuit_id: created_id, success, message: _ } = circuit_created {
-                    if success && created_id == circuit_id {
-                        self.id = created_id;
-                        info!("Circuit {} created successfully.", self.id);
-                    } else {
-                        return Err("Circuit creation failed.".to_string());
-                    }
-                } else {
-                    return Err("Unexpected response type for CircuitCreated.".to_string());
-                }
-
-                // 5. Send Data message (using the new circuit_id)
-                let data_message = PhantomBandMessage::Data { circuit_id: self.id, payload: b"Hello PhantomBand!".to_vec() };
-                let serialized_data = bincode::serialize(&data_message)
-                    .map_err(|e| format!("Failed to serialize Data message: {}", e))?;
-                let encrypted_data = crypto::encrypt(&serialized_data, self.relay_key.as_ref().unwrap())
-                    .map_err(|e| format!("Failed to encrypt Data message: {}", e))?;
-                send_message(&mut stream, &encrypted_data).await?;
-                info!("Sent Data message: {:?}", data_message);
-
-                // 6. Receive echoed Data message (optional, for demonstration)
-                let encrypted_echo = receive_message(&mut stream).await?;
-                let decrypted_echo = crypto::decrypt(&encrypted_echo, self.relay_key.as_ref().unwrap())
-                    .map_err(|e| format!("Failed to decrypt echoed Data message: {}", e))?;
-                let echoed_data: PhantomBandMessage = bincode::deserialize(&decrypted_echo)
-                    .map_err(|e| format!("Failed to deserialize echoed Data message: {}", e))?;
-                info!("Received echoed Data message: {:?}", echoed_data);
+        let stream = match tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect(relay_address)).await {
+            Err(_) => return Err(PhantomBandError::Timeout(format!("Connecting to relay {}", relay_address))),
+            Ok(Err(e)) => return Err(PhantomBandError::from(e).context("Failed to connect to relay")),
+            Ok(Ok(stream)) => stream,
+        };
+        info!("Successfully connected to relay at: {}", relay_address);
+
+        // 1. Negotiate the link version and run the Noise handshake. A fresh static
+        // key per link keeps the client anonymous; the relay proves its identity.
+        let session = LinkSession::initiate(stream, &LinkCredentials::ephemeral(), &relay.link_key).await
+            .map_err(|e| e.context("Link handshake failed"))?;
+        let link = session.parameters();
+        let fingerprint = session.remote_identity()
+            .ok_or_else(|| PhantomBandError::Internal("Link established without relay identity".to_string()))?
+            .fingerprint();
+        if fingerprint != relay.fingerprint() {
+            return Err(PhantomBandError::Policy(format!("Relay identity {} does not match its descriptor {}", fingerprint, relay.fingerprint())));
+        }
+        if let Some(pinned) = &self.pinned_relay {
+            if *pinned != fingerprint {
+                return Err(PhantomBandError::Policy(format!("Relay identity {} does not match pinned fingerprint {}", fingerprint, pinned)));
+            }
+        }
+        if self.require_pq && !link.capabilities.contains(Capabilities::PQ_HANDSHAKE) {
+            return Err(PhantomBandError::Policy("Relay does not support the post-quantum handshake".to_string()));
+        }
+        self.link = Some(link);
+        info!("Link to relay {} established (capabilities {})", fingerprint, link.capabilities);
+        let session = self.session.insert(session);
+
+        // 2. Run the circuit handshake against the relay's onion key. The link
+        // is ours alone, so any random id is free on it.
+        self.id = protocol::random_circuit_id();
+        let (handshake, request) = NtorClient::new(fingerprint, relay.onion_key);
+        session.send_message(&PhantomBandMessage::CircuitCreate { circuit_id: self.id, handshake: request }).await
+            .map_err(|e| e.context("Failed to send CircuitCreate"))?;
+
+        // 3. Receive CircuitCreated and check the relay's authenticator
+        let circuit_created = session.receive_message().await
+            .map_err(|e| e.context("Failed to receive CircuitCreated"))?;
+        match circuit_created {
+            PhantomBandMessage::CircuitCreated { circuit_id, handshake: reply } if circuit_id == self.id => {
+                let keys = handshake.finish(&reply)?;
+                self.onion.add_hop(HopLayer::new(&keys));
+                self.path.push(fingerprint);
+                Ok(())
+            },
+            PhantomBandMessage::Destroy { circuit_id, reason } if circuit_id == self.id => Err(PhantomBandError::Closed(reason)),
+            other => Err(unexpected_message(other, "CircuitCreated")),
+        }
+    }
+
+    /// Asks the last hop to extend the circuit to `relay`.
+    async fn extend(&mut self, relay: &RelayDescriptor) -> Result<(), PhantomBandError> {
+        let address = tcp_address(relay)?;
+        let last = self.last_hop()?;
+        let (handshake, request) = NtorClient::new(relay.fingerprint(), relay.onion_key);
+        let extend = PhantomBandMessage::Extend { address, link_key: relay.link_key, handshake: request };
+        self.send_cell(&extend.to_relay_cell(0)?, last).await
+            .map_err(|e| e.context("Failed to send Extend"))?;
 
+        let (hop, cell) = self.receive_cell().await
+            .map_err(|e| e.context("Failed to receive Extended"))?;
+        if hop != last {
+            return Err(PhantomBandError::Protocol(format!("Extended came from hop {}, not the last hop", hop + 1)));
+        }
+        match PhantomBandMessage::from_relay_cell(&cell)? {
+            PhantomBandMessage::Extended { handshake: reply } => {
+                let keys = handshake.finish(&reply)?;
+                self.onion.add_hop(HopLayer::new(&keys));
+                self.path.push(relay.fingerprint());
                 Ok(())
+            },
+            other => Err(unexpected_message(other, "Extended")),
+        }
+    }
+
+    fn last_hop(&self) -> Result<usize, PhantomBandError> {
+        self.onion.hop_count().checked_sub(1)
+            .ok_or_else(|| PhantomBandError::Internal("Circuit is not built".to_string()))
+    }
+
+    fn session(&mut self) -> Result<&mut LinkSession, PhantomBandError> {
+        self.session.as_mut().ok_or_else(|| PhantomBandError::Internal("Circuit is not built".to_string()))
+    }
+
+    /// Onion-encrypts `cell` for hop `hop` and sends it to the first hop.
+    async fn send_cell(&mut self, cell: &RelayCell, hop: usize) -> Result<(), PhantomBandError> {
+        let payload = self.onion.wrap(cell, hop)?;
+        let circuit_id = self.id;
+        self.session()?.send_message(&PhantomBandMessage::Relay { circuit_id, payload: payload.to_vec() }).await
+    }
+
+    /// Receives the next relay cell on this circuit and returns the index of
+    /// the hop it came from with the cell.
+    async fn receive_cell(&mut self) -> Result<(usize, RelayCell), PhantomBandError> {
+        let message = self.session()?.receive_message().await?;
+        match message {
+            PhantomBandMessage::Relay { circuit_id, payload } if circuit_id == self.id => {
+                let payload: OnionPayload = payload.as_slice().try_into()
+                    .map_err(|_| PhantomBandError::Protocol(format!("Relay payload is {} bytes", payload.len())))?;
+                self.onion.unwrap(&payload)
+            },
+            PhantomBandMessage::Destroy { circuit_id, reason } if circuit_id == self.id => Err(PhantomBandError::Closed(reason)),
+            other => Err(unexpected_message(other, "Relay")),
+        }
+    }
+
+    async fn receive_from_last_hop(&mut self) -> Result<RelayCell, PhantomBandError> {
+        let (hop, cell) = self.receive_cell().await?;
+        if hop != self.last_hop()? {
+            return Err(PhantomBandError::Protocol(format!("Expected a cell from the last hop, got {:?} from hop {}", cell.command, hop + 1)));
+        }
+        Ok(cell)
+    }
+
+    /// Tears the circuit down if `result` is an error. A cell that fails its
+    /// digest may have been tagged, so the circuit must not carry anything
+    /// else.
+    async fn fail_on_error<T>(&mut self, result: Result<T, PhantomBandError>) -> Result<T, PhantomBandError> {
+        if let Err(e) = &result {
+            self.teardown(e).await;
+        }
+        result
+    }
+
+    /// Drops the circuit after `error`, telling the first hop why unless the
+    /// circuit or link is already gone.
+    async fn teardown(&mut self, error: &PhantomBandError) {
+        let circuit_id = self.id;
+        let Some(mut session) = self.session.take() else {
+            return;
+        };
+        self.onion = ClientOnion::new();
+        if matches!(error, PhantomBandError::Transport(_)) {
+            return;
+        }
+        if !matches!(error, PhantomBandError::Closed(_)) {
+            if let Err(e) = session.send_message(&PhantomBandMessage::Destroy { circuit_id, reason: error.close_reason() }).await {
+                warn!("Failed to destroy circuit {}: {}", circuit_id, e);
+                return;
             }
-            Err(e) => Err(format!("Failed to connect to relay: {}", e)),
+        }
+        if let Err(e) = session.send_message(&PhantomBandMessage::Disconnect { reason: CloseReason::Requested }).await {
+            warn!("Failed to close link for circuit {}: {}", circuit_id, e);
         }
     }
-}
\ No newline at end of file
+}
+
+fn tcp_address(relay: &RelayDescriptor) -> Result<SocketAddr, PhantomBandError> {
+    relay.address_for(TransportKind::Tcp)
+        .ok_or_else(|| PhantomBandError::Policy(format!("Relay {} offers no TCP address", relay.fingerprint())))
+}
+
+/// Turns a message we did not expect into an error. A `Disconnect` from the
+/// relay carries its reason, which callers can match on.
+fn unexpected_message(message: PhantomBandMessage, expected: &str) -> PhantomBandError {
+    match message {
+        PhantomBandMessage::Disconnect { reason } => PhantomBandError::Closed(reason),
+        other => PhantomBandError::Protocol(format!("Expected {}, got {:?}", expected, other)),
+    }
+}
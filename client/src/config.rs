@@ -1,17 +1,75 @@
 // client/src/config.rs
 
+use common::crypto::IdentityPublicKey;
+use common::error::PhantomBandError;
+use common::protocol::AuthoritySet;
+use crate::socks::SocksCredentials;
+use std::fs;
+use std::path::PathBuf;
+
+/// Fewest hops a circuit may have: with fewer, one relay would see both who
+/// we are and where we connect.
+pub const MIN_CIRCUIT_LENGTH: usize = 3;
+
 pub struct ClientConfig {
+    /// Loopback port of the SOCKS5 proxy.
     pub socks_port: u16,
+    /// When set, SOCKS clients must authenticate with these.
+    pub socks_credentials: Option<SocksCredentials>,
     pub vpn_interface: bool,
     pub enable_stealth: bool,
+    /// Identity keys of the directory authorities whose consensus we trust.
+    pub directory_authorities: Vec<IdentityPublicKey>,
+    /// Where `load_authorities` reads `directory_authorities` from.
+    pub authorities_file: PathBuf,
+    /// Number of hops in the circuits we build.
+    pub circuit_length: usize,
+    /// Where our entry guards are kept between runs.
+    pub guard_state_file: PathBuf,
+}
+
+impl ClientConfig {
+    /// The configured authorities, a majority of which must sign a consensus.
+    pub fn authority_set(&self) -> Result<AuthoritySet, PhantomBandError> {
+        if self.directory_authorities.is_empty() {
+            return Err(PhantomBandError::Policy(format!("No directory authorities configured; list their identity keys in {}", self.authorities_file.display())));
+        }
+        AuthoritySet::majority(self.directory_authorities.clone())
+    }
+
+    /// Reads `directory_authorities` from `authorities_file`: one identity
+    /// key per line in hex. Blank lines and lines starting with `#` are
+    /// skipped.
+    pub fn load_authorities(&mut self) -> Result<(), PhantomBandError> {
+        let text = fs::read_to_string(&self.authorities_file)
+            .map_err(|e| PhantomBandError::Internal(format!("Failed to read authorities from {}: {}", self.authorities_file.display(), e)))?;
+        self.directory_authorities = text.lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(|line| line.parse::<IdentityPublicKey>().map_err(|e| e.context(&format!("In {}", self.authorities_file.display()))))
+            .collect::<Result<_, _>>()?;
+        Ok(())
+    }
+
+    pub fn validate(&self) -> Result<(), PhantomBandError> {
+        if self.circuit_length < MIN_CIRCUIT_LENGTH {
+            return Err(PhantomBandError::Policy(format!("Circuits need at least {} hops, {} configured", MIN_CIRCUIT_LENGTH, self.circuit_length)));
+        }
+        Ok(())
+    }
 }
 
 impl Default for ClientConfig {
     fn default() -> Self {
         ClientConfig {
             socks_port: 9050,
+            socks_credentials: None,
             vpn_interface: false,
             enable_stealth: true,
+            directory_authorities: Vec::new(),
+            authorities_file: PathBuf::from("authorities"),
+            circuit_length: MIN_CIRCUIT_LENGTH,
+            guard_state_file: PathBuf::from("guards.state"),
         }
     }
 }
@@ -1,6 +1,15 @@
 // client/src/controller.rs
 
-pub fn connect_to_controller(address: &str) {
-    println!("Connecting to controller at: {}", address);
-    // TODO: Implement actual connection logic
+use common::error::PhantomBandError;
+use common::protocol::{AuthoritySet, Consensus, SignedConsensus};
+use common::utils::Clock;
+use std::fs;
+use std::path::Path;
+
+/// Reads a consensus from `path` and accepts it only if a threshold of
+/// `authorities` signed it and it is currently valid.
+pub fn load_consensus(path: &Path, authorities: &AuthoritySet, clock: &dyn Clock) -> Result<Consensus, PhantomBandError> {
+    let bytes = fs::read(path)
+        .map_err(|e| PhantomBandError::Internal(format!("Failed to read {}: {}", path.display(), e)))?;
+    SignedConsensus::decode(&bytes)?.verify(authorities, clock.unix_time())
 }
@@ -0,0 +1,277 @@
+// client/src/guard.rs
+
+//! Entry guards.
+//!
+//! A client that picked a fresh first hop for every circuit would sooner or
+//! later pick one run by the adversary, who could then link it to its
+//! destinations whenever the exit is theirs too. Instead we keep a small
+//! sample of guards across restarts and enter the network only through them,
+//! preferring the guards that already worked. A sampled guard is kept for
+//! `GUARD_LIFETIME`, and for at least `CONFIRMED_GUARD_LIFETIME` after we
+//! first built a circuit through it, so our entry point changes only slowly.
+//!
+//! Unreachable guards are retried with exponential backoff. A guard through
+//! which unusually many circuits fail after it answered may be dropping the
+//! circuits it cannot deanonymize, so it is disabled for
+//! `PATH_BIAS_DISABLE_TIME` rather than given more chances at once. It keeps
+//! its place in the sample meanwhile, so that killing circuits does not make
+//! us sample ever more guards.
+
+use crate::path;
+use common::crypto::Fingerprint;
+use common::error::PhantomBandError;
+use common::protocol::wire::{self, WireFormat};
+use common::protocol::{Consensus, RelayDescriptor, RelayFlags};
+use common::utils::SharedClock;
+use log::{info, warn};
+use rand::Rng;
+use serde::{Serialize, Deserialize};
+use std::fs;
+use std::path::Path;
+
+const DAY: u64 = 24 * 60 * 60;
+
+/// Most guards we ever sample.
+pub const MAX_SAMPLE_SIZE: usize = 10;
+/// How long a sampled guard is kept.
+pub const GUARD_LIFETIME: u64 = 120 * DAY;
+/// How long a guard is kept at least after we first built a circuit through
+/// it.
+pub const CONFIRMED_GUARD_LIFETIME: u64 = 60 * DAY;
+/// How long a guard may be missing from the consensus before it is dropped.
+pub const UNLISTED_GUARD_LIFETIME: u64 = 20 * DAY;
+/// Delay before retrying an unreachable guard, doubled on every failure in
+/// a row up to `MAX_RETRY_DELAY`.
+pub const INITIAL_RETRY_DELAY: u64 = 60;
+pub const MAX_RETRY_DELAY: u64 = 36 * 60 * 60;
+/// Circuits through a guard before its success rate is judged.
+pub const PATH_BIAS_MIN_CIRCUITS: u32 = 20;
+/// A guard whose circuits succeed less often than this, in percent, is
+/// disabled.
+pub const PATH_BIAS_DISABLE_RATE: u32 = 30;
+/// How long a guard stays disabled before it is judged afresh.
+pub const PATH_BIAS_DISABLE_TIME: u64 = 7 * DAY;
+/// Counts are halved at this many circuits, so that the rate follows the
+/// guard's recent behavior.
+const PATH_BIAS_SCALE_AT: u32 = 300;
+const MAX_GUARD_STATE_LEN: usize = 64 * 1024;
+
+/// How a circuit through a guard went.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CircuitOutcome {
+    /// We could not open a link to the guard or create a circuit at it.
+    Unreachable,
+    /// The guard was reached, but the circuit failed further along.
+    Failed,
+    Succeeded,
+}
+
+impl CircuitOutcome {
+    /// How building a circuit that reached `hops_reached` hops counts against
+    /// its guard. Paths our own checks refused after the guard answered are
+    /// not the guard's doing and do not count.
+    pub fn of(built: &Result<(), PhantomBandError>, hops_reached: usize) -> Option<CircuitOutcome> {
+        match built {
+            Ok(()) => Some(CircuitOutcome::Succeeded),
+            Err(_) if hops_reached == 0 => Some(CircuitOutcome::Unreachable),
+            Err(PhantomBandError::Policy(_) | PhantomBandError::Internal(_)) => None,
+            Err(_) => Some(CircuitOutcome::Failed),
+        }
+    }
+}
+
+/// One sampled guard.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Guard {
+    pub fingerprint: Fingerprint,
+    /// Unix time the guard was sampled.
+    pub sampled_at: u64,
+    /// Unix time we first built a circuit through the guard.
+    pub confirmed_at: Option<u64>,
+    /// Unix time since which the consensus no longer lists the relay as a
+    /// guard.
+    pub unlisted_since: Option<u64>,
+    /// Failed attempts to reach the guard in a row.
+    pub failures: u32,
+    /// Unix time before which an unreachable guard is not tried again.
+    pub retry_at: u64,
+    /// Circuits that reached the guard, and how many of them were built.
+    pub circuits: u32,
+    pub successes: u32,
+    /// Unix time before which the guard is not used because too many
+    /// circuits through it failed.
+    pub disabled_until: u64,
+}
+
+impl Guard {
+    fn new(fingerprint: Fingerprint, now: u64) -> Self {
+        Guard { fingerprint, sampled_at: now, confirmed_at: None, unlisted_since: None, failures: 0, retry_at: 0, circuits: 0, successes: 0, disabled_until: 0 }
+    }
+
+    fn is_usable(&self, now: u64) -> bool {
+        self.unlisted_since.is_none() && self.disabled_until <= now && self.retry_at <= now
+    }
+
+    fn is_expired(&self, now: u64) -> bool {
+        let mut expires = self.sampled_at.saturating_add(GUARD_LIFETIME);
+        if let Some(confirmed_at) = self.confirmed_at {
+            expires = expires.max(confirmed_at.saturating_add(CONFIRMED_GUARD_LIFETIME));
+        }
+        let unlisted_too_long = self.unlisted_since.is_some_and(|since| now >= since.saturating_add(UNLISTED_GUARD_LIFETIME));
+        now >= expires || unlisted_too_long
+    }
+
+    /// Counts a circuit that reached the guard, and disables the guard once
+    /// too few of them succeed. Its counts start over when it is enabled
+    /// again.
+    fn count_circuit(&mut self, succeeded: bool, now: u64) {
+        self.circuits += 1;
+        self.successes += succeeded as u32;
+        if self.circuits >= PATH_BIAS_MIN_CIRCUITS && self.successes * 100 < self.circuits * PATH_BIAS_DISABLE_RATE {
+            warn!("Disabling guard {} for {}s: only {} of {} circuits through it succeeded", self.fingerprint, PATH_BIAS_DISABLE_TIME, self.successes, self.circuits);
+            self.disabled_until = now.saturating_add(PATH_BIAS_DISABLE_TIME);
+            self.circuits = 0;
+            self.successes = 0;
+        }
+        if self.circuits >= PATH_BIAS_SCALE_AT {
+            self.circuits /= 2;
+            self.successes /= 2;
+        }
+    }
+}
+
+/// The guards as stored between runs, in the order they were sampled.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct GuardState {
+    guards: Vec<Guard>,
+}
+
+impl WireFormat for GuardState {
+    const NAME: &'static str = "guard state";
+    const MAX_LEN: usize = MAX_GUARD_STATE_LEN;
+}
+
+/// Our sampled guards.
+#[derive(Debug)]
+pub struct GuardSet {
+    clock: SharedClock,
+    guards: Vec<Guard>,
+}
+
+impl GuardSet {
+    pub fn new(clock: SharedClock) -> Self {
+        GuardSet { clock, guards: Vec::new() }
+    }
+
+    /// Loads the guards saved at `path`, or starts without any if nothing was
+    /// saved yet.
+    pub fn load(path: &Path, clock: SharedClock) -> Result<Self, PhantomBandError> {
+        if !path.exists() {
+            return Ok(GuardSet::new(clock));
+        }
+        let bytes = fs::read(path)
+            .map_err(|e| PhantomBandError::Internal(format!("Failed to read guard state {}: {}", path.display(), e)))?;
+        let state: GuardState = wire::decode(&bytes)?;
+        if state.guards.len() > MAX_SAMPLE_SIZE {
+            return Err(PhantomBandError::Internal(format!("Guard state {} holds {} guards (max {})", path.display(), state.guards.len(), MAX_SAMPLE_SIZE)));
+        }
+        info!("Loaded {} guards from {}", state.guards.len(), path.display());
+        Ok(GuardSet { clock, guards: state.guards })
+    }
+
+    /// Writes the guards to `path`, replacing the previous state only once
+    /// the new one is complete.
+    pub fn save(&self, path: &Path) -> Result<(), PhantomBandError> {
+        let bytes = wire::encode(&GuardState { guards: self.guards.clone() })?;
+        let temporary = path.with_extension("tmp");
+        fs::write(&temporary, bytes)
+            .and_then(|()| fs::rename(&temporary, path))
+            .map_err(|e| PhantomBandError::Internal(format!("Failed to save guard state {}: {}", path.display(), e)))
+    }
+
+    /// The sampled guards, in the order they were sampled.
+    pub fn guards(&self) -> &[Guard] {
+        &self.guards
+    }
+
+    /// Picks the guard for the next circuit: the longest-confirmed usable
+    /// guard, else the earliest-sampled usable one. Only if none is usable is
+    /// a new guard sampled from `consensus`.
+    pub fn select<'a, R: Rng + ?Sized>(&mut self, consensus: &'a Consensus, rng: &mut R) -> Result<&'a RelayDescriptor, PhantomBandError> {
+        let now = self.clock.unix_time();
+        self.update(consensus, now);
+
+        let mut usable: Vec<&Guard> = self.guards.iter().filter(|guard| guard.is_usable(now)).collect();
+        usable.sort_by_key(|guard| guard.confirmed_at.unwrap_or(u64::MAX));
+        if let Some(guard) = usable.first() {
+            return listed(consensus, &guard.fingerprint)
+                .ok_or_else(|| PhantomBandError::Internal(format!("Guard {} is usable but not listed", guard.fingerprint)));
+        }
+
+        if self.guards.len() >= MAX_SAMPLE_SIZE {
+            let retry_at = self.guards.iter().filter(|guard| guard.unlisted_since.is_none()).map(|guard| guard.retry_at.max(guard.disabled_until)).min();
+            return Err(PhantomBandError::Policy(match retry_at {
+                Some(retry_at) => format!("All {} guards are unreachable or disabled; the next is retried in {}s", self.guards.len(), retry_at.saturating_sub(now)),
+                None => format!("All {} guards are unlisted", self.guards.len()),
+            }));
+        }
+        let sampled: Vec<Fingerprint> = self.guards.iter().map(|guard| guard.fingerprint).collect();
+        let relay = path::sample_guard(consensus, &sampled, rng)?;
+        info!("Sampled new guard {} ({} of at most {})", relay.fingerprint(), self.guards.len() + 1, MAX_SAMPLE_SIZE);
+        self.guards.push(Guard::new(relay.fingerprint(), now));
+        Ok(relay)
+    }
+
+    /// Records how a circuit through `guard` went.
+    pub fn record(&mut self, guard: &Fingerprint, outcome: CircuitOutcome) {
+        let now = self.clock.unix_time();
+        let Some(guard) = self.guards.iter_mut().find(|sampled| sampled.fingerprint == *guard) else {
+            return;
+        };
+        match outcome {
+            CircuitOutcome::Unreachable => {
+                guard.failures = guard.failures.saturating_add(1);
+                let delay = INITIAL_RETRY_DELAY.saturating_mul(1 << (guard.failures - 1).min(32)).min(MAX_RETRY_DELAY);
+                guard.retry_at = now.saturating_add(delay);
+                warn!("Guard {} unreachable {} times in a row; retrying in {}s", guard.fingerprint, guard.failures, delay);
+            },
+            CircuitOutcome::Failed | CircuitOutcome::Succeeded => {
+                guard.failures = 0;
+                guard.retry_at = 0;
+                let succeeded = outcome == CircuitOutcome::Succeeded;
+                if succeeded && guard.confirmed_at.is_none() {
+                    info!("Confirmed guard {}", guard.fingerprint);
+                    guard.confirmed_at = Some(now);
+                }
+                guard.count_circuit(succeeded, now);
+            },
+        }
+    }
+
+    /// Notes which guards the consensus lists, and drops expired guards.
+    fn update(&mut self, consensus: &Consensus, now: u64) {
+        for guard in &mut self.guards {
+            match (listed(consensus, &guard.fingerprint).is_some(), guard.unlisted_since) {
+                (true, _) => guard.unlisted_since = None,
+                (false, None) => guard.unlisted_since = Some(now),
+                (false, Some(_)) => {},
+            }
+        }
+        self.guards.retain(|guard| {
+            let expired = guard.is_expired(now);
+            if expired {
+                info!("Dropping guard {}", guard.fingerprint);
+            }
+            !expired
+        });
+    }
+}
+
+/// The descriptor of `fingerprint` if the consensus lists it as a running
+/// guard.
+fn listed<'a>(consensus: &'a Consensus, fingerprint: &Fingerprint) -> Option<&'a RelayDescriptor> {
+    let guard = RelayFlags::VALID.union(RelayFlags::RUNNING).union(RelayFlags::GUARD);
+    consensus.relay(fingerprint)
+        .filter(|relay| relay.flags.contains(guard))
+        .map(|relay| &relay.descriptor)
+}
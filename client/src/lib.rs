@@ -0,0 +1,371 @@
+// client/src/lib.rs
+pub mod circuit;
+pub mod config;
+pub mod controller;
+pub mod guard;
+pub mod path;
+pub mod socks;
+pub mod stream;
+pub mod utils;
+pub mod vpn;
+
+#[cfg(test)]
+mod tests {
+    use super::config::ClientConfig;
+    use super::controller;
+    use super::guard::{self, CircuitOutcome, GuardSet};
+    use super::path::{self, Position};
+    use super::socks::{self, SocksCredentials, SocksRequest};
+    use common::crypto::{IdentityKeypair, SecretKey};
+    use common::error::{CloseReason, PhantomBandError};
+    use common::protocol::consensus::{ConsensusEntry, ConsensusRelay};
+    use common::protocol::descriptor::{ExitAction, ExitPolicy, ExitRule};
+    use common::protocol::{Consensus, ConsensusDocument, Datagram, RelayDescriptor, RelayFlags, SignedConsensus, StreamTarget, TransportKind};
+    use rand::SeedableRng;
+    use common::utils::{Clock, ManualClock};
+    use rand::rngs::StdRng;
+    use std::net::IpAddr;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    fn flags(extra: &[RelayFlags]) -> RelayFlags {
+        let usable = RelayFlags::VALID.union(RelayFlags::RUNNING).union(RelayFlags::FAST);
+        extra.iter().fold(usable, |flags, flag| flags.union(*flag))
+    }
+
+    fn relay(address: &str, flags: RelayFlags, weight: u64) -> ConsensusRelay {
+        let exit_policy = match flags.contains(RelayFlags::EXIT) {
+            true => ExitPolicy { rules: vec![ExitRule { action: ExitAction::Accept, network: None, ports: (443, 443) }] },
+            false => ExitPolicy::reject_all(),
+        };
+        let descriptor = RelayDescriptor {
+            identity_key: IdentityKeypair::generate().public_key(),
+            link_key: SecretKey::generate().public_key(),
+            onion_key: SecretKey::generate().public_key(),
+            addresses: vec![address.parse().unwrap()],
+            transports: vec![TransportKind::Tcp],
+            bandwidth: weight,
+            exit_policy,
+            family: Vec::new(),
+            published: 1000,
+            valid_until: 1000 + 3600,
+        };
+        ConsensusRelay { descriptor, flags, weight }
+    }
+
+    /// The /16 or /32 network of a relay's first address.
+    fn subnet(relay: &RelayDescriptor) -> u128 {
+        match relay.addresses[0].ip().to_canonical() {
+            IpAddr::V4(ip) => u128::from(u32::from(ip) >> 16),
+            IpAddr::V6(ip) => u128::from(ip) >> 96 | 1 << 127,
+        }
+    }
+
+    fn consensus(mut relays: Vec<ConsensusRelay>) -> Consensus {
+        relays.sort_by_key(|relay| relay.fingerprint());
+        Consensus { valid_after: 1000, valid_until: 1000 + 3600, relays }
+    }
+
+    #[test]
+    fn test_path_fills_positions_with_diverse_relays() {
+        let mut relays = vec![
+            relay("192.0.2.1:443", flags(&[RelayFlags::GUARD]), 1000),
+            relay("198.51.100.1:443", flags(&[RelayFlags::GUARD]), 1000),
+            relay("203.0.113.1:443", flags(&[]), 1000),
+            relay("203.0.113.2:443", flags(&[]), 1000),
+            relay("[::ffff:203.0.113.3]:443", flags(&[]), 1000),
+            relay("[2001:db8::1]:443", flags(&[RelayFlags::EXIT]), 1000),
+            relay("[2001:db8:1::1]:443", flags(&[RelayFlags::EXIT]), 1000),
+            relay("100.64.0.1:443", flags(&[RelayFlags::EXIT, RelayFlags::BAD_EXIT]), 1000),
+        ];
+        // The two guards are run by one operator.
+        let (first, second) = (relays[0].fingerprint(), relays[1].fingerprint());
+        relays[0].descriptor.family = vec![second];
+        relays[1].descriptor.family = vec![first];
+        let consensus = consensus(relays);
+
+        let mut rng = StdRng::seed_from_u64(7);
+        for _ in 0..100 {
+            let path = path::select_path(&consensus, 4, 443, &mut rng).expect("No path found");
+            let flags_of = |hop: &RelayDescriptor| consensus.relay(&hop.fingerprint()).unwrap().flags;
+            assert!(flags_of(&path[0]).contains(RelayFlags::GUARD));
+            assert!(flags_of(&path[3]).contains(RelayFlags::EXIT) && !flags_of(&path[3]).contains(RelayFlags::BAD_EXIT));
+            for (i, hop) in path.iter().enumerate() {
+                for other in &path[i + 1..] {
+                    assert!(!hop.is_family_of(other));
+                    assert_ne!(subnet(hop), subnet(other), "Two hops share a subnet");
+                }
+            }
+        }
+
+        // Only one of the 203.0.113.0/24 relays fits on a circuit, even
+        // written as an IPv4-mapped address, so there are not enough middles
+        // for a five-hop path.
+        assert!(path::select_path(&consensus, 5, 443, &mut rng).is_err());
+        assert!(path::select_path(&consensus, 3, 22, &mut rng).is_err(), "No exit accepts port 22");
+    }
+
+    #[test]
+    fn test_selection_is_weighted_by_bandwidth_and_flags() {
+        let heavy = relay("192.0.2.1:443", flags(&[]), 9000);
+        let light = relay("198.51.100.1:443", flags(&[]), 1000);
+        // A guard with the same weight as the heavy relay counts for a third
+        // of it in the middle.
+        let guard = relay("203.0.113.1:443", flags(&[RelayFlags::GUARD]), 9000);
+        let (heavy_fp, light_fp, guard_fp) = (heavy.fingerprint(), light.fingerprint(), guard.fingerprint());
+        let consensus = consensus(vec![heavy, light, guard]);
+
+        let mut rng = StdRng::seed_from_u64(11);
+        let mut counts = (0, 0, 0);
+        for _ in 0..3000 {
+            let picked = path::select_relay(&consensus, Position::Middle, &[], &mut rng).unwrap().fingerprint();
+            match picked {
+                fp if fp == heavy_fp => counts.0 += 1,
+                fp if fp == light_fp => counts.1 += 1,
+                _ => counts.2 += 1,
+            }
+        }
+        // Expected shares are 9:1:3 out of 13.
+        assert!((1900..2250).contains(&counts.0), "{:?}", counts);
+        assert!((150..320).contains(&counts.1), "{:?}", counts);
+        assert!((560..830).contains(&counts.2), "{:?}", counts);
+        // Only the guard can be an entry.
+        assert_eq!(path::select_relay(&consensus, Position::Entry, &[], &mut rng).unwrap().fingerprint(), guard_fp);
+    }
+
+    /// Guards in distinct networks, each able to carry a whole circuit.
+    fn guard_consensus(count: u8) -> Consensus {
+        consensus((1..=count).map(|i| relay(&format!("10.{}.0.1:443", i), flags(&[RelayFlags::GUARD]), 1000)).collect())
+    }
+
+    #[test]
+    fn test_consensus_is_checked_against_configured_authorities() {
+        let authorities: Vec<_> = (0..3).map(|_| IdentityKeypair::generate()).collect();
+        let identity = IdentityKeypair::generate();
+        let mut descriptor = relay("192.0.2.1:443", flags(&[]), 100).descriptor;
+        descriptor.identity_key = identity.public_key();
+        let entry = ConsensusEntry { fingerprint: descriptor.fingerprint(), descriptor: descriptor.sign(&identity).unwrap(), flags: flags(&[]), weight: 100 };
+        let document = ConsensusDocument { valid_after: 1000, valid_until: 1000 + 3600, entries: vec![entry] };
+        let mut signed = SignedConsensus::new(&document).unwrap();
+        for authority in &authorities[..2] {
+            signed.add_signature(document.sign(authority).unwrap());
+        }
+
+        let directory = std::env::temp_dir().join(format!("phantomband-authorities-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let consensus_file = directory.join("consensus");
+        std::fs::write(&consensus_file, signed.encode().unwrap()).unwrap();
+        let keys: Vec<String> = authorities.iter().map(|authority| authority.public_key().to_string()).collect();
+        std::fs::write(directory.join("authorities"), format!("# Test authorities\n\n{}\n", keys.join("\n"))).unwrap();
+        let others: Vec<String> = (0..3).map(|_| IdentityKeypair::generate().public_key().to_string()).collect();
+        std::fs::write(directory.join("others"), others.join("\n")).unwrap();
+
+        let clock = ManualClock::new(1500);
+        let mut config = ClientConfig { authorities_file: directory.join("authorities"), ..ClientConfig::default() };
+        assert!(matches!(config.authority_set(), Err(PhantomBandError::Policy(_))), "Trusted a consensus without authorities");
+        config.load_authorities().expect("Loading authorities failed");
+        assert_eq!(config.directory_authorities, authorities.iter().map(|authority| authority.public_key()).collect::<Vec<_>>());
+        let consensus = controller::load_consensus(&consensus_file, &config.authority_set().unwrap(), &clock).expect("Consensus refused");
+        assert_eq!(consensus.relays[0].fingerprint(), identity.public_key().fingerprint());
+
+        let mut config = ClientConfig { authorities_file: directory.join("others"), ..ClientConfig::default() };
+        config.load_authorities().expect("Loading authorities failed");
+        let refused = controller::load_consensus(&consensus_file, &config.authority_set().unwrap(), &clock);
+        std::fs::remove_dir_all(&directory).unwrap();
+        assert!(refused.is_err(), "Consensus signed by authorities we do not trust was accepted");
+    }
+
+    #[test]
+    fn test_guards_persist_and_are_reused() {
+        let clock = ManualClock::new(1000);
+        let consensus = guard_consensus(8);
+        let mut rng = StdRng::seed_from_u64(3);
+        let mut guards = GuardSet::new(Arc::new(clock.clone()));
+        let first = guards.select(&consensus, &mut rng).unwrap().fingerprint();
+        guards.record(&first, CircuitOutcome::Succeeded);
+        assert_eq!(guards.guards()[0].confirmed_at, Some(1000));
+
+        let file = std::env::temp_dir().join(format!("phantomband-guards-{}.state", std::process::id()));
+        guards.save(&file).expect("Saving failed");
+        let mut restarted = GuardSet::load(&file, Arc::new(clock.clone())).expect("Loading failed");
+        std::fs::remove_file(&file).unwrap();
+        assert_eq!(restarted.guards(), guards.guards());
+        for _ in 0..20 {
+            assert_eq!(restarted.select(&consensus, &mut rng).unwrap().fingerprint(), first);
+        }
+        assert_eq!(restarted.guards().len(), 1, "A working guard needs no others");
+
+        // Guards rotate once their lifetime is over.
+        clock.advance(Duration::from_secs(guard::GUARD_LIFETIME));
+        restarted.select(&consensus, &mut rng).unwrap();
+        assert!(restarted.guards().iter().all(|sampled| sampled.fingerprint != first));
+    }
+
+    #[test]
+    fn test_unreachable_guard_is_retried_with_backoff() {
+        let clock = ManualClock::new(1000);
+        let consensus = guard_consensus(8);
+        let mut rng = StdRng::seed_from_u64(5);
+        let mut guards = GuardSet::new(Arc::new(clock.clone()));
+        let first = guards.select(&consensus, &mut rng).unwrap().fingerprint();
+        guards.record(&first, CircuitOutcome::Unreachable);
+        guards.record(&first, CircuitOutcome::Unreachable);
+        assert_eq!(guards.guards()[0].retry_at, clock.unix_time() + 2 * guard::INITIAL_RETRY_DELAY);
+
+        // Meanwhile a second guard is sampled, but the first is preferred
+        // again once it may be retried.
+        let second = guards.select(&consensus, &mut rng).unwrap().fingerprint();
+        assert_ne!(second, first);
+        clock.advance(Duration::from_secs(2 * guard::INITIAL_RETRY_DELAY));
+        assert_eq!(guards.select(&consensus, &mut rng).unwrap().fingerprint(), first);
+
+        // The sample is bounded even when every guard is down.
+        for _ in 0..2 * guard::MAX_SAMPLE_SIZE {
+            if let Ok(relay) = guards.select(&consensus, &mut rng) {
+                let fingerprint = relay.fingerprint();
+                guards.record(&fingerprint, CircuitOutcome::Unreachable);
+            }
+        }
+        assert!(guards.guards().len() <= guard::MAX_SAMPLE_SIZE);
+        assert!(guards.select(&consensus, &mut rng).is_err());
+    }
+
+    #[test]
+    fn test_path_bias_disables_guard() {
+        let clock = ManualClock::new(1000);
+        let consensus = guard_consensus(4);
+        let mut rng = StdRng::seed_from_u64(9);
+        let mut guards = GuardSet::new(Arc::new(clock.clone()));
+        let biased = guards.select(&consensus, &mut rng).unwrap().fingerprint();
+        for i in 0..guard::PATH_BIAS_MIN_CIRCUITS {
+            let outcome = if i % 5 == 0 { CircuitOutcome::Succeeded } else { CircuitOutcome::Failed };
+            guards.record(&biased, outcome);
+        }
+        assert_eq!(guards.guards()[0].disabled_until, clock.unix_time() + guard::PATH_BIAS_DISABLE_TIME, "Only a fifth of circuits succeeded");
+        let replacement = guards.select(&consensus, &mut rng).unwrap().fingerprint();
+        assert_ne!(replacement, biased);
+
+        // The guard is judged afresh once its time is up.
+        clock.advance(Duration::from_secs(guard::PATH_BIAS_DISABLE_TIME));
+        assert_eq!(guards.select(&consensus, &mut rng).unwrap().fingerprint(), biased);
+        assert_eq!((guards.guards()[0].circuits, guards.guards()[0].successes), (0, 0));
+
+        // Only failures past a guard that answered count against it, and not
+        // paths we refused ourselves.
+        let closed = Err(PhantomBandError::Closed(CloseReason::Protocol));
+        assert_eq!(CircuitOutcome::of(&closed, 0), Some(CircuitOutcome::Unreachable));
+        assert_eq!(CircuitOutcome::of(&closed, 1), Some(CircuitOutcome::Failed));
+        assert_eq!(CircuitOutcome::of(&Err(PhantomBandError::Policy("No TCP address".to_string())), 1), None);
+        assert_eq!(CircuitOutcome::of(&Ok(()), 3), Some(CircuitOutcome::Succeeded));
+
+        // A path through the guard never reuses it or its network.
+        let guard = consensus.relay(&replacement).unwrap().descriptor.clone();
+        let extra = vec![relay("192.0.2.1:443", flags(&[]), 1000), relay("198.51.100.1:443", flags(&[RelayFlags::EXIT]), 1000)];
+        let consensus = self::consensus(consensus.relays.into_iter().chain(extra).collect());
+        let path = path::select_path_from_guard(&consensus, &guard, 3, 443, &mut rng).unwrap();
+        assert_eq!(path[0], guard);
+        assert!(path[1..].iter().all(|hop| hop.fingerprint() != replacement));
+    }
+
+    /// Runs the proxy side of a SOCKS handshake against `client_bytes`, and
+    /// returns its result with everything the proxy answered.
+    async fn socks_handshake(client_bytes: &[u8], credentials: Option<&SocksCredentials>) -> (Result<SocksRequest, PhantomBandError>, Vec<u8>) {
+        let (mut client, mut proxy) = tokio::io::duplex(1024);
+        client.write_all(client_bytes).await.unwrap();
+        let result = socks::handshake(&mut proxy, credentials).await;
+        drop(proxy);
+        let mut answer = Vec::new();
+        client.read_to_end(&mut answer).await.unwrap();
+        (result, answer)
+    }
+
+    #[tokio::test]
+    async fn test_socks_passes_hostnames_to_the_exit() {
+        let mut request = vec![5, 1, 0, 5, 1, 0, 3, 11];
+        request.extend_from_slice(b"example.com");
+        request.extend_from_slice(&443u16.to_be_bytes());
+        let (result, answer) = socks_handshake(&request, None).await;
+        assert_eq!(result.unwrap(), SocksRequest::Connect(StreamTarget::Hostname { host: "example.com".to_string(), port: 443 }));
+        assert_eq!(answer, [5, 0], "Only the method is answered before the stream opens");
+
+        // Addresses given as hostnames, and IPv6 addresses.
+        let mut request = vec![5, 1, 0, 5, 1, 0, 3, 9];
+        request.extend_from_slice(b"192.0.2.1");
+        request.extend_from_slice(&80u16.to_be_bytes());
+        let (result, _) = socks_handshake(&request, None).await;
+        assert_eq!(result.unwrap(), SocksRequest::Connect(StreamTarget::Address("192.0.2.1:80".parse().unwrap())));
+        let mut request = vec![5, 1, 0, 5, 1, 0, 4];
+        request.extend_from_slice(&"2001:db8::1".parse::<std::net::Ipv6Addr>().unwrap().octets());
+        request.extend_from_slice(&80u16.to_be_bytes());
+        let (result, _) = socks_handshake(&request, None).await;
+        assert_eq!(result.unwrap(), SocksRequest::Connect(StreamTarget::Address("[2001:db8::1]:80".parse().unwrap())));
+
+        // BIND is refused with its reply code, as are unknown address types.
+        let (result, answer) = socks_handshake(&[5, 1, 0, 5, 2, 0, 1, 192, 0, 2, 1, 0, 80], None).await;
+        assert!(result.is_err());
+        assert_eq!(answer, [5, 0, 5, 7, 0, 1, 0, 0, 0, 0, 0, 0]);
+        let (result, answer) = socks_handshake(&[5, 1, 0, 5, 1, 0, 9], None).await;
+        assert!(result.is_err());
+        assert_eq!(answer[3], 8);
+    }
+
+    #[tokio::test]
+    async fn test_socks_username_password() {
+        let credentials = SocksCredentials { username: "user".to_string(), password: "secret".to_string() };
+        let login = |password: &[u8]| {
+            let mut bytes = vec![5, 1, 2, 1, 4];
+            bytes.extend_from_slice(b"user");
+            bytes.push(password.len() as u8);
+            bytes.extend_from_slice(password);
+            bytes.extend_from_slice(&[5, 1, 0, 1, 192, 0, 2, 1, 0, 80]);
+            bytes
+        };
+        let (result, answer) = socks_handshake(&login(b"secret"), Some(&credentials)).await;
+        assert!(result.is_ok());
+        assert_eq!(answer, [5, 2, 1, 0]);
+        let (result, answer) = socks_handshake(&login(b"wrong"), Some(&credentials)).await;
+        assert!(result.is_err());
+        assert_eq!(answer, [5, 2, 1, 1]);
+
+        // With credentials configured, skipping authentication is refused.
+        let (result, answer) = socks_handshake(&[5, 1, 0], Some(&credentials)).await;
+        assert!(result.is_err());
+        assert_eq!(answer, [5, 0xff]);
+        // Without, no authentication is preferred, and any login accepted.
+        let (result, answer) = socks_handshake(&[5, 2, 0, 2, 5, 1, 0, 1, 192, 0, 2, 1, 0, 80], None).await;
+        assert!(result.is_ok());
+        assert_eq!(answer, [5, 0]);
+        let (result, answer) = socks_handshake(&login(b"anything"), None).await;
+        assert!(result.is_ok());
+        assert_eq!(answer, [5, 2, 1, 0]);
+    }
+
+    #[tokio::test]
+    async fn test_socks_udp_associate() {
+        let (result, answer) = socks_handshake(&[5, 1, 0, 5, 3, 0, 1, 0, 0, 0, 0, 0, 0], None).await;
+        assert_eq!(result.unwrap(), SocksRequest::UdpAssociate(StreamTarget::Address("0.0.0.0:0".parse().unwrap())));
+        assert_eq!(answer, [5, 0]);
+
+        // Hostnames stay for the exit to resolve, both ways.
+        let mut packet = vec![0, 0, 0, 3, 11];
+        packet.extend_from_slice(b"example.com");
+        packet.extend_from_slice(&53u16.to_be_bytes());
+        packet.extend_from_slice(b"query");
+        let datagram = socks::parse_udp_packet(&packet).unwrap();
+        assert_eq!(datagram.peer, StreamTarget::Hostname { host: "example.com".to_string(), port: 53 });
+        assert_eq!(datagram.data, b"query");
+        assert_eq!(socks::udp_packet(&datagram), packet);
+
+        let reply = Datagram { peer: StreamTarget::Address("[2001:db8::1]:53".parse().unwrap()), data: b"answer".to_vec() };
+        let packet = socks::udp_packet(&reply);
+        assert_eq!(packet[3], 4);
+        assert_eq!(socks::parse_udp_packet(&packet).unwrap(), reply);
+
+        // Fragments, truncated headers and unknown address types are refused.
+        assert!(socks::parse_udp_packet(&[0, 0, 1, 1, 192, 0, 2, 1, 0, 53, 1]).is_err());
+        assert!(socks::parse_udp_packet(&[0, 0, 0, 1, 192, 0]).is_err());
+        assert!(socks::parse_udp_packet(&[0, 0, 0, 3, 11, b'e', b'x']).is_err());
+        assert!(socks::parse_udp_packet(&[0, 0, 0, 9, 1, 2, 3, 4, 0, 53]).is_err());
+    }
+}
@@ -1,22 +1,151 @@
 // client/src/main.rs
 
-use common::crypto;
-use crate::circuit::Circuit;
+use client::circuit::Circuit;
+use client::config::ClientConfig;
+use client::guard::{CircuitOutcome, GuardSet};
+use client::socks::SocksProxy;
+use client::stream::{CircuitHandle, CircuitRequest};
+use client::{controller, path};
+use common::error::PhantomBandError;
+use common::protocol::{RelayDescriptor, SignedRelayDescriptor};
+use common::utils::{Clock, SharedClock, SystemClock};
 use log::{info, error};
-use env_logger;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+/// Where the latest consensus is kept, until we fetch it from a directory.
+const CONSENSUS_FILE: &str = "consensus";
+/// Port the exit of the circuit the SOCKS proxy starts streams on must
+/// allow. Circuits are built before the streams they will carry are known;
+/// streams to ports its exit refuses go to circuits built for their port.
+const EXIT_PORT: u16 = 443;
+/// Circuit requests queued before SOCKS clients wait for the builder.
+const CIRCUIT_REQUEST_QUEUE_LEN: usize = 16;
+/// How long we wait before building a new circuit after one failed or closed.
+const REBUILD_DELAY: Duration = Duration::from_secs(5);
+
+fn load_relay_descriptor(clock: &dyn Clock, path: &str) -> Result<RelayDescriptor, PhantomBandError> {
+    let bytes = fs::read(path)
+        .map_err(|e| PhantomBandError::Internal(format!("Failed to read {}: {}", path, e)))?;
+    let relay = SignedRelayDescriptor::decode(&bytes)?.verify(clock.unix_time())?;
+    info!("Loaded descriptor for relay {}", relay.fingerprint());
+    Ok(relay)
+}
+
+/// Picks a path from the consensus that enters through one of our guards and
+/// leaves through an exit that allows `port`.
+fn select_path(config: &ClientConfig, guards: &mut GuardSet, port: u16, clock: &dyn Clock) -> Result<Vec<RelayDescriptor>, PhantomBandError> {
+    let consensus = controller::load_consensus(Path::new(CONSENSUS_FILE), &config.authority_set()?, clock)?;
+    info!("Loaded consensus with {} relays", consensus.relays.len());
+    let mut rng = rand::thread_rng();
+    let guard = guards.select(&consensus, &mut rng)?;
+    path::select_path_from_guard(&consensus, guard, config.circuit_length, port, &mut rng)
+}
+
+/// Builds a circuit through the relays in `files`, nearest hop first, or, if
+/// there are none, through a path from the consensus that enters through
+/// one of `guards`. Its exit must allow `port`. How the circuit went is
+/// recorded against its guard. With `files` the port is not checked.
+async fn build_circuit(config: &ClientConfig, files: &[String], guards: &mut Option<GuardSet>, port: u16, clock: &dyn Clock) -> Result<Circuit, PhantomBandError> {
+    let relays = match guards {
+        Some(guards) => select_path(config, guards, port, clock)?,
+        None => files.iter().map(|file| load_relay_descriptor(clock, file)).collect::<Result<Vec<_>, _>>()?,
+    };
+    if relays.len() < config.circuit_length {
+        return Err(PhantomBandError::Policy(format!("A {}-hop circuit needs {} relays, got {}", config.circuit_length, config.circuit_length, relays.len())));
+    }
+
+    let mut circuit = Circuit::new();
+    let built = circuit.build(&relays).await;
+    if let (Some(guards), Some(outcome)) = (guards, CircuitOutcome::of(&built, circuit.path.len())) {
+        guards.record(&relays[0].fingerprint(), outcome);
+        if let Err(e) = guards.save(&config.guard_state_file) {
+            error!("{}", e);
+        }
+    }
+    built.map(|()| circuit)
+}
+
+/// Builds circuits as they are asked for, one at a time, so that the SOCKS
+/// proxy keeps serving while a circuit is built. An open circuit built for a
+/// port is handed out again for that port, and, with the path fixed by
+/// `files`, for every port.
+async fn build_circuits(config: ClientConfig, files: Vec<String>, mut guards: Option<GuardSet>, clock: SharedClock, mut requests: mpsc::Receiver<CircuitRequest>) {
+    let mut circuits: HashMap<u16, CircuitHandle> = HashMap::new();
+    while let Some(request) = requests.recv().await {
+        circuits.retain(|_, circuit| !circuit.is_closed());
+        let port = if guards.is_some() { request.port } else { EXIT_PORT };
+        if let Some(circuit) = circuits.get(&port) {
+            let _ = request.answer.send(Some(circuit.clone()));
+            continue;
+        }
+        match build_circuit(&config, &files, &mut guards, request.port, clock.as_ref()).await {
+            Ok(circuit) => {
+                info!("Successfully built circuit {} through {} relays for port {}.", circuit.id, circuit.path.len(), request.port);
+                let circuit = CircuitHandle::spawn(circuit);
+                circuits.insert(port, circuit.clone());
+                let _ = request.answer.send(Some(circuit));
+            },
+            Err(e) => {
+                error!("Failed to build circuit for port {}: {}", request.port, e);
+                let _ = request.answer.send(None);
+            },
+        }
+    }
+}
 
 #[tokio::main]
 async fn main() {
     env_logger::init();
     info!("PhantomBand Client starting...");
-    let keypair = crypto::generate_keypair();
-    info!("Generated client keypair: {:?}", keypair);
 
-    let mut circuit = Circuit::new();
-    match circuit.connect_to_relay("127.0.0.1:8080").await {
-        Ok(_) => info!("Successfully connected to relay."),
-        Err(e) => error!("Failed to connect to relay: {}", e),
+    let mut config = ClientConfig::default();
+    if let Err(e) = config.validate() {
+        error!("Invalid configuration: {}", e);
+        return;
     }
+    let clock = SystemClock::shared();
+    // Descriptor files given as arguments fix the path, nearest hop first;
+    // otherwise it is picked from the consensus.
+    let files: Vec<String> = std::env::args().skip(1).collect();
+    let mut guards = None;
+    if files.is_empty() {
+        if let Err(e) = config.load_authorities().and_then(|()| config.authority_set()) {
+            error!("Cannot pick paths from the consensus: {}", e);
+            return;
+        }
+        match GuardSet::load(&config.guard_state_file, clock.clone()) {
+            Ok(loaded) => guards = Some(loaded),
+            Err(e) => {
+                error!("{}", e);
+                return;
+            },
+        }
+    }
+    let proxy = match SocksProxy::bind(config.socks_port, config.socks_credentials.clone()).await {
+        Ok(proxy) => proxy,
+        Err(e) => {
+            error!("{}", e);
+            return;
+        },
+    };
 
-    // TODO: Implement client logic for sending/receiving data
+    let (builder, requests) = mpsc::channel(CIRCUIT_REQUEST_QUEUE_LEN);
+    tokio::spawn(build_circuits(config, files, guards, clock, requests));
+    loop {
+        if let Some(circuit) = CircuitRequest::send(&builder, EXIT_PORT).await {
+            if let Ok(address) = proxy.local_addr() {
+                info!("SOCKS5 proxy on {} uses circuit {}", address, circuit.id());
+            }
+            if let Err(e) = proxy.serve(&circuit, &builder).await {
+                error!("SOCKS proxy failed: {}", e);
+                return;
+            }
+            info!("Circuit {} is gone; building a new one", circuit.id());
+        }
+        tokio::time::sleep(REBUILD_DELAY).await;
+    }
 }
@@ -0,0 +1,135 @@
+// client/src/path.rs
+
+//! Path selection.
+//!
+//! Hops are drawn from the verified consensus: the exit first, as the most
+//! constrained position, then the entry, then the middle hops. Each pick is
+//! random, weighted by the relay's consensus weight. Guards and exits count
+//! for less in positions other relays could fill, so that their bandwidth is
+//! left for the positions only they can. No two hops may be in one family or
+//! share a /16 IPv4 or /32 IPv6 network, so that a single operator or network
+//! never sees both ends of a circuit.
+
+use common::crypto::Fingerprint;
+use common::error::PhantomBandError;
+use common::protocol::{Consensus, RelayDescriptor, RelayFlags};
+use common::protocol::consensus::ConsensusRelay;
+use rand::Rng;
+use std::net::IpAddr;
+
+/// Guards and exits keep this fraction of their weight when picked for a
+/// position that needs neither flag.
+const SCARCE_WEIGHT_DIVISOR: u64 = 3;
+/// Prefix lengths of the networks two hops may not share.
+const IPV4_SUBNET_PREFIX: u32 = 16;
+const IPV6_SUBNET_PREFIX: u32 = 32;
+
+/// Where on a circuit a relay is placed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Position {
+    Entry,
+    Middle,
+    /// The last hop, which opens connections to `port`.
+    Exit { port: u16 },
+}
+
+/// Picks `length` relays for a circuit whose traffic leaves for `port`,
+/// nearest hop first.
+pub fn select_path<R: Rng + ?Sized>(consensus: &Consensus, length: usize, port: u16, rng: &mut R) -> Result<Vec<RelayDescriptor>, PhantomBandError> {
+    build_path(consensus, None, length, port, rng)
+}
+
+/// Like `select_path`, but the circuit enters the network through `guard`.
+pub fn select_path_from_guard<R: Rng + ?Sized>(consensus: &Consensus, guard: &RelayDescriptor, length: usize, port: u16, rng: &mut R) -> Result<Vec<RelayDescriptor>, PhantomBandError> {
+    build_path(consensus, Some(guard), length, port, rng)
+}
+
+fn build_path<R: Rng + ?Sized>(consensus: &Consensus, guard: Option<&RelayDescriptor>, length: usize, port: u16, rng: &mut R) -> Result<Vec<RelayDescriptor>, PhantomBandError> {
+    if length < 2 {
+        return Err(PhantomBandError::Policy(format!("A path needs an entry and an exit, not {} hops", length)));
+    }
+    let exit = select_relay(consensus, Position::Exit { port }, guard.as_slice(), rng)?;
+    let entry = match guard {
+        Some(guard) => guard,
+        None => select_relay(consensus, Position::Entry, &[exit], rng)?,
+    };
+    let mut path = vec![exit, entry];
+    for _ in 2..length {
+        let middle = select_relay(consensus, Position::Middle, &path, rng)?;
+        path.push(middle);
+    }
+    path.rotate_left(1);
+    Ok(path.into_iter().cloned().collect())
+}
+
+/// Picks a relay for `position` that may share a circuit with every relay in
+/// `path`.
+pub fn select_relay<'a, R: Rng + ?Sized>(consensus: &'a Consensus, position: Position, path: &[&RelayDescriptor], rng: &mut R) -> Result<&'a RelayDescriptor, PhantomBandError> {
+    pick(consensus, position, rng, |relay| !path.iter().any(|hop| conflicts(hop, &relay.descriptor)))
+}
+
+/// Picks a new entry guard that is not in `sampled` yet.
+pub fn sample_guard<'a, R: Rng + ?Sized>(consensus: &'a Consensus, sampled: &[Fingerprint], rng: &mut R) -> Result<&'a RelayDescriptor, PhantomBandError> {
+    pick(consensus, Position::Entry, rng, |relay| !sampled.contains(&relay.fingerprint()))
+}
+
+/// Picks a relay among those `allowed`, weighted for `position`.
+fn pick<'a, R: Rng + ?Sized>(consensus: &'a Consensus, position: Position, rng: &mut R, allowed: impl Fn(&ConsensusRelay) -> bool) -> Result<&'a RelayDescriptor, PhantomBandError> {
+    let candidates: Vec<(&ConsensusRelay, u64)> = consensus.relays.iter()
+        .filter(|relay| allowed(relay))
+        .map(|relay| (relay, position_weight(relay, position)))
+        .filter(|&(_, weight)| weight > 0)
+        .collect();
+    let total: u128 = candidates.iter().map(|&(_, weight)| weight as u128).sum();
+    if total == 0 {
+        return Err(PhantomBandError::Policy(format!("No usable relay for the {:?} position of the path", position)));
+    }
+    let mut point = rng.gen_range(0..total);
+    for (relay, weight) in candidates {
+        if point < weight as u128 {
+            return Ok(&relay.descriptor);
+        }
+        point -= weight as u128;
+    }
+    unreachable!("point is below the total weight")
+}
+
+/// The weight `relay` is picked with for `position`; zero if it cannot fill
+/// the position at all.
+fn position_weight(relay: &ConsensusRelay, position: Position) -> u64 {
+    let flags = relay.flags;
+    if !flags.contains(RelayFlags::VALID.union(RelayFlags::RUNNING).union(RelayFlags::FAST)) {
+        return 0;
+    }
+    let guard = flags.contains(RelayFlags::GUARD);
+    let exit = flags.contains(RelayFlags::EXIT) && !flags.contains(RelayFlags::BAD_EXIT);
+    let scarce = match position {
+        Position::Entry if !guard => return 0,
+        Position::Entry => exit,
+        Position::Middle => guard || exit,
+        Position::Exit { port } if !exit || !relay.descriptor.exit_policy.allows_port(port) => return 0,
+        Position::Exit { .. } => false,
+    };
+    if scarce {
+        relay.weight / SCARCE_WEIGHT_DIVISOR
+    } else {
+        relay.weight
+    }
+}
+
+/// Whether two relays must not be on one circuit together.
+fn conflicts(a: &RelayDescriptor, b: &RelayDescriptor) -> bool {
+    a.fingerprint() == b.fingerprint()
+        || a.is_family_of(b)
+        || a.addresses.iter().any(|x| b.addresses.iter().any(|y| same_subnet(x.ip(), y.ip())))
+}
+
+/// Whether two addresses share a /16 or /32 network. An IPv4-mapped IPv6
+/// address counts as the IPv4 address it maps.
+fn same_subnet(a: IpAddr, b: IpAddr) -> bool {
+    match (a.to_canonical(), b.to_canonical()) {
+        (IpAddr::V4(a), IpAddr::V4(b)) => u32::from(a) >> (32 - IPV4_SUBNET_PREFIX) == u32::from(b) >> (32 - IPV4_SUBNET_PREFIX),
+        (IpAddr::V6(a), IpAddr::V6(b)) => u128::from(a) >> (128 - IPV6_SUBNET_PREFIX) == u128::from(b) >> (128 - IPV6_SUBNET_PREFIX),
+        _ => false,
+    }
+}
@@ -1,7 +1,450 @@
 // client/src/socks.rs
 
-pub fn start_socks_proxy(port: u16) -> Result<(), String> {
-    println!("Starting SOCKS proxy on port: {}", port);
-    // TODO: Implement SOCKS proxy logic
+//! SOCKS5 proxy (RFC 1928), with username/password authentication
+//! (RFC 1929).
+//!
+//! Each accepted CONNECT becomes a stream on the current circuit. Hostnames
+//! are passed to the exit as they are and never resolved here, so that our
+//! own resolver does not learn where we connect. The exit of the current
+//! circuit was picked for one port only; a stream it refuses by its policy
+//! is tried again on a circuit built for the stream's port.
+//!
+//! Each UDP ASSOCIATE becomes a datagram stream, relayed through a UDP socket
+//! of its own on the loopback interface, for as long as the client keeps the
+//! TCP connection that asked for it open. Fragmented datagrams are dropped.
+
+use crate::stream::{BeginError, CircuitHandle, CircuitRequest, DatagramStream};
+use common::error::PhantomBandError;
+use common::protocol::stream::MAX_DATAGRAM_LEN;
+use common::protocol::{Datagram, EndReason, StreamTarget};
+use log::{info, warn};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use subtle::ConstantTimeEq;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::mpsc;
+
+pub const SOCKS_VERSION: u8 = 5;
+/// How long a client may take to send its greeting and request.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+const METHOD_NO_AUTH: u8 = 0x00;
+const METHOD_USERNAME_PASSWORD: u8 = 0x02;
+const METHOD_NONE_ACCEPTABLE: u8 = 0xff;
+const AUTH_VERSION: u8 = 1;
+
+const COMMAND_CONNECT: u8 = 1;
+const COMMAND_UDP_ASSOCIATE: u8 = 3;
+
+const ADDRESS_IPV4: u8 = 1;
+const ADDRESS_HOSTNAME: u8 = 3;
+const ADDRESS_IPV6: u8 = 4;
+
+/// The bound address of replies that have none to give.
+const NO_ADDRESS: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0);
+/// Largest UDP packet a client may send us.
+const MAX_UDP_PACKET_LEN: usize = 65_535;
+
+/// Reply codes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Reply {
+    Succeeded = 0,
+    GeneralFailure = 1,
+    NotAllowed = 2,
+    NetworkUnreachable = 3,
+    HostUnreachable = 4,
+    ConnectionRefused = 5,
+    TtlExpired = 6,
+    CommandNotSupported = 7,
+    AddressTypeNotSupported = 8,
+}
+
+impl From<EndReason> for Reply {
+    fn from(reason: EndReason) -> Self {
+        match reason {
+            EndReason::ExitPolicy => Reply::NotAllowed,
+            EndReason::ResolveFailed | EndReason::Unreachable => Reply::HostUnreachable,
+            EndReason::ConnectRefused => Reply::ConnectionRefused,
+            EndReason::Timeout => Reply::TtlExpired,
+            EndReason::Done | EndReason::Misc | EndReason::Unknown => Reply::GeneralFailure,
+        }
+    }
+}
+
+/// The username and password clients must give, when set.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SocksCredentials {
+    pub username: String,
+    pub password: String,
+}
+
+/// What a client asked for.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SocksRequest {
+    Connect(StreamTarget),
+    /// Relay UDP for the client, which says where it will send from, or
+    /// gives an unspecified address if it does not know.
+    UdpAssociate(StreamTarget),
+}
+
+/// A bound SOCKS5 proxy.
+pub struct SocksProxy {
+    listener: TcpListener,
+    credentials: Option<SocksCredentials>,
+}
+
+impl SocksProxy {
+    /// Listens on `port` of the loopback interface. Without `credentials`,
+    /// clients may skip authentication, and any username and password they
+    /// do send is accepted.
+    pub async fn bind(port: u16, credentials: Option<SocksCredentials>) -> Result<SocksProxy, PhantomBandError> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port)).await
+            .map_err(|e| PhantomBandError::from(e).context(&format!("Failed to bind SOCKS port {}", port)))?;
+        Ok(SocksProxy { listener, credentials })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, PhantomBandError> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    /// Accepts clients and attaches their streams to `circuit` until the
+    /// circuit is gone. Circuits for streams its exit refuses are asked of
+    /// `builder`.
+    pub async fn serve(&self, circuit: &CircuitHandle, builder: &mpsc::Sender<CircuitRequest>) -> Result<(), PhantomBandError> {
+        loop {
+            tokio::select! {
+                accepted = self.listener.accept() => {
+                    let (connection, address) = accepted?;
+                    tokio::spawn(serve_client(connection, address, self.credentials.clone(), circuit.clone(), builder.clone()));
+                },
+                () = circuit.closed() => return Ok(()),
+            }
+        }
+    }
+}
+
+async fn serve_client(mut connection: TcpStream, address: SocketAddr, credentials: Option<SocksCredentials>, circuit: CircuitHandle, builder: mpsc::Sender<CircuitRequest>) {
+    let request = match tokio::time::timeout(HANDSHAKE_TIMEOUT, handshake(&mut connection, credentials.as_ref())).await {
+        Ok(Ok(request)) => request,
+        Ok(Err(e)) => return warn!("SOCKS handshake with {} failed: {}", address, e),
+        Err(_) => return warn!("SOCKS handshake with {} timed out", address),
+    };
+    match request {
+        SocksRequest::Connect(target) => connect(connection, address, target, circuit, &builder).await,
+        SocksRequest::UdpAssociate(source) => associate(connection, address, source, &circuit).await,
+    }
+}
+
+async fn connect(mut connection: TcpStream, address: SocketAddr, target: StreamTarget, mut circuit: CircuitHandle, builder: &mpsc::Sender<CircuitRequest>) {
+    let mut begun = circuit.begin(target.clone()).await;
+    if let Err(BeginError::Refused(EndReason::ExitPolicy)) = begun {
+        let other = CircuitRequest::send(builder, target.port()).await.filter(|other| other.id() != circuit.id());
+        if let Some(other) = other {
+            info!("Exit of circuit {} refused a stream by its policy; trying circuit {}", circuit.id(), other.id());
+            circuit = other;
+            begun = circuit.begin(target).await;
+        }
+    }
+    let circuit = &circuit;
+    let stream = match begun {
+        Ok(stream) => stream,
+        Err(e) => return refuse(&mut connection, address, circuit, e).await,
+    };
+    if let Err(e) = send_reply(&mut connection, Reply::Succeeded, NO_ADDRESS).await {
+        return warn!("Failed to answer SOCKS client {}: {}", address, e);
+    }
+    let id = stream.id();
+    info!("Attached {} to stream {} on circuit {}", address, id, circuit.id());
+    if let Err(e) = stream.relay(connection).await {
+        warn!("Stream {} for {} failed: {}", id, address, e);
+    }
+}
+
+async fn associate(mut connection: TcpStream, address: SocketAddr, source: StreamTarget, circuit: &CircuitHandle) {
+    let socket = match UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await {
+        Ok(socket) => socket,
+        Err(e) => {
+            warn!("Failed to bind a UDP socket for {}: {}", address, e);
+            let _ = send_reply(&mut connection, Reply::GeneralFailure, NO_ADDRESS).await;
+            return;
+        },
+    };
+    let stream = match circuit.begin_datagram().await {
+        Ok(stream) => stream,
+        Err(e) => return refuse(&mut connection, address, circuit, e).await,
+    };
+    let bound = match socket.local_addr() {
+        Ok(bound) => bound,
+        Err(e) => {
+            warn!("Failed to bind a UDP socket for {}: {}", address, e);
+            let _ = send_reply(&mut connection, Reply::GeneralFailure, NO_ADDRESS).await;
+            return;
+        },
+    };
+    if let Err(e) = send_reply(&mut connection, Reply::Succeeded, bound).await {
+        return warn!("Failed to answer SOCKS client {}: {}", address, e);
+    }
+    let id = stream.id();
+    info!("Attached {} to datagram stream {} on circuit {} at {}", address, id, circuit.id(), bound);
+    if let Err(e) = relay_datagrams(&mut connection, address, source, &socket, stream).await {
+        warn!("Datagram stream {} for {} failed: {}", id, address, e);
+    }
+}
+
+async fn refuse(connection: &mut TcpStream, address: SocketAddr, circuit: &CircuitHandle, error: BeginError) {
+    warn!("Stream for {} on circuit {} failed: {}", address, circuit.id(), error);
+    let reply = match error {
+        BeginError::Refused(reason) => Reply::from(reason),
+        BeginError::Circuit(_) => Reply::GeneralFailure,
+    };
+    let _ = send_reply(connection, reply, NO_ADDRESS).await;
+}
+
+/// Carries datagrams between the client's UDP socket and `stream` until the
+/// client closes `connection` or the stream ends. Only the client's own host
+/// may use the association, from the port it named, or else from the port
+/// of its first datagram.
+async fn relay_datagrams(connection: &mut TcpStream, address: SocketAddr, source: StreamTarget, socket: &UdpSocket, mut stream: DatagramStream) -> Result<(), PhantomBandError> {
+    let mut client = match source {
+        StreamTarget::Address(source) if source.ip() == address.ip() && source.port() != 0 => Some(source),
+        _ => None,
+    };
+    let mut packet = vec![0u8; MAX_UDP_PACKET_LEN];
+    let mut control = [0u8; 1];
+    loop {
+        tokio::select! {
+            read = connection.read(&mut control) => {
+                // Anything the client sends here is meaningless, and closing
+                // the connection ends the association.
+                if read? == 0 {
+                    return Ok(());
+                }
+            },
+            received = socket.recv_from(&mut packet) => {
+                let (n, from) = received?;
+                if from.ip() != address.ip() || client.is_some_and(|client| client != from) {
+                    continue;
+                }
+                client = Some(from);
+                match parse_udp_packet(&packet[..n]) {
+                    Ok(datagram) => stream.send(datagram)?,
+                    Err(e) => warn!("Dropping UDP packet from {}: {}", from, e),
+                }
+            },
+            datagram = stream.receive() => {
+                let Some(datagram) = datagram else {
+                    return Ok(());
+                };
+                // Until the client sent something, nobody can have answered.
+                if let Some(client) = client {
+                    socket.send_to(&udp_packet(&datagram), client).await?;
+                }
+            },
+        }
+    }
+}
+
+/// Parses a SOCKS5 UDP request: `RSV(2) FRAG(1) ATYP DST.ADDR DST.PORT DATA`.
+pub fn parse_udp_packet(packet: &[u8]) -> Result<Datagram, PhantomBandError> {
+    let [_, _, fragment, address_type] = packet.get(..4)
+        .and_then(|header| <[u8; 4]>::try_from(header).ok())
+        .ok_or_else(|| PhantomBandError::Protocol(format!("SOCKS UDP packet of {} bytes", packet.len())))?;
+    if fragment != 0 {
+        return Err(PhantomBandError::Policy(format!("Fragmented SOCKS UDP packet ({})", fragment)));
+    }
+    let mut rest = &packet[4..];
+    let peer = parse_target(&mut rest, address_type)?;
+    if rest.len() > MAX_DATAGRAM_LEN {
+        return Err(PhantomBandError::Protocol(format!("Datagram is {} bytes (max {})", rest.len(), MAX_DATAGRAM_LEN)));
+    }
+    Ok(Datagram { peer, data: rest.to_vec() })
+}
+
+/// Builds the SOCKS5 UDP packet that carries `datagram` to the client.
+pub fn udp_packet(datagram: &Datagram) -> Vec<u8> {
+    let mut packet = vec![0, 0, 0];
+    write_target(&mut packet, &datagram.peer);
+    packet.extend_from_slice(&datagram.data);
+    packet
+}
+
+/// Runs the SOCKS5 greeting, authentication and request on `connection`.
+/// Requests we cannot serve are answered with the matching reply code
+/// before the error is returned.
+pub async fn handshake<S: AsyncRead + AsyncWrite + Unpin>(connection: &mut S, credentials: Option<&SocksCredentials>) -> Result<SocksRequest, PhantomBandError> {
+    let [version, count] = read_array(connection).await?;
+    check_version(version)?;
+    let mut methods = vec![0u8; count as usize];
+    connection.read_exact(&mut methods).await?;
+
+    let method = match credentials {
+        Some(_) if methods.contains(&METHOD_USERNAME_PASSWORD) => METHOD_USERNAME_PASSWORD,
+        None if methods.contains(&METHOD_NO_AUTH) => METHOD_NO_AUTH,
+        None if methods.contains(&METHOD_USERNAME_PASSWORD) => METHOD_USERNAME_PASSWORD,
+        _ => METHOD_NONE_ACCEPTABLE,
+    };
+    connection.write_all(&[SOCKS_VERSION, method]).await?;
+    match method {
+        METHOD_NONE_ACCEPTABLE => return Err(PhantomBandError::Policy(format!("No acceptable authentication method in {:?}", methods))),
+        METHOD_USERNAME_PASSWORD => authenticate(connection, credentials).await?,
+        _ => {},
+    }
+
+    let [version, command, _reserved, address_type] = read_array(connection).await?;
+    check_version(version)?;
+    let target = match read_target(connection, address_type).await {
+        Ok(target) => target,
+        Err(e) => {
+            let reply = match address_type {
+                ADDRESS_IPV4 | ADDRESS_HOSTNAME | ADDRESS_IPV6 => Reply::HostUnreachable,
+                _ => Reply::AddressTypeNotSupported,
+            };
+            send_reply(connection, reply, NO_ADDRESS).await?;
+            return Err(e);
+        },
+    };
+    match command {
+        COMMAND_CONNECT => Ok(SocksRequest::Connect(target)),
+        COMMAND_UDP_ASSOCIATE => Ok(SocksRequest::UdpAssociate(target)),
+        _ => {
+            send_reply(connection, Reply::CommandNotSupported, NO_ADDRESS).await?;
+            Err(PhantomBandError::Policy(format!("Unsupported SOCKS command {}", command)))
+        },
+    }
+}
+
+/// Username/password subnegotiation. Without configured credentials any
+/// pair is accepted.
+async fn authenticate<S: AsyncRead + AsyncWrite + Unpin>(connection: &mut S, credentials: Option<&SocksCredentials>) -> Result<(), PhantomBandError> {
+    let [version, username_len] = read_array(connection).await?;
+    if version != AUTH_VERSION {
+        return Err(PhantomBandError::Protocol(format!("Unsupported SOCKS authentication version {}", version)));
+    }
+    let mut username = vec![0u8; username_len as usize];
+    connection.read_exact(&mut username).await?;
+    let [password_len] = read_array(connection).await?;
+    let mut password = vec![0u8; password_len as usize];
+    connection.read_exact(&mut password).await?;
+
+    let accepted = credentials.is_none_or(|expected| {
+        let username_matches = username.ct_eq(expected.username.as_bytes());
+        let password_matches = password.ct_eq(expected.password.as_bytes());
+        bool::from(username_matches & password_matches)
+    });
+    connection.write_all(&[AUTH_VERSION, if accepted { 0 } else { 1 }]).await?;
+    if !accepted {
+        return Err(PhantomBandError::Policy("Wrong SOCKS username or password".to_string()));
+    }
     Ok(())
 }
+
+async fn read_target<S: AsyncRead + Unpin>(connection: &mut S, address_type: u8) -> Result<StreamTarget, PhantomBandError> {
+    let ip = match address_type {
+        ADDRESS_IPV4 => IpAddr::V4(Ipv4Addr::from(read_array::<_, 4>(connection).await?)),
+        ADDRESS_IPV6 => IpAddr::V6(Ipv6Addr::from(read_array::<_, 16>(connection).await?)),
+        ADDRESS_HOSTNAME => {
+            let [len] = read_array(connection).await?;
+            let mut host = vec![0u8; len as usize];
+            connection.read_exact(&mut host).await?;
+            let port = u16::from_be_bytes(read_array(connection).await?);
+            return hostname_target(host, port);
+        },
+        other => return Err(unsupported_address_type(other)),
+    };
+    let port = u16::from_be_bytes(read_array(connection).await?);
+    Ok(StreamTarget::Address(SocketAddr::new(ip, port)))
+}
+
+/// Parses `ATYP ADDR PORT` from the front of `bytes` and leaves the rest
+/// there, as `read_target` does from a connection.
+fn parse_target(bytes: &mut &[u8], address_type: u8) -> Result<StreamTarget, PhantomBandError> {
+    let ip = match address_type {
+        ADDRESS_IPV4 => IpAddr::V4(Ipv4Addr::from(take_array::<4>(bytes)?)),
+        ADDRESS_IPV6 => IpAddr::V6(Ipv6Addr::from(take_array::<16>(bytes)?)),
+        ADDRESS_HOSTNAME => {
+            let [len] = take_array(bytes)?;
+            let host = take(bytes, len as usize)?.to_vec();
+            let port = u16::from_be_bytes(take_array(bytes)?);
+            return hostname_target(host, port);
+        },
+        other => return Err(unsupported_address_type(other)),
+    };
+    let port = u16::from_be_bytes(take_array(bytes)?);
+    Ok(StreamTarget::Address(SocketAddr::new(ip, port)))
+}
+
+fn hostname_target(host: Vec<u8>, port: u16) -> Result<StreamTarget, PhantomBandError> {
+    let host = String::from_utf8(host)
+        .map_err(|_| PhantomBandError::Protocol("SOCKS hostname is not UTF-8".to_string()))?;
+    // Applications often pass addresses as hostnames.
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        return Ok(StreamTarget::Address(SocketAddr::new(ip, port)));
+    }
+    let target = StreamTarget::Hostname { host, port };
+    target.validate()?;
+    Ok(target)
+}
+
+fn unsupported_address_type(address_type: u8) -> PhantomBandError {
+    PhantomBandError::Protocol(format!("Unsupported SOCKS address type {}", address_type))
+}
+
+/// Sends a reply. Only UDP ASSOCIATE has a bound address to give: for
+/// CONNECT it would be the exit's, which is not ours to give away and which
+/// clients do not need.
+async fn send_reply<S: AsyncWrite + Unpin>(connection: &mut S, reply: Reply, bound: SocketAddr) -> Result<(), PhantomBandError> {
+    let mut bytes = vec![SOCKS_VERSION, reply as u8, 0];
+    write_target(&mut bytes, &StreamTarget::Address(bound));
+    connection.write_all(&bytes).await?;
+    Ok(())
+}
+
+/// Appends `ATYP ADDR PORT` for `target`.
+fn write_target(bytes: &mut Vec<u8>, target: &StreamTarget) {
+    match target {
+        StreamTarget::Address(SocketAddr::V4(address)) => {
+            bytes.push(ADDRESS_IPV4);
+            bytes.extend_from_slice(&address.ip().octets());
+        },
+        StreamTarget::Address(SocketAddr::V6(address)) => {
+            bytes.push(ADDRESS_IPV6);
+            bytes.extend_from_slice(&address.ip().octets());
+        },
+        StreamTarget::Hostname { host, .. } => {
+            // Hostnames are validated to fit in a byte.
+            bytes.push(ADDRESS_HOSTNAME);
+            bytes.push(host.len() as u8);
+            bytes.extend_from_slice(host.as_bytes());
+        },
+    }
+    bytes.extend_from_slice(&target.port().to_be_bytes());
+}
+
+fn check_version(version: u8) -> Result<(), PhantomBandError> {
+    if version != SOCKS_VERSION {
+        return Err(PhantomBandError::Protocol(format!("Unsupported SOCKS version {}", version)));
+    }
+    Ok(())
+}
+
+async fn read_array<S: AsyncRead + Unpin, const N: usize>(connection: &mut S) -> Result<[u8; N], PhantomBandError> {
+    let mut bytes = [0u8; N];
+    connection.read_exact(&mut bytes).await?;
+    Ok(bytes)
+}
+
+/// Splits `len` bytes off the front of `bytes`.
+fn take<'a>(bytes: &mut &'a [u8], len: usize) -> Result<&'a [u8], PhantomBandError> {
+    if bytes.len() < len {
+        return Err(PhantomBandError::Protocol(format!("SOCKS address truncated: {} bytes left, {} needed", bytes.len(), len)));
+    }
+    let (taken, rest) = bytes.split_at(len);
+    *bytes = rest;
+    Ok(taken)
+}
+
+fn take_array<const N: usize>(bytes: &mut &[u8]) -> Result<[u8; N], PhantomBandError> {
+    Ok(take(bytes, N)?.try_into().expect("Took N bytes"))
+}
@@ -0,0 +1,416 @@
+// client/src/stream.rs
+
+//! Streams over a built circuit.
+//!
+//! Once built, a circuit is handed to a task of its own, which owns the link
+//! to the first hop and multiplexes any number of streams over it by stream
+//! id. Applications reach the task through a `CircuitHandle`. There is no
+//! per-stream flow control yet: a stream whose application reads slower than
+//! its destination sends holds up the whole circuit. Datagram streams are
+//! the exception: datagrams are dropped rather than wait for room, either way.
+
+use crate::circuit::Circuit;
+use common::error::PhantomBandError;
+use common::protocol::stream::MAX_DATAGRAM_LEN;
+use common::protocol::{Datagram, DatagramAssembler, EndReason, PhantomBandMessage, RelayCell, RelayCommand, StreamTarget, RELAY_DATA_LEN};
+use log::{debug, info, warn};
+use std::collections::HashMap;
+use std::fmt;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, oneshot};
+
+/// How long the exit may take to open a stream.
+const BEGIN_TIMEOUT: Duration = Duration::from_secs(30);
+/// Requests queued for the circuit task before streams wait for it.
+const REQUEST_QUEUE_LEN: usize = 256;
+/// Cells queued for a stream before the circuit waits for it.
+const STREAM_QUEUE_LEN: usize = 64;
+
+/// What streams ask of the circuit task.
+enum Request {
+    /// Opens a stream with `Begin` or `BeginDatagram`.
+    Begin { message: PhantomBandMessage, events: mpsc::Sender<StreamEvent>, opened: oneshot::Sender<Result<u16, PhantomBandError>> },
+    Data { stream_id: u16, data: Vec<u8> },
+    Datagram { stream_id: u16, datagram: Datagram },
+    End { stream_id: u16, reason: EndReason },
+}
+
+/// What the exit sent for one stream.
+enum StreamEvent {
+    Connected,
+    Data(Vec<u8>),
+    Datagram(Datagram),
+    End(EndReason),
+}
+
+/// Why a stream could not be opened.
+#[derive(Debug)]
+pub enum BeginError {
+    /// The exit could not or would not connect to the target.
+    Refused(EndReason),
+    /// The circuit failed or is gone.
+    Circuit(PhantomBandError),
+}
+
+impl fmt::Display for BeginError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BeginError::Refused(reason) => write!(f, "Exit refused the stream: {:?}", reason),
+            BeginError::Circuit(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+/// Asks whoever builds circuits for one whose exit allows `port`. The answer
+/// is None when no such circuit can be built.
+pub struct CircuitRequest {
+    pub port: u16,
+    pub answer: oneshot::Sender<Option<CircuitHandle>>,
+}
+
+impl CircuitRequest {
+    /// Sends a request to `builder` and waits for the circuit.
+    pub async fn send(builder: &mpsc::Sender<CircuitRequest>, port: u16) -> Option<CircuitHandle> {
+        let (answer, circuit) = oneshot::channel();
+        builder.send(CircuitRequest { port, answer }).await.ok()?;
+        circuit.await.ok().flatten()
+    }
+}
+
+/// A built circuit, served by its own task. Clones share the circuit, which
+/// is closed once every handle and stream is dropped.
+#[derive(Clone)]
+pub struct CircuitHandle {
+    id: u64,
+    requests: mpsc::Sender<Request>,
+}
+
+impl CircuitHandle {
+    /// Hands `circuit` to a task that serves its streams.
+    pub fn spawn(circuit: Circuit) -> CircuitHandle {
+        let (requests, queue) = mpsc::channel(REQUEST_QUEUE_LEN);
+        let id = circuit.id;
+        tokio::spawn(serve_circuit(circuit, queue));
+        CircuitHandle { id, requests }
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Waits until the circuit is gone.
+    pub async fn closed(&self) {
+        self.requests.closed().await
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.requests.is_closed()
+    }
+
+    /// Asks the exit to open a stream to `target`, and waits for its answer.
+    pub async fn begin(&self, target: StreamTarget) -> Result<Stream, BeginError> {
+        let (id, events) = self.open(PhantomBandMessage::Begin { target }).await?;
+        Ok(Stream { id, requests: self.requests.clone(), events, ended: false })
+    }
+
+    /// Asks the exit for a datagram stream, and waits for its answer.
+    pub async fn begin_datagram(&self) -> Result<DatagramStream, BeginError> {
+        let (id, events) = self.open(PhantomBandMessage::BeginDatagram).await?;
+        Ok(DatagramStream { id, requests: self.requests.clone(), events, ended: false })
+    }
+
+    /// Sends `message` on a new stream, and returns the stream once the exit
+    /// answers `Connected`.
+    async fn open(&self, message: PhantomBandMessage) -> Result<(u16, mpsc::Receiver<StreamEvent>), BeginError> {
+        let (events_sender, mut events) = mpsc::channel(STREAM_QUEUE_LEN);
+        let (opened, id) = oneshot::channel();
+        self.requests.send(Request::Begin { message, events: events_sender, opened }).await
+            .map_err(|_| BeginError::Circuit(circuit_closed()))?;
+        let id = id.await.unwrap_or_else(|_| Err(circuit_closed())).map_err(BeginError::Circuit)?;
+
+        let answer = tokio::time::timeout(BEGIN_TIMEOUT, events.recv()).await;
+        let error = match answer {
+            Ok(Some(StreamEvent::Connected)) => return Ok((id, events)),
+            // The exit ended the stream, so there is nothing left to end.
+            Ok(Some(StreamEvent::End(reason))) => return Err(BeginError::Refused(reason)),
+            Ok(Some(_)) => BeginError::Circuit(PhantomBandError::Protocol("Exit sent data before Connected".to_string())),
+            Ok(None) => BeginError::Circuit(circuit_closed()),
+            Err(_) => BeginError::Refused(EndReason::Timeout),
+        };
+        let _ = self.requests.try_send(Request::End { stream_id: id, reason: EndReason::Done });
+        Err(error)
+    }
+}
+
+/// An open stream. Dropping it ends the stream.
+pub struct Stream {
+    id: u16,
+    requests: mpsc::Sender<Request>,
+    events: mpsc::Receiver<StreamEvent>,
+    /// Whether either side already sent `End`.
+    ended: bool,
+}
+
+impl Stream {
+    pub fn id(&self) -> u16 {
+        self.id
+    }
+
+    /// Carries data between `connection` and the stream until either side
+    /// closes.
+    pub async fn relay(mut self, connection: TcpStream) -> Result<(), PhantomBandError> {
+        let (mut reader, mut writer) = connection.into_split();
+        let (id, requests, events) = (self.id, &self.requests, &mut self.events);
+        // Both directions run at once, so that the circuit task waiting on
+        // our events never waits on us waiting on it.
+        let upstream = async {
+            let mut buffer = [0u8; RELAY_DATA_LEN];
+            loop {
+                let n = reader.read(&mut buffer).await?;
+                if n == 0 {
+                    return Ok(false);
+                }
+                requests.send(Request::Data { stream_id: id, data: buffer[..n].to_vec() }).await
+                    .map_err(|_| circuit_closed())?;
+            }
+        };
+        let downstream = async {
+            loop {
+                match events.recv().await {
+                    Some(StreamEvent::Data(data)) => writer.write_all(&data).await?,
+                    Some(StreamEvent::End(reason)) => {
+                        info!("Exit ended stream {}: {:?}", id, reason);
+                        writer.shutdown().await?;
+                        return Ok(true);
+                    },
+                    Some(StreamEvent::Connected) => return Err(PhantomBandError::Protocol(format!("Stream {} connected twice", id))),
+                    Some(StreamEvent::Datagram(_)) => return Err(PhantomBandError::Protocol(format!("Datagram on stream {}", id))),
+                    None => return Err(circuit_closed()),
+                }
+            }
+        };
+        let result: Result<bool, PhantomBandError> = tokio::select! {
+            result = upstream => result,
+            result = downstream => result,
+        };
+        self.ended = result.as_ref().is_ok_and(|ended_by_exit| *ended_by_exit);
+        result.map(|_| ())
+    }
+}
+
+impl Drop for Stream {
+    fn drop(&mut self) {
+        if !self.ended {
+            // Should the queue be full, the circuit task ends the stream once
+            // the exit next sends on it.
+            let _ = self.requests.try_send(Request::End { stream_id: self.id, reason: EndReason::Done });
+        }
+    }
+}
+
+/// An open datagram stream. Dropping it ends the stream.
+pub struct DatagramStream {
+    id: u16,
+    requests: mpsc::Sender<Request>,
+    events: mpsc::Receiver<StreamEvent>,
+    /// Whether the exit already sent `End`.
+    ended: bool,
+}
+
+impl DatagramStream {
+    pub fn id(&self) -> u16 {
+        self.id
+    }
+
+    /// Sends `datagram` through the exit to its peer. Like UDP, this does not
+    /// wait: the datagram is dropped when the circuit is congested.
+    pub fn send(&self, datagram: Datagram) -> Result<(), PhantomBandError> {
+        if datagram.data.len() > MAX_DATAGRAM_LEN {
+            return Err(PhantomBandError::Protocol(format!("Datagram is {} bytes (max {})", datagram.data.len(), MAX_DATAGRAM_LEN)));
+        }
+        datagram.peer.validate()?;
+        match self.requests.try_send(Request::Datagram { stream_id: self.id, datagram }) {
+            Ok(()) | Err(mpsc::error::TrySendError::Full(_)) => Ok(()),
+            Err(mpsc::error::TrySendError::Closed(_)) => Err(circuit_closed()),
+        }
+    }
+
+    /// The next datagram from any peer, or None once the stream has ended.
+    pub async fn receive(&mut self) -> Option<Datagram> {
+        match self.events.recv().await {
+            Some(StreamEvent::Datagram(datagram)) => Some(datagram),
+            Some(StreamEvent::End(reason)) => {
+                info!("Exit ended datagram stream {}: {:?}", self.id, reason);
+                self.ended = true;
+                None
+            },
+            Some(StreamEvent::Connected | StreamEvent::Data(_)) => {
+                warn!("Unexpected event on datagram stream {}", self.id);
+                None
+            },
+            None => None,
+        }
+    }
+}
+
+impl Drop for DatagramStream {
+    fn drop(&mut self) {
+        if !self.ended {
+            let _ = self.requests.try_send(Request::End { stream_id: self.id, reason: EndReason::Done });
+        }
+    }
+}
+
+/// Serves the streams of `circuit` until it fails or every handle to it is
+/// dropped.
+async fn serve_circuit(mut circuit: Circuit, mut requests: mpsc::Receiver<Request>) {
+    let id = circuit.id;
+    let mut streams = Streams::default();
+    let result = async {
+        loop {
+            tokio::select! {
+                request = requests.recv() => match request {
+                    Some(request) => streams.handle_request(&mut circuit, request).await?,
+                    None => return circuit.close().await,
+                },
+                cell = circuit.receive() => streams.handle_cell(&mut circuit, cell?).await?,
+            }
+        }
+    }.await;
+    match result {
+        Ok(()) => info!("Closed circuit {}", id),
+        Err(e) => {
+            warn!("Circuit {} failed: {}", id, e);
+            // Failures of the circuit itself have torn it down already.
+            if let Err(e) = circuit.close().await {
+                warn!("Failed to close circuit {}: {}", id, e);
+            }
+        },
+    }
+}
+
+/// The open streams of a circuit, by id.
+#[derive(Default)]
+struct Streams {
+    open: HashMap<u16, mpsc::Sender<StreamEvent>>,
+    /// The partial datagram of each open datagram stream.
+    assemblers: HashMap<u16, DatagramAssembler>,
+    last_id: u16,
+}
+
+impl Streams {
+    async fn handle_request(&mut self, circuit: &mut Circuit, request: Request) -> Result<(), PhantomBandError> {
+        match request {
+            Request::Begin { message, events, opened } => {
+                let Some(stream_id) = self.free_id() else {
+                    let _ = opened.send(Err(PhantomBandError::Policy(format!("Circuit {} has no free stream id", circuit.id))));
+                    return Ok(());
+                };
+                match &message {
+                    PhantomBandMessage::Begin { target } => {
+                        info!("Opening stream {} on circuit {}", stream_id, circuit.id);
+                        debug!("Stream {} on circuit {} goes to {}", stream_id, circuit.id, target);
+                    },
+                    _ => {
+                        info!("Opening datagram stream {} on circuit {}", stream_id, circuit.id);
+                        self.assemblers.insert(stream_id, DatagramAssembler::default());
+                    },
+                }
+                circuit.send(&message.to_relay_cell(stream_id)?).await?;
+                self.open.insert(stream_id, events);
+                let _ = opened.send(Ok(stream_id));
+            },
+            Request::Data { stream_id, data } => {
+                if self.open.contains_key(&stream_id) {
+                    circuit.send(&RelayCell::new(RelayCommand::Data, stream_id, &data)?).await?;
+                }
+            },
+            Request::Datagram { stream_id, datagram } => {
+                if self.assemblers.contains_key(&stream_id) {
+                    for cell in datagram.to_cells(stream_id)? {
+                        circuit.send(&cell).await?;
+                    }
+                }
+            },
+            Request::End { stream_id, reason } => {
+                self.assemblers.remove(&stream_id);
+                if self.open.remove(&stream_id).is_some() {
+                    circuit.send(&PhantomBandMessage::End { reason }.to_relay_cell(stream_id)?).await?;
+                }
+            },
+        }
+        Ok(())
+    }
+
+    /// Passes a cell from the exit to its stream. Cells for streams we
+    /// already ended are dropped.
+    async fn handle_cell(&mut self, circuit: &mut Circuit, cell: RelayCell) -> Result<(), PhantomBandError> {
+        let event = match cell.command {
+            RelayCommand::Data if cell.stream_id != 0 && !self.assemblers.contains_key(&cell.stream_id) => StreamEvent::Data(cell.data),
+            RelayCommand::Datagram if self.open.contains_key(&cell.stream_id) => {
+                let assembler = self.assemblers.get_mut(&cell.stream_id)
+                    .ok_or_else(|| PhantomBandError::Protocol(format!("Datagram cell on stream {}", cell.stream_id)))?;
+                let Some(datagram) = assembler.push(&cell)? else {
+                    return Ok(());
+                };
+                return self.pass_datagram(circuit, cell.stream_id, datagram).await;
+            },
+            // What the exit sends on a stream we already ended is dropped.
+            RelayCommand::Datagram => return Ok(()),
+            RelayCommand::Connected | RelayCommand::End => match PhantomBandMessage::from_relay_cell(&cell)? {
+                PhantomBandMessage::Connected => StreamEvent::Connected,
+                PhantomBandMessage::End { reason } => StreamEvent::End(reason),
+                other => return Err(PhantomBandError::Protocol(format!("Unexpected stream message {:?}", other))),
+            },
+            command => return Err(PhantomBandError::Protocol(format!("Unexpected relay {:?} cell on stream {}", command, cell.stream_id))),
+        };
+        let ended = matches!(event, StreamEvent::End(_));
+        let Some(stream) = self.open.get(&cell.stream_id) else {
+            return Ok(());
+        };
+        if stream.send(event).await.is_err() && !ended {
+            // The application is gone without ending the stream.
+            self.open.remove(&cell.stream_id);
+            self.assemblers.remove(&cell.stream_id);
+            return circuit.send(&PhantomBandMessage::End { reason: EndReason::Done }.to_relay_cell(cell.stream_id)?).await;
+        }
+        if ended {
+            self.open.remove(&cell.stream_id);
+            self.assemblers.remove(&cell.stream_id);
+        }
+        Ok(())
+    }
+
+    /// Passes a datagram to its stream, or drops it if the application has
+    /// not kept up.
+    async fn pass_datagram(&mut self, circuit: &mut Circuit, stream_id: u16, datagram: Datagram) -> Result<(), PhantomBandError> {
+        let Some(stream) = self.open.get(&stream_id) else {
+            return Ok(());
+        };
+        match stream.try_send(StreamEvent::Datagram(datagram)) {
+            Ok(()) | Err(mpsc::error::TrySendError::Full(_)) => Ok(()),
+            Err(mpsc::error::TrySendError::Closed(_)) => {
+                self.open.remove(&stream_id);
+                self.assemblers.remove(&stream_id);
+                circuit.send(&PhantomBandMessage::End { reason: EndReason::Done }.to_relay_cell(stream_id)?).await
+            },
+        }
+    }
+
+    /// The next stream id not in use, if any is left.
+    fn free_id(&mut self) -> Option<u16> {
+        for _ in 0..u16::MAX {
+            self.last_id = self.last_id.checked_add(1).unwrap_or(1);
+            if !self.open.contains_key(&self.last_id) {
+                return Some(self.last_id);
+            }
+        }
+        None
+    }
+}
+
+fn circuit_closed() -> PhantomBandError {
+    PhantomBandError::Transport("Circuit is closed".to_string())
+}
@@ -1,6 +1,8 @@
 // client/src/vpn.rs
 
-pub fn start_vpn_service() -> Result<(), String> {
+use common::error::PhantomBandError;
+
+pub fn start_vpn_service() -> Result<(), PhantomBandError> {
     println!("Starting VPN service...");
     // TODO: Implement VPN service logic
     Ok(())
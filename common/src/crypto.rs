@@ -1,54 +1,296 @@
+// common/src/crypto.rs
 
-use rand::Rng;
-use chacha20poly1305::{
-    ChaCha20Poly1305, Key, Nonce // Or `XChaCha20Poly1305`, `XNonce`
-};
-use chacha20poly1305::aead::{Aead, NewAead};
+pub mod cipher;
+pub mod kdf;
+pub mod mlkem;
+pub mod ntor;
+pub mod onion;
+pub mod replay;
+pub mod secret;
+pub mod sphinx;
+
+pub use cipher::{CipherState, DeliveryMode};
+pub use secret::SessionKey;
+
+use rand::rngs::OsRng;
+use x25519_dalek::StaticSecret;
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
 use log::{info, error};
+use serde::{Serialize, Deserialize};
+use crate::error::PhantomBandError;
+use secret::SecretBox;
+use zeroize::Zeroizing;
+use std::fmt;
+use std::str::FromStr;
+
+pub const KEY_LEN: usize = 32;
+
+const LINK_HANDSHAKE_CONTEXT: &[u8] = b"PhantomBand v1 link handshake";
+
+/// An X25519 public key, as carried in handshake messages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct PublicKey([u8; KEY_LEN]);
+
+impl PublicKey {
+    pub fn from_bytes(bytes: [u8; KEY_LEN]) -> Self {
+        PublicKey(bytes)
+    }
+
+    pub fn as_bytes(&self) -> &[u8; KEY_LEN] {
+        &self.0
+    }
+
+    pub fn to_bytes(&self) -> [u8; KEY_LEN] {
+        self.0
+    }
+}
+
+impl From<[u8; KEY_LEN]> for PublicKey {
+    fn from(bytes: [u8; KEY_LEN]) -> Self {
+        PublicKey(bytes)
+    }
+}
+
+impl fmt::Display for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write_hex(f, &self.0)
+    }
+}
+
+impl FromStr for PublicKey {
+    type Err = PhantomBandError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse_hex(s, "public key").map(PublicKey)
+    }
+}
+
+/// An X25519 secret key. It never leaves the process and is wiped on drop.
+pub struct SecretKey(SecretBox<StaticSecret>);
+
+impl SecretKey {
+    pub fn generate() -> Self {
+        SecretKey(SecretBox::new(StaticSecret::random_from_rng(OsRng)))
+    }
+
+    pub fn from_bytes(bytes: &[u8; KEY_LEN]) -> Self {
+        SecretKey(SecretBox::new(StaticSecret::from(*bytes)))
+    }
+
+    pub fn to_bytes(&self) -> Zeroizing<[u8; KEY_LEN]> {
+        Zeroizing::new(self.0.to_bytes())
+    }
 
-pub fn generate_keypair() -> [u8; 32] {
-    let mut rng = rand::thread_rng();
-    let mut key = [0u8; 32];
-    rng.fill(&mut key);
+    pub fn public_key(&self) -> PublicKey {
+        PublicKey(x25519_dalek::PublicKey::from(&*self.0).to_bytes())
+    }
+}
+
+impl fmt::Debug for SecretKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SecretKey(<redacted>)")
+    }
+}
+
+#[derive(Debug)]
+pub struct Keypair {
+    pub secret: SecretKey,
+    pub public: PublicKey,
+}
+
+/// Generates a fresh X25519 keypair. Callers use one per handshake so that
+/// session keys have forward secrecy.
+pub fn generate_keypair() -> Keypair {
+    let secret = SecretKey::generate();
+    let public = secret.public_key();
     info!("Generated new keypair.");
-    key
-}
-
-pub fn encrypt(data: &[u8], key: &[u8; 32]) -> Result<Vec<u8>, String> {
-    let cipher = ChaCha20Poly1305::new(Key::from_slice(key));
-    let mut rng = rand::thread_rng();
-    let mut nonce_bytes = [0u8; 12]; // 96-bit nonce for ChaCha20Poly1305
-    rng.fill(&mut nonce_bytes);
-    let nonce = Nonce::from_slice(&nonce_bytes);
-
-    cipher.encrypt(nonce, data)
-        .map(|mut ciphertext| {
-            let mut result = nonce_bytes.to_vec();
-            result.append(&mut ciphertext);
-            info!("Data encrypted successfully.");
-            result
-        })
-        .map_err(|e| {
-            error!("Encryption error: {:?}", e);
-            format!("Encryption error: {:?}", e)
-        })
+    Keypair { secret, public }
+}
+
+/// Computes the X25519 shared secret between our secret key and a peer's
+/// public key. Low-order peer keys, which would force a known all-zero
+/// output, are rejected. The result is wiped when dropped.
+pub fn diffie_hellman(secret: &SecretKey, peer: &PublicKey) -> Result<Zeroizing<[u8; KEY_LEN]>, PhantomBandError> {
+    let shared = secret.0.diffie_hellman(&x25519_dalek::PublicKey::from(peer.0));
+    if !shared.was_contributory() {
+        error!("Peer sent a low-order public key.");
+        return Err(PhantomBandError::Crypto("Non-contributory Diffie-Hellman output".to_string()));
+    }
+    Ok(Zeroizing::new(shared.to_bytes()))
+}
+
+/// Derives the directional link keys from a DH output, binding both handshake
+/// public keys so that neither side can be substituted.
+pub fn derive_session_keys(shared_secret: &[u8; KEY_LEN], client_public: &PublicKey, relay_public: &PublicKey) -> kdf::SessionKeys {
+    let mut context = Vec::with_capacity(LINK_HANDSHAKE_CONTEXT.len() + KEY_LEN * 2);
+    context.extend_from_slice(LINK_HANDSHAKE_CONTEXT);
+    context.extend_from_slice(client_public.as_bytes());
+    context.extend_from_slice(relay_public.as_bytes());
+    kdf::derive_session_keys(shared_secret, &context)
+}
+
+pub const SIGNATURE_LEN: usize = 64;
+
+const FINGERPRINT_CONTEXT: &str = "PhantomBand v1 relay fingerprint";
+const LINK_CERTIFICATE_PREFIX: &[u8] = b"PhantomBand v1 link key certificate";
+
+/// A long-term Ed25519 identity key. Relays keep one across restarts so that
+/// clients can pin them by fingerprint.
+pub struct IdentityKeypair(SecretBox<SigningKey>);
+
+impl IdentityKeypair {
+    pub fn generate() -> Self {
+        IdentityKeypair(SecretBox::new(SigningKey::generate(&mut OsRng)))
+    }
+
+    pub fn from_secret_bytes(bytes: &[u8; KEY_LEN]) -> Self {
+        IdentityKeypair(SecretBox::new(SigningKey::from_bytes(bytes)))
+    }
+
+    pub fn to_secret_bytes(&self) -> Zeroizing<[u8; KEY_LEN]> {
+        Zeroizing::new(self.0.to_bytes())
+    }
+
+    pub fn public_key(&self) -> IdentityPublicKey {
+        IdentityPublicKey(self.0.verifying_key().to_bytes())
+    }
+
+    pub fn sign(&self, message: &[u8]) -> [u8; SIGNATURE_LEN] {
+        self.0.sign(message).to_bytes()
+    }
+}
+
+impl fmt::Debug for IdentityKeypair {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("IdentityKeypair").field(&self.public_key()).finish()
+    }
+}
+
+/// The public half of an Ed25519 identity key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct IdentityPublicKey([u8; KEY_LEN]);
+
+impl IdentityPublicKey {
+    pub fn from_bytes(bytes: [u8; KEY_LEN]) -> Self {
+        IdentityPublicKey(bytes)
+    }
+
+    pub fn as_bytes(&self) -> &[u8; KEY_LEN] {
+        &self.0
+    }
+
+    pub fn to_bytes(&self) -> [u8; KEY_LEN] {
+        self.0
+    }
+
+    pub fn fingerprint(&self) -> Fingerprint {
+        Fingerprint(blake3::derive_key(FINGERPRINT_CONTEXT, &self.0))
+    }
+}
+
+impl fmt::Display for IdentityPublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write_hex(f, &self.0)
+    }
+}
+
+impl FromStr for IdentityPublicKey {
+    type Err = PhantomBandError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse_hex(s, "identity key").map(IdentityPublicKey)
+    }
 }
 
-pub fn decrypt(encrypted_data: &[u8], key: &[u8; 32]) -> Result<Vec<u8>, String> {
-    if encrypted_data.len() < 12 {
-        error!("Ciphertext too short to contain nonce.");
-        return Err("Ciphertext too short to contain nonce".to_string());
+/// A stable relay identifier derived from its identity key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct Fingerprint([u8; KEY_LEN]);
+
+impl Fingerprint {
+    pub fn from_bytes(bytes: [u8; KEY_LEN]) -> Self {
+        Fingerprint(bytes)
     }
 
-    let nonce_bytes = &encrypted_data[..12];
-    let ciphertext = &encrypted_data[12..];
+    pub fn as_bytes(&self) -> &[u8; KEY_LEN] {
+        &self.0
+    }
+}
+
+impl fmt::Display for Fingerprint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write_hex(f, &self.0)
+    }
+}
+
+impl FromStr for Fingerprint {
+    type Err = PhantomBandError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse_hex(s, "fingerprint").map(Fingerprint)
+    }
+}
+
+fn write_hex(f: &mut fmt::Formatter<'_>, bytes: &[u8]) -> fmt::Result {
+    for byte in bytes {
+        write!(f, "{:02X}", byte)?;
+    }
+    Ok(())
+}
 
-    let cipher = ChaCha20Poly1305::new(Key::from_slice(key));
-    let nonce = Nonce::from_slice(nonce_bytes);
+fn parse_hex(s: &str, what: &str) -> Result<[u8; KEY_LEN], PhantomBandError> {
+    if s.len() != KEY_LEN * 2 || !s.is_ascii() {
+        return Err(PhantomBandError::Protocol(format!("Invalid {} length: {}", what, s.len())));
+    }
+    let mut bytes = [0u8; KEY_LEN];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&s[i * 2..i * 2 + 2], 16)
+            .map_err(|e| PhantomBandError::Protocol(format!("Invalid {}: {}", what, e)))?;
+    }
+    Ok(bytes)
+}
 
-    cipher.decrypt(nonce, ciphertext)
+/// Verifies an Ed25519 signature made by `public` over `message`.
+pub fn verify_signature(public: &IdentityPublicKey, message: &[u8], signature: &[u8]) -> Result<(), PhantomBandError> {
+    let verifying_key = VerifyingKey::from_bytes(&public.0)
+        .map_err(|e| PhantomBandError::Crypto(format!("Invalid identity key: {}", e)))?;
+    let signature = Signature::from_slice(signature)
+        .map_err(|e| PhantomBandError::Crypto(format!("Malformed signature: {}", e)))?;
+    verifying_key.verify_strict(message, &signature)
         .map_err(|e| {
-            error!("Decryption error: {:?}", e);
-            format!("Decryption error: {:?}", e)
+            error!("Signature verification failed: {}", e);
+            PhantomBandError::Crypto(format!("Signature verification failed: {}", e))
         })
 }
+
+/// Binds a relay's X25519 link key, which authenticates it in the Noise link
+/// handshake, to its long-term identity.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LinkCertificate {
+    pub identity_key: [u8; KEY_LEN],
+    pub signature: Vec<u8>,
+}
+
+impl LinkCertificate {
+    pub fn sign(identity: &IdentityKeypair, link_key: &PublicKey) -> Self {
+        LinkCertificate {
+            identity_key: identity.public_key().to_bytes(),
+            signature: identity.sign(&link_certificate_material(link_key)).to_vec(),
+        }
+    }
+
+    /// Checks that the certificate covers `link_key` and returns the identity
+    /// that signed it.
+    pub fn verify(&self, link_key: &PublicKey) -> Result<IdentityPublicKey, PhantomBandError> {
+        let identity = IdentityPublicKey::from_bytes(self.identity_key);
+        verify_signature(&identity, &link_certificate_material(link_key), &self.signature)
+            .map_err(|e| e.context("Invalid link key certificate"))?;
+        Ok(identity)
+    }
+}
+
+fn link_certificate_material(link_key: &PublicKey) -> Vec<u8> {
+    let mut material = Vec::with_capacity(LINK_CERTIFICATE_PREFIX.len() + KEY_LEN);
+    material.extend_from_slice(LINK_CERTIFICATE_PREFIX);
+    material.extend_from_slice(link_key.as_bytes());
+    material
+}
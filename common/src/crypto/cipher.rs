@@ -0,0 +1,250 @@
+// common/src/crypto/cipher.rs
+
+//! Stateful AEAD for one direction of a session.
+//!
+//! Every message carries an explicit 64-bit counter, and the ChaCha20-Poly1305
+//! nonce is derived from it, so a nonce is never reused under one key.
+//! Receivers refuse counters they have already accepted: in-order transports
+//! require the exact next counter, and datagram transports accept reordering
+//! within a sliding window. Every `REKEY_INTERVAL` messages both sides move to
+//! a new key derived from the old one, which is then wiped.
+
+use super::kdf::{self, DirectionalKeys, NONCE_BASE_LEN};
+use super::SessionKey;
+use crate::error::PhantomBandError;
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use chacha20poly1305::aead::{Aead, KeyInit};
+use std::fmt;
+
+pub const COUNTER_LEN: usize = 8;
+pub const TAG_LEN: usize = 16;
+/// Bytes an encrypted message is longer than its plaintext.
+pub const CIPHER_OVERHEAD: usize = COUNTER_LEN + TAG_LEN;
+
+/// Messages sent under one key before both sides rekey.
+pub const REKEY_INTERVAL: u64 = 1 << 20;
+/// How far behind the newest message a datagram may arrive and still be accepted.
+pub const REPLAY_WINDOW: u64 = 1024;
+
+const REKEY_LABEL: &str = "PhantomBand v1 cipher rekey";
+
+/// How the transport under a `CipherState` delivers messages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeliveryMode {
+    /// Reliable and ordered, like TCP: every message must carry the next counter.
+    InOrder,
+    /// Datagrams may be lost or reordered; duplicates are still rejected.
+    Datagram,
+}
+
+/// Encrypts or decrypts one direction of traffic. Use separate states for
+/// sending and receiving.
+pub struct CipherState {
+    keys: KeySchedule,
+    nonce_base: [u8; NONCE_BASE_LEN],
+    mode: DeliveryMode,
+    rekey_interval: u64,
+    /// The next counter to send, or for receivers the next one expected.
+    next_counter: u64,
+    window: ReplayWindow,
+}
+
+impl CipherState {
+    pub fn new(key: SessionKey, nonce_base: [u8; NONCE_BASE_LEN], mode: DeliveryMode) -> Self {
+        CipherState {
+            keys: KeySchedule { epoch: 0, current: key, previous: None },
+            nonce_base,
+            mode,
+            rekey_interval: REKEY_INTERVAL,
+            next_counter: 0,
+            window: ReplayWindow::default(),
+        }
+    }
+
+    pub fn from_keys(keys: &DirectionalKeys, mode: DeliveryMode) -> Self {
+        CipherState::new(keys.aead_key.clone(), keys.nonce_base, mode)
+    }
+
+    /// Shortens the rekey interval so tests can cross key epochs.
+    #[cfg(test)]
+    pub(crate) fn with_rekey_interval(mut self, rekey_interval: u64) -> Self {
+        self.rekey_interval = rekey_interval;
+        self
+    }
+
+    /// Seals `plaintext` as `counter || ciphertext`.
+    pub fn encrypt(&mut self, plaintext: &[u8]) -> Result<Vec<u8>, PhantomBandError> {
+        let counter = self.next_counter;
+        if counter == u64::MAX {
+            return Err(PhantomBandError::Crypto("Nonce space exhausted; the session must be re-established".to_string()));
+        }
+        let epoch = counter / self.rekey_interval;
+        if epoch > self.keys.epoch {
+            // The sender never needs an old key again.
+            self.keys.current = self.keys.next();
+            self.keys.epoch += 1;
+        }
+
+        let ciphertext = cipher(&self.keys.current).encrypt(&self.nonce(counter), plaintext)
+            .map_err(|e| PhantomBandError::Crypto(format!("Encryption error: {:?}", e)))?;
+        self.next_counter += 1;
+
+        let mut message = Vec::with_capacity(COUNTER_LEN + ciphertext.len());
+        message.extend_from_slice(&counter.to_be_bytes());
+        message.extend_from_slice(&ciphertext);
+        Ok(message)
+    }
+
+    /// Opens a message produced by the peer's `encrypt`. Replayed, stale or
+    /// forged messages are rejected without changing the state.
+    pub fn decrypt(&mut self, message: &[u8]) -> Result<Vec<u8>, PhantomBandError> {
+        if message.len() < CIPHER_OVERHEAD {
+            return Err(PhantomBandError::Crypto(format!("Encrypted message too short: {} bytes", message.len())));
+        }
+        let counter = u64::from_be_bytes(message[..COUNTER_LEN].try_into().unwrap());
+        match self.mode {
+            DeliveryMode::InOrder if counter != self.next_counter => {
+                return Err(PhantomBandError::Crypto(format!("Out-of-order or replayed message: counter {}, expected {}", counter, self.next_counter)));
+            },
+            DeliveryMode::InOrder => {},
+            DeliveryMode::Datagram => self.window.check(counter)?,
+        }
+
+        let epoch = counter / self.rekey_interval;
+        let next_key = (epoch == self.keys.epoch + 1).then(|| self.keys.next());
+        let key = match (&next_key, &self.keys.previous) {
+            _ if epoch == self.keys.epoch => &self.keys.current,
+            (Some(next), _) => next,
+            (None, Some(previous)) if epoch + 1 == self.keys.epoch => previous,
+            _ => return Err(PhantomBandError::Crypto(format!("Message counter {} is outside the current key epoch {}", counter, self.keys.epoch))),
+        };
+
+        let plaintext = cipher(key).decrypt(&self.nonce(counter), &message[COUNTER_LEN..])
+            .map_err(|e| PhantomBandError::Crypto(format!("Decryption error: {:?}", e)))?;
+
+        // Only authenticated messages may move the state forward.
+        if let Some(next) = next_key {
+            self.keys.previous = Some(std::mem::replace(&mut self.keys.current, next));
+            self.keys.epoch += 1;
+        }
+        match self.mode {
+            DeliveryMode::InOrder => {
+                self.next_counter = counter.saturating_add(1);
+                self.keys.previous = None;
+            },
+            DeliveryMode::Datagram => {
+                self.window.accept(counter);
+                self.next_counter = self.next_counter.max(counter.saturating_add(1));
+                // Once the window has moved past the previous epoch, its key can go.
+                if self.keys.previous.is_some() && self.window.oldest_acceptable() >= self.keys.epoch * self.rekey_interval {
+                    self.keys.previous = None;
+                }
+            },
+        }
+        Ok(plaintext)
+    }
+
+    fn nonce(&self, counter: u64) -> Nonce {
+        let mut nonce = self.nonce_base;
+        for (byte, counter_byte) in nonce[NONCE_BASE_LEN - COUNTER_LEN..].iter_mut().zip(counter.to_be_bytes()) {
+            *byte ^= counter_byte;
+        }
+        *Nonce::from_slice(&nonce)
+    }
+}
+
+impl fmt::Debug for CipherState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CipherState")
+            .field("mode", &self.mode)
+            .field("epoch", &self.keys.epoch)
+            .field("next_counter", &self.next_counter)
+            .finish_non_exhaustive()
+    }
+}
+
+fn cipher(key: &SessionKey) -> ChaCha20Poly1305 {
+    ChaCha20Poly1305::new(Key::from_slice(key.as_bytes()))
+}
+
+/// The key for the current epoch, plus the previous one while datagrams
+/// from it may still arrive.
+struct KeySchedule {
+    epoch: u64,
+    current: SessionKey,
+    previous: Option<SessionKey>,
+}
+
+impl KeySchedule {
+    fn next(&self) -> SessionKey {
+        SessionKey::fill_with(|key| kdf::expand(self.current.as_bytes(), REKEY_LABEL, key))
+    }
+}
+
+/// Remembers which of the last `REPLAY_WINDOW` counters were accepted, as a
+/// ring of bits indexed by counter.
+#[derive(Default)]
+struct ReplayWindow {
+    highest: Option<u64>,
+    seen: [u64; (REPLAY_WINDOW / 64) as usize],
+}
+
+impl ReplayWindow {
+    fn check(&self, counter: u64) -> Result<(), PhantomBandError> {
+        let highest = match self.highest {
+            Some(highest) if counter <= highest => highest,
+            _ => return Ok(()),
+        };
+        if highest - counter >= REPLAY_WINDOW {
+            return Err(PhantomBandError::Crypto(format!("Message counter {} is too old (newest {})", counter, highest)));
+        }
+        if self.is_set(counter) {
+            return Err(PhantomBandError::Crypto(format!("Replayed message: counter {}", counter)));
+        }
+        Ok(())
+    }
+
+    fn accept(&mut self, counter: u64) {
+        match self.highest {
+            Some(highest) if counter <= highest => {},
+            Some(highest) => {
+                // Slots between the old and new highest counter now belong to
+                // counters nobody has sent yet.
+                for skipped in highest + 1..counter.min(highest + REPLAY_WINDOW) {
+                    self.clear(skipped);
+                }
+                if counter - highest >= REPLAY_WINDOW {
+                    self.seen = Default::default();
+                }
+                self.clear(counter);
+                self.highest = Some(counter);
+            },
+            None => self.highest = Some(counter),
+        }
+        self.set(counter);
+    }
+
+    fn oldest_acceptable(&self) -> u64 {
+        self.highest.map_or(0, |highest| (highest + 1).saturating_sub(REPLAY_WINDOW))
+    }
+
+    fn slot(counter: u64) -> (usize, u64) {
+        let index = counter % REPLAY_WINDOW;
+        ((index / 64) as usize, 1 << (index % 64))
+    }
+
+    fn is_set(&self, counter: u64) -> bool {
+        let (word, bit) = ReplayWindow::slot(counter);
+        self.seen[word] & bit != 0
+    }
+
+    fn set(&mut self, counter: u64) {
+        let (word, bit) = ReplayWindow::slot(counter);
+        self.seen[word] |= bit;
+    }
+
+    fn clear(&mut self, counter: u64) {
+        let (word, bit) = ReplayWindow::slot(counter);
+        self.seen[word] &= !bit;
+    }
+}
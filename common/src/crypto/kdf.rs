@@ -0,0 +1,83 @@
+// common/src/crypto/kdf.rs
+
+//! BLAKE3-based key derivation.
+//!
+//! A handshake secret is first condensed into a pseudorandom key, then expanded
+//! into independent per-direction material. Every output uses its own
+//! domain-separation label, so a key derived for one purpose or direction can
+//! never equal a key derived for another.
+
+use super::{SessionKey, KEY_LEN};
+use std::fmt;
+use zeroize::{Zeroize, ZeroizeOnDrop, Zeroizing};
+
+pub const NONCE_BASE_LEN: usize = 12;
+
+const EXTRACT_LABEL: &str = "PhantomBand v1 kdf extract";
+const FORWARD_AEAD_KEY_LABEL: &str = "PhantomBand v1 kdf forward aead key";
+const FORWARD_NONCE_BASE_LABEL: &str = "PhantomBand v1 kdf forward nonce base";
+const FORWARD_DIGEST_SEED_LABEL: &str = "PhantomBand v1 kdf forward digest seed";
+const BACKWARD_AEAD_KEY_LABEL: &str = "PhantomBand v1 kdf backward aead key";
+const BACKWARD_NONCE_BASE_LABEL: &str = "PhantomBand v1 kdf backward nonce base";
+const BACKWARD_DIGEST_SEED_LABEL: &str = "PhantomBand v1 kdf backward digest seed";
+
+/// Key material for one direction of a session.
+#[derive(Clone, Zeroize, ZeroizeOnDrop)]
+pub struct DirectionalKeys {
+    #[zeroize(skip)]
+    pub aead_key: SessionKey,
+    pub nonce_base: [u8; NONCE_BASE_LEN],
+    #[zeroize(skip)]
+    pub digest_seed: SessionKey,
+}
+
+impl fmt::Debug for DirectionalKeys {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("DirectionalKeys(<redacted>)")
+    }
+}
+
+/// Key material for both directions. "Forward" is initiator to responder
+/// (client towards the relay), "backward" is the reverse.
+#[derive(Clone, Debug)]
+pub struct SessionKeys {
+    pub forward: DirectionalKeys,
+    pub backward: DirectionalKeys,
+}
+
+/// Condenses a secret and its handshake context into a pseudorandom key.
+pub fn extract(secret: &[u8], context: &[u8]) -> Zeroizing<[u8; KEY_LEN]> {
+    let mut hasher = blake3::Hasher::new_derive_key(EXTRACT_LABEL);
+    hasher.update(&(secret.len() as u64).to_le_bytes());
+    hasher.update(secret);
+    hasher.update(context);
+    let mut prk = Zeroizing::new([0u8; KEY_LEN]);
+    hasher.finalize_xof().fill(&mut *prk);
+    prk
+}
+
+/// Expands a pseudorandom key into `out.len()` bytes bound to `label`.
+pub fn expand(prk: &[u8; KEY_LEN], label: &str, out: &mut [u8]) {
+    let mut hasher = blake3::Hasher::new_derive_key(label);
+    hasher.update(prk);
+    hasher.finalize_xof().fill(out);
+}
+
+/// Derives the directional session keys for a handshake secret.
+pub fn derive_session_keys(secret: &[u8], context: &[u8]) -> SessionKeys {
+    let prk = extract(secret, context);
+    SessionKeys {
+        forward: directional_keys(&prk, FORWARD_AEAD_KEY_LABEL, FORWARD_NONCE_BASE_LABEL, FORWARD_DIGEST_SEED_LABEL),
+        backward: directional_keys(&prk, BACKWARD_AEAD_KEY_LABEL, BACKWARD_NONCE_BASE_LABEL, BACKWARD_DIGEST_SEED_LABEL),
+    }
+}
+
+fn directional_keys(prk: &[u8; KEY_LEN], key_label: &str, nonce_label: &str, digest_label: &str) -> DirectionalKeys {
+    let mut nonce_base = [0u8; NONCE_BASE_LEN];
+    expand(prk, nonce_label, &mut nonce_base);
+    DirectionalKeys {
+        aead_key: SessionKey::fill_with(|key| expand(prk, key_label, key)),
+        nonce_base,
+        digest_seed: SessionKey::fill_with(|seed| expand(prk, digest_label, seed)),
+    }
+}
@@ -0,0 +1,150 @@
+// common/src/crypto/mlkem.rs
+
+//! ML-KEM-768 key encapsulation (FIPS 203).
+//!
+//! A thin wrapper over the RustCrypto `ml-kem` crate for the one parameter
+//! set the hybrid handshake uses, taking and giving plain byte strings. The
+//! crate does not check that a peer's encapsulation key is reduced modulo q,
+//! so parsing a key does that here.
+
+use super::secret::SecretBox;
+use crate::error::PhantomBandError;
+use ml_kem::kem::{Decapsulate, DecapsulationKey as MlKemDecapsulationKey, EncapsulationKey as MlKemEncapsulationKey};
+use ml_kem::{EncapsulateDeterministic, EncodedSizeUser, KemCore, MlKem768, MlKem768Params};
+use rand::RngCore;
+use rand::rngs::OsRng;
+use std::fmt;
+use zeroize::Zeroizing;
+
+pub const ENCAPSULATION_KEY_LEN: usize = 1184;
+pub const DECAPSULATION_KEY_LEN: usize = 2400;
+pub const CIPHERTEXT_LEN: usize = 1088;
+pub const SHARED_SECRET_LEN: usize = 32;
+
+/// The public half of an ML-KEM-768 keypair, as carried in handshake messages.
+#[derive(Clone)]
+pub struct EncapsulationKey {
+    key: MlKemEncapsulationKey<MlKem768Params>,
+    bytes: Vec<u8>,
+}
+
+impl EncapsulationKey {
+    /// Parses a peer's key, applying the length and modulus checks of FIPS
+    /// 203 section 7.2.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, PhantomBandError> {
+        let encoded = bytes.try_into()
+            .map_err(|_| PhantomBandError::Crypto(format!("ML-KEM encapsulation key is {} bytes, expected {}", bytes.len(), ENCAPSULATION_KEY_LEN)))?;
+        let key = MlKemEncapsulationKey::<MlKem768Params>::from_bytes(encoded);
+        // Decoding reduces every coefficient, so a key that was not reduced
+        // encodes differently.
+        if key.as_bytes().as_slice() != bytes {
+            return Err(PhantomBandError::Crypto("ML-KEM encapsulation key is not reduced modulo q".to_string()));
+        }
+        Ok(EncapsulationKey { key, bytes: bytes.to_vec() })
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    /// Algorithm 17: returns the ciphertext to send and the shared secret.
+    pub fn encapsulate(&self) -> (Ciphertext, Zeroizing<[u8; SHARED_SECRET_LEN]>) {
+        let mut m = Zeroizing::new([0u8; 32]);
+        OsRng.fill_bytes(&mut *m);
+        self.encapsulate_deterministic(&m)
+    }
+
+    /// Algorithm 17 with the randomness `m` given.
+    pub(crate) fn encapsulate_deterministic(&self, m: &[u8; 32]) -> (Ciphertext, Zeroizing<[u8; SHARED_SECRET_LEN]>) {
+        let (ciphertext, shared) = self.key.encapsulate_deterministic(&(*m).into())
+            .expect("ML-KEM encapsulation is infallible");
+        (Ciphertext(ciphertext.to_vec()), shared_secret(shared))
+    }
+}
+
+impl PartialEq for EncapsulationKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.bytes == other.bytes
+    }
+}
+
+impl Eq for EncapsulationKey {}
+
+impl fmt::Debug for EncapsulationKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "EncapsulationKey({:02x?}..)", &self.bytes[..8])
+    }
+}
+
+/// An ML-KEM-768 ciphertext.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Ciphertext(Vec<u8>);
+
+impl Ciphertext {
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, PhantomBandError> {
+        if bytes.len() != CIPHERTEXT_LEN {
+            return Err(PhantomBandError::Crypto(format!("ML-KEM ciphertext is {} bytes, expected {}", bytes.len(), CIPHERTEXT_LEN)));
+        }
+        Ok(Ciphertext(bytes.to_vec()))
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl fmt::Debug for Ciphertext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Ciphertext({:02x?}..)", &self.0[..8])
+    }
+}
+
+/// The secret half of an ML-KEM-768 keypair. The crate wipes it on drop.
+pub struct DecapsulationKey(SecretBox<MlKemDecapsulationKey<MlKem768Params>>);
+
+impl DecapsulationKey {
+    /// Algorithm 18. A ciphertext that was tampered with yields an unrelated
+    /// pseudorandom secret rather than an error, so the failure only shows up
+    /// once the derived keys disagree.
+    pub fn decapsulate(&self, ciphertext: &Ciphertext) -> Zeroizing<[u8; SHARED_SECRET_LEN]> {
+        let encoded = ciphertext.0.as_slice().try_into().expect("Ciphertext length is checked on parsing");
+        shared_secret(self.0.decapsulate(encoded).expect("ML-KEM decapsulation is infallible"))
+    }
+}
+
+impl fmt::Debug for DecapsulationKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("DecapsulationKey(<redacted>)")
+    }
+}
+
+#[derive(Debug)]
+pub struct KemKeypair {
+    pub decapsulation_key: DecapsulationKey,
+    pub encapsulation_key: EncapsulationKey,
+}
+
+/// Generates a fresh ML-KEM-768 keypair (algorithm 16).
+pub fn generate_keypair() -> KemKeypair {
+    let mut seed = Zeroizing::new([0u8; 64]);
+    OsRng.fill_bytes(&mut *seed);
+    let d = Zeroizing::new(seed[..32].try_into().expect("Seed is 64 bytes"));
+    let z = Zeroizing::new(seed[32..].try_into().expect("Seed is 64 bytes"));
+    keypair_from_seed(&d, &z)
+}
+
+/// Algorithm 16 with the seeds given.
+pub(crate) fn keypair_from_seed(d: &[u8; 32], z: &[u8; 32]) -> KemKeypair {
+    let (decapsulation_key, encapsulation_key) = MlKem768::generate_deterministic(&(*d).into(), &(*z).into());
+    let bytes = encapsulation_key.as_bytes().to_vec();
+    KemKeypair {
+        decapsulation_key: DecapsulationKey(SecretBox::new(decapsulation_key)),
+        encapsulation_key: EncapsulationKey { key: encapsulation_key, bytes },
+    }
+}
+
+fn shared_secret(shared: ml_kem::SharedKey<MlKem768>) -> Zeroizing<[u8; SHARED_SECRET_LEN]> {
+    let mut secret = Zeroizing::new([0u8; SHARED_SECRET_LEN]);
+    secret.copy_from_slice(&shared);
+    secret
+}
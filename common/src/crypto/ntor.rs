@@ -0,0 +1,187 @@
+// common/src/crypto/ntor.rs
+
+//! The ntor circuit handshake.
+//!
+//! A client creates or extends a circuit to a relay it knows by identity
+//! fingerprint `ID` and onion key `B`. It sends a fresh key `X`; the relay
+//! answers with a fresh key `Y` and an authenticator:
+//!
+//! ```text
+//! secret_input = DH(x, Y) | DH(x, B) | ID | B | X | Y | PROTOCOL_ID
+//! keys         = KDF(secret_input)
+//! auth         = MAC(verify(secret_input), ID | B | Y | X | PROTOCOL_ID | "relay")
+//! ```
+//!
+//! Only the holder of the onion key for `B` can compute `auth`, so a client
+//! that accepts it shares keys with exactly the relay it chose. The relay
+//! learns nothing about the client. Both ephemeral keys give forward secrecy
+//! once they are wiped, even if the onion key is later compromised.
+
+use super::kdf::{self, SessionKeys};
+use super::replay::ReplayTag;
+use super::{Fingerprint, PublicKey, SecretKey, KEY_LEN};
+use crate::error::PhantomBandError;
+use serde::{Serialize, Deserialize};
+use std::fmt;
+use subtle::ConstantTimeEq;
+use zeroize::Zeroizing;
+
+pub const AUTH_LEN: usize = 32;
+
+const PROTOCOL_ID: &[u8] = b"PhantomBand v1 ntor x25519 blake3";
+const VERIFY_LABEL: &str = "PhantomBand v1 ntor verify";
+const RELAY_ROLE: &[u8] = b"relay";
+const REPLAY_TAG_LABEL: &str = "PhantomBand v1 ntor replay tag";
+
+/// The client's half of the handshake, carried in `CircuitCreate`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NtorRequest {
+    /// The relay the client means to reach.
+    pub relay: Fingerprint,
+    /// The onion key the client took from the relay's descriptor.
+    pub onion_key: PublicKey,
+    pub client_key: PublicKey,
+}
+
+impl NtorRequest {
+    /// Identifies the request for the relay's replay cache. The client key is
+    /// fresh for every honest handshake, so a repeated tag is a replay.
+    pub fn replay_tag(&self) -> ReplayTag {
+        let mut hasher = blake3::Hasher::new_derive_key(REPLAY_TAG_LABEL);
+        hasher.update(self.relay.as_bytes());
+        hasher.update(self.onion_key.as_bytes());
+        hasher.update(self.client_key.as_bytes());
+        *hasher.finalize().as_bytes()
+    }
+}
+
+/// The relay's half of the handshake, carried in `CircuitCreated`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NtorReply {
+    pub relay_key: PublicKey,
+    pub auth: [u8; AUTH_LEN],
+}
+
+/// A client handshake waiting for the relay's reply.
+pub struct NtorClient {
+    relay: Fingerprint,
+    onion_key: PublicKey,
+    secret: SecretKey,
+    public: PublicKey,
+}
+
+impl NtorClient {
+    pub fn new(relay: Fingerprint, onion_key: PublicKey) -> (NtorClient, NtorRequest) {
+        let secret = SecretKey::generate();
+        let public = secret.public_key();
+        let request = NtorRequest { relay, onion_key, client_key: public };
+        (NtorClient { relay, onion_key, secret, public }, request)
+    }
+
+    /// Checks the relay's authenticator and returns the hop's keys.
+    pub fn finish(self, reply: &NtorReply) -> Result<SessionKeys, PhantomBandError> {
+        let ephemeral = super::diffie_hellman(&self.secret, &reply.relay_key)?;
+        let static_ = super::diffie_hellman(&self.secret, &self.onion_key)?;
+        let (keys, auth) = derive(&ephemeral, &static_, &self.relay, &self.onion_key, &self.public, &reply.relay_key);
+        if !bool::from(auth.ct_eq(&reply.auth)) {
+            return Err(PhantomBandError::Crypto(format!("Circuit handshake with {} failed authentication", self.relay)));
+        }
+        Ok(keys)
+    }
+}
+
+impl fmt::Debug for NtorClient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("NtorClient").field("relay", &self.relay).finish_non_exhaustive()
+    }
+}
+
+/// A relay's side of the handshake: its identity, its current onion key and
+/// the one it replaced. Clients may still hold a descriptor with the previous
+/// key, so handshakes under either are answered.
+pub struct NtorRelay {
+    fingerprint: Fingerprint,
+    onion_key: SecretKey,
+    onion_public: PublicKey,
+    previous: Option<(SecretKey, PublicKey)>,
+}
+
+impl NtorRelay {
+    pub fn new(fingerprint: Fingerprint, onion_key: SecretKey) -> Self {
+        let onion_public = onion_key.public_key();
+        NtorRelay { fingerprint, onion_key, onion_public, previous: None }
+    }
+
+    pub fn fingerprint(&self) -> Fingerprint {
+        self.fingerprint
+    }
+
+    pub fn onion_key(&self) -> PublicKey {
+        self.onion_public
+    }
+
+    /// Makes `onion_key` the current key. The current one is still accepted
+    /// until the next rotation; the one before it is forgotten.
+    pub fn rotate(&mut self, onion_key: SecretKey) {
+        let onion_public = onion_key.public_key();
+        let retired = std::mem::replace(&mut self.onion_key, onion_key);
+        self.previous = Some((retired, self.onion_public));
+        self.onion_public = onion_public;
+    }
+
+    /// Answers a request meant for this relay and returns the reply with the
+    /// hop's keys.
+    pub fn respond(&self, request: &NtorRequest) -> Result<(NtorReply, SessionKeys), PhantomBandError> {
+        if request.relay != self.fingerprint {
+            return Err(PhantomBandError::Policy(format!("Circuit handshake is for relay {}, not us", request.relay)));
+        }
+        let (onion_key, onion_public) = match &self.previous {
+            _ if request.onion_key == self.onion_public => (&self.onion_key, self.onion_public),
+            Some((previous, previous_public)) if request.onion_key == *previous_public => (previous, *previous_public),
+            _ => return Err(PhantomBandError::Policy(format!("Circuit handshake uses unknown onion key {}", request.onion_key))),
+        };
+        let secret = SecretKey::generate();
+        let relay_key = secret.public_key();
+        let ephemeral = super::diffie_hellman(&secret, &request.client_key)?;
+        let static_ = super::diffie_hellman(onion_key, &request.client_key)?;
+        let (keys, auth) = derive(&ephemeral, &static_, &self.fingerprint, &onion_public, &request.client_key, &relay_key);
+        Ok((NtorReply { relay_key, auth }, keys))
+    }
+}
+
+impl fmt::Debug for NtorRelay {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("NtorRelay").field("fingerprint", &self.fingerprint).finish_non_exhaustive()
+    }
+}
+
+fn derive(
+    ephemeral: &[u8; KEY_LEN],
+    static_: &[u8; KEY_LEN],
+    relay: &Fingerprint,
+    onion_key: &PublicKey,
+    client_key: &PublicKey,
+    relay_key: &PublicKey,
+) -> (SessionKeys, [u8; AUTH_LEN]) {
+    let mut secret_input = Zeroizing::new(Vec::with_capacity(KEY_LEN * 6 + PROTOCOL_ID.len()));
+    secret_input.extend_from_slice(ephemeral);
+    secret_input.extend_from_slice(static_);
+    secret_input.extend_from_slice(relay.as_bytes());
+    secret_input.extend_from_slice(onion_key.as_bytes());
+    secret_input.extend_from_slice(client_key.as_bytes());
+    secret_input.extend_from_slice(relay_key.as_bytes());
+    secret_input.extend_from_slice(PROTOCOL_ID);
+    let keys = kdf::derive_session_keys(&secret_input, PROTOCOL_ID);
+
+    let prk = kdf::extract(&secret_input, PROTOCOL_ID);
+    let mut verify = Zeroizing::new([0u8; KEY_LEN]);
+    kdf::expand(&prk, VERIFY_LABEL, &mut *verify);
+    let mut mac = blake3::Hasher::new_keyed(&verify);
+    mac.update(relay.as_bytes());
+    mac.update(onion_key.as_bytes());
+    mac.update(relay_key.as_bytes());
+    mac.update(client_key.as_bytes());
+    mac.update(PROTOCOL_ID);
+    mac.update(RELAY_ROLE);
+    (keys, *mac.finalize().as_bytes())
+}
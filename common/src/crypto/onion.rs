@@ -0,0 +1,186 @@
+// common/src/crypto/onion.rs
+
+//! Onion layers for relay cells on multi-hop circuits.
+//!
+//! Each hop shares `SessionKeys` with the client. Per direction, a hop keeps a
+//! ChaCha20 keystream that runs across all cells of the circuit, plus a keyed
+//! BLAKE3 running digest. Layers are length-preserving, so a relay cell stays
+//! `CELL_PAYLOAD_LEN` bytes at every hop.
+//!
+//! Going forward, the client seals the cell's digest for the target hop and
+//! then adds the layers of that hop and every hop before it, nearest hop last.
+//! Each relay peels one layer. A relay knows a cell is addressed to it when
+//! the `recognized` field is zero and the digest matches its running digest;
+//! otherwise it passes the cell on. Going backward, the originating relay seals
+//! its digest, every relay on the way adds its layer, and the client peels
+//! layers until one hop recognizes the cell.
+
+use super::kdf::{DirectionalKeys, SessionKeys};
+use crate::error::PhantomBandError;
+use crate::protocol::cell::{RelayCell, CELL_PAYLOAD_LEN, RELAY_DIGEST_LEN, RELAY_DIGEST_OFFSET, RELAY_RECOGNIZED_OFFSET};
+use chacha20::ChaCha20;
+use chacha20::cipher::{KeyIvInit, StreamCipher};
+use std::fmt;
+use std::ops::Range;
+use subtle::ConstantTimeEq;
+
+/// A relay cell payload with any number of onion layers on it.
+pub type OnionPayload = [u8; CELL_PAYLOAD_LEN];
+
+const DIGEST_RANGE: Range<usize> = RELAY_DIGEST_OFFSET..RELAY_DIGEST_OFFSET + RELAY_DIGEST_LEN;
+const RECOGNIZED_RANGE: Range<usize> = RELAY_RECOGNIZED_OFFSET..RELAY_RECOGNIZED_OFFSET + 2;
+
+/// One direction of one hop's layer.
+struct LayerState {
+    keystream: ChaCha20,
+    digest: blake3::Hasher,
+}
+
+impl LayerState {
+    fn new(keys: &DirectionalKeys) -> Self {
+        LayerState {
+            keystream: ChaCha20::new(keys.aead_key.as_bytes().into(), &keys.nonce_base.into()),
+            digest: blake3::Hasher::new_keyed(keys.digest_seed.as_bytes()),
+        }
+    }
+
+    /// Adds or removes this layer; the operation is its own inverse.
+    fn apply(&mut self, payload: &mut OnionPayload) -> Result<(), PhantomBandError> {
+        self.keystream.try_apply_keystream(payload)
+            .map_err(|_| PhantomBandError::Crypto("Onion layer keystream exhausted; the circuit must be rebuilt".to_string()))
+    }
+
+    /// Writes the running digest into a plaintext relay cell.
+    fn seal(&mut self, payload: &mut OnionPayload) {
+        payload[DIGEST_RANGE].fill(0);
+        self.digest.update(payload);
+        let digest = self.digest.finalize();
+        payload[DIGEST_RANGE].copy_from_slice(&digest.as_bytes()[..RELAY_DIGEST_LEN]);
+    }
+
+    /// Whether a payload with this layer removed is a cell sealed by our peer.
+    /// The running digest only moves forward when it is.
+    fn recognize(&mut self, payload: &OnionPayload) -> bool {
+        if payload[RECOGNIZED_RANGE] != [0, 0] {
+            return false;
+        }
+        let mut unsealed = *payload;
+        unsealed[DIGEST_RANGE].fill(0);
+        let mut digest = self.digest.clone();
+        digest.update(&unsealed);
+        if !bool::from(digest.finalize().as_bytes()[..RELAY_DIGEST_LEN].ct_eq(&payload[DIGEST_RANGE])) {
+            return false;
+        }
+        self.digest = digest;
+        true
+    }
+}
+
+/// The layer shared by the client and one hop of a circuit.
+pub struct HopLayer {
+    forward: LayerState,
+    backward: LayerState,
+}
+
+impl HopLayer {
+    pub fn new(keys: &SessionKeys) -> Self {
+        HopLayer { forward: LayerState::new(&keys.forward), backward: LayerState::new(&keys.backward) }
+    }
+}
+
+impl fmt::Debug for HopLayer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("HopLayer(<redacted>)")
+    }
+}
+
+/// The client's layers for a circuit, nearest hop first.
+#[derive(Debug, Default)]
+pub struct ClientOnion {
+    hops: Vec<HopLayer>,
+}
+
+impl ClientOnion {
+    pub fn new() -> Self {
+        ClientOnion::default()
+    }
+
+    /// Appends the layer of the next hop once the circuit is extended to it.
+    pub fn add_hop(&mut self, layer: HopLayer) {
+        self.hops.push(layer);
+    }
+
+    pub fn hop_count(&self) -> usize {
+        self.hops.len()
+    }
+
+    /// Encrypts `cell` so that hop `hop` (0 is the nearest) recognizes it
+    /// after the relays before it have each peeled their layer.
+    pub fn wrap(&mut self, cell: &RelayCell, hop: usize) -> Result<OnionPayload, PhantomBandError> {
+        if hop >= self.hops.len() {
+            return Err(PhantomBandError::Internal(format!("Circuit has no hop {} (length {})", hop, self.hops.len())));
+        }
+        let mut payload = cell.encode()?;
+        self.hops[hop].forward.seal(&mut payload);
+        for layer in self.hops[..=hop].iter_mut().rev() {
+            layer.forward.apply(&mut payload)?;
+        }
+        Ok(payload)
+    }
+
+    /// Peels backward layers until a hop recognizes the cell, and returns
+    /// that hop's index with the cell.
+    pub fn unwrap(&mut self, payload: &OnionPayload) -> Result<(usize, RelayCell), PhantomBandError> {
+        let mut payload = *payload;
+        for (hop, layer) in self.hops.iter_mut().enumerate() {
+            layer.backward.apply(&mut payload)?;
+            if layer.backward.recognize(&payload) {
+                return Ok((hop, RelayCell::decode(&payload)?));
+            }
+        }
+        Err(PhantomBandError::Protocol("Relay cell was not recognized by any hop".to_string()))
+    }
+}
+
+/// Result of a relay peeling its layer off a forward cell.
+#[derive(Debug)]
+pub enum Peeled {
+    /// The cell is addressed to this relay.
+    Recognized(RelayCell),
+    /// The cell is for a later hop; forward the peeled payload.
+    Forward,
+}
+
+/// A relay's layer for one circuit.
+#[derive(Debug)]
+pub struct RelayOnion {
+    layer: HopLayer,
+}
+
+impl RelayOnion {
+    pub fn new(keys: &SessionKeys) -> Self {
+        RelayOnion { layer: HopLayer::new(keys) }
+    }
+
+    /// Removes this relay's layer from a forward cell in place.
+    pub fn peel(&mut self, payload: &mut OnionPayload) -> Result<Peeled, PhantomBandError> {
+        self.layer.forward.apply(payload)?;
+        if !self.layer.forward.recognize(payload) {
+            return Ok(Peeled::Forward);
+        }
+        Ok(Peeled::Recognized(RelayCell::decode(payload)?))
+    }
+
+    /// Seals and encrypts a cell this relay sends back to the client.
+    pub fn originate(&mut self, cell: &RelayCell) -> Result<OnionPayload, PhantomBandError> {
+        let mut payload = cell.encode()?;
+        self.layer.backward.seal(&mut payload);
+        self.layer.backward.apply(&mut payload)?;
+        Ok(payload)
+    }
+
+    /// Adds this relay's layer to a backward cell from a later hop.
+    pub fn add_layer(&mut self, payload: &mut OnionPayload) -> Result<(), PhantomBandError> {
+        self.layer.backward.apply(payload)
+    }
+}
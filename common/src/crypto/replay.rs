@@ -0,0 +1,193 @@
+// common/src/crypto/replay.rs
+
+//! Replay detection for handshakes and mix packets.
+//!
+//! A tag has to be remembered for as long as the key it was made under is
+//! still accepted, the `retention` given to the cache. Tags are kept in a
+//! series of short time buckets, each a Bloom filter sized for `capacity`
+//! tags, and a bucket is dropped once everything in it is older than the
+//! retention. A bucket that fills up is followed by another for the same
+//! time, up to `max_buckets` in all, which bounds memory. Past that the newest
+//! bucket takes tags beyond its capacity: fresh handshakes are then more
+//! likely to be taken for replays, but a flood never refuses every client and
+//! never makes the cache forget a tag early.
+//!
+//! The filters are indexed by a keyed hash of the tag, with a key fresh for
+//! every cache, so an attacker cannot pick tags that collide on purpose. The
+//! sizes keep the chance that a fresh tag is taken for a replay below
+//! `FALSE_POSITIVE_RATE` as long as no bucket is filled past its capacity.
+
+use crate::error::PhantomBandError;
+use crate::utils::SharedClock;
+use rand::rngs::OsRng;
+use rand::RngCore;
+use std::collections::VecDeque;
+use std::f64::consts::LN_2;
+use std::fmt;
+
+/// Identifies one handshake or packet. Equal tags mean a replay.
+pub type ReplayTag = [u8; 32];
+
+/// Chance that a fresh tag is refused as a replay, over all buckets.
+pub const FALSE_POSITIVE_RATE: f64 = 1e-6;
+
+/// Counters for monitoring.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ReplayStats {
+    pub accepted: u64,
+    pub replays: u64,
+    /// Tags added to a full bucket because the cache was at its memory
+    /// limit, each raising the false positive rate.
+    pub overfilled: u64,
+}
+
+impl fmt::Display for ReplayStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} accepted, {} replays, {} past capacity", self.accepted, self.replays, self.overfilled)
+    }
+}
+
+/// The tags seen during one bucket of time.
+struct Bucket {
+    /// Start of the bucket in units of the bucket length.
+    index: u64,
+    bits: Vec<u64>,
+    count: usize,
+}
+
+impl Bucket {
+    fn contains(&self, positions: &[usize]) -> bool {
+        positions.iter().all(|&bit| self.bits[bit / 64] & (1 << (bit % 64)) != 0)
+    }
+
+    fn insert(&mut self, positions: &[usize]) {
+        for &bit in positions {
+            self.bits[bit / 64] |= 1 << (bit % 64);
+        }
+        self.count += 1;
+    }
+}
+
+pub struct ReplayCache {
+    clock: SharedClock,
+    hash_key: [u8; 32],
+    /// How long a tag is remembered at least, in seconds.
+    retention: u64,
+    /// Length of a bucket in seconds.
+    bucket_length: u64,
+    /// Tags one bucket is sized for.
+    capacity: usize,
+    /// Most buckets kept at once.
+    max_buckets: usize,
+    /// Size of each bucket's filter in 64-bit words.
+    words: usize,
+    hashes: usize,
+    buckets: VecDeque<Bucket>,
+    stats: ReplayStats,
+}
+
+impl ReplayCache {
+    /// A cache remembering each tag for at least `retention` seconds, with
+    /// buckets `bucket_length` seconds long sized for `capacity` tags, and at
+    /// most `max_buckets` of them. At least one bucket is kept for every
+    /// bucket length the retention spans.
+    pub fn new(clock: SharedClock, retention: u64, bucket_length: u64, capacity: usize, max_buckets: usize) -> Self {
+        let bucket_length = bucket_length.max(1);
+        let capacity = capacity.max(1);
+        let max_buckets = max_buckets.max(retention.div_ceil(bucket_length) as usize + 1);
+        let bucket_rate = FALSE_POSITIVE_RATE / max_buckets as f64;
+        let bits = (capacity as f64 * -bucket_rate.ln() / (LN_2 * LN_2)).ceil() as usize;
+        let words = bits.div_ceil(64);
+        let hashes = ((words * 64) as f64 / capacity as f64 * LN_2).round().max(1.0) as usize;
+        let mut hash_key = [0u8; 32];
+        OsRng.fill_bytes(&mut hash_key);
+        ReplayCache { clock, hash_key, retention, bucket_length, capacity, max_buckets, words, hashes, buckets: VecDeque::new(), stats: ReplayStats::default() }
+    }
+
+    /// Records `tag`, failing if it was seen within the retention.
+    pub fn check(&mut self, tag: &ReplayTag) -> Result<(), PhantomBandError> {
+        let now = self.clock.unix_time();
+        self.expire(now);
+        let positions = self.positions(tag);
+        if self.buckets.iter().any(|bucket| bucket.contains(&positions)) {
+            self.stats.replays += 1;
+            return Err(PhantomBandError::Policy("Replayed handshake".to_string()));
+        }
+        let index = now / self.bucket_length;
+        let full = self.buckets.back().is_some_and(|bucket| bucket.count >= self.capacity);
+        if self.buckets.back().is_none_or(|bucket| bucket.index < index) || (full && self.extra_buckets() < self.max_buckets - self.spans()) {
+            self.buckets.push_back(Bucket { index, bits: vec![0; self.words], count: 0 });
+        } else if full {
+            self.stats.overfilled += 1;
+        }
+        let bucket = self.buckets.back_mut().expect("Current bucket was just added");
+        bucket.insert(&positions);
+        self.stats.accepted += 1;
+        Ok(())
+    }
+
+    pub fn stats(&self) -> ReplayStats {
+        self.stats
+    }
+
+    /// Number of tags currently remembered.
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(|bucket| bucket.count).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Bytes the filters currently take.
+    pub fn memory(&self) -> usize {
+        self.buckets.len() * self.words * 8
+    }
+
+    /// Bytes the filters take when every bucket is in use.
+    pub fn max_memory(&self) -> usize {
+        self.max_buckets * self.words * 8
+    }
+
+    /// Buckets the retention spans, one for each bucket length. These are
+    /// always kept, since a tag must not be forgotten early.
+    fn spans(&self) -> usize {
+        self.retention.div_ceil(self.bucket_length) as usize + 1
+    }
+
+    /// Buckets added because an earlier one for the same time was full.
+    fn extra_buckets(&self) -> usize {
+        self.buckets.iter().zip(self.buckets.iter().skip(1)).filter(|(earlier, later)| earlier.index == later.index).count()
+    }
+
+    /// Drops the buckets whose newest tag is older than the retention.
+    fn expire(&mut self, now: u64) {
+        while let Some(bucket) = self.buckets.front() {
+            if (bucket.index + 1) * self.bucket_length + self.retention > now {
+                break;
+            }
+            self.buckets.pop_front();
+        }
+    }
+
+    /// The filter bits for `tag`, by double hashing of a keyed hash.
+    fn positions(&self, tag: &ReplayTag) -> Vec<usize> {
+        let hash = blake3::keyed_hash(&self.hash_key, tag);
+        let hash = hash.as_bytes();
+        let first = u64::from_le_bytes(hash[..8].try_into().expect("Hash is 32 bytes"));
+        let step = u64::from_le_bytes(hash[8..16].try_into().expect("Hash is 32 bytes")) | 1;
+        let bits = (self.words * 64) as u64;
+        (0..self.hashes as u64).map(|i| (first.wrapping_add(i.wrapping_mul(step)) % bits) as usize).collect()
+    }
+}
+
+impl fmt::Debug for ReplayCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ReplayCache")
+            .field("retention", &self.retention)
+            .field("bucket_length", &self.bucket_length)
+            .field("len", &self.len())
+            .field("stats", &self.stats)
+            .finish_non_exhaustive()
+    }
+}
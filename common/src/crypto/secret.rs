@@ -0,0 +1,102 @@
+// common/src/crypto/secret.rs
+
+//! Storage for key material.
+//!
+//! Secrets live on the heap so they are never silently copied by moves, are
+//! wiped when dropped, and never show up in logs. With the `mlock` feature
+//! their pages are also locked into RAM on a best-effort basis; locking is
+//! per page, so dropping one secret may unlock a page another secret shares.
+
+use super::KEY_LEN;
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+use subtle::ConstantTimeEq;
+use zeroize::{Zeroize, Zeroizing};
+
+/// A heap-allocated secret. `T` is responsible for wiping itself on drop.
+pub(crate) struct SecretBox<T> {
+    inner: Box<T>,
+    #[cfg(feature = "mlock")]
+    _lock: Option<region::LockGuard>,
+}
+
+impl<T> SecretBox<T> {
+    pub(crate) fn new(value: T) -> Self {
+        let inner = Box::new(value);
+        #[cfg(feature = "mlock")]
+        let _lock = region::lock(&*inner as *const T, std::mem::size_of::<T>())
+            .map_err(|e| log::warn!("Failed to lock secret in memory: {}", e))
+            .ok();
+        SecretBox {
+            inner,
+            #[cfg(feature = "mlock")]
+            _lock,
+        }
+    }
+}
+
+impl<T> Deref for SecretBox<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.inner
+    }
+}
+
+impl<T> DerefMut for SecretBox<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+}
+
+impl<T: Clone> Clone for SecretBox<T> {
+    fn clone(&self) -> Self {
+        SecretBox::new((*self.inner).clone())
+    }
+}
+
+impl<T> fmt::Debug for SecretBox<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("<redacted>")
+    }
+}
+
+/// A symmetric key. Not `Copy`, so every duplicate is an explicit `clone`,
+/// and each copy is wiped when it is dropped.
+#[derive(Clone)]
+pub struct SessionKey(SecretBox<Zeroizing<[u8; KEY_LEN]>>);
+
+impl SessionKey {
+    /// Takes ownership of `bytes`, wiping the caller's copy.
+    pub fn from_bytes(mut bytes: [u8; KEY_LEN]) -> Self {
+        let key = SessionKey::fill_with(|key| key.copy_from_slice(&bytes));
+        bytes.zeroize();
+        key
+    }
+
+    /// Builds a key in place, so the material is never staged on the stack.
+    pub(crate) fn fill_with(fill: impl FnOnce(&mut [u8; KEY_LEN])) -> Self {
+        let mut secret = SecretBox::new(Zeroizing::new([0u8; KEY_LEN]));
+        fill(&mut secret.inner);
+        SessionKey(secret)
+    }
+
+    pub fn as_bytes(&self) -> &[u8; KEY_LEN] {
+        &self.0
+    }
+}
+
+impl fmt::Debug for SessionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SessionKey(<redacted>)")
+    }
+}
+
+/// Constant-time, so comparing keys leaks nothing through timing.
+impl PartialEq for SessionKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.as_bytes().ct_eq(other.as_bytes()).into()
+    }
+}
+
+impl Eq for SessionKey {}
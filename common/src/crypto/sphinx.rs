@@ -0,0 +1,491 @@
+// common/src/crypto/sphinx.rs
+
+//! Sphinx packets for mixnet messages.
+//!
+//! A packet is a header and a fixed-size payload. The header carries one
+//! group element `alpha`, the encrypted routing information and a MAC. Each
+//! mix derives a shared secret from `alpha`, checks the MAC, decrypts its
+//! routing slot, shifts the routing information left by one slot and blinds
+//! `alpha` for the next hop. The routing information stays the same length at
+//! every hop, so a mix cannot tell where it sits on the route, and because
+//! every part of the packet is re-encrypted, packets entering and leaving a
+//! mix cannot be matched bit for bit.
+//!
+//! The payload is encrypted with the LIONESS wide-block cipher, once per hop,
+//! so any change to it in transit destroys the whole message and is caught by
+//! the zero tag at its start.
+//!
+//! Single-use reply blocks (SURBs) let a recipient answer without learning the
+//! sender's route: the sender builds the header in advance and keeps the keys
+//! needed to open the reply.
+
+use super::{diffie_hellman, kdf, Fingerprint, PublicKey, SecretKey, KEY_LEN};
+use crate::error::PhantomBandError;
+use chacha20::ChaCha20;
+use chacha20::cipher::{KeyIvInit, StreamCipher};
+use curve25519_dalek::{MontgomeryPoint, Scalar};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use subtle::ConstantTimeEq;
+use zeroize::Zeroizing;
+use std::fmt;
+
+pub const MAX_HOPS: usize = 5;
+pub const ADDRESS_LEN: usize = 32;
+pub const MAC_LEN: usize = 32;
+/// One hop's routing slot: command (u8) | address | MAC of the next header.
+pub const ROUTING_SLOT_LEN: usize = 1 + ADDRESS_LEN + MAC_LEN;
+pub const ROUTING_INFO_LEN: usize = MAX_HOPS * ROUTING_SLOT_LEN;
+/// Header: alpha | routing information | MAC
+pub const HEADER_LEN: usize = KEY_LEN + ROUTING_INFO_LEN + MAC_LEN;
+pub const PAYLOAD_LEN: usize = 1024;
+/// Zero bytes at the start of every plaintext payload.
+const PAYLOAD_TAG_LEN: usize = 16;
+/// Payload plaintext: zero tag | message length (u16) | message | zero padding
+pub const MAX_MESSAGE_LEN: usize = PAYLOAD_LEN - PAYLOAD_TAG_LEN - 2;
+pub const PACKET_LEN: usize = HEADER_LEN + PAYLOAD_LEN;
+/// SURB: first hop | header | reply payload key
+pub const SURB_LEN: usize = ADDRESS_LEN + HEADER_LEN + KEY_LEN;
+
+const HEADER_MAC_LABEL: &str = "PhantomBand v1 sphinx header mac";
+const HEADER_STREAM_LABEL: &str = "PhantomBand v1 sphinx header stream";
+const PAYLOAD_KEY_LABEL: &str = "PhantomBand v1 sphinx payload key";
+const BLINDING_LABEL: &str = "PhantomBand v1 sphinx blinding factor";
+const REPLAY_TAG_LABEL: &str = "PhantomBand v1 sphinx replay tag";
+const LIONESS_LABELS: [&str; 4] = [
+    "PhantomBand v1 lioness round 1",
+    "PhantomBand v1 lioness round 2",
+    "PhantomBand v1 lioness round 3",
+    "PhantomBand v1 lioness round 4",
+];
+
+/// Where the final hop sends a message: an opaque destination or SURB id.
+pub type Address = [u8; ADDRESS_LEN];
+
+/// Identifies a packet's shared secret. A mix must drop any packet whose tag
+/// it has seen before, or replays would let an observer trace the packet.
+pub use super::replay::ReplayTag;
+
+type PayloadKey = Zeroizing<[u8; KEY_LEN]>;
+
+/// What a hop does with a packet, as found in its routing slot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RoutingCommand {
+    /// Pass the packet on to the mix with this fingerprint.
+    Relay(Fingerprint),
+    /// Final hop: hand the message to `destination`.
+    Deliver(Address),
+    /// Final hop of a SURB: return the still-encrypted payload to the
+    /// sender that created the SURB with this id.
+    Reply(Address),
+}
+
+impl RoutingCommand {
+    fn encode(&self, next_mac: &[u8; MAC_LEN]) -> [u8; ROUTING_SLOT_LEN] {
+        let (code, address) = match self {
+            RoutingCommand::Relay(fingerprint) => (0u8, fingerprint.as_bytes()),
+            RoutingCommand::Deliver(destination) => (1, destination),
+            RoutingCommand::Reply(surb_id) => (2, surb_id),
+        };
+        let mut slot = [0u8; ROUTING_SLOT_LEN];
+        slot[0] = code;
+        slot[1..1 + ADDRESS_LEN].copy_from_slice(address);
+        slot[1 + ADDRESS_LEN..].copy_from_slice(next_mac);
+        slot
+    }
+
+    fn decode(slot: &[u8]) -> Result<(RoutingCommand, [u8; MAC_LEN]), PhantomBandError> {
+        let address: Address = slot[1..1 + ADDRESS_LEN].try_into().unwrap();
+        let next_mac = slot[1 + ADDRESS_LEN..ROUTING_SLOT_LEN].try_into().unwrap();
+        let command = match slot[0] {
+            0 => RoutingCommand::Relay(Fingerprint::from_bytes(address)),
+            1 => RoutingCommand::Deliver(address),
+            2 => RoutingCommand::Reply(address),
+            code => return Err(PhantomBandError::Protocol(format!("Unknown Sphinx routing command: {}", code))),
+        };
+        Ok((command, next_mac))
+    }
+}
+
+/// A mix on a packet's route.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PathHop {
+    pub fingerprint: Fingerprint,
+    pub public_key: PublicKey,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Header {
+    alpha: [u8; KEY_LEN],
+    routing_info: [u8; ROUTING_INFO_LEN],
+    mac: [u8; MAC_LEN],
+}
+
+impl Header {
+    pub fn to_bytes(&self) -> [u8; HEADER_LEN] {
+        let mut bytes = [0u8; HEADER_LEN];
+        bytes[..KEY_LEN].copy_from_slice(&self.alpha);
+        bytes[KEY_LEN..KEY_LEN + ROUTING_INFO_LEN].copy_from_slice(&self.routing_info);
+        bytes[KEY_LEN + ROUTING_INFO_LEN..].copy_from_slice(&self.mac);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Header, PhantomBandError> {
+        if bytes.len() != HEADER_LEN {
+            return Err(PhantomBandError::Protocol(format!("Sphinx header is {} bytes, expected {}", bytes.len(), HEADER_LEN)));
+        }
+        Ok(Header {
+            alpha: bytes[..KEY_LEN].try_into().unwrap(),
+            routing_info: bytes[KEY_LEN..KEY_LEN + ROUTING_INFO_LEN].try_into().unwrap(),
+            mac: bytes[KEY_LEN + ROUTING_INFO_LEN..].try_into().unwrap(),
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SphinxPacket {
+    header: Header,
+    /// Always `PAYLOAD_LEN` bytes.
+    payload: Vec<u8>,
+}
+
+impl SphinxPacket {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(PACKET_LEN);
+        bytes.extend_from_slice(&self.header.to_bytes());
+        bytes.extend_from_slice(&self.payload);
+        bytes
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<SphinxPacket, PhantomBandError> {
+        if bytes.len() != PACKET_LEN {
+            return Err(PhantomBandError::Protocol(format!("Sphinx packet is {} bytes, expected {}", bytes.len(), PACKET_LEN)));
+        }
+        Ok(SphinxPacket { header: Header::from_bytes(&bytes[..HEADER_LEN])?, payload: bytes[HEADER_LEN..].to_vec() })
+    }
+}
+
+/// What a mix should do with a packet it processed.
+#[derive(Debug)]
+pub enum Action {
+    Forward { next_hop: Fingerprint, packet: Box<SphinxPacket> },
+    Deliver { destination: Address, message: Vec<u8> },
+    /// The payload can only be opened with the `SurbKeys` for `surb_id`.
+    Reply { surb_id: Address, payload: Vec<u8> },
+}
+
+#[derive(Debug)]
+pub struct ProcessedPacket {
+    /// Must be checked against the mix's replay cache before acting.
+    pub replay_tag: ReplayTag,
+    pub action: Action,
+}
+
+/// Builds a packet that travels `path` and delivers `message` to
+/// `destination` at the last hop. Send it to the first hop.
+pub fn create_packet(path: &[PathHop], destination: Address, message: &[u8]) -> Result<SphinxPacket, PhantomBandError> {
+    let (header, payload_keys) = build_header(path, RoutingCommand::Deliver(destination))?;
+    let mut payload = encode_message(message)?;
+    for key in payload_keys.iter().rev() {
+        lioness_encrypt(key, &mut payload);
+    }
+    Ok(SphinxPacket { header, payload })
+}
+
+/// Removes one layer from a packet with the mix's Sphinx key.
+pub fn process_packet(secret: &SecretKey, packet: &SphinxPacket) -> Result<ProcessedPacket, PhantomBandError> {
+    let shared = diffie_hellman(secret, &PublicKey::from_bytes(packet.header.alpha))?;
+    let secrets = HopSecrets::derive(&shared, &packet.header.alpha);
+    let mac = header_mac(&secrets.mac_key, &packet.header.routing_info);
+    if !bool::from(mac.ct_eq(&packet.header.mac)) {
+        return Err(PhantomBandError::Crypto("Sphinx header MAC mismatch".to_string()));
+    }
+
+    let mut routing_info = [0u8; ROUTING_INFO_LEN + ROUTING_SLOT_LEN];
+    routing_info[..ROUTING_INFO_LEN].copy_from_slice(&packet.header.routing_info);
+    apply_stream(&secrets.stream_key, &mut routing_info);
+    let (command, next_mac) = RoutingCommand::decode(&routing_info[..ROUTING_SLOT_LEN])?;
+
+    let mut payload = packet.payload.clone();
+    lioness_decrypt(&secrets.payload_key, &mut payload);
+
+    let action = match command {
+        RoutingCommand::Relay(next_hop) => {
+            let header = Header {
+                alpha: (MontgomeryPoint(packet.header.alpha) * *secrets.blinding).to_bytes(),
+                routing_info: routing_info[ROUTING_SLOT_LEN..].try_into().unwrap(),
+                mac: next_mac,
+            };
+            Action::Forward { next_hop, packet: Box::new(SphinxPacket { header, payload }) }
+        },
+        RoutingCommand::Deliver(destination) => Action::Deliver { destination, message: decode_message(&payload)? },
+        RoutingCommand::Reply(surb_id) => Action::Reply { surb_id, payload },
+    };
+    Ok(ProcessedPacket { replay_tag: secrets.replay_tag, action })
+}
+
+/// A single-use reply block: lets its holder send one message back along a
+/// route it cannot see.
+#[derive(Clone)]
+pub struct Surb {
+    first_hop: Fingerprint,
+    header: Header,
+    payload_key: Zeroizing<[u8; KEY_LEN]>,
+}
+
+impl Surb {
+    /// Where the reply packet must be sent.
+    pub fn first_hop(&self) -> Fingerprint {
+        self.first_hop
+    }
+
+    /// Builds the reply packet. Consumes the SURB, since using a header twice
+    /// would let the mixes link the two replies.
+    pub fn reply(self, message: &[u8]) -> Result<SphinxPacket, PhantomBandError> {
+        let mut payload = encode_message(message)?;
+        lioness_encrypt(&self.payload_key, &mut payload);
+        Ok(SphinxPacket { header: self.header, payload })
+    }
+
+    pub fn to_bytes(&self) -> Zeroizing<Vec<u8>> {
+        let mut bytes = Zeroizing::new(Vec::with_capacity(SURB_LEN));
+        bytes.extend_from_slice(self.first_hop.as_bytes());
+        bytes.extend_from_slice(&self.header.to_bytes());
+        bytes.extend_from_slice(&*self.payload_key);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Surb, PhantomBandError> {
+        if bytes.len() != SURB_LEN {
+            return Err(PhantomBandError::Protocol(format!("SURB is {} bytes, expected {}", bytes.len(), SURB_LEN)));
+        }
+        let mut payload_key = Zeroizing::new([0u8; KEY_LEN]);
+        payload_key.copy_from_slice(&bytes[ADDRESS_LEN + HEADER_LEN..]);
+        Ok(Surb {
+            first_hop: Fingerprint::from_bytes(bytes[..ADDRESS_LEN].try_into().unwrap()),
+            header: Header::from_bytes(&bytes[ADDRESS_LEN..ADDRESS_LEN + HEADER_LEN])?,
+            payload_key,
+        })
+    }
+}
+
+impl fmt::Debug for Surb {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Surb").field("first_hop", &self.first_hop).finish_non_exhaustive()
+    }
+}
+
+/// Kept by the creator of a SURB to open the reply.
+pub struct SurbKeys {
+    surb_id: Address,
+    reply_key: Zeroizing<[u8; KEY_LEN]>,
+    hop_keys: Vec<PayloadKey>,
+}
+
+impl SurbKeys {
+    pub fn surb_id(&self) -> &Address {
+        &self.surb_id
+    }
+
+    /// Decrypts the payload of a reply delivered for this SURB.
+    pub fn open(self, payload: &[u8]) -> Result<Vec<u8>, PhantomBandError> {
+        if payload.len() != PAYLOAD_LEN {
+            return Err(PhantomBandError::Protocol(format!("Sphinx payload is {} bytes, expected {}", payload.len(), PAYLOAD_LEN)));
+        }
+        let mut payload = payload.to_vec();
+        // Each hop decrypted once on the way; undo them from the last hop back.
+        for key in self.hop_keys.iter().rev() {
+            lioness_encrypt(key, &mut payload);
+        }
+        lioness_decrypt(&self.reply_key, &mut payload);
+        decode_message(&payload)
+    }
+}
+
+impl fmt::Debug for SurbKeys {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SurbKeys(<redacted>)")
+    }
+}
+
+/// Creates a SURB for replies along `path`, whose last hop returns the reply
+/// payload tagged with `surb_id`. Hand out the `Surb`, keep the `SurbKeys`.
+pub fn create_surb(path: &[PathHop], surb_id: Address) -> Result<(Surb, SurbKeys), PhantomBandError> {
+    let (header, hop_keys) = build_header(path, RoutingCommand::Reply(surb_id))?;
+    let mut reply_key = Zeroizing::new([0u8; KEY_LEN]);
+    OsRng.fill_bytes(&mut *reply_key);
+    let surb = Surb { first_hop: path[0].fingerprint, header, payload_key: reply_key.clone() };
+    Ok((surb, SurbKeys { surb_id, reply_key, hop_keys }))
+}
+
+/// Per-hop keys derived from the hop's shared secret.
+struct HopSecrets {
+    mac_key: Zeroizing<[u8; KEY_LEN]>,
+    stream_key: Zeroizing<[u8; KEY_LEN]>,
+    payload_key: Zeroizing<[u8; KEY_LEN]>,
+    blinding: Zeroizing<Scalar>,
+    replay_tag: ReplayTag,
+}
+
+impl HopSecrets {
+    fn derive(shared: &[u8; KEY_LEN], alpha: &[u8; KEY_LEN]) -> Self {
+        let prk = kdf::extract(shared, alpha);
+        let key = |label| {
+            let mut key = Zeroizing::new([0u8; KEY_LEN]);
+            kdf::expand(&prk, label, &mut *key);
+            key
+        };
+        let mut wide = Zeroizing::new([0u8; 64]);
+        kdf::expand(&prk, BLINDING_LABEL, &mut *wide);
+        let mut replay_tag = [0u8; 32];
+        kdf::expand(&prk, REPLAY_TAG_LABEL, &mut replay_tag);
+        HopSecrets {
+            mac_key: key(HEADER_MAC_LABEL),
+            stream_key: key(HEADER_STREAM_LABEL),
+            payload_key: key(PAYLOAD_KEY_LABEL),
+            blinding: Zeroizing::new(Scalar::from_bytes_mod_order_wide(&wide)),
+            replay_tag,
+        }
+    }
+}
+
+/// Builds a header for `path` whose last hop executes `last`, and returns the
+/// hops' payload keys in route order.
+fn build_header(path: &[PathHop], last: RoutingCommand) -> Result<(Header, Vec<PayloadKey>), PhantomBandError> {
+    if path.is_empty() || path.len() > MAX_HOPS {
+        return Err(PhantomBandError::Internal(format!("Sphinx route must have 1 to {} hops, got {}", MAX_HOPS, path.len())));
+    }
+    let hops = path.len();
+
+    // Each hop's alpha is the original one blinded by every earlier hop, so
+    // the sender tracks the product of the blinding factors.
+    let mut wide = Zeroizing::new([0u8; 64]);
+    OsRng.fill_bytes(&mut *wide);
+    let mut exponent = Zeroizing::new(Scalar::from_bytes_mod_order_wide(&wide));
+    let mut alpha = None;
+    let mut secrets = Vec::with_capacity(hops);
+    for hop in path {
+        let hop_alpha = MontgomeryPoint::mul_base(&exponent).to_bytes();
+        let shared = Zeroizing::new((MontgomeryPoint(*hop.public_key.as_bytes()) * *exponent).to_bytes());
+        if bool::from(shared.ct_eq(&[0u8; KEY_LEN])) {
+            return Err(PhantomBandError::Crypto(format!("Mix {} has a low-order Sphinx key", hop.fingerprint)));
+        }
+        let hop_secrets = HopSecrets::derive(&shared, &hop_alpha);
+        *exponent *= *hop_secrets.blinding;
+        alpha.get_or_insert(hop_alpha);
+        secrets.push(hop_secrets);
+    }
+
+    // The filler is what the routing information of the last hop ends with:
+    // the slots every earlier hop appended as zeros and then decrypted.
+    let mut filler = Vec::with_capacity((hops - 1) * ROUTING_SLOT_LEN);
+    for (i, hop_secrets) in secrets[..hops - 1].iter().enumerate() {
+        filler.extend_from_slice(&[0u8; ROUTING_SLOT_LEN]);
+        let stream = header_stream(&hop_secrets.stream_key);
+        for (byte, key_byte) in filler.iter_mut().zip(&stream[ROUTING_INFO_LEN - i * ROUTING_SLOT_LEN..]) {
+            *byte ^= key_byte;
+        }
+    }
+
+    let last_secrets = &secrets[hops - 1];
+    let mut routing_info = [0u8; ROUTING_INFO_LEN];
+    let prefix_len = ROUTING_INFO_LEN - filler.len();
+    routing_info[..ROUTING_SLOT_LEN].copy_from_slice(&last.encode(&[0u8; MAC_LEN]));
+    OsRng.fill_bytes(&mut routing_info[ROUTING_SLOT_LEN..prefix_len]);
+    let stream = header_stream(&last_secrets.stream_key);
+    for (byte, key_byte) in routing_info[..prefix_len].iter_mut().zip(stream.iter()) {
+        *byte ^= key_byte;
+    }
+    routing_info[prefix_len..].copy_from_slice(&filler);
+    let mut mac = header_mac(&last_secrets.mac_key, &routing_info);
+
+    for i in (0..hops - 1).rev() {
+        let mut next = [0u8; ROUTING_INFO_LEN];
+        next[..ROUTING_SLOT_LEN].copy_from_slice(&RoutingCommand::Relay(path[i + 1].fingerprint).encode(&mac));
+        next[ROUTING_SLOT_LEN..].copy_from_slice(&routing_info[..ROUTING_INFO_LEN - ROUTING_SLOT_LEN]);
+        let stream = header_stream(&secrets[i].stream_key);
+        for (byte, key_byte) in next.iter_mut().zip(stream.iter()) {
+            *byte ^= key_byte;
+        }
+        routing_info = next;
+        mac = header_mac(&secrets[i].mac_key, &routing_info);
+    }
+
+    let header = Header { alpha: alpha.expect("route is not empty"), routing_info, mac };
+    Ok((header, secrets.into_iter().map(|hop_secrets| hop_secrets.payload_key).collect()))
+}
+
+fn header_mac(key: &[u8; KEY_LEN], routing_info: &[u8]) -> [u8; MAC_LEN] {
+    *blake3::keyed_hash(key, routing_info).as_bytes()
+}
+
+/// The keystream a hop XORs over its routing information and one extra slot.
+fn header_stream(key: &[u8; KEY_LEN]) -> Zeroizing<Vec<u8>> {
+    let mut stream = Zeroizing::new(vec![0u8; ROUTING_INFO_LEN + ROUTING_SLOT_LEN]);
+    apply_stream(key, &mut stream);
+    stream
+}
+
+fn apply_stream(key: &[u8; KEY_LEN], data: &mut [u8]) {
+    ChaCha20::new(key.into(), &[0u8; 12].into()).apply_keystream(data);
+}
+
+fn encode_message(message: &[u8]) -> Result<Vec<u8>, PhantomBandError> {
+    if message.len() > MAX_MESSAGE_LEN {
+        return Err(PhantomBandError::Protocol(format!("Sphinx message too large: {} bytes (max {})", message.len(), MAX_MESSAGE_LEN)));
+    }
+    let mut payload = vec![0u8; PAYLOAD_LEN];
+    payload[PAYLOAD_TAG_LEN..PAYLOAD_TAG_LEN + 2].copy_from_slice(&(message.len() as u16).to_be_bytes());
+    payload[PAYLOAD_TAG_LEN + 2..PAYLOAD_TAG_LEN + 2 + message.len()].copy_from_slice(message);
+    Ok(payload)
+}
+
+fn decode_message(payload: &[u8]) -> Result<Vec<u8>, PhantomBandError> {
+    if payload[..PAYLOAD_TAG_LEN].iter().any(|&byte| byte != 0) {
+        return Err(PhantomBandError::Crypto("Sphinx payload failed its integrity check".to_string()));
+    }
+    let len = u16::from_be_bytes([payload[PAYLOAD_TAG_LEN], payload[PAYLOAD_TAG_LEN + 1]]) as usize;
+    if len > MAX_MESSAGE_LEN {
+        return Err(PhantomBandError::Protocol(format!("Sphinx message length {} exceeds payload", len)));
+    }
+    Ok(payload[PAYLOAD_TAG_LEN + 2..PAYLOAD_TAG_LEN + 2 + len].to_vec())
+}
+
+/// LIONESS: a four-round Feistel cipher over the whole payload, built from
+/// keyed BLAKE3 and ChaCha20. The left half is one hash output wide.
+fn lioness_encrypt(key: &[u8; KEY_LEN], block: &mut [u8]) {
+    let keys = lioness_keys(key);
+    let (left, right) = block.split_at_mut(KEY_LEN);
+    lioness_stream_round(&keys[0], left, right);
+    lioness_hash_round(&keys[1], left, right);
+    lioness_stream_round(&keys[2], left, right);
+    lioness_hash_round(&keys[3], left, right);
+}
+
+fn lioness_decrypt(key: &[u8; KEY_LEN], block: &mut [u8]) {
+    let keys = lioness_keys(key);
+    let (left, right) = block.split_at_mut(KEY_LEN);
+    lioness_hash_round(&keys[3], left, right);
+    lioness_stream_round(&keys[2], left, right);
+    lioness_hash_round(&keys[1], left, right);
+    lioness_stream_round(&keys[0], left, right);
+}
+
+fn lioness_keys(key: &[u8; KEY_LEN]) -> [Zeroizing<[u8; KEY_LEN]>; 4] {
+    LIONESS_LABELS.map(|label| {
+        let mut round_key = Zeroizing::new([0u8; KEY_LEN]);
+        kdf::expand(key, label, &mut *round_key);
+        round_key
+    })
+}
+
+fn lioness_stream_round(key: &[u8; KEY_LEN], left: &[u8], right: &mut [u8]) {
+    let stream_key = Zeroizing::new(*blake3::keyed_hash(key, left).as_bytes());
+    apply_stream(&stream_key, right);
+}
+
+fn lioness_hash_round(key: &[u8; KEY_LEN], left: &mut [u8], right: &[u8]) {
+    for (byte, hash_byte) in left.iter_mut().zip(blake3::keyed_hash(key, right).as_bytes()) {
+        *byte ^= hash_byte;
+    }
+}
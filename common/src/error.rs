@@ -0,0 +1,123 @@
+// common/src/error.rs
+
+use serde::{Serialize, Deserialize};
+use std::fmt;
+
+/// Why a link or circuit was closed. Sent on the wire in `Disconnect` and
+/// `Destroy` messages, so the numeric values are part of the protocol.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(into = "u8", from = "u8")]
+#[repr(u8)]
+pub enum CloseReason {
+    None = 0,
+    Protocol = 1,
+    Internal = 2,
+    Requested = 3,
+    Timeout = 4,
+    Crypto = 5,
+    Transport = 6,
+    Policy = 7,
+    /// A code this build does not know. Kept so that newer peers can add
+    /// reasons without breaking older ones.
+    Unknown = 255,
+}
+
+impl From<u8> for CloseReason {
+    fn from(code: u8) -> Self {
+        match code {
+            0 => CloseReason::None,
+            1 => CloseReason::Protocol,
+            2 => CloseReason::Internal,
+            3 => CloseReason::Requested,
+            4 => CloseReason::Timeout,
+            5 => CloseReason::Crypto,
+            6 => CloseReason::Transport,
+            7 => CloseReason::Policy,
+            _ => CloseReason::Unknown,
+        }
+    }
+}
+
+impl From<CloseReason> for u8 {
+    fn from(reason: CloseReason) -> Self {
+        reason as u8
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PhantomBandError {
+    /// Key agreement, signature or AEAD failure.
+    Crypto(String),
+    /// The peer sent something malformed or unexpected.
+    Protocol(String),
+    /// The underlying connection failed.
+    Transport(String),
+    /// The peer did not answer in time.
+    Timeout(String),
+    /// The operation was refused by local or remote policy.
+    Policy(String),
+    /// A local failure unrelated to the peer.
+    Internal(String),
+    /// The peer closed the link or circuit and told us why.
+    Closed(CloseReason),
+}
+
+impl PhantomBandError {
+    /// The reason code to send when this error tears down a link or circuit.
+    pub fn close_reason(&self) -> CloseReason {
+        match self {
+            PhantomBandError::Crypto(_) => CloseReason::Crypto,
+            PhantomBandError::Protocol(_) => CloseReason::Protocol,
+            PhantomBandError::Transport(_) => CloseReason::Transport,
+            PhantomBandError::Timeout(_) => CloseReason::Timeout,
+            PhantomBandError::Policy(_) => CloseReason::Policy,
+            PhantomBandError::Internal(_) => CloseReason::Internal,
+            PhantomBandError::Closed(reason) => *reason,
+        }
+    }
+
+    /// Adds context to the message while keeping the category.
+    pub fn context(self, context: &str) -> PhantomBandError {
+        match self {
+            PhantomBandError::Crypto(e) => PhantomBandError::Crypto(format!("{}: {}", context, e)),
+            PhantomBandError::Protocol(e) => PhantomBandError::Protocol(format!("{}: {}", context, e)),
+            PhantomBandError::Transport(e) => PhantomBandError::Transport(format!("{}: {}", context, e)),
+            PhantomBandError::Timeout(e) => PhantomBandError::Timeout(format!("{}: {}", context, e)),
+            PhantomBandError::Policy(e) => PhantomBandError::Policy(format!("{}: {}", context, e)),
+            PhantomBandError::Internal(e) => PhantomBandError::Internal(format!("{}: {}", context, e)),
+            PhantomBandError::Closed(reason) => PhantomBandError::Closed(reason),
+        }
+    }
+}
+
+impl fmt::Display for PhantomBandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PhantomBandError::Crypto(e) => write!(f, "Crypto error: {}", e),
+            PhantomBandError::Protocol(e) => write!(f, "Protocol error: {}", e),
+            PhantomBandError::Transport(e) => write!(f, "Transport error: {}", e),
+            PhantomBandError::Timeout(e) => write!(f, "Timed out: {}", e),
+            PhantomBandError::Policy(e) => write!(f, "Policy violation: {}", e),
+            PhantomBandError::Internal(e) => write!(f, "Internal error: {}", e),
+            PhantomBandError::Closed(reason) => write!(f, "Closed by peer: {:?}", reason),
+        }
+    }
+}
+
+impl std::error::Error for PhantomBandError {}
+
+impl From<std::io::Error> for PhantomBandError {
+    fn from(e: std::io::Error) -> Self {
+        if e.kind() == std::io::ErrorKind::TimedOut {
+            PhantomBandError::Timeout(e.to_string())
+        } else {
+            PhantomBandError::Transport(e.to_string())
+        }
+    }
+}
+
+impl From<bincode::Error> for PhantomBandError {
+    fn from(e: bincode::Error) -> Self {
+        PhantomBandError::Protocol(e.to_string())
+    }
+}
@@ -1,43 +1,987 @@
 // common/src/lib.rs
 pub mod crypto;
+pub mod error;
 pub mod protocol;
 pub mod utils;
 
 #[cfg(test)]
 mod tests {
-    use super::crypto;
+    use super::crypto::{self, CipherState, DeliveryMode};
+    use super::crypto::ntor::{NtorClient, NtorRelay, NtorReply, NtorRequest};
+    use super::crypto::onion::{ClientOnion, HopLayer, Peeled, RelayOnion};
+    use super::crypto::replay::{ReplayCache, ReplayStats};
+    use super::crypto::sphinx::{self, PathHop, SphinxPacket};
+    use super::error::{CloseReason, PhantomBandError};
+    use super::protocol::consensus::{ConsensusEntry, MAX_CONSENSUS_LIFETIME};
+    use super::protocol::descriptor::{ExitAction, ExitPolicy, ExitRule};
+    use super::protocol::handshake::{HandshakeInit, MAX_HANDSHAKE_PAYLOAD_LEN};
+    use super::protocol::wire;
+    use super::protocol::{AuthoritySet, ConsensusDocument, RelayFlags, SignedConsensus};
+    use super::protocol::{Datagram, DatagramAssembler, EndReason, StreamTarget};
+    use super::protocol::{self, version, RelayDescriptor, SignedRelayDescriptor, TransportKind, Capabilities, Cell, CellCommand, PhantomBandMessage, RelayCell, RelayCommand, Versions};
+    use super::utils::{Clock, ManualClock, SharedClock, SystemClock};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    fn session_key() -> crypto::SessionKey {
+        let client = crypto::generate_keypair();
+        let relay = crypto::generate_keypair();
+        let shared = crypto::diffie_hellman(&client.secret, &relay.public).expect("DH failed");
+        crypto::derive_session_keys(&shared, &client.public, &relay.public).forward.aead_key.clone()
+    }
+
+    /// A sending and a receiving state sharing one key.
+    fn cipher_pair(mode: DeliveryMode) -> (CipherState, CipherState) {
+        let key = session_key();
+        (CipherState::new(key.clone(), [7u8; 12], mode), CipherState::new(key, [7u8; 12], mode))
+    }
+
+    #[test]
+    fn test_diffie_hellman_agreement() {
+        let client = crypto::generate_keypair();
+        let relay = crypto::generate_keypair();
+
+        let client_shared = crypto::diffie_hellman(&client.secret, &relay.public).expect("DH failed");
+        let relay_shared = crypto::diffie_hellman(&relay.secret, &client.public).expect("DH failed");
+        assert_eq!(client_shared, relay_shared);
+
+        let client_keys = crypto::derive_session_keys(&client_shared, &client.public, &relay.public);
+        let relay_keys = crypto::derive_session_keys(&relay_shared, &client.public, &relay.public);
+        assert_eq!(client_keys.forward.aead_key, relay_keys.forward.aead_key);
+        assert_eq!(client_keys.backward.aead_key, relay_keys.backward.aead_key);
+        assert_ne!(client_keys.forward.aead_key.as_bytes(), &*client_shared);
+    }
+
+    #[test]
+    fn test_kdf_separates_directions_and_purposes() {
+        let keys = crypto::kdf::derive_session_keys(b"handshake secret", b"context");
+
+        assert_ne!(keys.forward.aead_key, keys.backward.aead_key);
+        assert_ne!(keys.forward.nonce_base, keys.backward.nonce_base);
+        assert_ne!(keys.forward.digest_seed, keys.backward.digest_seed);
+        assert_ne!(keys.forward.aead_key, keys.forward.digest_seed);
+
+        let other_context = crypto::kdf::derive_session_keys(b"handshake secret", b"other context");
+        assert_ne!(keys.forward.aead_key, other_context.forward.aead_key);
+    }
+
+    #[test]
+    fn test_directional_keys_reject_reflection() {
+        let keys = crypto::kdf::derive_session_keys(b"handshake secret", b"context");
+        let ciphertext = CipherState::from_keys(&keys.forward, DeliveryMode::InOrder).encrypt(b"client to relay").expect("Encryption failed");
+
+        assert!(CipherState::from_keys(&keys.backward, DeliveryMode::InOrder).decrypt(&ciphertext).is_err());
+    }
+
+    #[test]
+    fn test_diffie_hellman_rejects_low_order_point() {
+        let client = crypto::generate_keypair();
+        let zero_point = crypto::PublicKey::from_bytes([0u8; 32]);
+
+        assert!(crypto::diffie_hellman(&client.secret, &zero_point).is_err());
+    }
+
+    #[test]
+    fn test_mlkem_encapsulation_round_trip() {
+        let keypair = crypto::mlkem::generate_keypair();
+        assert_eq!(keypair.encapsulation_key.as_bytes().len(), crypto::mlkem::ENCAPSULATION_KEY_LEN);
+
+        let (ciphertext, shared) = keypair.encapsulation_key.encapsulate();
+        assert_eq!(ciphertext.as_bytes().len(), crypto::mlkem::CIPHERTEXT_LEN);
+        assert_eq!(*keypair.decapsulation_key.decapsulate(&ciphertext), *shared);
+
+        // Implicit rejection: a modified ciphertext decapsulates to an unrelated secret.
+        let mut tampered = ciphertext.as_bytes().to_vec();
+        tampered[0] ^= 1;
+        let tampered = crypto::mlkem::Ciphertext::from_bytes(&tampered).expect("Length unchanged");
+        assert_ne!(*keypair.decapsulation_key.decapsulate(&tampered), *shared);
+    }
+
+    /// Known answers from OpenSSL 3.5's FIPS 203 implementation: the seed
+    /// `d || z` and encapsulation randomness `m`, then SHA3-256 of the
+    /// encapsulation key and ciphertext, the shared secret, and the secret a
+    /// ciphertext with its first bit flipped decapsulates to.
+    const MLKEM_KNOWN_ANSWERS: [[&str; 6]; 3] = [
+        [
+            "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f202122232425262728292a2b2c2d2e2f303132333435363738393a3b3c3d3e3f",
+            "6465666768696a6b6c6d6e6f707172737475767778797a7b7c7d7e7f80818283",
+            "a24e16d8f8f9383a95b77050f4d9fd2f5733eec1d63ef3c23ebf9918173669a7",
+            "ce221a0989a8597aa562b69a8c235edc93ccf72fadc91d96785c9a09075e5cd1",
+            "c5a74110c158acbaf9c01deb86fa6cc10c14533feda54bec1fdd000d61f07e4e",
+            "bb28c25ed3222c13ce49d65f663f1c9f148565a664747e142f1abe06f33f4826",
+        ],
+        [
+            "02d6c8d9a65165a5283fb603e25dc3f02a08b87d1780345d744a43391cf2f148125f15d7e7da2e6ee33ff2122881999de5b2975d2f4ec7f7b10d9e7b87752c09",
+            "3d1ac4b2e963c3688d6f5ddfca9001390e05f0e00b0097d95dcebe91f1ff0e4f",
+            "872fe07634ed97e4a61eb4d62729d6d65b940475a5cc81c06588dad5b4d0dd3c",
+            "0cbb3291b4f69c6815c2b3feecb58b77f99b30a8b50d863f85165c6a8f24e7f4",
+            "2ac1395ab094b352f9f891544168393d2990cbb6ae955ca83a05f843fb22d6e8",
+            "bf2fb61dfb424dadaa067ed2d415bf5083af52c7cf835886075e7c5cb8416145",
+        ],
+        [
+            "9022b9279ebf3e3fc4d722ac77b62707281ddd770a431f7d5831a5d82d39091fb0eb0c757127cab20d2ffd12a48d19d4ac050a2dcbcddd3750faf44b04ac0427",
+            "b233afca071ba86ddd468f23279be4f25997af32ba214912c634cbcab6423476",
+            "0c72deccb7f64dd868d896d1fd69a4693147f98e443d6232ab5cac7ddf799331",
+            "9f84e94faf7e05ebc773bb04f0f07dd2909978122b3a69800cb1a2f7bfce7b24",
+            "c65eb000ad60ea4cdbace652fba3003cc61f3b0a70abfe3804e671174eef1a4a",
+            "ef677325276ebf16bf5d03258089a8312b6e43c2e81f1059f39fb87786851454",
+        ],
+    ];
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    fn unhex<const N: usize>(s: &str) -> [u8; N] {
+        let bytes: Vec<u8> = (0..s.len()).step_by(2).map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap()).collect();
+        bytes.try_into().unwrap()
+    }
+
+    #[test]
+    fn test_mlkem_known_answers() {
+        use sha3::{Digest, Sha3_256};
+        for [seed, m, ek_hash, ciphertext_hash, shared, rejected] in MLKEM_KNOWN_ANSWERS {
+            let seed: [u8; 64] = unhex(seed);
+            let (d, z) = (seed[..32].try_into().unwrap(), seed[32..].try_into().unwrap());
+            let keypair = crypto::mlkem::keypair_from_seed(d, z);
+            assert_eq!(hex(&Sha3_256::digest(keypair.encapsulation_key.as_bytes())), ek_hash);
+
+            let (ciphertext, secret) = keypair.encapsulation_key.encapsulate_deterministic(&unhex(m));
+            assert_eq!(hex(&Sha3_256::digest(ciphertext.as_bytes())), ciphertext_hash);
+            assert_eq!(hex(&*secret), shared);
+            assert_eq!(hex(&*keypair.decapsulation_key.decapsulate(&ciphertext)), shared);
+
+            let mut tampered = ciphertext.as_bytes().to_vec();
+            tampered[0] ^= 1;
+            let tampered = crypto::mlkem::Ciphertext::from_bytes(&tampered).unwrap();
+            assert_eq!(hex(&*keypair.decapsulation_key.decapsulate(&tampered)), rejected);
+        }
+    }
+
+    #[test]
+    fn test_mlkem_rejects_malformed_inputs() {
+        let keypair = crypto::mlkem::generate_keypair();
+        let encapsulation_key = keypair.encapsulation_key.as_bytes();
+        assert!(crypto::mlkem::EncapsulationKey::from_bytes(&encapsulation_key[1..]).is_err());
+        assert!(crypto::mlkem::Ciphertext::from_bytes(&[0u8; 16]).is_err());
+
+        // The first coefficient set to 4095, which is not reduced modulo q.
+        let mut unreduced = encapsulation_key.to_vec();
+        unreduced[0] = 0xff;
+        unreduced[1] |= 0x0f;
+        assert!(crypto::mlkem::EncapsulationKey::from_bytes(&unreduced).is_err());
+    }
+
+    #[test]
+    fn test_identity_sign_verify() {
+        let identity = crypto::IdentityKeypair::generate();
+        let message = b"relay handshake material";
+
+        let signature = identity.sign(message);
+        assert!(crypto::verify_signature(&identity.public_key(), message, &signature).is_ok());
+        assert!(crypto::verify_signature(&identity.public_key(), b"other material", &signature).is_err());
+
+        let other = crypto::IdentityKeypair::generate();
+        assert!(crypto::verify_signature(&other.public_key(), message, &signature).is_err());
+    }
+
+    #[test]
+    fn test_link_certificate_binds_link_key() {
+        let identity = crypto::IdentityKeypair::generate();
+        let link_key = crypto::generate_keypair().public;
+        let certificate = crypto::LinkCertificate::sign(&identity, &link_key);
+
+        assert_eq!(certificate.verify(&link_key).expect("Certificate should verify"), identity.public_key());
+        assert!(certificate.verify(&crypto::generate_keypair().public).is_err());
+
+        let payload = protocol::handshake::HandshakeReply { certificate, kem_ciphertext: None };
+        let encoded = wire::encode(&payload).expect("Encoding failed");
+        let decoded: protocol::handshake::HandshakeReply = wire::decode(&encoded).expect("Decoding failed");
+        assert_eq!(decoded.certificate, payload.certificate);
+    }
+
+    #[test]
+    fn test_public_key_hex_round_trip() {
+        let public = crypto::generate_keypair().public;
+        assert_eq!(public, public.to_string().parse().expect("Public key should parse"));
+        assert!("00".parse::<crypto::PublicKey>().is_err());
+    }
+
+    #[test]
+    fn test_identity_fingerprint_is_stable() {
+        let identity = crypto::IdentityKeypair::generate();
+        let restored = crypto::IdentityKeypair::from_secret_bytes(&identity.to_secret_bytes());
+        let fingerprint = identity.public_key().fingerprint();
+
+        assert_eq!(fingerprint, restored.public_key().fingerprint());
+        assert_eq!(fingerprint, fingerprint.to_string().parse().expect("Fingerprint should parse"));
+    }
+
+    #[test]
+    fn test_secrets_are_redacted_in_debug_output() {
+        let keypair = crypto::generate_keypair();
+        let identity = crypto::IdentityKeypair::generate();
+        let keys = crypto::kdf::derive_session_keys(b"handshake secret", b"context");
+        let key_hex: String = keys.forward.aead_key.as_bytes().iter().map(|b| format!("{:02x}", b)).collect();
+
+        assert!(format!("{:?}", keypair.secret).contains("redacted"));
+        assert!(format!("{:?}", keys).contains("redacted"));
+        assert!(!format!("{:?}", keys).contains(&key_hex));
+        assert!(!format!("{:?}", identity).contains(&format!("{:?}", identity.to_secret_bytes().as_slice())));
+        assert_eq!(keys.forward.aead_key, keys.forward.aead_key.clone());
+    }
 
     #[test]
     fn test_encryption_decryption() {
-        let key = crypto::generate_keypair();
+        let (mut sender, mut receiver) = cipher_pair(DeliveryMode::InOrder);
         let original_data = b"Hello, PhantomBand!";
 
-        let encrypted_data = crypto::encrypt(original_data, &key).expect("Encryption failed");
-        let decrypted_data = crypto::decrypt(&encrypted_data, &key).expect("Decryption failed");
+        let encrypted_data = sender.encrypt(original_data).expect("Encryption failed");
+        let decrypted_data = receiver.decrypt(&encrypted_data).expect("Decryption failed");
 
         assert_eq!(original_data.to_vec(), decrypted_data);
     }
 
     #[test]
     fn test_encryption_decryption_empty_data() {
-        let key = crypto::generate_keypair();
+        let (mut sender, mut receiver) = cipher_pair(DeliveryMode::InOrder);
         let original_data = b"";
 
-        let encrypted_data = crypto::encrypt(original_data, &key).expect("Encryption failed");
-        let decrypted_data = crypto::decrypt(&encrypted_data, &key).expect("Decryption failed");
+        let encrypted_data = sender.encrypt(original_data).expect("Encryption failed");
+        let decrypted_data = receiver.decrypt(&encrypted_data).expect("Decryption failed");
 
         assert_eq!(original_data.to_vec(), decrypted_data);
     }
 
     #[test]
     fn test_decryption_with_wrong_key() {
-        let key1 = crypto::generate_keypair();
-        let key2 = crypto::generate_keypair(); // Different key
+        let mut sender = CipherState::new(session_key(), [0u8; 12], DeliveryMode::InOrder);
+        let mut receiver = CipherState::new(session_key(), [0u8; 12], DeliveryMode::InOrder); // Different key
         let original_data = b"Secret message";
 
-        let encrypted_data = crypto::encrypt(original_data, &key1).expect("Encryption failed");
-        let result = crypto::decrypt(&encrypted_data, &key2);
+        let encrypted_data = sender.encrypt(original_data).expect("Encryption failed");
+        let result = receiver.decrypt(&encrypted_data);
 
         assert!(result.is_err());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_in_order_cipher_rejects_replay_and_reordering() {
+        let (mut sender, mut receiver) = cipher_pair(DeliveryMode::InOrder);
+        let first = sender.encrypt(b"first").expect("Encryption failed");
+        let second = sender.encrypt(b"second").expect("Encryption failed");
+
+        assert!(receiver.decrypt(&second).is_err());
+        assert_eq!(receiver.decrypt(&first).expect("Decryption failed"), b"first");
+        assert!(receiver.decrypt(&first).is_err());
+
+        // A forged counter changes the nonce, so authentication fails and the
+        // receiver still expects the genuine second message.
+        let mut forged = second.clone();
+        forged[7] ^= 1;
+        assert!(receiver.decrypt(&forged).is_err());
+        assert_eq!(receiver.decrypt(&second).expect("Decryption failed"), b"second");
+    }
+
+    #[test]
+    fn test_datagram_cipher_window() {
+        let (mut sender, mut receiver) = cipher_pair(DeliveryMode::Datagram);
+        let messages: Vec<Vec<u8>> = (0..crypto::cipher::REPLAY_WINDOW + 10)
+            .map(|i| sender.encrypt(&i.to_be_bytes()).expect("Encryption failed"))
+            .collect();
+
+        assert!(receiver.decrypt(&messages[5]).is_ok());
+        assert!(receiver.decrypt(&messages[2]).is_ok(), "Reordered datagram within the window");
+        assert!(receiver.decrypt(&messages[5]).is_err(), "Replayed datagram");
+        assert!(receiver.decrypt(&messages[messages.len() - 1]).is_ok());
+        assert!(receiver.decrypt(&messages[3]).is_err(), "Datagram older than the window");
+        assert!(receiver.decrypt(&messages[messages.len() - 2]).is_ok());
+    }
+
+    #[test]
+    fn test_cipher_rekeys_across_epochs() {
+        let key = session_key();
+        let mut sender = CipherState::new(key.clone(), [0u8; 12], DeliveryMode::Datagram).with_rekey_interval(4);
+        let mut receiver = CipherState::new(key.clone(), [0u8; 12], DeliveryMode::Datagram).with_rekey_interval(4);
+        let messages: Vec<Vec<u8>> = (0..10u8)
+            .map(|i| sender.encrypt(&[i]).expect("Encryption failed"))
+            .collect();
+
+        // Counter 5 belongs to the second epoch; counter 3 still opens with the previous key.
+        assert_eq!(receiver.decrypt(&messages[5]).expect("Decryption failed"), [5]);
+        assert_eq!(receiver.decrypt(&messages[3]).expect("Decryption failed"), [3]);
+        assert_eq!(receiver.decrypt(&messages[9]).expect("Decryption failed"), [9]);
+        assert_eq!(receiver.decrypt(&messages[7]).expect("Decryption failed"), [7]);
+        assert!(receiver.decrypt(&messages[2]).is_err(), "Key from two epochs ago was kept");
+
+        // A receiver cannot jump over a whole epoch, and the original key no
+        // longer opens anything past the first one.
+        let mut behind = CipherState::new(key.clone(), [0u8; 12], DeliveryMode::Datagram).with_rekey_interval(4);
+        assert!(behind.decrypt(&messages[9]).is_err());
+        let mut stale = CipherState::new(key, [0u8; 12], DeliveryMode::Datagram).with_rekey_interval(1 << 20);
+        assert!(stale.decrypt(&messages[5]).is_err());
+    }
+
+    fn onion_circuit(hops: usize) -> (ClientOnion, Vec<RelayOnion>) {
+        let mut client = ClientOnion::new();
+        let mut relays = Vec::new();
+        for hop in 0..hops {
+            let keys = crypto::kdf::derive_session_keys(&rand::random::<[u8; 32]>(), &[hop as u8]);
+            client.add_hop(HopLayer::new(&keys));
+            relays.push(RelayOnion::new(&keys));
+        }
+        (client, relays)
+    }
+
+    #[test]
+    fn test_onion_forward_cells_are_recognized_by_target_hop() {
+        let (mut client, mut relays) = onion_circuit(3);
+        for target in [2, 0, 1, 2] {
+            let cell = RelayCell::new(RelayCommand::Data, 7, format!("for hop {}", target).as_bytes()).unwrap();
+            let mut payload = client.wrap(&cell, target).expect("Wrapping failed");
+            for (hop, relay) in relays.iter_mut().enumerate().take(target + 1) {
+                match relay.peel(&mut payload).expect("Peeling failed") {
+                    Peeled::Recognized(received) => {
+                        assert_eq!(hop, target, "Hop {} recognized a cell for hop {}", hop, target);
+                        assert_eq!(received.data, cell.data);
+                    },
+                    Peeled::Forward => assert!(hop < target, "Hop {} did not recognize its cell", hop),
+                }
+            }
+        }
+        assert!(client.wrap(&RelayCell::new(RelayCommand::Data, 0, b"").unwrap(), 3).is_err());
+    }
+
+    #[test]
+    fn test_onion_backward_cells_name_their_origin() {
+        let (mut client, mut relays) = onion_circuit(3);
+        for origin in [1, 2, 0] {
+            let cell = RelayCell::new(RelayCommand::Data, 3, format!("from hop {}", origin).as_bytes()).unwrap();
+            let mut payload = relays[origin].originate(&cell).expect("Sealing failed");
+            for relay in relays[..origin].iter_mut().rev() {
+                relay.add_layer(&mut payload).expect("Adding layer failed");
+            }
+            let (hop, received) = client.unwrap(&payload).expect("Unwrapping failed");
+            assert_eq!(hop, origin);
+            assert_eq!(received.data, cell.data);
+        }
+    }
+
+    #[test]
+    fn test_onion_tampered_cell_is_not_recognized() {
+        let (mut client, mut relays) = onion_circuit(2);
+        let cell = RelayCell::new(RelayCommand::Data, 1, b"do not touch").unwrap();
+        let mut payload = client.wrap(&cell, 1).expect("Wrapping failed");
+        payload[protocol::CELL_PAYLOAD_LEN - 1] ^= 0x80;
+        assert!(matches!(relays[0].peel(&mut payload), Ok(Peeled::Forward)));
+        assert!(matches!(relays[1].peel(&mut payload), Ok(Peeled::Forward)));
+
+        let mut payload = relays[1].originate(&cell).expect("Sealing failed");
+        payload[20] ^= 1;
+        relays[0].add_layer(&mut payload).expect("Adding layer failed");
+        assert!(matches!(client.unwrap(&payload), Err(PhantomBandError::Protocol(_))));
+    }
+
+    #[test]
+    fn test_circuit_keys_detect_tagging() {
+        let (mut client_onion, mut relay) = ntor_hop();
+
+        // A bit flipped anywhere in a cell, header or data, is caught by the
+        // hop the cell is for, and only the untouched cells get through.
+        let cell = RelayCell::new(RelayCommand::Data, 2, b"tag me if you can").unwrap();
+        for position in [0, protocol::RELAY_DATA_LEN / 2, protocol::CELL_PAYLOAD_LEN - 1] {
+            let mut payload = client_onion.wrap(&cell, 0).unwrap();
+            payload[position] ^= 0x04;
+            assert!(matches!(relay.peel(&mut payload), Ok(Peeled::Forward)), "Flipped bit at {} went unnoticed", position);
+        }
+
+        // Tampering leaves the running digests out of step, which is why the
+        // circuit is torn down; check the backward direction on a fresh one.
+        let (mut client_onion, mut relay) = ntor_hop();
+        let mut payload = client_onion.wrap(&cell, 0).unwrap();
+        assert!(matches!(relay.peel(&mut payload), Ok(Peeled::Recognized(received)) if received.data == cell.data));
+        let mut reply = relay.originate(&cell).unwrap();
+        reply[protocol::cell::RELAY_HEADER_LEN] ^= 0x01;
+        assert!(client_onion.unwrap(&reply).is_err());
+    }
+
+    /// A one-hop circuit keyed by the ntor handshake.
+    fn ntor_hop() -> (ClientOnion, RelayOnion) {
+        let fingerprint = crypto::IdentityKeypair::generate().public_key().fingerprint();
+        let relay = NtorRelay::new(fingerprint, crypto::SecretKey::generate());
+        let (client, request) = NtorClient::new(fingerprint, relay.onion_key());
+        let (reply, relay_keys) = relay.respond(&request).expect("Relay handshake failed");
+        let mut client_onion = ClientOnion::new();
+        client_onion.add_hop(HopLayer::new(&client.finish(&reply).expect("Client handshake failed")));
+        (client_onion, RelayOnion::new(&relay_keys))
+    }
+
+    #[test]
+    fn test_ntor_authenticates_the_intended_relay() {
+        let identity = crypto::IdentityKeypair::generate().public_key().fingerprint();
+        let relay = NtorRelay::new(identity, crypto::SecretKey::generate());
+
+        // A relay only answers handshakes for its own identity and onion key.
+        let (_, request) = NtorClient::new(crypto::Fingerprint::from_bytes([9u8; 32]), relay.onion_key());
+        assert!(matches!(relay.respond(&request), Err(PhantomBandError::Policy(_))));
+        let (_, request) = NtorClient::new(identity, crypto::SecretKey::generate().public_key());
+        assert!(matches!(relay.respond(&request), Err(PhantomBandError::Policy(_))));
+
+        // An impostor without the onion key cannot produce the authenticator,
+        // even when it claims the right identity and key.
+        let (client, request) = NtorClient::new(identity, relay.onion_key());
+        let impostor = NtorRelay::new(identity, crypto::SecretKey::generate());
+        let forged_request = NtorRequest { onion_key: impostor.onion_key(), ..request.clone() };
+        let (forged, _) = impostor.respond(&forged_request).unwrap();
+        assert!(matches!(client.finish(&forged), Err(PhantomBandError::Crypto(_))));
+
+        // Replies are bound to the request they answer.
+        let (client, request) = NtorClient::new(identity, relay.onion_key());
+        let (mut reply, _) = relay.respond(&request).unwrap();
+        reply.auth[0] ^= 1;
+        assert!(client.finish(&reply).is_err());
+        let (client, _) = NtorClient::new(identity, relay.onion_key());
+        let (other_reply, _) = relay.respond(&request).unwrap();
+        assert!(client.finish(&other_reply).is_err());
+    }
+
+    #[test]
+    fn test_ntor_accepts_the_previous_onion_key_until_the_next_rotation() {
+        let fingerprint = crypto::Fingerprint::from_bytes([5u8; 32]);
+        let mut relay = NtorRelay::new(fingerprint, crypto::SecretKey::generate());
+        let first = relay.onion_key();
+        relay.rotate(crypto::SecretKey::generate());
+        assert_ne!(relay.onion_key(), first);
+        for onion_key in [first, relay.onion_key()] {
+            let (client, request) = NtorClient::new(fingerprint, onion_key);
+            let (reply, _) = relay.respond(&request).expect("Handshake under a live key refused");
+            client.finish(&reply).expect("Reply failed authentication");
+        }
+
+        relay.rotate(crypto::SecretKey::generate());
+        let (_, request) = NtorClient::new(fingerprint, first);
+        assert!(matches!(relay.respond(&request), Err(PhantomBandError::Policy(_))));
+    }
+
+    #[test]
+    fn test_replay_cache_remembers_tags_for_the_retention() {
+        let clock = ManualClock::new(1000 * 3600);
+        let mut cache = ReplayCache::new(Arc::new(clock.clone()), 2 * 3600, 600, 2, 14);
+        let fingerprint = crypto::Fingerprint::from_bytes([4u8; 32]);
+        let onion_key = crypto::SecretKey::generate().public_key();
+        let tag = || NtorClient::new(fingerprint, onion_key).1.replay_tag();
+        let (first, second) = (tag(), tag());
+        assert_ne!(first, second);
+
+        cache.check(&first).expect("Fresh handshake rejected");
+        assert!(matches!(cache.check(&first), Err(PhantomBandError::Policy(_))));
+
+        // A tag is remembered for the retention, while its onion key may
+        // still be accepted, and forgotten once its bucket has aged out.
+        clock.advance(Duration::from_secs(3600));
+        assert!(cache.check(&first).is_err());
+        cache.check(&second).unwrap();
+        clock.advance(Duration::from_secs(3600));
+        assert!(cache.check(&first).is_err());
+        clock.advance(Duration::from_secs(600));
+        assert!(cache.check(&second).is_err());
+        cache.check(&first).expect("Tag outlived the retention");
+
+        // A full bucket is followed by another for the same time while the
+        // memory limit allows, and past it the newest bucket takes more tags
+        // than it was sized for instead of refusing them.
+        let (third, fourth, fifth, sixth) = (tag(), tag(), tag(), tag());
+        cache.check(&third).unwrap();
+        cache.check(&fourth).expect("Full bucket refused a tag");
+        assert_eq!(cache.memory(), 3 * cache.max_memory() / 14);
+        cache.check(&fifth).unwrap();
+        cache.check(&sixth).expect("Cache at its memory limit refused a tag");
+        assert_eq!(cache.memory(), 3 * cache.max_memory() / 14);
+        assert!(cache.check(&fourth).is_err());
+        assert!(cache.check(&sixth).is_err());
+        assert_eq!(cache.len(), 6);
+        assert_eq!(cache.stats(), ReplayStats { accepted: 7, replays: 6, overfilled: 1 });
+
+        // At least the buckets the retention spans are kept, and fresh
+        // tags are not mistaken for replays.
+        let mut cache = ReplayCache::new(Arc::new(clock.clone()), 2 * 3600, 600, 1000, 1);
+        for _ in 0..1001 {
+            cache.check(&rand::random()).expect("Fresh tag taken for a replay");
+        }
+        assert_eq!(cache.stats().overfilled, 1);
+        assert_eq!(cache.memory() * 13, cache.max_memory());
+    }
+
+    fn sphinx_mixes(count: u8) -> Vec<(crypto::SecretKey, PathHop)> {
+        (0..count).map(|i| {
+            let secret = crypto::SecretKey::generate();
+            let hop = PathHop { fingerprint: crypto::Fingerprint::from_bytes([i; 32]), public_key: secret.public_key() };
+            (secret, hop)
+        }).collect()
+    }
+
+    /// Runs a packet through `mixes` and returns the final hop's action.
+    fn run_sphinx_route(mixes: &[(crypto::SecretKey, PathHop)], mut packet: SphinxPacket) -> sphinx::Action {
+        for (i, (secret, _)) in mixes.iter().enumerate() {
+            assert_eq!(packet.encode().len(), sphinx::PACKET_LEN);
+            let processed = sphinx::process_packet(secret, &packet).expect("Processing failed");
+            match processed.action {
+                sphinx::Action::Forward { next_hop, packet: next } => {
+                    assert_eq!(next_hop, mixes[i + 1].1.fingerprint);
+                    packet = SphinxPacket::decode(&next.encode()).expect("Packet decoding failed");
+                },
+                action => {
+                    assert_eq!(i, mixes.len() - 1, "Route ended early at hop {}", i);
+                    return action;
+                },
+            }
+        }
+        panic!("Route did not end at the last hop");
+    }
+
+    #[test]
+    fn test_sphinx_packet_traverses_route() {
+        for hops in [1, sphinx::MAX_HOPS as u8] {
+            let mixes = sphinx_mixes(hops);
+            let path: Vec<PathHop> = mixes.iter().map(|(_, hop)| *hop).collect();
+            let packet = sphinx::create_packet(&path, [9u8; 32], b"mix message").expect("Packet creation failed");
+            match run_sphinx_route(&mixes, packet) {
+                sphinx::Action::Deliver { destination, message } => {
+                    assert_eq!(destination, [9u8; 32]);
+                    assert_eq!(message, b"mix message");
+                },
+                action => panic!("Unexpected action: {:?}", action),
+            }
+        }
+        let too_long: Vec<PathHop> = sphinx_mixes(sphinx::MAX_HOPS as u8 + 1).into_iter().map(|(_, hop)| hop).collect();
+        assert!(sphinx::create_packet(&too_long, [0u8; 32], b"").is_err());
+    }
+
+    #[test]
+    fn test_sphinx_reply_with_surb() {
+        let mixes = sphinx_mixes(3);
+        let path: Vec<PathHop> = mixes.iter().map(|(_, hop)| *hop).collect();
+        let (surb, keys) = sphinx::create_surb(&path, [7u8; 32]).expect("SURB creation failed");
+
+        let surb = sphinx::Surb::from_bytes(&surb.to_bytes()).expect("SURB decoding failed");
+        assert_eq!(surb.first_hop(), path[0].fingerprint);
+        match run_sphinx_route(&mixes, surb.reply(b"the answer").expect("Reply failed")) {
+            sphinx::Action::Reply { surb_id, payload } => {
+                assert_eq!(&surb_id, keys.surb_id());
+                assert_eq!(keys.open(&payload).expect("Opening reply failed"), b"the answer");
+            },
+            action => panic!("Unexpected action: {:?}", action),
+        }
+    }
+
+    #[test]
+    fn test_sphinx_rejects_tampering_and_exposes_replays() {
+        let mixes = sphinx_mixes(2);
+        let path: Vec<PathHop> = mixes.iter().map(|(_, hop)| *hop).collect();
+        let packet = sphinx::create_packet(&path, [1u8; 32], b"tamper with me").expect("Packet creation failed");
+        let first = sphinx::process_packet(&mixes[0].0, &packet).expect("Processing failed");
+        let again = sphinx::process_packet(&mixes[0].0, &packet).expect("Processing failed");
+        assert_eq!(first.replay_tag, again.replay_tag);
+
+        // Wrong mix key or a modified header fail the MAC check.
+        assert!(matches!(sphinx::process_packet(&mixes[1].0, &packet), Err(PhantomBandError::Crypto(_))));
+        let mut bytes = packet.encode();
+        bytes[40] ^= 1;
+        assert!(matches!(sphinx::process_packet(&mixes[0].0, &SphinxPacket::decode(&bytes).unwrap()), Err(PhantomBandError::Crypto(_))));
+
+        // A modified payload garbles the whole message at the last hop.
+        let mut bytes = packet.encode();
+        bytes[sphinx::PACKET_LEN - 1] ^= 1;
+        let next = match sphinx::process_packet(&mixes[0].0, &SphinxPacket::decode(&bytes).unwrap()).expect("Processing failed").action {
+            sphinx::Action::Forward { packet, .. } => packet,
+            action => panic!("Unexpected action: {:?}", action),
+        };
+        assert!(matches!(sphinx::process_packet(&mixes[1].0, &next), Err(PhantomBandError::Crypto(_))));
+
+        let other = sphinx::create_packet(&path, [1u8; 32], b"tamper with me").expect("Packet creation failed");
+        assert_ne!(sphinx::process_packet(&mixes[0].0, &other).unwrap().replay_tag, first.replay_tag);
+    }
+
+    fn relay_descriptor(identity: &crypto::IdentityKeypair, published: u64) -> RelayDescriptor {
+        RelayDescriptor {
+            identity_key: identity.public_key(),
+            link_key: crypto::SecretKey::generate().public_key(),
+            onion_key: crypto::SecretKey::generate().public_key(),
+            addresses: vec!["192.0.2.1:443".parse().unwrap()],
+            transports: vec![TransportKind::Tcp, TransportKind::Quic],
+            bandwidth: 1_000_000,
+            exit_policy: ExitPolicy::reject_all(),
+            family: Vec::new(),
+            published,
+            valid_until: published + 3600,
+        }
+    }
+
+    #[test]
+    fn test_relay_descriptor_sign_verify() {
+        let identity = crypto::IdentityKeypair::generate();
+        let descriptor = relay_descriptor(&identity, 1000);
+        let signed = descriptor.sign(&identity).expect("Signing failed");
+
+        let decoded = SignedRelayDescriptor::decode(&signed.encode().unwrap()).expect("Decoding failed");
+        assert_eq!(decoded.verify(1500).expect("Verification failed"), descriptor);
+        assert!(matches!(decoded.verify(1000 + 3600), Err(PhantomBandError::Policy(_))), "Expired descriptor accepted");
+        assert_eq!(descriptor.address_for(TransportKind::Quic), Some("192.0.2.1:443".parse().unwrap()));
+        assert_eq!(descriptor.address_for(TransportKind::Obfs4), None);
+
+        // Signing with another identity, or a descriptor that breaks the
+        // canonical form, is refused.
+        assert!(descriptor.sign(&crypto::IdentityKeypair::generate()).is_err());
+        let mut unsorted = descriptor.clone();
+        unsorted.transports.reverse();
+        assert!(unsorted.sign(&identity).is_err());
+    }
+
+    #[test]
+    fn test_relay_descriptor_rejects_tampering() {
+        let identity = crypto::IdentityKeypair::generate();
+        let signed = relay_descriptor(&identity, 1000).sign(&identity).expect("Signing failed");
+        let mut bytes = signed.encode().unwrap();
+        // The last bytes of the body are the validity window; extend it.
+        let body_end = bytes.len() - 8 - 64;
+        bytes[body_end - 1] ^= 0x01;
+        let tampered = SignedRelayDescriptor::decode(&bytes).expect("Decoding failed");
+        assert!(matches!(tampered.verify(1500), Err(PhantomBandError::Crypto(_))));
+    }
+
+    #[test]
+    fn test_exit_policy_and_family() {
+        let policy = ExitPolicy {
+            rules: vec![
+                ExitRule { action: ExitAction::Reject, network: Some(("10.0.0.0".parse().unwrap(), 8)), ports: (0, u16::MAX) },
+                ExitRule { action: ExitAction::Accept, network: None, ports: (80, 443) },
+            ],
+        };
+        assert!(policy.allows("192.0.2.7".parse().unwrap(), 443));
+        assert!(!policy.allows("10.1.2.3".parse().unwrap(), 443));
+        assert!(!policy.allows("192.0.2.7".parse().unwrap(), 22));
+        assert!(!policy.allows("2001:db8::1".parse().unwrap(), 8080));
+        assert!(policy.is_exit() && !ExitPolicy::reject_all().is_exit());
+        // Without an address, a rule for one network cannot rule a port out.
+        assert!(policy.allows_port(443) && !policy.allows_port(22));
+        let closed = ExitPolicy { rules: vec![ExitRule { action: ExitAction::Reject, network: None, ports: (443, 443) }, policy.rules[1].clone()] };
+        assert!(!closed.allows_port(443) && closed.allows_port(80));
+
+        let (first_identity, second_identity) = (crypto::IdentityKeypair::generate(), crypto::IdentityKeypair::generate());
+        let mut first = relay_descriptor(&first_identity, 0);
+        let mut second = relay_descriptor(&second_identity, 0);
+        first.family = vec![second.fingerprint()];
+        assert!(!first.is_family_of(&second), "Family must be declared by both relays");
+        second.family = vec![first.fingerprint()];
+        assert!(first.is_family_of(&second) && second.is_family_of(&first));
+    }
+
+    #[test]
+    fn test_cells_have_fixed_length() {
+        let relay = |data: &[u8]| RelayCell::new(RelayCommand::Data, 0, data).unwrap().encode().unwrap().to_vec();
+        let short = PhantomBandMessage::Relay { circuit_id: 7, payload: relay(b"hi") };
+        let long = PhantomBandMessage::Relay { circuit_id: 7, payload: relay(&[0xAB; protocol::RELAY_DATA_LEN]) };
+        let (_, handshake) = NtorClient::new(crypto::Fingerprint::from_bytes([1u8; 32]), crypto::SecretKey::generate().public_key());
+        let create = PhantomBandMessage::CircuitCreate { circuit_id: 7, handshake };
+
+        for message in [short, long, create] {
+            let encoded = protocol::encode_message(&message).expect("Encoding failed");
+            assert_eq!(encoded.len(), protocol::CELL_LEN);
+        }
+    }
+
+    #[test]
+    fn test_cell_message_round_trip() {
+        let (_, request) = NtorClient::new(crypto::Fingerprint::from_bytes([3u8; 32]), crypto::SecretKey::generate().public_key());
+        let messages = vec![
+            PhantomBandMessage::CircuitCreate { circuit_id: 9, handshake: request },
+            PhantomBandMessage::CircuitCreated { circuit_id: 9, handshake: NtorReply { relay_key: crypto::SecretKey::generate().public_key(), auth: [5u8; 32] } },
+            PhantomBandMessage::Relay { circuit_id: 9, payload: vec![0xAB; protocol::CELL_PAYLOAD_LEN] },
+            PhantomBandMessage::Destroy { circuit_id: 9, reason: CloseReason::Timeout },
+            PhantomBandMessage::Disconnect { reason: CloseReason::Requested },
+        ];
+
+        for message in messages {
+            let encoded = protocol::encode_message(&message).expect("Encoding failed");
+            let decoded = protocol::decode_message(&encoded).expect("Decoding failed");
+            assert_eq!(format!("{:?}", message), format!("{:?}", decoded));
+        }
+    }
+
+    #[test]
+    fn test_extend_travels_in_relay_cells() {
+        let (_, request) = NtorClient::new(crypto::Fingerprint::from_bytes([3u8; 32]), crypto::SecretKey::generate().public_key());
+        let extend = PhantomBandMessage::Extend { address: "127.0.0.1:8081".parse().unwrap(), link_key: crypto::SecretKey::generate().public_key(), handshake: request };
+        let cell = extend.to_relay_cell(0).expect("Extend does not fit a relay cell");
+        assert_eq!(cell.command, RelayCommand::Extend);
+        let decoded = PhantomBandMessage::from_relay_cell(&RelayCell::decode(&cell.encode().unwrap()).unwrap()).expect("Decoding failed");
+        assert_eq!(format!("{:?}", extend), format!("{:?}", decoded));
+
+        // Neither message is a link-level cell, and a relay cell must carry
+        // the message its command names.
+        assert!(extend.to_cell().is_err());
+        let extended = PhantomBandMessage::Extended { handshake: NtorReply { relay_key: crypto::SecretKey::generate().public_key(), auth: [5u8; 32] } };
+        assert!(extended.to_cell().is_err());
+        let mut mislabeled = extended.to_relay_cell(0).expect("Extended does not fit a relay cell");
+        mislabeled.command = RelayCommand::Extend;
+        assert!(PhantomBandMessage::from_relay_cell(&mislabeled).is_err());
+        assert!(PhantomBandMessage::Destroy { circuit_id: 1, reason: CloseReason::None }.to_relay_cell(0).is_err());
+        assert_ne!(protocol::random_circuit_id(), 0);
+    }
+
+    #[test]
+    fn test_stream_messages_carry_their_stream() {
+        let target = StreamTarget::Hostname { host: "example.com".to_string(), port: 443 };
+        let begin = PhantomBandMessage::Begin { target: target.clone() };
+        let cell = begin.to_relay_cell(7).expect("Begin does not fit a relay cell");
+        assert_eq!((cell.command, cell.stream_id), (RelayCommand::Begin, 7));
+        match PhantomBandMessage::from_relay_cell(&RelayCell::decode(&cell.encode().unwrap()).unwrap()) {
+            Ok(PhantomBandMessage::Begin { target: decoded }) => assert_eq!(decoded, target),
+            other => panic!("Expected Begin, got {:?}", other),
+        }
+        assert_eq!(target.to_string(), "example.com:443");
+
+        // Stream messages need a stream, circuit messages must not have one.
+        assert!(begin.to_relay_cell(0).is_err());
+        assert!(PhantomBandMessage::Connected.to_relay_cell(0).is_err());
+        let (_, request) = NtorClient::new(crypto::Fingerprint::from_bytes([3u8; 32]), crypto::SecretKey::generate().public_key());
+        let extend = PhantomBandMessage::Extend { address: "127.0.0.1:8081".parse().unwrap(), link_key: crypto::SecretKey::generate().public_key(), handshake: request };
+        assert!(extend.to_relay_cell(7).is_err());
+
+        // Hostnames are checked before the exit goes near a resolver.
+        for host in ["", "exa mple.com", "a\0b", &"a".repeat(256)] {
+            let begin = PhantomBandMessage::Begin { target: StreamTarget::Hostname { host: host.to_string(), port: 80 } };
+            let cell = begin.to_relay_cell(1).expect("Begin does not fit a relay cell");
+            assert!(PhantomBandMessage::from_relay_cell(&cell).is_err(), "Accepted hostname {:?}", host);
+        }
+
+        let end = PhantomBandMessage::End { reason: EndReason::ExitPolicy }.to_relay_cell(2).unwrap();
+        assert!(matches!(PhantomBandMessage::from_relay_cell(&end), Ok(PhantomBandMessage::End { reason: EndReason::ExitPolicy })));
+    }
+
+    #[test]
+    fn test_datagrams_span_cells() {
+        let datagram = Datagram { peer: StreamTarget::Address("192.0.2.1:443".parse().unwrap()), data: (0..1500).map(|i| i as u8).collect() };
+        let cells = datagram.to_cells(3).expect("Splitting failed");
+        assert_eq!(cells.len(), 4, "1500 bytes and their peer need four cells");
+        assert!(cells.iter().all(|cell| cell.command == RelayCommand::Datagram && cell.stream_id == 3));
+        let mut assembler = DatagramAssembler::default();
+        let (last, rest) = cells.split_last().unwrap();
+        for cell in rest {
+            assert_eq!(assembler.push(cell).unwrap(), None);
+        }
+        assert_eq!(assembler.push(last).unwrap(), Some(datagram.clone()));
+
+        // Small datagrams fit one cell, and the assembler starts afresh.
+        let small = Datagram { peer: StreamTarget::Hostname { host: "dns.example".to_string(), port: 53 }, data: vec![1, 2, 3] };
+        let cells = small.to_cells(3).unwrap();
+        assert_eq!(cells.len(), 1);
+        assert_eq!(assembler.push(&cells[0]).unwrap(), Some(small));
+
+        let mut bad_flag = cells[0].clone();
+        bad_flag.data[0] = 2;
+        assert!(DatagramAssembler::default().push(&bad_flag).is_err());
+        let oversized = Datagram { peer: datagram.peer.clone(), data: vec![0; protocol::stream::MAX_DATAGRAM_LEN + 1] };
+        assert!(oversized.to_cells(3).is_err());
+        assert!(datagram.to_cells(0).is_err());
+        assert!(PhantomBandMessage::BeginDatagram.to_relay_cell(0).is_err());
+    }
+
+    #[test]
+    fn test_relay_cell_round_trip() {
+        let mut relay_cell = RelayCell::new(RelayCommand::Data, 42, b"stream data").expect("Relay cell too large");
+        relay_cell.digest = [1, 2, 3, 4];
+        let cell = Cell::from_relay(5, &relay_cell).expect("Relay cell encoding failed");
+
+        let decoded = Cell::decode(&cell.encode()).expect("Cell decoding failed");
+        assert_eq!(decoded.command, CellCommand::Relay);
+        assert_eq!(decoded.relay_cell().expect("Relay cell decoding failed"), relay_cell);
+    }
+
+    #[test]
+    fn test_cell_rejects_bad_lengths() {
+        assert!(RelayCell::new(RelayCommand::Data, 0, &vec![0u8; protocol::RELAY_DATA_LEN + 1]).is_err());
+        assert!(PhantomBandMessage::Relay { circuit_id: 1, payload: vec![0u8; protocol::RELAY_DATA_LEN] }.to_cell().is_err());
+
+        let encoded = PhantomBandMessage::Disconnect { reason: CloseReason::None }.to_cell().expect("Encoding failed").encode();
+        assert!(Cell::decode(&encoded[..encoded.len() - 1]).is_err());
+        let mut with_trailing = encoded.clone();
+        with_trailing.push(0);
+        assert!(Cell::decode(&with_trailing).is_err());
+    }
+
+    #[test]
+    fn test_wire_decoding_is_bounded_and_canonical() {
+        // A length prefix claiming far more than the input holds fails
+        // without allocating; the key's prefix follows the option tag.
+        let init = wire::encode(&HandshakeInit { kem_public_key: Some(b"key".to_vec()) }).expect("Encoding failed");
+        let mut huge = init.clone();
+        huge[1..9].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(matches!(wire::decode::<HandshakeInit>(&huge), Err(PhantomBandError::Protocol(_))));
+        assert!(wire::decode::<HandshakeInit>(&init).is_ok());
+
+        let body = wire::encode(&PhantomBandMessage::Destroy { circuit_id: 9, reason: CloseReason::Policy }).expect("Encoding failed");
+        let mut trailing = body.clone();
+        trailing.push(0);
+        let cell = Cell::from_body(9, CellCommand::Destroy, &trailing).unwrap();
+        assert!(matches!(PhantomBandMessage::from_cell(&cell), Err(PhantomBandError::Protocol(_))));
+
+        // Unknown reason codes decode to `Unknown`, which would re-encode
+        // differently, so the original bytes are refused.
+        let mut non_canonical = body;
+        *non_canonical.last_mut().unwrap() = 200;
+        assert!(wire::decode::<PhantomBandMessage>(&non_canonical).is_err());
+
+        // Payloads over the limit are refused by both ends.
+        let init = HandshakeInit { kem_public_key: Some(vec![0u8; MAX_HANDSHAKE_PAYLOAD_LEN]) };
+        assert!(wire::encode(&init).is_err());
+        assert!(wire::decode::<HandshakeInit>(&vec![1u8; MAX_HANDSHAKE_PAYLOAD_LEN + 1]).is_err());
+    }
+
+    #[test]
+    fn test_version_negotiation_picks_highest_common() {
+        let ours = Versions { versions: vec![1, 2, 3], capabilities: Capabilities::FIXED_CELLS.union(Capabilities::PADDING) };
+        let theirs = Versions { versions: vec![2, 3, 4], capabilities: Capabilities::FIXED_CELLS.union(Capabilities::PQ_HANDSHAKE) };
+
+        let link = version::negotiate(&ours, &theirs).expect("Negotiation failed");
+        assert_eq!(link.version, 3);
+        assert_eq!(link.capabilities, Capabilities::FIXED_CELLS);
+    }
+
+    #[test]
+    fn test_version_negotiation_reports_mismatch() {
+        let ours = Versions { versions: vec![1], capabilities: Capabilities::supported() };
+        let theirs = Versions { versions: vec![2, 3], capabilities: Capabilities::supported() };
+
+        let error = version::negotiate(&ours, &theirs).expect_err("Negotiation should fail");
+        assert_eq!(error.close_reason(), CloseReason::Protocol);
+        let message = error.to_string();
+        assert!(message.contains("[1]") && message.contains("[2, 3]"), "Unhelpful error: {}", message);
+    }
+
+    #[test]
+    fn test_versions_cell_round_trip() {
+        let versions = Versions { versions: vec![1, 7], capabilities: Capabilities::from_bits(0x8000_0001) };
+        let cell = Cell::decode(&versions.to_cell().expect("Encoding failed").encode()).expect("Cell decoding failed");
+
+        assert_eq!(cell.command, CellCommand::Versions);
+        assert_eq!(Versions::from_cell(&cell).expect("Versions decoding failed"), versions);
+    }
+
+    fn consensus_document(relays: &[crypto::IdentityKeypair], valid_after: u64) -> ConsensusDocument {
+        let mut entries: Vec<ConsensusEntry> = relays.iter()
+            .map(|identity| ConsensusEntry {
+                fingerprint: identity.public_key().fingerprint(),
+                descriptor: relay_descriptor(identity, valid_after).sign(identity).unwrap(),
+                flags: RelayFlags::VALID.union(RelayFlags::RUNNING),
+                weight: 1_000_000,
+            })
+            .collect();
+        entries.sort_by_key(|entry| entry.fingerprint);
+        ConsensusDocument { valid_after, valid_until: valid_after + 3600, entries }
+    }
+
+    #[test]
+    fn test_consensus_requires_authority_threshold() {
+        let authorities: Vec<_> = (0..3).map(|_| crypto::IdentityKeypair::generate()).collect();
+        let set = AuthoritySet::majority(authorities.iter().map(|a| a.public_key()).collect()).unwrap();
+        assert_eq!(set.threshold(), 2);
+        let relays: Vec<_> = (0..2).map(|_| crypto::IdentityKeypair::generate()).collect();
+        let document = consensus_document(&relays, 1000);
+
+        // One authority alone, even signing twice, is not enough; neither is
+        // help from a key outside the set.
+        let mut signed = SignedConsensus::new(&document).unwrap();
+        signed.add_signature(document.sign(&authorities[0]).unwrap());
+        signed.add_signature(document.sign(&authorities[0]).unwrap());
+        signed.add_signature(document.sign(&crypto::IdentityKeypair::generate()).unwrap());
+        assert!(matches!(signed.verify(&set, 1500), Err(PhantomBandError::Crypto(_))));
+
+        // A signature over a different document does not count either.
+        let forged = consensus_document(&relays[..1], 1000);
+        let mut bad = signed.clone();
+        bad.add_signature(forged.sign(&authorities[1]).unwrap());
+        assert!(matches!(bad.verify(&set, 1500), Err(PhantomBandError::Crypto(_))));
+
+        signed.add_signature(document.sign(&authorities[2]).unwrap());
+        let decoded = SignedConsensus::decode(&signed.encode().unwrap()).unwrap();
+        let consensus = decoded.verify(&set, 1500).expect("Verification failed");
+        assert_eq!(consensus.relays.len(), 2);
+        assert!(consensus.relay(&relays[1].public_key().fingerprint()).is_some());
+        assert!(matches!(decoded.verify(&set, 1000 + 3600), Err(PhantomBandError::Policy(_))), "Expired consensus accepted");
+    }
+
+    #[test]
+    fn test_consensus_rejects_malformed_documents() {
+        let authority = crypto::IdentityKeypair::generate();
+        let set = AuthoritySet::majority(vec![authority.public_key()]).unwrap();
+        let relays: Vec<_> = (0..2).map(|_| crypto::IdentityKeypair::generate()).collect();
+        let sign = |document: &ConsensusDocument| {
+            let mut signed = SignedConsensus::new(document).unwrap();
+            signed.add_signature(document.sign(&authority).unwrap());
+            signed
+        };
+
+        let mut unsorted = consensus_document(&relays, 1000);
+        unsorted.entries.reverse();
+        assert!(matches!(sign(&unsorted).verify(&set, 1500), Err(PhantomBandError::Protocol(_))));
+
+        let mut too_long = consensus_document(&relays, 1000);
+        too_long.valid_until = too_long.valid_after + MAX_CONSENSUS_LIFETIME + 1;
+        assert!(matches!(sign(&too_long).verify(&set, 1500), Err(PhantomBandError::Protocol(_))));
+
+        let mut mislabeled = consensus_document(&relays, 1000);
+        let other = mislabeled.entries[1].descriptor.clone();
+        mislabeled.entries[0].descriptor = other;
+        assert!(matches!(sign(&mislabeled).verify(&set, 1500), Err(PhantomBandError::Protocol(_))));
+
+        // A threshold that is not a majority would let two camps of
+        // authorities each sign a consensus of their own.
+        let keys: Vec<_> = (0..4).map(|_| crypto::IdentityKeypair::generate().public_key()).collect();
+        assert!(AuthoritySet::new(keys.clone(), 2).is_err());
+        assert!(AuthoritySet::new(keys.clone(), 5).is_err());
+        assert_eq!(AuthoritySet::majority(keys).unwrap().threshold(), 3);
+        assert!(AuthoritySet::majority(Vec::new()).is_err());
+    }
+
+    #[test]
+    fn test_manual_clock_moves_only_when_advanced() {
+        let clock = ManualClock::new(1_700_000_000);
+        let shared: SharedClock = Arc::new(clock.clone());
+        let start = shared.instant();
+        assert_eq!(shared.unix_time(), 1_700_000_000);
+        assert_eq!(shared.instant(), start);
+
+        clock.advance(Duration::from_millis(2500));
+        assert_eq!(shared.unix_time(), 1_700_000_002);
+        assert_eq!(shared.instant() - start, Duration::from_millis(2500));
+
+        // The system clock reports real wall-clock time.
+        assert!(SystemClock.unix_time() > 1_600_000_000);
+    }
+
+    #[test]
+    fn test_errors_map_to_close_reasons() {
+        let (_, mut receiver) = cipher_pair(DeliveryMode::InOrder);
+        let error = receiver.decrypt(&[0u8; 40]).expect_err("Decryption should fail");
+        assert!(matches!(error, PhantomBandError::Crypto(_)));
+        assert_eq!(error.close_reason(), CloseReason::Crypto);
+
+        let error = protocol::decode_message(&[0u8; 3]).expect_err("Decoding should fail");
+        assert!(matches!(error, PhantomBandError::Protocol(_)));
+
+        let timeout = PhantomBandError::from(std::io::Error::new(std::io::ErrorKind::TimedOut, "slow peer"));
+        assert_eq!(timeout.close_reason(), CloseReason::Timeout);
+    }
+
+    #[test]
+    fn test_close_reason_wire_codes() {
+        for reason in [CloseReason::None, CloseReason::Protocol, CloseReason::Policy] {
+            assert_eq!(CloseReason::from(u8::from(reason)), reason);
+        }
+        assert_eq!(CloseReason::from(200), CloseReason::Unknown);
+
+        let encoded = bincode::serialize(&CloseReason::Policy).expect("Serialization failed");
+        assert_eq!(encoded, vec![CloseReason::Policy as u8]);
+    }
+}
@@ -1,13 +1,185 @@
 // common/src/protocol.rs
 
+pub mod cell;
+pub mod consensus;
+pub mod descriptor;
+pub mod handshake;
+pub mod stream;
+pub mod version;
+pub mod wire;
+
+pub use cell::{Cell, CellCommand, RelayCell, RelayCommand, CELL_BODY_MAX_LEN, CELL_LEN, CELL_PAYLOAD_LEN, RELAY_DATA_LEN};
+pub use consensus::{AuthoritySet, Consensus, ConsensusDocument, RelayFlags, SignedConsensus};
+pub use descriptor::{RelayDescriptor, SignedRelayDescriptor, TransportKind};
+pub use stream::{Datagram, DatagramAssembler, EndReason, StreamTarget};
+pub use version::{Capabilities, LinkParameters, Versions};
+
 use serde::{Serialize, Deserialize};
+use crate::crypto::PublicKey;
+use crate::crypto::ntor::{NtorReply, NtorRequest};
+use crate::error::{CloseReason, PhantomBandError};
+use rand::RngCore;
+use rand::rngs::OsRng;
+use std::net::SocketAddr;
+use wire::WireFormat;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum PhantomBandMessage {
-    ConnectRequest { client_id: String, public_key: [u8; 32] },
-    ConnectResponse { relay_id: String, public_key: [u8; 32], success: bool, message: Option<String> },
-    CircuitCreate { circuit_id: u64, public_key: [u8; 32] },
-    CircuitCreated { circuit_id: u64, success: bool, message: Option<String> },
-    Data { circuit_id: u64, payload: Vec<u8> },
-    Disconnect,
-}
\ No newline at end of file
+    CircuitCreate { circuit_id: u64, handshake: NtorRequest },
+    CircuitCreated { circuit_id: u64, handshake: NtorReply },
+    /// A relay cell payload under the circuit's onion layers, exactly
+    /// `CELL_PAYLOAD_LEN` bytes.
+    Relay { circuit_id: u64, payload: Vec<u8> },
+    /// Tears down one circuit.
+    Destroy { circuit_id: u64, reason: CloseReason },
+    /// Closes the whole link.
+    Disconnect { reason: CloseReason },
+    /// Asks the last hop of a circuit to extend it to the relay at `address`,
+    /// whose link key is `link_key`. Travels inside a relay cell.
+    Extend { address: SocketAddr, link_key: PublicKey, handshake: NtorRequest },
+    /// The new hop's answer to `Extend`, passed back by the hop before it.
+    /// Travels inside a relay cell.
+    Extended { handshake: NtorReply },
+    /// Asks the last hop to open a stream to `target`. This and the stream
+    /// messages below travel inside relay cells carrying the stream's id.
+    Begin { target: StreamTarget },
+    /// Asks the last hop to open a datagram stream, over which it relays UDP
+    /// to any destination its exit policy allows.
+    BeginDatagram,
+    /// The last hop's answer once the stream's connection is open, or once it
+    /// is ready to relay datagrams.
+    Connected,
+    /// Closes a stream, or refuses to open it.
+    End { reason: EndReason },
+}
+
+impl PhantomBandMessage {
+    /// Packs the message into the cell that carries it on the wire. `Relay`
+    /// payloads fill a relay cell as they are; everything else is serialized
+    /// into the body of a cell whose command matches the message type.
+    pub fn to_cell(&self) -> Result<Cell, PhantomBandError> {
+        if let Some(command) = self.relay_command() {
+            return Err(PhantomBandError::Protocol(format!("{:?} messages travel inside relay cells", command)));
+        }
+        let (circuit_id, command) = self.cell_header();
+        if let PhantomBandMessage::Relay { payload, .. } = self {
+            if payload.len() != CELL_PAYLOAD_LEN {
+                return Err(PhantomBandError::Protocol(format!("Relay payload is {} bytes, expected {}", payload.len(), CELL_PAYLOAD_LEN)));
+            }
+            return Ok(Cell { circuit_id, command, payload: payload.clone() });
+        }
+        let body = wire::encode(self)?;
+        Cell::from_body(circuit_id, command, &body)
+    }
+
+    pub fn from_cell(cell: &Cell) -> Result<PhantomBandMessage, PhantomBandError> {
+        if cell.command == CellCommand::Relay {
+            return Ok(PhantomBandMessage::Relay { circuit_id: cell.circuit_id, payload: cell.payload.clone() });
+        }
+
+        let message: PhantomBandMessage = wire::decode(cell.body()?)?;
+        let expected = message.cell_header();
+        if expected != (cell.circuit_id, cell.command) {
+            return Err(PhantomBandError::Protocol(format!("Cell header {:?} does not match its {:?} message", (cell.circuit_id, cell.command), expected.1)));
+        }
+        Ok(message)
+    }
+
+    /// Packs a message that travels inside relay cells into the cell that
+    /// carries it between the client and a hop. Stream messages need the
+    /// stream's id; `Extend` and `Extended` belong to no stream and take zero.
+    pub fn to_relay_cell(&self, stream_id: u16) -> Result<RelayCell, PhantomBandError> {
+        let command = self.relay_command()
+            .ok_or_else(|| PhantomBandError::Protocol(format!("{:?} does not travel inside relay cells", self)))?;
+        check_stream_id(command, stream_id)?;
+        RelayCell::new(command, stream_id, &wire::encode(self)?)
+    }
+
+    pub fn from_relay_cell(cell: &RelayCell) -> Result<PhantomBandMessage, PhantomBandError> {
+        let message: PhantomBandMessage = wire::decode(&cell.data)?;
+        if message.relay_command() != Some(cell.command) {
+            return Err(PhantomBandError::Protocol(format!("Relay {:?} cell does not carry a matching message", cell.command)));
+        }
+        check_stream_id(cell.command, cell.stream_id)?;
+        if let PhantomBandMessage::Begin { target } = &message {
+            target.validate()?;
+        }
+        Ok(message)
+    }
+
+    /// The circuit a link-level message belongs to.
+    pub fn circuit_id(&self) -> Option<u64> {
+        match self {
+            PhantomBandMessage::CircuitCreate { circuit_id, .. }
+            | PhantomBandMessage::CircuitCreated { circuit_id, .. }
+            | PhantomBandMessage::Relay { circuit_id, .. }
+            | PhantomBandMessage::Destroy { circuit_id, .. } => Some(*circuit_id),
+            _ => None,
+        }
+    }
+
+    fn relay_command(&self) -> Option<RelayCommand> {
+        match self {
+            PhantomBandMessage::Extend { .. } => Some(RelayCommand::Extend),
+            PhantomBandMessage::Extended { .. } => Some(RelayCommand::Extended),
+            PhantomBandMessage::Begin { .. } => Some(RelayCommand::Begin),
+            PhantomBandMessage::BeginDatagram => Some(RelayCommand::BeginDatagram),
+            PhantomBandMessage::Connected => Some(RelayCommand::Connected),
+            PhantomBandMessage::End { .. } => Some(RelayCommand::End),
+            _ => None,
+        }
+    }
+
+    fn cell_header(&self) -> (u64, CellCommand) {
+        match self {
+            PhantomBandMessage::CircuitCreate { circuit_id, .. } => (*circuit_id, CellCommand::Create),
+            PhantomBandMessage::CircuitCreated { circuit_id, .. } => (*circuit_id, CellCommand::Created),
+            PhantomBandMessage::Relay { circuit_id, .. } => (*circuit_id, CellCommand::Relay),
+            PhantomBandMessage::Destroy { circuit_id, .. } => (*circuit_id, CellCommand::Destroy),
+            PhantomBandMessage::Disconnect { .. } => (0, CellCommand::Disconnect),
+            // Never sent as cells of their own, so no cell header matches.
+            PhantomBandMessage::Extend { .. }
+            | PhantomBandMessage::Extended { .. }
+            | PhantomBandMessage::Begin { .. }
+            | PhantomBandMessage::BeginDatagram
+            | PhantomBandMessage::Connected
+            | PhantomBandMessage::End { .. } => (0, CellCommand::Relay),
+        }
+    }
+}
+
+impl WireFormat for PhantomBandMessage {
+    const NAME: &'static str = "message";
+    const MAX_LEN: usize = CELL_BODY_MAX_LEN;
+}
+
+/// Stream commands need a stream; every other relay command uses id zero.
+fn check_stream_id(command: RelayCommand, stream_id: u16) -> Result<(), PhantomBandError> {
+    let stream_command = !matches!(command, RelayCommand::Extend | RelayCommand::Extended);
+    if stream_command != (stream_id != 0) {
+        return Err(PhantomBandError::Protocol(format!("Relay {:?} cell has stream id {}", command, stream_id)));
+    }
+    Ok(())
+}
+
+/// Picks an id for a new circuit. Ids are random so that they say nothing
+/// about how many circuits a link has carried; zero is reserved for
+/// link-level messages.
+pub fn random_circuit_id() -> u64 {
+    loop {
+        let id = OsRng.next_u64();
+        if id != 0 {
+            return id;
+        }
+    }
+}
+
+/// Encodes a message as the bytes of the cell that carries it.
+pub fn encode_message(message: &PhantomBandMessage) -> Result<Vec<u8>, PhantomBandError> {
+    Ok(message.to_cell()?.encode())
+}
+
+/// Decodes the bytes of exactly one cell back into a message.
+pub fn decode_message(bytes: &[u8]) -> Result<PhantomBandMessage, PhantomBandError> {
+    PhantomBandMessage::from_cell(&Cell::decode(bytes)?)
+}
@@ -0,0 +1,242 @@
+// common/src/protocol/cell.rs
+
+//! Wire cells.
+//!
+//! Everything sent on a circuit travels in fixed-size cells so that packet
+//! sizes reveal nothing about the payload. Only link handshake commands use
+//! variable-length cells, since they carry key material of varying size and
+//! are exchanged before any circuit exists.
+//!
+//! Fixed-length cell:    circuit id (u64) | command (u8) | payload (CELL_PAYLOAD_LEN)
+//! Variable-length cell: circuit id (u64) | command (u8) | length (u16) | payload
+//!
+//! All integers are big-endian.
+
+use crate::error::PhantomBandError;
+
+pub const CELL_LEN: usize = 512;
+pub const CELL_HEADER_LEN: usize = 9;
+pub const CELL_PAYLOAD_LEN: usize = CELL_LEN - CELL_HEADER_LEN;
+pub const VAR_CELL_HEADER_LEN: usize = CELL_HEADER_LEN + 2;
+
+/// Largest body a fixed-length control cell can carry after its length prefix.
+pub const CELL_BODY_MAX_LEN: usize = CELL_PAYLOAD_LEN - 2;
+
+/// Relay cell header: command (u8) | recognized (u16) | stream id (u16) | digest (4) | length (u16)
+pub const RELAY_HEADER_LEN: usize = 11;
+pub const RELAY_DATA_LEN: usize = CELL_PAYLOAD_LEN - RELAY_HEADER_LEN;
+pub const RELAY_DIGEST_LEN: usize = 4;
+/// Offsets of the fields onion layers inspect in an encoded relay cell.
+pub const RELAY_RECOGNIZED_OFFSET: usize = 1;
+pub const RELAY_DIGEST_OFFSET: usize = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum CellCommand {
+    Padding = 0,
+    Create = 1,
+    Created = 2,
+    Relay = 3,
+    Disconnect = 4,
+    Destroy = 5,
+    Versions = 130,
+}
+
+impl CellCommand {
+    /// Commands from 128 upwards use variable-length cells.
+    pub fn is_variable_length(self) -> bool {
+        self as u8 >= 128
+    }
+}
+
+impl TryFrom<u8> for CellCommand {
+    type Error = PhantomBandError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(CellCommand::Padding),
+            1 => Ok(CellCommand::Create),
+            2 => Ok(CellCommand::Created),
+            3 => Ok(CellCommand::Relay),
+            4 => Ok(CellCommand::Disconnect),
+            5 => Ok(CellCommand::Destroy),
+            130 => Ok(CellCommand::Versions),
+            _ => Err(PhantomBandError::Protocol(format!("Unknown cell command: {}", value))),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cell {
+    pub circuit_id: u64,
+    pub command: CellCommand,
+    /// Exactly `CELL_PAYLOAD_LEN` bytes for fixed-length commands.
+    pub payload: Vec<u8>,
+}
+
+impl Cell {
+    /// Builds a cell carrying `body`. Fixed-length cells prefix the body with
+    /// its length and pad it with zeros to the full payload size.
+    pub fn from_body(circuit_id: u64, command: CellCommand, body: &[u8]) -> Result<Cell, PhantomBandError> {
+        let payload = if command.is_variable_length() {
+            if body.len() > u16::MAX as usize {
+                return Err(PhantomBandError::Protocol(format!("Variable-length cell body too large: {} bytes", body.len())));
+            }
+            body.to_vec()
+        } else {
+            if body.len() > CELL_BODY_MAX_LEN {
+                return Err(PhantomBandError::Protocol(format!("Cell body too large: {} bytes (max {})", body.len(), CELL_BODY_MAX_LEN)));
+            }
+            let mut payload = vec![0u8; CELL_PAYLOAD_LEN];
+            payload[..2].copy_from_slice(&(body.len() as u16).to_be_bytes());
+            payload[2..2 + body.len()].copy_from_slice(body);
+            payload
+        };
+        Ok(Cell { circuit_id, command, payload })
+    }
+
+    /// Returns the body written by `from_body`.
+    pub fn body(&self) -> Result<&[u8], PhantomBandError> {
+        if self.command.is_variable_length() {
+            return Ok(&self.payload);
+        }
+        let len = u16::from_be_bytes([self.payload[0], self.payload[1]]) as usize;
+        if len > CELL_BODY_MAX_LEN {
+            return Err(PhantomBandError::Protocol(format!("Cell body length {} exceeds payload", len)));
+        }
+        Ok(&self.payload[2..2 + len])
+    }
+
+    pub fn from_relay(circuit_id: u64, relay_cell: &RelayCell) -> Result<Cell, PhantomBandError> {
+        Ok(Cell { circuit_id, command: CellCommand::Relay, payload: relay_cell.encode()?.to_vec() })
+    }
+
+    pub fn relay_cell(&self) -> Result<RelayCell, PhantomBandError> {
+        if self.command != CellCommand::Relay {
+            return Err(PhantomBandError::Protocol(format!("Expected a relay cell, got {:?}", self.command)));
+        }
+        RelayCell::decode(&self.payload)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(VAR_CELL_HEADER_LEN + self.payload.len());
+        bytes.extend_from_slice(&self.circuit_id.to_be_bytes());
+        bytes.push(self.command as u8);
+        if self.command.is_variable_length() {
+            bytes.extend_from_slice(&(self.payload.len() as u16).to_be_bytes());
+        }
+        bytes.extend_from_slice(&self.payload);
+        bytes
+    }
+
+    /// Decodes exactly one cell. Trailing bytes are an error.
+    pub fn decode(bytes: &[u8]) -> Result<Cell, PhantomBandError> {
+        if bytes.len() < CELL_HEADER_LEN {
+            return Err(PhantomBandError::Protocol(format!("Cell too short: {} bytes", bytes.len())));
+        }
+        let circuit_id = u64::from_be_bytes(bytes[..8].try_into().unwrap());
+        let command = CellCommand::try_from(bytes[8])?;
+
+        let payload = if command.is_variable_length() {
+            if bytes.len() < VAR_CELL_HEADER_LEN {
+                return Err(PhantomBandError::Protocol(format!("Variable-length cell too short: {} bytes", bytes.len())));
+            }
+            let len = u16::from_be_bytes([bytes[9], bytes[10]]) as usize;
+            if bytes.len() != VAR_CELL_HEADER_LEN + len {
+                return Err(PhantomBandError::Protocol(format!("Variable-length cell is {} bytes, header says {}", bytes.len(), VAR_CELL_HEADER_LEN + len)));
+            }
+            bytes[VAR_CELL_HEADER_LEN..].to_vec()
+        } else {
+            if bytes.len() != CELL_LEN {
+                return Err(PhantomBandError::Protocol(format!("Fixed-length cell is {} bytes, expected {}", bytes.len(), CELL_LEN)));
+            }
+            bytes[CELL_HEADER_LEN..].to_vec()
+        };
+        Ok(Cell { circuit_id, command, payload })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum RelayCommand {
+    Data = 1,
+    /// Carries a `PhantomBandMessage::Extend` to the last hop.
+    Extend = 2,
+    /// Carries a `PhantomBandMessage::Extended` back from the last hop.
+    Extended = 3,
+    /// Carry the `PhantomBandMessage` of the same name for one stream.
+    Begin = 4,
+    Connected = 5,
+    End = 6,
+    BeginDatagram = 7,
+    /// Part of a `stream::Datagram` on a datagram stream.
+    Datagram = 8,
+}
+
+impl TryFrom<u8> for RelayCommand {
+    type Error = PhantomBandError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            1 => Ok(RelayCommand::Data),
+            2 => Ok(RelayCommand::Extend),
+            3 => Ok(RelayCommand::Extended),
+            4 => Ok(RelayCommand::Begin),
+            5 => Ok(RelayCommand::Connected),
+            6 => Ok(RelayCommand::End),
+            7 => Ok(RelayCommand::BeginDatagram),
+            8 => Ok(RelayCommand::Datagram),
+            _ => Err(PhantomBandError::Protocol(format!("Unknown relay command: {}", value))),
+        }
+    }
+}
+
+/// The payload of a `Relay` cell once all onion layers are removed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RelayCell {
+    pub command: RelayCommand,
+    /// Zero in a plaintext relay cell; a hop treats a cell as addressed to
+    /// itself only if this is zero and the digest matches.
+    pub recognized: u16,
+    pub stream_id: u16,
+    pub digest: [u8; RELAY_DIGEST_LEN],
+    pub data: Vec<u8>,
+}
+
+impl RelayCell {
+    pub fn new(command: RelayCommand, stream_id: u16, data: &[u8]) -> Result<RelayCell, PhantomBandError> {
+        if data.len() > RELAY_DATA_LEN {
+            return Err(PhantomBandError::Protocol(format!("Relay data too large: {} bytes (max {})", data.len(), RELAY_DATA_LEN)));
+        }
+        Ok(RelayCell { command, recognized: 0, stream_id, digest: [0u8; RELAY_DIGEST_LEN], data: data.to_vec() })
+    }
+
+    pub fn encode(&self) -> Result<[u8; CELL_PAYLOAD_LEN], PhantomBandError> {
+        if self.data.len() > RELAY_DATA_LEN {
+            return Err(PhantomBandError::Protocol(format!("Relay data too large: {} bytes (max {})", self.data.len(), RELAY_DATA_LEN)));
+        }
+        let mut payload = [0u8; CELL_PAYLOAD_LEN];
+        payload[0] = self.command as u8;
+        payload[RELAY_RECOGNIZED_OFFSET..RELAY_RECOGNIZED_OFFSET + 2].copy_from_slice(&self.recognized.to_be_bytes());
+        payload[3..5].copy_from_slice(&self.stream_id.to_be_bytes());
+        payload[RELAY_DIGEST_OFFSET..RELAY_DIGEST_OFFSET + RELAY_DIGEST_LEN].copy_from_slice(&self.digest);
+        payload[9..11].copy_from_slice(&(self.data.len() as u16).to_be_bytes());
+        payload[RELAY_HEADER_LEN..RELAY_HEADER_LEN + self.data.len()].copy_from_slice(&self.data);
+        Ok(payload)
+    }
+
+    pub fn decode(payload: &[u8]) -> Result<RelayCell, PhantomBandError> {
+        if payload.len() != CELL_PAYLOAD_LEN {
+            return Err(PhantomBandError::Protocol(format!("Relay payload is {} bytes, expected {}", payload.len(), CELL_PAYLOAD_LEN)));
+        }
+        let command = RelayCommand::try_from(payload[0])?;
+        let recognized = u16::from_be_bytes([payload[RELAY_RECOGNIZED_OFFSET], payload[RELAY_RECOGNIZED_OFFSET + 1]]);
+        let stream_id = u16::from_be_bytes([payload[3], payload[4]]);
+        let digest = payload[RELAY_DIGEST_OFFSET..RELAY_DIGEST_OFFSET + RELAY_DIGEST_LEN].try_into().unwrap();
+        let len = u16::from_be_bytes([payload[9], payload[10]]) as usize;
+        if len > RELAY_DATA_LEN {
+            return Err(PhantomBandError::Protocol(format!("Relay data length {} exceeds payload", len)));
+        }
+        Ok(RelayCell { command, recognized, stream_id, digest, data: payload[RELAY_HEADER_LEN..RELAY_HEADER_LEN + len].to_vec() })
+    }
+}
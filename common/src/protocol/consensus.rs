@@ -0,0 +1,292 @@
+// common/src/protocol/consensus.rs
+
+//! Network consensus documents.
+//!
+//! Directory authorities each vote on the relays they know, aggregate the
+//! votes into the same `ConsensusDocument` and sign its canonical encoding.
+//! Clients trust a consensus only when a threshold of the authorities they
+//! are configured with signed it, so no single authority can hand them a
+//! relay list of its own choosing. Every entry carries the relay's own signed
+//! descriptor, so authorities can select relays but cannot forge their keys.
+
+use super::wire::{self, WireFormat};
+use super::descriptor::{RelayDescriptor, SignedRelayDescriptor, MAX_CLOCK_SKEW};
+use crate::crypto::{self, Fingerprint, IdentityKeypair, IdentityPublicKey};
+use crate::error::PhantomBandError;
+use log::warn;
+use serde::{Serialize, Deserialize};
+use std::fmt;
+
+pub const MAX_CONSENSUS_LEN: usize = 4 * 1024 * 1024;
+/// Longest window a consensus may claim to be valid for.
+pub const MAX_CONSENSUS_LIFETIME: u64 = 24 * 60 * 60;
+
+const CONSENSUS_SIGNATURE_PREFIX: &[u8] = b"PhantomBand v1 consensus";
+
+/// What the authorities agreed about a relay.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
+pub struct RelayFlags(u16);
+
+impl RelayFlags {
+    /// The relay's descriptor is well-formed and the relay is not blocked.
+    pub const VALID: RelayFlags = RelayFlags(1 << 0);
+    /// The authorities could reach the relay recently.
+    pub const RUNNING: RelayFlags = RelayFlags(1 << 1);
+    /// Fast enough to carry ordinary traffic.
+    pub const FAST: RelayFlags = RelayFlags(1 << 2);
+    /// Suitable as an entry guard.
+    pub const GUARD: RelayFlags = RelayFlags(1 << 3);
+    /// The exit policy allows some exit traffic.
+    pub const EXIT: RelayFlags = RelayFlags(1 << 4);
+    /// Must not be used as an exit, whatever its exit policy says.
+    pub const BAD_EXIT: RelayFlags = RelayFlags(1 << 5);
+
+    /// Every flag this build knows, in bit order.
+    pub const ALL: [RelayFlags; 6] = [
+        RelayFlags::VALID,
+        RelayFlags::RUNNING,
+        RelayFlags::FAST,
+        RelayFlags::GUARD,
+        RelayFlags::EXIT,
+        RelayFlags::BAD_EXIT,
+    ];
+
+    pub fn empty() -> RelayFlags {
+        RelayFlags(0)
+    }
+
+    pub fn from_bits(bits: u16) -> RelayFlags {
+        RelayFlags(bits)
+    }
+
+    pub fn bits(self) -> u16 {
+        self.0
+    }
+
+    pub fn contains(self, other: RelayFlags) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn union(self, other: RelayFlags) -> RelayFlags {
+        RelayFlags(self.0 | other.0)
+    }
+}
+
+impl fmt::Display for RelayFlags {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        const NAMES: [&str; 6] = ["valid", "running", "fast", "guard", "exit", "bad-exit"];
+        let names: Vec<&str> = RelayFlags::ALL.iter().zip(NAMES)
+            .filter(|(flag, _)| self.contains(**flag))
+            .map(|(_, name)| name)
+            .collect();
+        write!(f, "[{}]", names.join(", "))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConsensusEntry {
+    pub fingerprint: Fingerprint,
+    pub descriptor: SignedRelayDescriptor,
+    pub flags: RelayFlags,
+    /// Relative weight for path selection, from measured bandwidth.
+    pub weight: u64,
+}
+
+/// The relay list the authorities sign.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConsensusDocument {
+    pub valid_after: u64,
+    pub valid_until: u64,
+    /// Sorted by fingerprint, without duplicates.
+    pub entries: Vec<ConsensusEntry>,
+}
+
+impl ConsensusDocument {
+    pub fn encode(&self) -> Result<Vec<u8>, PhantomBandError> {
+        wire::encode(self)
+    }
+
+    /// An authority's signature over this document.
+    pub fn sign(&self, authority: &IdentityKeypair) -> Result<ConsensusSignature, PhantomBandError> {
+        let body = self.encode()?;
+        Ok(ConsensusSignature { identity_key: authority.public_key(), signature: authority.sign(&signature_material(&body)).to_vec() })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConsensusSignature {
+    pub identity_key: IdentityPublicKey,
+    pub signature: Vec<u8>,
+}
+
+/// The directory authorities a client trusts, and how many of them must sign
+/// a consensus.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuthoritySet {
+    keys: Vec<IdentityPublicKey>,
+    threshold: usize,
+}
+
+impl AuthoritySet {
+    /// `threshold` must be a majority of the distinct `keys`, so that two
+    /// conflicting consensuses can never both be accepted.
+    pub fn new(mut keys: Vec<IdentityPublicKey>, threshold: usize) -> Result<AuthoritySet, PhantomBandError> {
+        keys.sort_by_key(|key| key.to_bytes());
+        keys.dedup();
+        if threshold > keys.len() || threshold <= keys.len() / 2 {
+            return Err(PhantomBandError::Policy(format!("Authority threshold {} is not a majority of {} authorities", threshold, keys.len())));
+        }
+        Ok(AuthoritySet { keys, threshold })
+    }
+
+    /// A set that requires signatures from more than half of `keys`.
+    pub fn majority(mut keys: Vec<IdentityPublicKey>) -> Result<AuthoritySet, PhantomBandError> {
+        keys.sort_by_key(|key| key.to_bytes());
+        keys.dedup();
+        let threshold = keys.len() / 2 + 1;
+        AuthoritySet::new(keys, threshold)
+    }
+
+    pub fn contains(&self, key: &IdentityPublicKey) -> bool {
+        self.keys.contains(key)
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    pub fn threshold(&self) -> usize {
+        self.threshold
+    }
+}
+
+/// A consensus as distributed: the signed encoding and every signature
+/// collected for it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SignedConsensus {
+    body: Vec<u8>,
+    signatures: Vec<ConsensusSignature>,
+}
+
+impl SignedConsensus {
+    pub fn new(document: &ConsensusDocument) -> Result<SignedConsensus, PhantomBandError> {
+        Ok(SignedConsensus { body: document.encode()?, signatures: Vec::new() })
+    }
+
+    /// Adds an authority's signature, replacing an earlier one by the same key.
+    pub fn add_signature(&mut self, signature: ConsensusSignature) {
+        self.signatures.retain(|existing| existing.identity_key != signature.identity_key);
+        self.signatures.push(signature);
+    }
+
+    pub fn encode(&self) -> Result<Vec<u8>, PhantomBandError> {
+        wire::encode(self)
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<SignedConsensus, PhantomBandError> {
+        wire::decode(bytes)
+    }
+
+    /// Checks that enough of `authorities` signed the consensus and that it
+    /// is valid at `now`. Relays whose descriptors do not verify are left out.
+    pub fn verify(&self, authorities: &AuthoritySet, now: u64) -> Result<Consensus, PhantomBandError> {
+        let material = signature_material(&self.body);
+        let mut signers: Vec<&IdentityPublicKey> = Vec::new();
+        for signature in &self.signatures {
+            let key = &signature.identity_key;
+            if !authorities.contains(key) || signers.contains(&key) {
+                continue;
+            }
+            match crypto::verify_signature(key, &material, &signature.signature) {
+                Ok(()) => signers.push(key),
+                Err(e) => warn!("Ignoring bad consensus signature by {}: {}", key.fingerprint(), e),
+            }
+        }
+        if signers.len() < authorities.threshold() {
+            return Err(PhantomBandError::Crypto(format!("Consensus has {} valid authority signatures, {} required", signers.len(), authorities.threshold())));
+        }
+
+        let document: ConsensusDocument = wire::decode(&self.body)?;
+        if !document.entries.windows(2).all(|pair| pair[0].fingerprint < pair[1].fingerprint) {
+            return Err(PhantomBandError::Protocol("Consensus entries are not sorted and unique".to_string()));
+        }
+        if document.valid_until <= document.valid_after || document.valid_until - document.valid_after > MAX_CONSENSUS_LIFETIME {
+            return Err(PhantomBandError::Protocol(format!("Consensus validity {}..{} is empty or too long", document.valid_after, document.valid_until)));
+        }
+        if document.valid_after > now.saturating_add(MAX_CLOCK_SKEW) || now >= document.valid_until {
+            return Err(PhantomBandError::Policy(format!("Consensus is not valid at {} (valid {}..{})", now, document.valid_after, document.valid_until)));
+        }
+
+        let mut relays = Vec::with_capacity(document.entries.len());
+        for entry in document.entries {
+            let descriptor = match entry.descriptor.verify(now) {
+                Ok(descriptor) => descriptor,
+                Err(e) => {
+                    warn!("Leaving relay {} out of the consensus: {}", entry.fingerprint, e);
+                    continue;
+                }
+            };
+            if descriptor.fingerprint() != entry.fingerprint {
+                return Err(PhantomBandError::Protocol(format!("Consensus entry {} carries the descriptor of {}", entry.fingerprint, descriptor.fingerprint())));
+            }
+            relays.push(ConsensusRelay { descriptor, flags: entry.flags, weight: entry.weight });
+        }
+        Ok(Consensus { valid_after: document.valid_after, valid_until: document.valid_until, relays })
+    }
+}
+
+/// A relay in a verified consensus.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConsensusRelay {
+    pub descriptor: RelayDescriptor,
+    pub flags: RelayFlags,
+    pub weight: u64,
+}
+
+impl ConsensusRelay {
+    pub fn fingerprint(&self) -> Fingerprint {
+        self.descriptor.fingerprint()
+    }
+}
+
+/// A consensus whose signatures and descriptors have been verified.
+#[derive(Debug, Clone)]
+pub struct Consensus {
+    pub valid_after: u64,
+    pub valid_until: u64,
+    /// Sorted by fingerprint.
+    pub relays: Vec<ConsensusRelay>,
+}
+
+impl Consensus {
+    pub fn relay(&self, fingerprint: &Fingerprint) -> Option<&ConsensusRelay> {
+        self.relays.binary_search_by(|relay| relay.fingerprint().cmp(fingerprint))
+            .ok()
+            .map(|index| &self.relays[index])
+    }
+
+    pub fn is_valid_at(&self, now: u64) -> bool {
+        self.valid_after <= now.saturating_add(MAX_CLOCK_SKEW) && now < self.valid_until
+    }
+}
+
+impl WireFormat for ConsensusDocument {
+    const NAME: &'static str = "consensus";
+    const MAX_LEN: usize = MAX_CONSENSUS_LEN;
+}
+
+impl WireFormat for SignedConsensus {
+    const NAME: &'static str = "signed consensus";
+    const MAX_LEN: usize = MAX_CONSENSUS_LEN;
+}
+
+fn signature_material(body: &[u8]) -> Vec<u8> {
+    let mut material = Vec::with_capacity(CONSENSUS_SIGNATURE_PREFIX.len() + body.len());
+    material.extend_from_slice(CONSENSUS_SIGNATURE_PREFIX);
+    material.extend_from_slice(body);
+    material
+}
@@ -0,0 +1,252 @@
+// common/src/protocol/descriptor.rs
+
+//! Signed relay descriptors.
+//!
+//! A relay describes itself in a `RelayDescriptor` and signs it with its
+//! Ed25519 identity key. The signature covers the canonical bincode encoding
+//! of the descriptor, which is kept next to the signature, so every component
+//! verifies exactly the bytes the relay signed. Decoding rejects encodings
+//! that are not canonical, and descriptors with unsorted lists.
+
+use super::wire::{self, WireFormat};
+use crate::crypto::{self, Fingerprint, IdentityKeypair, IdentityPublicKey, PublicKey};
+use crate::error::PhantomBandError;
+use serde::{Serialize, Deserialize};
+use std::net::{IpAddr, SocketAddr};
+
+/// Longest window a descriptor may claim to be valid for.
+pub const MAX_DESCRIPTOR_LIFETIME: u64 = 7 * 24 * 60 * 60;
+/// How far in the future a descriptor's publication time may lie, to allow
+/// for clock skew between the relay and its readers.
+pub const MAX_CLOCK_SKEW: u64 = 5 * 60;
+pub const MAX_DESCRIPTOR_LEN: usize = 16 * 1024;
+pub const MAX_ADDRESSES: usize = 8;
+pub const MAX_FAMILY_LEN: usize = 64;
+pub const MAX_EXIT_RULES: usize = 256;
+
+const DESCRIPTOR_SIGNATURE_PREFIX: &[u8] = b"PhantomBand v1 relay descriptor";
+
+/// A transport a relay accepts links over.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum TransportKind {
+    Tcp,
+    Quic,
+    WebSocket,
+    Doh,
+    Obfs4,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ExitAction {
+    Accept,
+    Reject,
+}
+
+/// One line of an exit policy. `network` is an address prefix; `None`
+/// matches every address.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExitRule {
+    pub action: ExitAction,
+    pub network: Option<(IpAddr, u8)>,
+    pub ports: (u16, u16),
+}
+
+impl ExitRule {
+    fn matches(&self, address: IpAddr, port: u16) -> bool {
+        if port < self.ports.0 || port > self.ports.1 {
+            return false;
+        }
+        match (self.network, address) {
+            (None, _) => true,
+            (Some((IpAddr::V4(network), prefix)), IpAddr::V4(address)) => {
+                let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+                u32::from(network) & mask == u32::from(address) & mask
+            },
+            (Some((IpAddr::V6(network), prefix)), IpAddr::V6(address)) => {
+                let mask = u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0);
+                u128::from(network) & mask == u128::from(address) & mask
+            },
+            _ => false,
+        }
+    }
+
+    fn validate(&self) -> Result<(), PhantomBandError> {
+        if self.ports.0 > self.ports.1 {
+            return Err(PhantomBandError::Protocol(format!("Exit rule has empty port range {}-{}", self.ports.0, self.ports.1)));
+        }
+        match self.network {
+            Some((IpAddr::V4(_), prefix)) if prefix > 32 => Err(PhantomBandError::Protocol(format!("Invalid IPv4 prefix length {}", prefix))),
+            Some((IpAddr::V6(_), prefix)) if prefix > 128 => Err(PhantomBandError::Protocol(format!("Invalid IPv6 prefix length {}", prefix))),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Which destinations a relay will connect to as an exit. Rules are checked
+/// in order and the first match decides; anything unmatched is rejected.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExitPolicy {
+    pub rules: Vec<ExitRule>,
+}
+
+impl ExitPolicy {
+    /// The policy of a relay that is never an exit.
+    pub fn reject_all() -> Self {
+        ExitPolicy::default()
+    }
+
+    pub fn allows(&self, address: IpAddr, port: u16) -> bool {
+        self.rules.iter()
+            .find(|rule| rule.matches(address, port))
+            .is_some_and(|rule| rule.action == ExitAction::Accept)
+    }
+
+    /// Whether the policy may allow `port` on some address, for when the
+    /// destination address is not known yet, as with a hostname the exit
+    /// resolves. Rules for specific networks are given the benefit of the
+    /// doubt; only a rule matching every address can reject the port.
+    pub fn allows_port(&self, port: u16) -> bool {
+        for rule in &self.rules {
+            if port < rule.ports.0 || port > rule.ports.1 {
+                continue;
+            }
+            match (rule.action, rule.network) {
+                (ExitAction::Accept, _) => return true,
+                (ExitAction::Reject, None) => return false,
+                (ExitAction::Reject, Some(_)) => {},
+            }
+        }
+        false
+    }
+
+    /// Whether the relay accepts exit traffic to any destination at all.
+    pub fn is_exit(&self) -> bool {
+        self.rules.iter().any(|rule| rule.action == ExitAction::Accept)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RelayDescriptor {
+    /// Signs this descriptor; the relay's fingerprint is derived from it.
+    pub identity_key: IdentityPublicKey,
+    /// Static key clients authenticate in the Noise link handshake.
+    pub link_key: PublicKey,
+    /// Key for circuit extension handshakes.
+    pub onion_key: PublicKey,
+    pub addresses: Vec<SocketAddr>,
+    /// Sorted, without duplicates.
+    pub transports: Vec<TransportKind>,
+    /// Advertised bandwidth in bytes per second.
+    pub bandwidth: u64,
+    pub exit_policy: ExitPolicy,
+    /// Fingerprints of relays run by the same operator, which must never be
+    /// on one circuit together. Sorted, without duplicates.
+    pub family: Vec<Fingerprint>,
+    /// Unix time the descriptor was published.
+    pub published: u64,
+    /// Unix time after which the descriptor must no longer be used.
+    pub valid_until: u64,
+}
+
+impl RelayDescriptor {
+    pub fn fingerprint(&self) -> Fingerprint {
+        self.identity_key.fingerprint()
+    }
+
+    pub fn is_valid_at(&self, now: u64) -> bool {
+        self.published <= now.saturating_add(MAX_CLOCK_SKEW) && now < self.valid_until
+    }
+
+    /// The first address reachable over `transport`, if the relay offers it.
+    pub fn address_for(&self, transport: TransportKind) -> Option<SocketAddr> {
+        self.transports.contains(&transport).then(|| self.addresses.first().copied()).flatten()
+    }
+
+    /// Whether the two relays list each other as family. Both sides must
+    /// agree, so a relay cannot claim another operator's relays.
+    pub fn is_family_of(&self, other: &RelayDescriptor) -> bool {
+        self.family.binary_search(&other.fingerprint()).is_ok() && other.family.binary_search(&self.fingerprint()).is_ok()
+    }
+
+    pub fn sign(&self, identity: &IdentityKeypair) -> Result<SignedRelayDescriptor, PhantomBandError> {
+        if identity.public_key() != self.identity_key {
+            return Err(PhantomBandError::Internal("Descriptor identity key does not match the signing key".to_string()));
+        }
+        self.validate()?;
+        let body = wire::encode(self)?;
+        let signature = identity.sign(&signature_material(&body)).to_vec();
+        Ok(SignedRelayDescriptor { body, signature })
+    }
+
+    /// Checks the rules that do not depend on the current time.
+    fn validate(&self) -> Result<(), PhantomBandError> {
+        if self.addresses.is_empty() || self.addresses.len() > MAX_ADDRESSES {
+            return Err(PhantomBandError::Protocol(format!("Relay descriptor must list 1 to {} addresses, has {}", MAX_ADDRESSES, self.addresses.len())));
+        }
+        if !is_strictly_sorted(&self.transports) {
+            return Err(PhantomBandError::Protocol("Relay descriptor transports are not sorted and unique".to_string()));
+        }
+        if self.family.len() > MAX_FAMILY_LEN || !is_strictly_sorted(&self.family) {
+            return Err(PhantomBandError::Protocol("Relay descriptor family is too long or not sorted and unique".to_string()));
+        }
+        if self.exit_policy.rules.len() > MAX_EXIT_RULES {
+            return Err(PhantomBandError::Protocol(format!("Relay descriptor has {} exit rules (max {})", self.exit_policy.rules.len(), MAX_EXIT_RULES)));
+        }
+        self.exit_policy.rules.iter().try_for_each(ExitRule::validate)?;
+        if self.valid_until <= self.published || self.valid_until - self.published > MAX_DESCRIPTOR_LIFETIME {
+            return Err(PhantomBandError::Protocol(format!("Relay descriptor validity {}..{} is empty or too long", self.published, self.valid_until)));
+        }
+        Ok(())
+    }
+}
+
+/// A descriptor as published: the signed encoding and its signature.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SignedRelayDescriptor {
+    body: Vec<u8>,
+    signature: Vec<u8>,
+}
+
+impl SignedRelayDescriptor {
+    pub fn encode(&self) -> Result<Vec<u8>, PhantomBandError> {
+        wire::encode(self)
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<SignedRelayDescriptor, PhantomBandError> {
+        wire::decode(bytes)
+    }
+
+    /// Checks the signature, the encoding and that the descriptor is valid at
+    /// `now`, and returns the descriptor.
+    pub fn verify(&self, now: u64) -> Result<RelayDescriptor, PhantomBandError> {
+        let descriptor: RelayDescriptor = wire::decode(&self.body)?;
+        crypto::verify_signature(&descriptor.identity_key, &signature_material(&self.body), &self.signature)
+            .map_err(|e| e.context("Invalid relay descriptor signature"))?;
+        descriptor.validate()?;
+        if !descriptor.is_valid_at(now) {
+            return Err(PhantomBandError::Policy(format!("Relay descriptor for {} is not valid at {} (valid {}..{})", descriptor.fingerprint(), now, descriptor.published, descriptor.valid_until)));
+        }
+        Ok(descriptor)
+    }
+}
+
+impl WireFormat for RelayDescriptor {
+    const NAME: &'static str = "relay descriptor";
+    const MAX_LEN: usize = MAX_DESCRIPTOR_LEN;
+}
+
+impl WireFormat for SignedRelayDescriptor {
+    const NAME: &'static str = "signed descriptor";
+    const MAX_LEN: usize = MAX_DESCRIPTOR_LEN;
+}
+
+fn signature_material(body: &[u8]) -> Vec<u8> {
+    let mut material = Vec::with_capacity(DESCRIPTOR_SIGNATURE_PREFIX.len() + body.len());
+    material.extend_from_slice(DESCRIPTOR_SIGNATURE_PREFIX);
+    material.extend_from_slice(body);
+    material
+}
+
+fn is_strictly_sorted<T: Ord>(items: &[T]) -> bool {
+    items.windows(2).all(|pair| pair[0] < pair[1])
+}
@@ -0,0 +1,73 @@
+// common/src/protocol/handshake.rs
+
+//! Payloads of the Noise link handshake.
+//!
+//! Links are set up with the Noise XK pattern: the initiator already knows the
+//! responder's static link key, and reveals its own static key only in the
+//! last, encrypted message. Clients use a throwaway static key per link, so
+//! only relays are identified. Each handshake message carries one payload:
+//!
+//! ```text
+//! -> e, es               HandshakeInit    ML-KEM encapsulation key
+//! <- e, ee               HandshakeReply   responder certificate, ML-KEM ciphertext
+//! -> s, se [, psk]       HandshakeFinish  initiator certificate (relays only)
+//! ```
+//!
+//! When the link negotiated `Capabilities::PQ_HANDSHAKE`, the pattern is
+//! XKpsk3 and the ML-KEM-768 shared secret is the PSK, so the transport keys
+//! stay secret unless both X25519 and ML-KEM are broken.
+
+use super::wire::WireFormat;
+use crate::crypto::LinkCertificate;
+use serde::{Serialize, Deserialize};
+
+pub const NOISE_PARAMS: &str = "Noise_XK_25519_ChaChaPoly_BLAKE2s";
+pub const NOISE_PARAMS_PQ: &str = "Noise_XKpsk3_25519_ChaChaPoly_BLAKE2s";
+pub const PQ_PSK_LOCATION: usize = 3;
+/// Largest handshake payload; fits an ML-KEM-768 key or ciphertext plus a
+/// certificate.
+pub const MAX_HANDSHAKE_PAYLOAD_LEN: usize = 2048;
+
+const PROLOGUE_PREFIX: &[u8] = b"PhantomBand v1 link";
+
+/// The Noise prologue: both encoded Versions cells. Anyone who tampers with
+/// the version negotiation, say to strip the post-quantum capability, makes
+/// the handshake fail.
+pub fn prologue(initiator_versions: &[u8], responder_versions: &[u8]) -> Vec<u8> {
+    let mut prologue = Vec::with_capacity(PROLOGUE_PREFIX.len() + initiator_versions.len() + responder_versions.len());
+    prologue.extend_from_slice(PROLOGUE_PREFIX);
+    prologue.extend_from_slice(initiator_versions);
+    prologue.extend_from_slice(responder_versions);
+    prologue
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct HandshakeInit {
+    pub kem_public_key: Option<Vec<u8>>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct HandshakeReply {
+    pub certificate: LinkCertificate,
+    pub kem_ciphertext: Option<Vec<u8>>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct HandshakeFinish {
+    pub certificate: Option<LinkCertificate>,
+}
+
+impl WireFormat for HandshakeInit {
+    const NAME: &'static str = "handshake init";
+    const MAX_LEN: usize = MAX_HANDSHAKE_PAYLOAD_LEN;
+}
+
+impl WireFormat for HandshakeReply {
+    const NAME: &'static str = "handshake reply";
+    const MAX_LEN: usize = MAX_HANDSHAKE_PAYLOAD_LEN;
+}
+
+impl WireFormat for HandshakeFinish {
+    const NAME: &'static str = "handshake finish";
+    const MAX_LEN: usize = MAX_HANDSHAKE_PAYLOAD_LEN;
+}
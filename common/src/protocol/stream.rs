@@ -0,0 +1,190 @@
+// common/src/protocol/stream.rs
+
+//! Streams: connections the exit opens on the client's behalf, multiplexed
+//! over a circuit by the stream id in each relay cell.
+//!
+//! The client asks for a stream with `Begin`, naming the destination by
+//! address or by hostname. Hostnames are resolved by the exit, so the
+//! client's own resolver never learns where it connects. The exit answers
+//! with `Connected`, after which both sides send `Data` cells, or with `End`
+//! saying why it could not connect. Either side closes the stream with `End`.
+//!
+//! A datagram stream, opened with `BeginDatagram`, carries UDP instead. Each
+//! datagram names its peer, the destination on the way out and the source
+//! on the way back, and is split over as many `Datagram` cells as it needs.
+//! Cells of one stream arrive in order, so the first byte of each cell only
+//! says whether more of the datagram follows.
+
+use super::cell::{RelayCell, RelayCommand, RELAY_DATA_LEN};
+use super::wire::{self, WireFormat};
+use crate::error::PhantomBandError;
+use serde::{Serialize, Deserialize};
+use std::fmt;
+use std::net::SocketAddr;
+
+/// Longest hostname a stream may name, as in DNS.
+pub const MAX_HOSTNAME_LEN: usize = 255;
+/// Largest datagram a datagram stream carries, the most one UDP packet over
+/// IPv4 holds.
+pub const MAX_DATAGRAM_LEN: usize = 65_507;
+/// Room for the peer and length prefixes around a datagram's data.
+const DATAGRAM_OVERHEAD: usize = 512;
+/// Most cells one datagram takes.
+pub const MAX_DATAGRAM_CELLS: usize = Datagram::MAX_LEN.div_ceil(RELAY_DATA_LEN - 1);
+const FRAGMENT_LAST: u8 = 0;
+const FRAGMENT_MORE: u8 = 1;
+
+/// Where a stream connects to.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum StreamTarget {
+    Address(SocketAddr),
+    /// A hostname for the exit to resolve.
+    Hostname { host: String, port: u16 },
+}
+
+impl StreamTarget {
+    pub fn port(&self) -> u16 {
+        match self {
+            StreamTarget::Address(address) => address.port(),
+            StreamTarget::Hostname { port, .. } => *port,
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), PhantomBandError> {
+        match self {
+            StreamTarget::Hostname { host, .. } if host.is_empty() || host.len() > MAX_HOSTNAME_LEN => {
+                Err(PhantomBandError::Protocol(format!("Hostname is {} bytes (max {})", host.len(), MAX_HOSTNAME_LEN)))
+            },
+            StreamTarget::Hostname { host, .. } if !host.bytes().all(|byte| byte.is_ascii_alphanumeric() || byte == b'-' || byte == b'.' || byte == b'_') => {
+                Err(PhantomBandError::Protocol(format!("Invalid hostname {:?}", host)))
+            },
+            _ => Ok(()),
+        }
+    }
+}
+
+impl fmt::Display for StreamTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StreamTarget::Address(address) => write!(f, "{}", address),
+            StreamTarget::Hostname { host, port } => write!(f, "{}:{}", host, port),
+        }
+    }
+}
+
+/// One UDP datagram on a datagram stream.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Datagram {
+    pub peer: StreamTarget,
+    pub data: Vec<u8>,
+}
+
+impl WireFormat for Datagram {
+    const NAME: &'static str = "datagram";
+    const MAX_LEN: usize = MAX_DATAGRAM_LEN + DATAGRAM_OVERHEAD;
+}
+
+impl Datagram {
+    /// Splits the datagram over the `Datagram` cells that carry it on stream
+    /// `stream_id`, in the order they must be sent.
+    pub fn to_cells(&self, stream_id: u16) -> Result<Vec<RelayCell>, PhantomBandError> {
+        if stream_id == 0 {
+            return Err(PhantomBandError::Protocol("Datagram without a stream".to_string()));
+        }
+        if self.data.len() > MAX_DATAGRAM_LEN {
+            return Err(PhantomBandError::Protocol(format!("Datagram is {} bytes (max {})", self.data.len(), MAX_DATAGRAM_LEN)));
+        }
+        self.peer.validate()?;
+        let bytes = wire::encode(self)?;
+        let chunks: Vec<&[u8]> = bytes.chunks(RELAY_DATA_LEN - 1).collect();
+        chunks.iter().enumerate().map(|(i, chunk)| {
+            let flag = if i + 1 < chunks.len() { FRAGMENT_MORE } else { FRAGMENT_LAST };
+            RelayCell::new(RelayCommand::Datagram, stream_id, &[&[flag], *chunk].concat())
+        }).collect()
+    }
+}
+
+/// Puts the datagrams of one datagram stream back together from its cells.
+#[derive(Debug, Default)]
+pub struct DatagramAssembler {
+    buffer: Vec<u8>,
+}
+
+impl DatagramAssembler {
+    /// Adds the stream's next `Datagram` cell, and returns the datagram it
+    /// completes, if any.
+    pub fn push(&mut self, cell: &RelayCell) -> Result<Option<Datagram>, PhantomBandError> {
+        if cell.command != RelayCommand::Datagram {
+            return Err(PhantomBandError::Protocol(format!("Expected a datagram, got a {:?} cell", cell.command)));
+        }
+        let (&flag, chunk) = cell.data.split_first()
+            .ok_or_else(|| PhantomBandError::Protocol("Empty datagram cell".to_string()))?;
+        if self.buffer.len() + chunk.len() > Datagram::MAX_LEN {
+            return Err(PhantomBandError::Protocol(format!("Datagram exceeds {} bytes", Datagram::MAX_LEN)));
+        }
+        self.buffer.extend_from_slice(chunk);
+        match flag {
+            FRAGMENT_MORE => Ok(None),
+            FRAGMENT_LAST => {
+                let datagram: Datagram = wire::decode(&std::mem::take(&mut self.buffer))?;
+                datagram.peer.validate()?;
+                Ok(Some(datagram))
+            },
+            other => Err(PhantomBandError::Protocol(format!("Invalid datagram fragment flag {}", other))),
+        }
+    }
+}
+
+/// Why a stream ended, or why the exit could not open it. Sent on the wire in
+/// `End` messages, so the numeric values are part of the protocol.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(into = "u8", from = "u8")]
+#[repr(u8)]
+pub enum EndReason {
+    /// The stream was closed normally.
+    Done = 0,
+    Misc = 1,
+    /// The exit's policy does not allow the destination.
+    ExitPolicy = 2,
+    /// The exit could not resolve the hostname.
+    ResolveFailed = 3,
+    /// The destination refused the connection.
+    ConnectRefused = 4,
+    /// The destination could not be reached.
+    Unreachable = 5,
+    Timeout = 6,
+    /// A code this build does not know.
+    Unknown = 255,
+}
+
+impl From<u8> for EndReason {
+    fn from(code: u8) -> Self {
+        match code {
+            0 => EndReason::Done,
+            1 => EndReason::Misc,
+            2 => EndReason::ExitPolicy,
+            3 => EndReason::ResolveFailed,
+            4 => EndReason::ConnectRefused,
+            5 => EndReason::Unreachable,
+            6 => EndReason::Timeout,
+            _ => EndReason::Unknown,
+        }
+    }
+}
+
+impl From<EndReason> for u8 {
+    fn from(reason: EndReason) -> Self {
+        reason as u8
+    }
+}
+
+impl From<&std::io::Error> for EndReason {
+    fn from(e: &std::io::Error) -> Self {
+        match e.kind() {
+            std::io::ErrorKind::ConnectionRefused => EndReason::ConnectRefused,
+            std::io::ErrorKind::TimedOut => EndReason::Timeout,
+            std::io::ErrorKind::HostUnreachable | std::io::ErrorKind::NetworkUnreachable | std::io::ErrorKind::AddrNotAvailable => EndReason::Unreachable,
+            _ => EndReason::Misc,
+        }
+    }
+}
@@ -0,0 +1,142 @@
+// common/src/protocol/version.rs
+
+//! Link protocol version and capability negotiation.
+//!
+//! Both ends open a link by sending a `Versions` cell. Its layout must never
+//! change, since it is parsed before the two sides agree on anything:
+//!
+//! ```text
+//! count (u16) | count x version (u16) | capabilities (u32)
+//! ```
+//!
+//! The highest version both sides list is used for the rest of the link, and
+//! only capabilities both sides advertise are enabled.
+
+use super::cell::{Cell, CellCommand};
+use crate::error::PhantomBandError;
+use std::fmt;
+
+/// Link protocol versions this build speaks, in ascending order.
+pub const SUPPORTED_VERSIONS: &[u16] = &[1];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct Capabilities(u32);
+
+impl Capabilities {
+    pub const FIXED_CELLS: Capabilities = Capabilities(1 << 0);
+    pub const PADDING: Capabilities = Capabilities(1 << 1);
+    pub const CONGESTION_CONTROL: Capabilities = Capabilities(1 << 2);
+    pub const PQ_HANDSHAKE: Capabilities = Capabilities(1 << 3);
+
+    /// Capabilities implemented by this build.
+    pub fn supported() -> Capabilities {
+        Capabilities::FIXED_CELLS.union(Capabilities::PQ_HANDSHAKE)
+    }
+
+    pub fn empty() -> Capabilities {
+        Capabilities(0)
+    }
+
+    pub fn from_bits(bits: u32) -> Capabilities {
+        Capabilities(bits)
+    }
+
+    pub fn bits(self) -> u32 {
+        self.0
+    }
+
+    pub fn contains(self, other: Capabilities) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn union(self, other: Capabilities) -> Capabilities {
+        Capabilities(self.0 | other.0)
+    }
+
+    pub fn intersection(self, other: Capabilities) -> Capabilities {
+        Capabilities(self.0 & other.0)
+    }
+}
+
+impl fmt::Display for Capabilities {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        const NAMES: [(Capabilities, &str); 4] = [
+            (Capabilities::FIXED_CELLS, "fixed-cells"),
+            (Capabilities::PADDING, "padding"),
+            (Capabilities::CONGESTION_CONTROL, "congestion-control"),
+            (Capabilities::PQ_HANDSHAKE, "pq-handshake"),
+        ];
+        let names: Vec<&str> = NAMES.iter()
+            .filter(|(capability, _)| self.contains(*capability))
+            .map(|(_, name)| *name)
+            .collect();
+        write!(f, "[{}]", names.join(", "))
+    }
+}
+
+/// The body of a `Versions` cell.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Versions {
+    pub versions: Vec<u16>,
+    pub capabilities: Capabilities,
+}
+
+impl Versions {
+    /// What this build advertises.
+    pub fn ours() -> Versions {
+        Versions { versions: SUPPORTED_VERSIONS.to_vec(), capabilities: Capabilities::supported() }
+    }
+
+    pub fn to_cell(&self) -> Result<Cell, PhantomBandError> {
+        let mut body = Vec::with_capacity(2 + self.versions.len() * 2 + 4);
+        let count = u16::try_from(self.versions.len())
+            .map_err(|_| PhantomBandError::Protocol(format!("Too many versions: {}", self.versions.len())))?;
+        body.extend_from_slice(&count.to_be_bytes());
+        for version in &self.versions {
+            body.extend_from_slice(&version.to_be_bytes());
+        }
+        body.extend_from_slice(&self.capabilities.bits().to_be_bytes());
+        Cell::from_body(0, CellCommand::Versions, &body)
+    }
+
+    pub fn from_cell(cell: &Cell) -> Result<Versions, PhantomBandError> {
+        if cell.command != CellCommand::Versions {
+            return Err(PhantomBandError::Protocol(format!("Expected a Versions cell, got {:?}", cell.command)));
+        }
+        let body = cell.body()?;
+        if body.len() < 2 {
+            return Err(PhantomBandError::Protocol("Versions cell too short".to_string()));
+        }
+        let count = u16::from_be_bytes([body[0], body[1]]) as usize;
+        if body.len() != 2 + count * 2 + 4 {
+            return Err(PhantomBandError::Protocol(format!("Versions cell is {} bytes, expected {} for {} versions", body.len(), 2 + count * 2 + 4, count)));
+        }
+        let versions = body[2..2 + count * 2]
+            .chunks_exact(2)
+            .map(|chunk| u16::from_be_bytes([chunk[0], chunk[1]]))
+            .collect();
+        let capabilities = Capabilities::from_bits(u32::from_be_bytes(body[2 + count * 2..].try_into().unwrap()));
+        Ok(Versions { versions, capabilities })
+    }
+}
+
+/// The parameters both ends of a link agreed on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LinkParameters {
+    pub version: u16,
+    pub capabilities: Capabilities,
+}
+
+/// Picks the highest version both sides support and the capabilities both
+/// sides advertise.
+pub fn negotiate(ours: &Versions, theirs: &Versions) -> Result<LinkParameters, PhantomBandError> {
+    let version = ours.versions.iter()
+        .filter(|version| theirs.versions.contains(version))
+        .max()
+        .copied()
+        .ok_or_else(|| PhantomBandError::Protocol(format!(
+            "No common link protocol version: we support {:?}, peer supports {:?}",
+            ours.versions, theirs.versions
+        )))?;
+    Ok(LinkParameters { version, capabilities: ours.capabilities.intersection(theirs.capabilities) })
+}
@@ -0,0 +1,52 @@
+// common/src/protocol/wire.rs
+
+//! Bounded, canonical encoding of the serde-based protocol structures.
+//!
+//! Plain `bincode::deserialize` trusts the length prefixes of its input, so a
+//! few attacker-supplied bytes can ask for a multi-gigabyte `String` or
+//! `Vec`, and it ignores trailing bytes, so one message has many encodings.
+//! Every type sent on the wire instead declares the largest encoding it may
+//! have. Decoding rejects anything longer up front, never allocates more than
+//! the input could hold, and accepts only input that re-encodes to exactly
+//! the same bytes.
+
+use crate::error::PhantomBandError;
+use bincode::Options;
+use serde::{Serialize, de::DeserializeOwned};
+
+/// A type with a bounded wire encoding.
+pub trait WireFormat: Serialize + DeserializeOwned {
+    /// What the type is called in error messages.
+    const NAME: &'static str;
+    /// Largest encoding that is encoded or accepted.
+    const MAX_LEN: usize;
+}
+
+/// Fixed-width little-endian integers, the same layout `bincode::serialize`
+/// produces, but with a byte limit and without trailing bytes.
+fn options(limit: usize) -> impl Options {
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .with_little_endian()
+        .reject_trailing_bytes()
+        .with_limit(limit as u64)
+}
+
+pub fn encode<T: WireFormat>(value: &T) -> Result<Vec<u8>, PhantomBandError> {
+    options(T::MAX_LEN).serialize(value)
+        .map_err(|e| PhantomBandError::Protocol(format!("Failed to serialize {} (max {} bytes): {}", T::NAME, T::MAX_LEN, e)))
+}
+
+pub fn decode<T: WireFormat>(bytes: &[u8]) -> Result<T, PhantomBandError> {
+    if bytes.len() > T::MAX_LEN {
+        return Err(PhantomBandError::Protocol(format!("{} too large: {} bytes (max {})", T::NAME, bytes.len(), T::MAX_LEN)));
+    }
+    // Every length prefix is checked against what is left of the input
+    // before anything is allocated for it.
+    let value: T = options(bytes.len()).deserialize(bytes)
+        .map_err(|e| PhantomBandError::Protocol(format!("Failed to deserialize {}: {}", T::NAME, e)))?;
+    if encode(&value)? != bytes {
+        return Err(PhantomBandError::Protocol(format!("{} is not canonically encoded", T::NAME)));
+    }
+    Ok(value)
+}
@@ -1,6 +1,70 @@
 // common/src/utils.rs
 
-pub fn get_timestamp() -> u64 {
-    // Dummy timestamp
-    0
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// A source of time. Components take a clock instead of asking the system, so
+/// that tests can control time with a `ManualClock`.
+pub trait Clock: Send + Sync + fmt::Debug {
+    /// Wall-clock Unix time in seconds, for validity windows.
+    fn unix_time(&self) -> u64;
+    /// Monotonic time, for delays and timeouts.
+    fn instant(&self) -> Instant;
+}
+
+/// A clock shared between the components of one process.
+pub type SharedClock = Arc<dyn Clock>;
+
+/// The operating system's clocks.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl SystemClock {
+    pub fn shared() -> SharedClock {
+        Arc::new(SystemClock)
+    }
+}
+
+impl Clock for SystemClock {
+    fn unix_time(&self) -> u64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_secs())
+    }
+
+    fn instant(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// A clock that only moves when told to. Clones share the same time.
+#[derive(Debug, Clone)]
+pub struct ManualClock {
+    unix_start: u64,
+    instant_start: Instant,
+    elapsed: Arc<Mutex<Duration>>,
+}
+
+impl ManualClock {
+    /// A clock stopped at Unix time `unix_time`.
+    pub fn new(unix_time: u64) -> Self {
+        ManualClock { unix_start: unix_time, instant_start: Instant::now(), elapsed: Arc::new(Mutex::new(Duration::ZERO)) }
+    }
+
+    pub fn advance(&self, by: Duration) {
+        *self.elapsed.lock().unwrap_or_else(|e| e.into_inner()) += by;
+    }
+
+    fn elapsed(&self) -> Duration {
+        *self.elapsed.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Clock for ManualClock {
+    fn unix_time(&self) -> u64 {
+        self.unix_start + self.elapsed().as_secs()
+    }
+
+    fn instant(&self) -> Instant {
+        self.instant_start + self.elapsed()
+    }
 }
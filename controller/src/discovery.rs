@@ -0,0 +1,71 @@
+// controller/src/discovery.rs
+
+use crate::voting::{self, Vote, VoteEntry};
+use common::crypto::{Fingerprint, IdentityPublicKey};
+use common::error::PhantomBandError;
+use common::protocol::{RelayDescriptor, SignedRelayDescriptor};
+use common::utils::SharedClock;
+use std::collections::HashMap;
+
+struct StoredDescriptor {
+    /// Kept as signed so it can be voted on and served unchanged.
+    signed: SignedRelayDescriptor,
+    descriptor: RelayDescriptor,
+}
+
+/// The latest verified descriptor of every known relay.
+pub struct DescriptorStore {
+    clock: SharedClock,
+    descriptors: HashMap<Fingerprint, StoredDescriptor>,
+}
+
+impl DescriptorStore {
+    pub fn new(clock: SharedClock) -> Self {
+        DescriptorStore { clock, descriptors: HashMap::new() }
+    }
+
+    /// Verifies `signed` and stores it, replacing the relay's previous
+    /// descriptor only if this one was published later.
+    pub fn insert(&mut self, signed: SignedRelayDescriptor) -> Result<Fingerprint, PhantomBandError> {
+        let descriptor = signed.verify(self.clock.unix_time())?;
+        let fingerprint = descriptor.fingerprint();
+        if let Some(stored) = self.descriptors.get(&fingerprint) {
+            if stored.descriptor.published >= descriptor.published {
+                return Err(PhantomBandError::Policy(format!("Descriptor for {} is not newer than the stored one", fingerprint)));
+            }
+        }
+        self.descriptors.insert(fingerprint, StoredDescriptor { signed, descriptor });
+        Ok(fingerprint)
+    }
+
+    /// Descriptors that are currently valid.
+    pub fn valid(&self) -> impl Iterator<Item = &RelayDescriptor> {
+        let now = self.clock.unix_time();
+        self.descriptors.values()
+            .map(|stored| &stored.descriptor)
+            .filter(move |descriptor| descriptor.is_valid_at(now))
+    }
+
+    /// Drops expired descriptors and returns how many were removed.
+    pub fn prune(&mut self) -> usize {
+        let now = self.clock.unix_time();
+        let before = self.descriptors.len();
+        self.descriptors.retain(|_, stored| now < stored.descriptor.valid_until);
+        before - self.descriptors.len()
+    }
+
+    /// This authority's vote on the relays valid at `valid_after`. Until
+    /// relays are measured, their advertised bandwidth is used.
+    pub fn vote(&self, authority: IdentityPublicKey, valid_after: u64, valid_until: u64) -> Vote {
+        let mut entries: Vec<(Fingerprint, VoteEntry)> = self.descriptors.iter()
+            .filter(|(_, stored)| stored.descriptor.is_valid_at(valid_after))
+            .map(|(fingerprint, stored)| {
+                let measured_bandwidth = stored.descriptor.bandwidth;
+                let flags = voting::assign_flags(&stored.descriptor, measured_bandwidth);
+                (*fingerprint, VoteEntry { descriptor: stored.signed.clone(), flags, measured_bandwidth })
+            })
+            .collect();
+        entries.sort_by_key(|(fingerprint, _)| *fingerprint);
+        Vote { authority, valid_after, valid_until, entries: entries.into_iter().map(|(_, entry)| entry).collect() }
+    }
+}
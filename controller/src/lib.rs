@@ -0,0 +1,135 @@
+// controller/src/lib.rs
+pub mod discovery;
+pub mod voting;
+
+#[cfg(test)]
+mod tests {
+    use super::discovery::DescriptorStore;
+    use super::voting::{self, Vote, VoteEntry, SignedVote};
+    use common::crypto::{IdentityKeypair, SecretKey};
+    use common::error::PhantomBandError;
+    use common::protocol::descriptor::{ExitAction, ExitPolicy, ExitRule};
+    use common::protocol::{AuthoritySet, RelayDescriptor, RelayFlags, SignedConsensus, SignedRelayDescriptor, TransportKind};
+    use common::utils::ManualClock;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    fn signed_descriptor(identity: &IdentityKeypair, bandwidth: u64, exit: bool) -> SignedRelayDescriptor {
+        let exit_policy = match exit {
+            true => ExitPolicy { rules: vec![ExitRule { action: ExitAction::Accept, network: None, ports: (443, 443) }] },
+            false => ExitPolicy::reject_all(),
+        };
+        RelayDescriptor {
+            identity_key: identity.public_key(),
+            link_key: SecretKey::generate().public_key(),
+            onion_key: SecretKey::generate().public_key(),
+            addresses: vec!["192.0.2.1:443".parse().unwrap()],
+            transports: vec![TransportKind::Tcp],
+            bandwidth,
+            exit_policy,
+            family: Vec::new(),
+            published: 1000,
+            valid_until: 1000 + 3600,
+        }.sign(identity).unwrap()
+    }
+
+    fn vote(authority: &IdentityKeypair, entries: Vec<VoteEntry>) -> Vote {
+        Vote { authority: authority.public_key(), valid_after: 1000, valid_until: 1000 + 3600, entries }
+    }
+
+    fn entry(descriptor: &SignedRelayDescriptor, flags: RelayFlags, measured_bandwidth: u64) -> VoteEntry {
+        VoteEntry { descriptor: descriptor.clone(), flags, measured_bandwidth }
+    }
+
+    #[test]
+    fn test_consensus_from_majority_of_votes() {
+        let authorities: Vec<_> = (0..3).map(|_| IdentityKeypair::generate()).collect();
+        let set = AuthoritySet::majority(authorities.iter().map(|a| a.public_key()).collect()).unwrap();
+        let (honest, injected) = (IdentityKeypair::generate(), IdentityKeypair::generate());
+        let honest_descriptor = signed_descriptor(&honest, 500_000, false);
+        let injected_descriptor = signed_descriptor(&injected, 500_000, true);
+        let running = RelayFlags::VALID.union(RelayFlags::RUNNING);
+        let all = RelayFlags::ALL.iter().fold(RelayFlags::empty(), |flags, flag| flags.union(*flag));
+
+        // The last authority is compromised: it adds its own relay, hands
+        // out every flag and claims a huge bandwidth.
+        let votes = vec![
+            vote(&authorities[0], vec![entry(&honest_descriptor, running, 400_000)]),
+            vote(&authorities[1], vec![entry(&honest_descriptor, running.union(RelayFlags::FAST), 500_000)]),
+            vote(&authorities[2], vec![entry(&honest_descriptor, all, u64::MAX), entry(&injected_descriptor, all, u64::MAX)]),
+        ];
+        let document = voting::compute_consensus(&votes, &set, 1500).expect("Aggregation failed");
+        assert_eq!(document.entries.len(), 1, "Relay listed by one authority was included");
+        let relay = &document.entries[0];
+        assert_eq!(relay.fingerprint, honest.public_key().fingerprint());
+        assert_eq!(relay.flags, running.union(RelayFlags::FAST));
+        assert_eq!(relay.weight, 500_000);
+
+        // Every authority computes the same document, so their signatures add up.
+        let mut signed = SignedConsensus::new(&document).unwrap();
+        for authority in &authorities[..2] {
+            let own = voting::compute_consensus(&votes, &set, 1500).unwrap();
+            signed.add_signature(own.sign(authority).unwrap());
+        }
+        assert_eq!(signed.verify(&set, 1500).expect("Verification failed").relays.len(), 1);
+    }
+
+    #[test]
+    fn test_vote_rules() {
+        let authorities: Vec<_> = (0..3).map(|_| IdentityKeypair::generate()).collect();
+        let set = AuthoritySet::majority(authorities.iter().map(|a| a.public_key()).collect()).unwrap();
+        let outsider = IdentityKeypair::generate();
+
+        // Too few votes from known authorities, or two from the same one.
+        let votes = vec![vote(&authorities[0], Vec::new()), vote(&outsider, Vec::new())];
+        assert!(matches!(voting::compute_consensus(&votes, &set, 1500), Err(PhantomBandError::Policy(_))));
+        let votes = vec![vote(&authorities[0], Vec::new()), vote(&authorities[0], Vec::new())];
+        assert!(voting::compute_consensus(&votes, &set, 1500).is_err());
+
+        let signed = vote(&authorities[1], Vec::new()).sign(&authorities[1]).unwrap();
+        let decoded = SignedVote::decode(&signed.encode().unwrap()).unwrap();
+        assert_eq!(decoded.verify(&set).unwrap().authority, authorities[1].public_key());
+        assert!(vote(&authorities[1], Vec::new()).sign(&authorities[2]).is_err());
+        let signed = vote(&outsider, Vec::new()).sign(&outsider).unwrap();
+        assert!(matches!(signed.verify(&set), Err(PhantomBandError::Policy(_))));
+    }
+
+    #[test]
+    fn test_store_vote_assigns_flags() {
+        let (slow, guard) = (IdentityKeypair::generate(), IdentityKeypair::generate());
+        let mut store = DescriptorStore::new(Arc::new(ManualClock::new(1500)));
+        store.insert(signed_descriptor(&slow, 50_000, true)).unwrap();
+        store.insert(signed_descriptor(&guard, voting::GUARD_BANDWIDTH, false)).unwrap();
+
+        let authority = IdentityKeypair::generate();
+        let vote = store.vote(authority.public_key(), 1500, 1500 + 3600);
+        assert_eq!(vote.entries.len(), 2);
+        for entry in &vote.entries {
+            let descriptor = entry.descriptor.verify(1500).unwrap();
+            let expected = if descriptor.fingerprint() == guard.public_key().fingerprint() {
+                RelayFlags::VALID.union(RelayFlags::RUNNING).union(RelayFlags::FAST).union(RelayFlags::GUARD)
+            } else {
+                RelayFlags::VALID.union(RelayFlags::RUNNING).union(RelayFlags::EXIT)
+            };
+            assert_eq!(entry.flags, expected);
+        }
+        assert!(store.vote(authority.public_key(), 1000 + 3600, 1000 + 7200).entries.is_empty());
+    }
+
+    #[test]
+    fn test_store_expires_descriptors_with_clock() {
+        let clock = ManualClock::new(1500);
+        let mut store = DescriptorStore::new(Arc::new(clock.clone()));
+        let identity = IdentityKeypair::generate();
+        store.insert(signed_descriptor(&identity, 50_000, false)).unwrap();
+        assert!(store.insert(signed_descriptor(&identity, 50_000, false)).is_err(), "Descriptor that is not newer replaced the stored one");
+
+        clock.advance(Duration::from_secs(1000 + 3600 - 1500 - 1));
+        assert_eq!(store.valid().count(), 1);
+        assert_eq!(store.prune(), 0);
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(store.valid().count(), 0);
+        assert_eq!(store.prune(), 1);
+        assert!(matches!(store.insert(signed_descriptor(&identity, 50_000, false)), Err(PhantomBandError::Policy(_))));
+    }
+}
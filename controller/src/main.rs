@@ -1,10 +1,41 @@
 // controller/src/main.rs
 
-use common::crypto;
+use common::crypto::IdentityKeypair;
+use common::error::PhantomBandError;
+use common::protocol::consensus::MAX_CONSENSUS_LIFETIME;
+use common::protocol::SignedRelayDescriptor;
+use common::utils::SystemClock;
+use controller::discovery::DescriptorStore;
+use std::env;
+use std::fs;
 
 fn main() {
     println!("PhantomBand Controller starting...");
-    let keypair = crypto::generate_keypair();
-    println!("Generated keypair: {}", keypair);
-    // TODO: Implement controller logic
+    let clock = SystemClock::shared();
+
+    // Descriptor files to load are given on the command line until relays
+    // can upload them.
+    let mut store = DescriptorStore::new(clock.clone());
+    for path in env::args().skip(1) {
+        let result = fs::read(&path)
+            .map_err(|e| PhantomBandError::Internal(format!("Failed to read {}: {}", path, e)))
+            .and_then(|bytes| SignedRelayDescriptor::decode(&bytes))
+            .and_then(|signed| store.insert(signed));
+        match result {
+            Ok(fingerprint) => println!("Stored descriptor for relay {} from {}", fingerprint, path),
+            Err(e) => println!("Rejected descriptor {}: {}", path, e),
+        }
+    }
+    store.prune();
+
+    for descriptor in store.valid() {
+        println!("Relay {} at {:?} ({:?}, {} B/s)", descriptor.fingerprint(), descriptor.addresses, descriptor.transports, descriptor.bandwidth);
+    }
+
+    // TODO: Persist the authority key and exchange votes with the other
+    // authorities before computing and signing the consensus
+    let authority = IdentityKeypair::generate();
+    let now = clock.unix_time();
+    let vote = store.vote(authority.public_key(), now, now + MAX_CONSENSUS_LIFETIME);
+    println!("Authority {} votes for {} relays", authority.public_key().fingerprint(), vote.entries.len());
 }
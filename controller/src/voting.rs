@@ -0,0 +1,186 @@
+// controller/src/voting.rs
+
+//! Directory authority votes and their aggregation into a consensus.
+//!
+//! Each authority signs a vote listing the relays it considers usable. The
+//! consensus is computed deterministically from the votes, so every authority
+//! that saw the same votes signs the same document:
+//!
+//! - a relay is listed only if a majority of all authorities voted for it,
+//! - it carries the descriptor most of those votes agree on,
+//! - a flag is set if a majority of the votes listing the relay set it,
+//! - its weight is the median of the bandwidths the votes measured.
+//!
+//! A single dishonest authority can therefore neither add a relay, nor give
+//! one a flag on its own, nor inflate a relay's weight.
+
+use common::crypto::{self, Fingerprint, IdentityKeypair, IdentityPublicKey};
+use common::error::PhantomBandError;
+use common::protocol::consensus::{ConsensusEntry, MAX_CONSENSUS_LEN};
+use common::protocol::wire::{self, WireFormat};
+use common::protocol::descriptor::RelayDescriptor;
+use common::protocol::{AuthoritySet, ConsensusDocument, RelayFlags, SignedRelayDescriptor};
+use serde::{Serialize, Deserialize};
+use std::collections::BTreeMap;
+
+/// Bandwidth in bytes per second from which a relay is flagged `FAST`.
+pub const FAST_BANDWIDTH: u64 = 100 * 1024;
+/// Bandwidth in bytes per second from which a relay is flagged `GUARD`.
+pub const GUARD_BANDWIDTH: u64 = 1024 * 1024;
+
+const VOTE_SIGNATURE_PREFIX: &[u8] = b"PhantomBand v1 authority vote";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VoteEntry {
+    pub descriptor: SignedRelayDescriptor,
+    pub flags: RelayFlags,
+    /// Bandwidth the authority measured, in bytes per second.
+    pub measured_bandwidth: u64,
+}
+
+/// One authority's opinion of the network.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Vote {
+    pub authority: IdentityPublicKey,
+    pub valid_after: u64,
+    pub valid_until: u64,
+    pub entries: Vec<VoteEntry>,
+}
+
+impl Vote {
+    pub fn sign(&self, authority: &IdentityKeypair) -> Result<SignedVote, PhantomBandError> {
+        if authority.public_key() != self.authority {
+            return Err(PhantomBandError::Internal("Vote authority does not match the signing key".to_string()));
+        }
+        let body = wire::encode(self)?;
+        let signature = authority.sign(&signature_material(&body)).to_vec();
+        Ok(SignedVote { body, signature })
+    }
+}
+
+/// A vote as exchanged between authorities.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SignedVote {
+    body: Vec<u8>,
+    signature: Vec<u8>,
+}
+
+impl SignedVote {
+    pub fn encode(&self) -> Result<Vec<u8>, PhantomBandError> {
+        wire::encode(self)
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<SignedVote, PhantomBandError> {
+        wire::decode(bytes)
+    }
+
+    /// Checks that the vote was signed by the authority it names, and that
+    /// the authority is one of `authorities`.
+    pub fn verify(&self, authorities: &AuthoritySet) -> Result<Vote, PhantomBandError> {
+        let vote: Vote = wire::decode(&self.body)?;
+        if !authorities.contains(&vote.authority) {
+            return Err(PhantomBandError::Policy(format!("Vote from unknown authority {}", vote.authority.fingerprint())));
+        }
+        crypto::verify_signature(&vote.authority, &signature_material(&self.body), &self.signature)
+            .map_err(|e| e.context("Invalid vote signature"))?;
+        Ok(vote)
+    }
+}
+
+impl WireFormat for Vote {
+    const NAME: &'static str = "vote";
+    const MAX_LEN: usize = MAX_CONSENSUS_LEN;
+}
+
+impl WireFormat for SignedVote {
+    const NAME: &'static str = "signed vote";
+    const MAX_LEN: usize = MAX_CONSENSUS_LEN;
+}
+
+/// The flags an authority assigns a relay from its descriptor and measured
+/// bandwidth.
+pub fn assign_flags(descriptor: &RelayDescriptor, measured_bandwidth: u64) -> RelayFlags {
+    let mut flags = RelayFlags::VALID.union(RelayFlags::RUNNING);
+    if measured_bandwidth >= FAST_BANDWIDTH {
+        flags = flags.union(RelayFlags::FAST);
+    }
+    if measured_bandwidth >= GUARD_BANDWIDTH {
+        flags = flags.union(RelayFlags::GUARD);
+    }
+    if descriptor.exit_policy.is_exit() {
+        flags = flags.union(RelayFlags::EXIT);
+    }
+    flags
+}
+
+/// Aggregates the votes of `authorities` into a consensus. Votes from other
+/// keys are ignored; at least a threshold of authorities must have voted.
+pub fn compute_consensus(votes: &[Vote], authorities: &AuthoritySet, now: u64) -> Result<ConsensusDocument, PhantomBandError> {
+    let mut counted: Vec<&Vote> = Vec::new();
+    for vote in votes.iter().filter(|vote| authorities.contains(&vote.authority)) {
+        if counted.iter().any(|other| other.authority == vote.authority) {
+            return Err(PhantomBandError::Protocol(format!("Authority {} voted twice", vote.authority.fingerprint())));
+        }
+        counted.push(vote);
+    }
+    if counted.len() < authorities.threshold() {
+        return Err(PhantomBandError::Policy(format!("Only {} authorities voted, {} required", counted.len(), authorities.threshold())));
+    }
+
+    let mut listings: BTreeMap<Fingerprint, Vec<(&VoteEntry, u64)>> = BTreeMap::new();
+    for vote in &counted {
+        let mut listed = Vec::new();
+        for entry in &vote.entries {
+            // Votes can only list relays whose own signature checks out.
+            let Ok(descriptor) = entry.descriptor.verify(now) else { continue };
+            let fingerprint = descriptor.fingerprint();
+            if listed.contains(&fingerprint) {
+                continue;
+            }
+            listed.push(fingerprint);
+            listings.entry(fingerprint).or_default().push((entry, descriptor.published));
+        }
+    }
+
+    let mut entries = Vec::new();
+    for (fingerprint, listing) in listings {
+        if listing.len() < authorities.threshold() {
+            continue;
+        }
+        let flags = RelayFlags::ALL.iter()
+            .filter(|flag| listing.iter().filter(|(entry, _)| entry.flags.contains(**flag)).count() * 2 > listing.len())
+            .fold(RelayFlags::empty(), |flags, flag| flags.union(*flag));
+        let weight = low_median(listing.iter().map(|(entry, _)| entry.measured_bandwidth).collect());
+        entries.push(ConsensusEntry { fingerprint, descriptor: agreed_descriptor(&listing)?, flags, weight });
+    }
+
+    let valid_after = low_median(counted.iter().map(|vote| vote.valid_after).collect());
+    let valid_until = low_median(counted.iter().map(|vote| vote.valid_until).collect());
+    Ok(ConsensusDocument { valid_after, valid_until, entries })
+}
+
+/// The descriptor most votes list; ties go to the most recently published,
+/// then to the smallest encoding, so every authority picks the same one.
+fn agreed_descriptor(listing: &[(&VoteEntry, u64)]) -> Result<SignedRelayDescriptor, PhantomBandError> {
+    let mut candidates: Vec<(usize, u64, Vec<u8>, &SignedRelayDescriptor)> = Vec::new();
+    for (entry, published) in listing {
+        match candidates.iter_mut().find(|candidate| candidate.3 == &entry.descriptor) {
+            Some(candidate) => candidate.0 += 1,
+            None => candidates.push((1, *published, entry.descriptor.encode()?, &entry.descriptor)),
+        }
+    }
+    candidates.sort_by(|a, b| b.0.cmp(&a.0).then(b.1.cmp(&a.1)).then(a.2.cmp(&b.2)));
+    Ok(candidates[0].3.clone())
+}
+
+fn low_median(mut values: Vec<u64>) -> u64 {
+    values.sort_unstable();
+    values[(values.len() - 1) / 2]
+}
+
+fn signature_material(body: &[u8]) -> Vec<u8> {
+    let mut material = Vec::with_capacity(VOTE_SIGNATURE_PREFIX.len() + body.len());
+    material.extend_from_slice(VOTE_SIGNATURE_PREFIX);
+    material.extend_from_slice(body);
+    material
+}
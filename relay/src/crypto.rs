@@ -0,0 +1,62 @@
+// relay/src/crypto.rs
+
+use common::crypto::{IdentityKeypair, SecretKey, KEY_LEN};
+use common::error::PhantomBandError;
+use log::info;
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use zeroize::Zeroizing;
+
+pub const IDENTITY_KEY_FILE: &str = "relay_identity.key";
+pub const LINK_KEY_FILE: &str = "relay_link.key";
+/// How long an onion key is used before it is replaced, in seconds. Keys are
+/// replaced at multiples of this in Unix time, and a replaced key is still
+/// accepted for one more period, as long as descriptors naming it are valid.
+/// Onion keys live only in memory: a restarted relay starts with a fresh key,
+/// since its replay cache does not survive the restart and handshakes made
+/// to the old keys could be replayed otherwise.
+pub const ONION_KEY_ROTATION: u64 = 24 * 60 * 60;
+
+/// Loads the relay's long-term identity key from `path`, creating and
+/// persisting a new one on first start.
+pub fn load_or_create_identity(path: &Path) -> Result<IdentityKeypair, PhantomBandError> {
+    load_or_create_secret(path, "identity key", || IdentityKeypair::generate().to_secret_bytes())
+        .map(|secret| IdentityKeypair::from_secret_bytes(&secret))
+}
+
+/// Loads the relay's static X25519 link key, which clients authenticate in
+/// the Noise link handshake.
+pub fn load_or_create_link_key(path: &Path) -> Result<SecretKey, PhantomBandError> {
+    load_or_create_secret(path, "link key", || SecretKey::generate().to_bytes())
+        .map(|secret| SecretKey::from_bytes(&secret))
+}
+
+fn load_or_create_secret(path: &Path, what: &str, generate: impl FnOnce() -> Zeroizing<[u8; KEY_LEN]>) -> Result<Zeroizing<[u8; KEY_LEN]>, PhantomBandError> {
+    if path.exists() {
+        let bytes = Zeroizing::new(fs::read(path)
+            .map_err(|e| PhantomBandError::Internal(format!("Failed to read {} {}: {}", what, path.display(), e)))?);
+        let mut secret = Zeroizing::new([0u8; KEY_LEN]);
+        if bytes.len() != KEY_LEN {
+            return Err(PhantomBandError::Internal(format!("{} {} has invalid length {}", what, path.display(), bytes.len())));
+        }
+        secret.copy_from_slice(&bytes);
+        info!("Loaded relay {} from {}", what, path.display());
+        return Ok(secret);
+    }
+
+    let secret = generate();
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(path)
+        .map_err(|e| PhantomBandError::Internal(format!("Failed to create {} {}: {}", what, path.display(), e)))?;
+    file.write_all(&*secret)
+        .map_err(|e| PhantomBandError::Internal(format!("Failed to write {} {}: {}", what, path.display(), e)))?;
+    info!("Generated new relay {} at {}", what, path.display());
+    Ok(secret)
+}
@@ -0,0 +1,101 @@
+// relay/src/descriptor.rs
+
+use common::crypto::{IdentityKeypair, PublicKey};
+use common::error::PhantomBandError;
+use common::protocol::descriptor::{ExitAction, ExitPolicy, ExitRule, RelayDescriptor, SignedRelayDescriptor, TransportKind};
+use common::utils::Clock;
+use log::info;
+use std::fs;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+
+/// Where the signed descriptor is published for clients and controllers,
+/// until relays upload it to a directory.
+pub const DESCRIPTOR_FILE: &str = "relay.desc";
+/// How long a freshly published descriptor stays valid.
+pub const DESCRIPTOR_LIFETIME: u64 = 24 * 60 * 60;
+/// Bandwidth we advertise, in bytes per second, until it is measured.
+const ADVERTISED_BANDWIDTH: u64 = 1_000_000;
+/// Networks an exit never connects to, so that its streams cannot reach the
+/// relay's own host or the networks behind it. Multicast and reserved
+/// addresses reach no single public host, and NAT64 addresses may stand for
+/// private IPv4 ones.
+const PRIVATE_NETWORKS: &[(&str, u8)] = &[
+    ("0.0.0.0", 8), ("10.0.0.0", 8), ("100.64.0.0", 10), ("127.0.0.0", 8), ("169.254.0.0", 16), ("172.16.0.0", 12), ("192.168.0.0", 16),
+    ("224.0.0.0", 4), ("240.0.0.0", 4),
+    ("::", 128), ("::1", 128), ("64:ff9b::", 96), ("fc00::", 7), ("fe80::", 10), ("ff00::", 8),
+];
+
+/// The policy of an exit: any port on public addresses, nothing on private
+/// networks or on our own addresses, `address` and those of `interfaces`.
+/// Listening on an unspecified address, we are reachable on every interface.
+/// Private interface addresses are rejected with their networks already and
+/// are left out, so that the published policy does not reveal them.
+pub fn exit_policy(address: SocketAddr, interfaces: &[IpAddr]) -> ExitPolicy {
+    let public = ExitPolicy { rules: private_networks().chain(std::iter::once(accept_all())).collect() };
+    let own: Vec<ExitRule> = std::iter::once(address.ip()).chain(interfaces.iter().copied())
+        .map(|ip| ip.to_canonical())
+        .filter(|&ip| public.allows(ip, address.port()))
+        .map(|ip| reject(ip, if ip.is_ipv4() { 32 } else { 128 }))
+        .collect();
+    ExitPolicy { rules: own.into_iter().chain(public.rules).collect() }
+}
+
+/// The addresses of this host's network interfaces.
+pub fn interface_addresses() -> Result<Vec<IpAddr>, PhantomBandError> {
+    let interfaces = if_addrs::get_if_addrs()
+        .map_err(|e| PhantomBandError::Internal(format!("Failed to list network interfaces: {}", e)))?;
+    Ok(interfaces.iter().map(|interface| interface.ip()).collect())
+}
+
+/// Which addresses we open links to when extending circuits. A relay on a
+/// public address only extends to public addresses, so that clients cannot
+/// use it to reach the networks behind it. A relay on a private address is
+/// part of a test network and extends anywhere.
+pub fn extend_policy(address: SocketAddr) -> ExitPolicy {
+    let public = ExitPolicy { rules: private_networks().chain(std::iter::once(accept_all())).collect() };
+    if public.allows(address.ip(), address.port()) {
+        public
+    } else {
+        ExitPolicy { rules: vec![accept_all()] }
+    }
+}
+
+fn private_networks() -> impl Iterator<Item = ExitRule> {
+    PRIVATE_NETWORKS.iter().map(|&(network, prefix)| reject(network.parse().expect("Invalid private network"), prefix))
+}
+
+fn reject(network: IpAddr, prefix: u8) -> ExitRule {
+    ExitRule { action: ExitAction::Reject, network: Some((network, prefix)), ports: (1, u16::MAX) }
+}
+
+fn accept_all() -> ExitRule {
+    ExitRule { action: ExitAction::Accept, network: None, ports: (1, u16::MAX) }
+}
+
+/// Describes this relay as published now.
+pub fn describe(clock: &dyn Clock, identity: &IdentityKeypair, link_key: PublicKey, onion_key: PublicKey, address: SocketAddr, exit_policy: ExitPolicy) -> RelayDescriptor {
+    let published = clock.unix_time();
+    RelayDescriptor {
+        identity_key: identity.public_key(),
+        link_key,
+        onion_key,
+        addresses: vec![address],
+        transports: vec![TransportKind::Tcp],
+        bandwidth: ADVERTISED_BANDWIDTH,
+        exit_policy,
+        family: Vec::new(),
+        published,
+        valid_until: published + DESCRIPTOR_LIFETIME,
+    }
+}
+
+/// Signs a descriptor for this relay, published now, and writes it to `path`.
+pub fn publish(clock: &dyn Clock, identity: &IdentityKeypair, link_key: PublicKey, onion_key: PublicKey, address: SocketAddr, exit_policy: ExitPolicy, path: &Path) -> Result<SignedRelayDescriptor, PhantomBandError> {
+    let descriptor = describe(clock, identity, link_key, onion_key, address, exit_policy);
+    let signed = descriptor.sign(identity)?;
+    fs::write(path, signed.encode()?)
+        .map_err(|e| PhantomBandError::Internal(format!("Failed to write descriptor {}: {}", path.display(), e)))?;
+    info!("Published relay descriptor to {} (valid until {})", path.display(), descriptor.valid_until);
+    Ok(signed)
+}
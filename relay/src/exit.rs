@@ -0,0 +1,405 @@
+// relay/src/exit.rs
+
+//! Exit streams: the connections we open for the clients of circuits that
+//! end here.
+//!
+//! Hostnames are resolved here rather than by the client, and every address
+//! is checked against our exit policy before we connect or send to it. There
+//! is no per-stream flow control yet: a destination slower than the client
+//! holds up its stream's queue and, once that is full, the whole link.
+//! Datagrams never wait for room on the way back; when the link is congested
+//! they are dropped, as any router would.
+//!
+//! A datagram stream relays UDP through a socket of its own. Each peer the
+//! client sends to opens a flow, and only datagrams from peers with a live
+//! flow are passed back, so that nobody the client did not contact first can
+//! reach it. Hostnames of peers are looked up apart from the stream, so that
+//! a slow lookup holds up no other datagram.
+
+use crate::listener::Backward;
+use common::error::PhantomBandError;
+use common::protocol::descriptor::ExitPolicy;
+use common::protocol::stream::MAX_DATAGRAM_LEN;
+use common::protocol::{Datagram, DatagramAssembler, EndReason, PhantomBandMessage, RelayCell, RelayCommand, StreamTarget, RELAY_DATA_LEN};
+use common::utils::SharedClock;
+use log::{debug, info, warn};
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::sync::mpsc;
+use tokio::task::JoinSet;
+
+/// How long resolving and connecting to a stream's target may take.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Cells queued for a destination before the link waits for it.
+pub const STREAM_QUEUE_LEN: usize = 64;
+/// How long a UDP flow lives without a datagram either way. Hostnames a
+/// datagram stream resolved are remembered as long.
+pub const UDP_FLOW_TIMEOUT: Duration = Duration::from_secs(60);
+/// How long a datagram stream without flows stays open.
+pub const DATAGRAM_STREAM_IDLE_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+/// How often expired flows are dropped.
+const FLOW_SWEEP_INTERVAL: Duration = Duration::from_secs(5);
+/// Most peers one datagram stream may have flows with at once.
+pub const MAX_FLOWS_PER_STREAM: usize = 64;
+/// Most hostname lookups one datagram stream may have running at once.
+const MAX_LOOKUPS_PER_STREAM: usize = 16;
+
+/// What a client opened an exit stream for.
+pub enum StreamRequest {
+    /// A TCP connection, from `Begin`.
+    Connect(StreamTarget),
+    /// UDP to whichever peers our policy allows, from `BeginDatagram`.
+    Datagrams,
+}
+
+impl fmt::Display for StreamRequest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StreamRequest::Connect(target) => write!(f, "{}", target),
+            StreamRequest::Datagrams => write!(f, "datagrams"),
+        }
+    }
+}
+
+/// One stream's end of the circuit it belongs to.
+pub struct StreamCircuit {
+    /// The circuit's id on the link towards the client.
+    pub circuit_id: u64,
+    pub stream_id: u16,
+    pub backward: mpsc::Sender<Backward>,
+}
+
+impl StreamCircuit {
+    /// Sends a cell for this stream back to the client. Fails once the
+    /// link is gone.
+    async fn send(&self, cell: RelayCell) -> Result<(), PhantomBandError> {
+        self.backward.send(Backward::Stream(self.circuit_id, cell)).await
+            .map_err(|_| link_closed())
+    }
+
+    async fn send_message(&self, message: PhantomBandMessage) -> Result<(), PhantomBandError> {
+        self.send(message.to_relay_cell(self.stream_id)?).await
+    }
+
+    /// Sends all cells of `datagram` back to the client if the link has room
+    /// for them now, and drops it otherwise. Fails once the link is gone.
+    fn try_send_datagram(&self, datagram: &Datagram) -> Result<(), PhantomBandError> {
+        let cells = datagram.to_cells(self.stream_id)?;
+        let permits = match self.backward.try_reserve_many(cells.len()) {
+            Ok(permits) => permits,
+            Err(mpsc::error::TrySendError::Full(())) => return Ok(()),
+            Err(mpsc::error::TrySendError::Closed(())) => return Err(link_closed()),
+        };
+        for (permit, cell) in permits.zip(cells) {
+            permit.send(Backward::Stream(self.circuit_id, cell));
+        }
+        Ok(())
+    }
+}
+
+/// Opens what a client asked for, answers `Connected` or `End`, and then
+/// carries the stream's traffic both ways. Cells from the client arrive
+/// through `incoming`, which is closed when the client ends the stream or the
+/// circuit goes away.
+pub async fn serve_stream(clock: SharedClock, policy: ExitPolicy, request: StreamRequest, circuit: StreamCircuit, incoming: mpsc::Receiver<RelayCell>) {
+    let ended = match request {
+        StreamRequest::Connect(target) => serve_connection(&policy, &target, &circuit, incoming).await,
+        StreamRequest::Datagrams => serve_datagrams(clock, &policy, &circuit, incoming).await,
+    };
+    if let Some(reason) = ended {
+        let _ = circuit.send_message(PhantomBandMessage::End { reason }).await;
+    }
+}
+
+/// Returns why the stream ended, or None if the client ended it.
+async fn serve_connection(policy: &ExitPolicy, target: &StreamTarget, circuit: &StreamCircuit, mut incoming: mpsc::Receiver<RelayCell>) -> Option<EndReason> {
+    let connection = match tokio::time::timeout(CONNECT_TIMEOUT, connect(policy, target)).await {
+        Ok(Ok(connection)) => connection,
+        Ok(Err(reason)) => return Some(refuse(circuit, target, reason)),
+        Err(_) => return Some(refuse(circuit, target, EndReason::Timeout)),
+    };
+    info!("Opened stream {} on circuit {}", circuit.stream_id, circuit.circuit_id);
+    debug!("Stream {} on circuit {} goes to {}", circuit.stream_id, circuit.circuit_id, target);
+    circuit.send_message(PhantomBandMessage::Connected).await.ok()?;
+
+    let (mut reader, mut writer) = connection.into_split();
+    // Both directions run at once, so that a client waiting on its queue
+    // towards us never waits on a destination waiting on us.
+    let upstream = async {
+        while let Some(cell) = incoming.recv().await {
+            if cell.command != RelayCommand::Data {
+                warn!("Unexpected {:?} cell on stream {} of circuit {}", cell.command, circuit.stream_id, circuit.circuit_id);
+                return Some(EndReason::Misc);
+            }
+            if let Err(e) = writer.write_all(&cell.data).await {
+                return Some(EndReason::from(&e));
+            }
+        }
+        // The client ended the stream, so it expects nothing more.
+        None
+    };
+    let downstream = async {
+        let mut buffer = [0u8; RELAY_DATA_LEN];
+        loop {
+            let n = match reader.read(&mut buffer).await {
+                Ok(0) => return Some(EndReason::Done),
+                Ok(n) => n,
+                Err(e) => return Some(EndReason::from(&e)),
+            };
+            let sent = match RelayCell::new(RelayCommand::Data, circuit.stream_id, &buffer[..n]) {
+                Ok(cell) => circuit.send(cell).await,
+                Err(e) => Err(e),
+            };
+            if sent.is_err() {
+                return None;
+            }
+        }
+    };
+    let ended = tokio::select! {
+        reason = upstream => reason,
+        reason = downstream => reason,
+    };
+    if let Some(reason) = ended {
+        info!("Stream {} on circuit {} ended: {:?}", circuit.stream_id, circuit.circuit_id, reason);
+    }
+    ended
+}
+
+/// Returns why the stream ended, or None if the client ended it.
+async fn serve_datagrams(clock: SharedClock, policy: &ExitPolicy, circuit: &StreamCircuit, mut incoming: mpsc::Receiver<RelayCell>) -> Option<EndReason> {
+    let request = StreamRequest::Datagrams;
+    if !policy.is_exit() {
+        return Some(refuse(circuit, &request, EndReason::ExitPolicy));
+    }
+    let socket = match bind_udp().await {
+        Ok(socket) => socket,
+        Err(e) => return Some(refuse(circuit, &request, EndReason::from(&e))),
+    };
+    info!("Opened datagram stream {} on circuit {}", circuit.stream_id, circuit.circuit_id);
+    circuit.send_message(PhantomBandMessage::Connected).await.ok()?;
+
+    // Shared by both directions, which only hold the lock between awaits.
+    let flows = Mutex::new(Flows::new(clock.clone()));
+    let (socket, flows) = (&socket, &flows);
+    let upstream = async {
+        let mut assembler = DatagramAssembler::default();
+        let mut resolved: HashMap<StreamTarget, (SocketAddr, Instant)> = HashMap::new();
+        // Dropped with the stream, which cancels the lookups still running.
+        let mut lookups = JoinSet::new();
+        loop {
+            let (datagram, peer) = tokio::select! {
+                cell = incoming.recv() => {
+                    let datagram = match assembler.push(&cell?) {
+                        Ok(Some(datagram)) => datagram,
+                        Ok(None) => continue,
+                        Err(e) => {
+                            warn!("Bad datagram on stream {} of circuit {}: {}", circuit.stream_id, circuit.circuit_id, e);
+                            return Some(EndReason::Misc);
+                        },
+                    };
+                    let now = clock.instant();
+                    resolved.retain(|_, (_, at)| now.duration_since(*at) < UDP_FLOW_TIMEOUT);
+                    match (&datagram.peer, resolved.get(&datagram.peer)) {
+                        (_, Some(&(peer, _))) => (datagram, Ok(peer)),
+                        (StreamTarget::Hostname { .. }, None) if lookups.len() < MAX_LOOKUPS_PER_STREAM => {
+                            lookups.spawn(resolve_peer(policy.clone(), datagram));
+                            continue;
+                        },
+                        (StreamTarget::Hostname { .. }, None) => (datagram, Err(EndReason::ResolveFailed)),
+                        (StreamTarget::Address(_), None) => resolve_peer(policy.clone(), datagram).await,
+                    }
+                },
+                Some(looked_up) = lookups.join_next() => {
+                    let Ok((datagram, peer)) = looked_up else {
+                        continue;
+                    };
+                    if let Ok(peer) = peer {
+                        if resolved.len() < MAX_FLOWS_PER_STREAM {
+                            resolved.insert(datagram.peer.clone(), (peer, clock.instant()));
+                        }
+                    }
+                    (datagram, peer)
+                },
+            };
+            // Datagrams we cannot deliver are dropped, as on any network.
+            let peer = match peer {
+                Ok(peer) => peer,
+                Err(reason) => {
+                    debug!("Dropping datagram to {}: {:?}", datagram.peer, reason);
+                    continue;
+                },
+            };
+            if !flows.lock().unwrap_or_else(|e| e.into_inner()).open(peer) {
+                warn!("Datagram stream {} on circuit {} has {} flows, dropping a datagram to a new peer", circuit.stream_id, circuit.circuit_id, MAX_FLOWS_PER_STREAM);
+                continue;
+            }
+            if let Err(e) = socket.send_to(&datagram.data, socket_address(socket, peer)).await {
+                warn!("Failed to send a datagram on stream {} of circuit {}: {}", circuit.stream_id, circuit.circuit_id, e);
+            }
+        }
+    };
+    let downstream = async {
+        let mut buffer = vec![0u8; MAX_DATAGRAM_LEN];
+        let mut sweep = tokio::time::interval(FLOW_SWEEP_INTERVAL);
+        loop {
+            tokio::select! {
+                received = socket.recv_from(&mut buffer) => {
+                    let (n, peer) = match received {
+                        Ok(received) => received,
+                        Err(e) => return Some(EndReason::from(&e)),
+                    };
+                    let peer = SocketAddr::new(peer.ip().to_canonical(), peer.port());
+                    if !flows.lock().unwrap_or_else(|e| e.into_inner()).touch(peer) {
+                        continue;
+                    }
+                    let datagram = Datagram { peer: StreamTarget::Address(peer), data: buffer[..n].to_vec() };
+                    if circuit.try_send_datagram(&datagram).is_err() {
+                        return None;
+                    }
+                },
+                _ = sweep.tick() => {
+                    if flows.lock().unwrap_or_else(|e| e.into_inner()).expire() {
+                        return Some(EndReason::Timeout);
+                    }
+                },
+            }
+        }
+    };
+    let ended = tokio::select! {
+        reason = upstream => reason,
+        reason = downstream => reason,
+    };
+    if let Some(reason) = ended {
+        info!("Datagram stream {} on circuit {} ended: {:?}", circuit.stream_id, circuit.circuit_id, reason);
+    }
+    ended
+}
+
+fn refuse(circuit: &StreamCircuit, request: &dyn fmt::Display, reason: EndReason) -> EndReason {
+    warn!("Refusing stream {} on circuit {}: {:?}", circuit.stream_id, circuit.circuit_id, reason);
+    debug!("Refused stream {} on circuit {} was for {}", circuit.stream_id, circuit.circuit_id, request);
+    reason
+}
+
+/// Resolves `target` to the addresses our policy allows.
+pub async fn resolve(policy: &ExitPolicy, target: &StreamTarget) -> Result<Vec<SocketAddr>, EndReason> {
+    let addresses: Vec<SocketAddr> = match target {
+        StreamTarget::Address(address) => vec![*address],
+        StreamTarget::Hostname { host, port } => tokio::net::lookup_host((host.as_str(), *port)).await
+            .map_err(|_| EndReason::ResolveFailed)?
+            .collect(),
+    };
+    if addresses.is_empty() {
+        return Err(EndReason::ResolveFailed);
+    }
+    // An IPv4 address in IPv6 form must meet the IPv4 rules, or it would
+    // slip past every rule for private IPv4 networks.
+    let allowed: Vec<SocketAddr> = addresses.into_iter()
+        .map(|address| SocketAddr::new(address.ip().to_canonical(), address.port()))
+        .filter(|address| policy.allows(address.ip(), address.port()))
+        .collect();
+    if allowed.is_empty() {
+        return Err(EndReason::ExitPolicy);
+    }
+    Ok(allowed)
+}
+
+/// Connects to the first address of `target` that our policy allows and
+/// that answers.
+pub async fn connect(policy: &ExitPolicy, target: &StreamTarget) -> Result<TcpStream, EndReason> {
+    let mut reason = EndReason::ExitPolicy;
+    for address in resolve(policy, target).await? {
+        match TcpStream::connect(address).await {
+            Ok(connection) => return Ok(connection),
+            Err(e) => reason = EndReason::from(&e),
+        }
+    }
+    Err(reason)
+}
+
+/// Resolves the peer of `datagram` to the first address our policy allows,
+/// and returns the datagram with it.
+async fn resolve_peer(policy: ExitPolicy, datagram: Datagram) -> (Datagram, Result<SocketAddr, EndReason>) {
+    let peer = match tokio::time::timeout(CONNECT_TIMEOUT, resolve(&policy, &datagram.peer)).await {
+        Ok(Ok(addresses)) => Ok(addresses[0]),
+        Ok(Err(reason)) => Err(reason),
+        Err(_) => Err(EndReason::Timeout),
+    };
+    (datagram, peer)
+}
+
+/// A UDP socket for IPv6 and IPv4 peers alike where the host has IPv6, and
+/// for IPv4 peers alone otherwise.
+async fn bind_udp() -> std::io::Result<UdpSocket> {
+    match UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0)).await {
+        Ok(socket) => Ok(socket),
+        Err(_) => UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await,
+    }
+}
+
+/// `peer` as `socket` must be given it.
+fn socket_address(socket: &UdpSocket, peer: SocketAddr) -> SocketAddr {
+    match (socket.local_addr(), peer.ip()) {
+        (Ok(SocketAddr::V6(_)), IpAddr::V4(ip)) => SocketAddr::new(IpAddr::V6(ip.to_ipv6_mapped()), peer.port()),
+        _ => peer,
+    }
+}
+
+fn link_closed() -> PhantomBandError {
+    PhantomBandError::Transport("Link to the client is closed".to_string())
+}
+
+/// The UDP flows of one datagram stream.
+pub struct Flows {
+    clock: SharedClock,
+    /// When each peer last sent or was sent a datagram.
+    last_seen: HashMap<SocketAddr, Instant>,
+    /// When the stream last had a flow.
+    last_active: Instant,
+}
+
+impl Flows {
+    pub fn new(clock: SharedClock) -> Self {
+        let last_active = clock.instant();
+        Flows { clock, last_seen: HashMap::new(), last_active }
+    }
+
+    /// Opens or refreshes the flow to `peer`. Returns false if the stream
+    /// has no room for another flow.
+    pub fn open(&mut self, peer: SocketAddr) -> bool {
+        if !self.last_seen.contains_key(&peer) && self.last_seen.len() >= MAX_FLOWS_PER_STREAM {
+            return false;
+        }
+        let now = self.clock.instant();
+        self.last_seen.insert(peer, now);
+        self.last_active = now;
+        true
+    }
+
+    /// Refreshes the flow with `peer`. Returns false if there is none.
+    pub fn touch(&mut self, peer: SocketAddr) -> bool {
+        let now = self.clock.instant();
+        let Some(last_seen) = self.last_seen.get_mut(&peer) else {
+            return false;
+        };
+        *last_seen = now;
+        self.last_active = now;
+        true
+    }
+
+    /// Drops expired flows. Returns true once the stream has gone without
+    /// any for long enough to be closed.
+    pub fn expire(&mut self) -> bool {
+        let now = self.clock.instant();
+        self.last_seen.retain(|_, last_seen| now.duration_since(*last_seen) < UDP_FLOW_TIMEOUT);
+        if !self.last_seen.is_empty() {
+            self.last_active = now;
+        }
+        now.duration_since(self.last_active) >= DATAGRAM_STREAM_IDLE_TIMEOUT
+    }
+}
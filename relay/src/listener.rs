@@ -0,0 +1,584 @@
+// relay/src/listener.rs
+
+use common::crypto::{Fingerprint, PublicKey};
+use common::crypto::ntor::{NtorRelay, NtorRequest};
+use common::crypto::onion::{OnionPayload, Peeled, RelayOnion};
+use common::crypto::replay::ReplayCache;
+use common::error::{CloseReason, PhantomBandError};
+use common::protocol::descriptor::ExitPolicy;
+use common::protocol::stream::MAX_DATAGRAM_CELLS;
+use common::protocol::{self, EndReason, PhantomBandMessage, RelayCell, RelayCommand};
+use common::utils::SharedClock;
+use crate::exit::{self, StreamCircuit, StreamRequest};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use transports::noise::{LinkCredentials, LinkSession};
+use log::{debug, info, warn, error};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+/// How long a peer may take over the link handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long we wait to open a link to the next hop of a circuit.
+const EXTEND_TIMEOUT: Duration = Duration::from_secs(10);
+/// Messages queued for a circuit's next hop before the circuit waits for it.
+const NEXT_HOP_QUEUE_LEN: usize = 64;
+/// Messages queued for a next-hop link before its circuits wait for it.
+const LINK_QUEUE_LEN: usize = 256;
+/// Most circuits one link may carry, whichever side opened it.
+const MAX_CIRCUITS_PER_LINK: usize = 4096;
+/// Most links we keep open to next hops at once.
+const MAX_NEXT_HOP_LINKS: usize = 1024;
+/// Messages queued back towards the client before circuits wait for the
+/// link. Datagrams are queued whole or dropped, so it holds two of the
+/// largest.
+const BACKWARD_QUEUE_LEN: usize = 2 * MAX_DATAGRAM_CELLS;
+/// Most streams a circuit may have open at once.
+const MAX_STREAMS_PER_CIRCUIT: usize = 256;
+
+/// What all connections of the relay share.
+pub struct RelayState {
+    pub clock: SharedClock,
+    pub credentials: LinkCredentials,
+    /// Answers circuit handshakes under the current and previous onion
+    /// keys, replaced when the keys rotate.
+    pub ntor: RwLock<NtorRelay>,
+    /// Circuit handshakes seen while their onion key may still be accepted.
+    pub replays: Mutex<ReplayCache>,
+    /// Which destinations our exit streams may connect to.
+    pub exit_policy: ExitPolicy,
+    /// Which addresses we may extend circuits to.
+    pub extend_policy: ExitPolicy,
+    /// Our links to the next hops of circuits.
+    pub next_hops: NextHopLinks,
+}
+
+/// A next hop, by the relay and the address we reach it at.
+type LinkId = (Fingerprint, SocketAddr);
+
+/// Our links to next hops, one for each relay and address. Every circuit we
+/// extend to a relay shares its link, so that the link's traffic does not
+/// tell when single circuits open and close.
+#[derive(Default)]
+pub struct NextHopLinks {
+    table: Mutex<LinkTable>,
+}
+
+#[derive(Default)]
+struct LinkTable {
+    links: HashMap<LinkId, NextHopLink>,
+    /// Links opened so far, which numbers them. A link that failed must not
+    /// tear down the one that replaced it.
+    opened: u64,
+}
+
+/// One link to a next hop, served by its own task.
+struct NextHopLink {
+    serial: u64,
+    outgoing: mpsc::Sender<PhantomBandMessage>,
+    /// The circuits on the link by their id there, each with its id on the
+    /// link towards its client and where messages for the client go.
+    circuits: HashMap<u64, (u64, mpsc::Sender<Backward>)>,
+}
+
+/// A circuit's place on a next-hop link.
+struct LinkCircuit {
+    link: LinkId,
+    serial: u64,
+    /// The circuit's id on the link.
+    next_id: u64,
+    outgoing: mpsc::Sender<PhantomBandMessage>,
+}
+
+/// Something to pass back to the client of a circuit, keyed by the
+/// circuit's id on the link towards the client.
+pub enum Backward {
+    /// A message from the circuit's next hop.
+    NextHop(u64, PhantomBandMessage),
+    /// A relay cell from one of the circuit's exit streams.
+    Stream(u64, RelayCell),
+}
+
+/// State of one established link.
+struct Connection {
+    session: LinkSession,
+    addr: SocketAddr,
+    state: Arc<RelayState>,
+    /// Every circuit open on this link.
+    circuits: HashMap<u64, RelayCircuit>,
+    backward: mpsc::Receiver<Backward>,
+    backward_sender: mpsc::Sender<Backward>,
+}
+
+/// Our part of one circuit.
+struct RelayCircuit {
+    onion: RelayOnion,
+    /// Set once the client asked us to extend the circuit.
+    next: Option<NextHop>,
+    /// Queues of the cells for the circuit's exit streams.
+    streams: HashMap<u16, mpsc::Sender<RelayCell>>,
+}
+
+/// A circuit's queue towards its next hop, served by its own task. Dropping
+/// this takes the circuit off the link and destroys it at the next hop.
+struct NextHop {
+    circuit_id: u64,
+    outgoing: mpsc::Sender<PhantomBandMessage>,
+    /// Whether the next hop has answered our `CircuitCreate`.
+    created: bool,
+}
+
+/// What the client asked for in an `Extend` cell.
+struct ExtendRequest {
+    address: SocketAddr,
+    link_key: PublicKey,
+    handshake: NtorRequest,
+}
+
+/// Runs the link handshake on an accepted connection and serves it until the
+/// peer disconnects or an error closes it. Errors after the handshake are
+/// reported to the peer as a `Disconnect` carrying the matching reason code.
+pub async fn serve_connection(socket: TcpStream, addr: SocketAddr, state: Arc<RelayState>) {
+    let session = match tokio::time::timeout(HANDSHAKE_TIMEOUT, LinkSession::accept(socket, &state.credentials)).await {
+        Ok(Ok(session)) => session,
+        Ok(Err(e)) => {
+            error!("Link handshake with {} failed: {}", addr, e);
+            return;
+        },
+        Err(_) => {
+            error!("Link handshake with {} timed out", addr);
+            return;
+        },
+    };
+    match session.remote_identity() {
+        Some(identity) => info!("Link from {} authenticated as relay {}", addr, identity.fingerprint()),
+        None => info!("Link from {} established with an anonymous client", addr),
+    }
+
+    let (backward_sender, backward) = mpsc::channel(BACKWARD_QUEUE_LEN);
+    let mut connection = Connection { session, addr, state, circuits: HashMap::new(), backward, backward_sender };
+    match connection.run().await {
+        Ok(()) => info!("Connection from {} closed.", addr),
+        Err(e) => {
+            error!("Closing connection from {}: {}", addr, e);
+            connection.close(&e).await;
+        }
+    }
+}
+
+impl Connection {
+    async fn run(&mut self) -> Result<(), PhantomBandError> {
+        loop {
+            tokio::select! {
+                message = self.session.receive_message() => {
+                    if !self.handle_message(message?).await? {
+                        return Ok(());
+                    }
+                },
+                Some(backward) = self.backward.recv() => self.handle_backward(backward).await?,
+            }
+        }
+    }
+
+    /// Handles a message from the peer. Returns false once the peer closed
+    /// the link.
+    async fn handle_message(&mut self, message: PhantomBandMessage) -> Result<bool, PhantomBandError> {
+        match message {
+            PhantomBandMessage::CircuitCreate { circuit_id, handshake } => {
+                info!("Received CircuitCreate for circuit {} from {}", circuit_id, self.addr);
+                if circuit_id == 0 || self.circuits.contains_key(&circuit_id) {
+                    return Err(PhantomBandError::Protocol(format!("Circuit id {} is reserved or in use", circuit_id)));
+                }
+                if self.circuits.len() >= MAX_CIRCUITS_PER_LINK {
+                    warn!("Refusing circuit {} from {}: {} circuits open", circuit_id, self.addr, self.circuits.len());
+                    let reason = PhantomBandError::Policy("Too many circuits on the link".to_string()).close_reason();
+                    self.session.send_message(&PhantomBandMessage::Destroy { circuit_id, reason }).await?;
+                    return Ok(true);
+                }
+                let response = self.state.ntor.read().unwrap_or_else(|e| e.into_inner()).respond(&handshake);
+                let (reply, keys) = match response.and_then(|response| self.check_replay(&handshake).map(|()| response)) {
+                    Ok(response) => response,
+                    Err(e) => {
+                        warn!("Refusing circuit {} from {}: {}", circuit_id, self.addr, e);
+                        self.session.send_message(&PhantomBandMessage::Destroy { circuit_id, reason: e.close_reason() }).await?;
+                        return Ok(true);
+                    },
+                };
+                self.circuits.insert(circuit_id, RelayCircuit { onion: RelayOnion::new(&keys), next: None, streams: HashMap::new() });
+                self.session.send_message(&PhantomBandMessage::CircuitCreated { circuit_id, handshake: reply }).await?;
+                info!("Created circuit {} for {}", circuit_id, self.addr);
+            },
+            PhantomBandMessage::Relay { circuit_id, payload } => {
+                if let Err(e) = self.handle_relay(circuit_id, &payload).await {
+                    self.destroy(circuit_id, &e).await?;
+                }
+            },
+            PhantomBandMessage::Destroy { circuit_id, reason } => {
+                info!("Client {} destroyed circuit {}: {:?}", self.addr, circuit_id, reason);
+                self.circuits.remove(&circuit_id);
+            },
+            PhantomBandMessage::Disconnect { reason } => {
+                info!("Received Disconnect from {} ({:?}). Closing connection.", self.addr, reason);
+                return Ok(false);
+            },
+            PhantomBandMessage::CircuitCreated { .. }
+            | PhantomBandMessage::Extend { .. }
+            | PhantomBandMessage::Extended { .. }
+            | PhantomBandMessage::Begin { .. }
+            | PhantomBandMessage::BeginDatagram
+            | PhantomBandMessage::Connected
+            | PhantomBandMessage::End { .. } => {
+                return Err(PhantomBandError::Protocol(format!("Unexpected message from client: {:?}", message)));
+            },
+        }
+        Ok(true)
+    }
+
+    /// Refuses a handshake this relay has answered before. Called only once
+    /// the handshake is known to be for our identity and onion key, so that
+    /// junk handshakes take no room in the cache.
+    fn check_replay(&self, handshake: &NtorRequest) -> Result<(), PhantomBandError> {
+        let mut replays = self.state.replays.lock().unwrap_or_else(|e| e.into_inner());
+        replays.check(&handshake.replay_tag()).inspect_err(|_| {
+            warn!("Replay cache rejected a handshake from {} ({})", self.addr, replays.stats());
+        })
+    }
+
+    /// Peels our layer off a relay cell. Cells we do not recognize go on to
+    /// the next hop. If we are the last hop, such a cell was tampered with on
+    /// the way, possibly to tag the circuit, and the circuit must not be used
+    /// further.
+    async fn handle_relay(&mut self, circuit_id: u64, payload: &[u8]) -> Result<(), PhantomBandError> {
+        let circuit = self.circuits.get_mut(&circuit_id)
+            .ok_or_else(|| PhantomBandError::Protocol(format!("Relay cell for unknown circuit {}", circuit_id)))?;
+        let mut payload: OnionPayload = payload.try_into()
+            .map_err(|_| PhantomBandError::Protocol(format!("Relay payload is {} bytes", payload.len())))?;
+        let cell = match circuit.onion.peel(&mut payload)? {
+            Peeled::Recognized(cell) => cell,
+            Peeled::Forward => {
+                let next = circuit.next.as_ref()
+                    .ok_or_else(|| PhantomBandError::Crypto("Relay cell failed its integrity check".to_string()))?;
+                return next.send(PhantomBandMessage::Relay { circuit_id: next.circuit_id, payload: payload.to_vec() }).await;
+            },
+        };
+        info!("Received relay {:?} on circuit {} from {}", cell.command, circuit_id, self.addr);
+
+        match cell.command {
+            RelayCommand::Data | RelayCommand::Datagram => {
+                if cell.stream_id == 0 {
+                    return Err(PhantomBandError::Protocol(format!("{:?} cell without a stream", cell.command)));
+                }
+                // Data for a stream that just ended on our side is dropped.
+                match circuit.streams.get(&cell.stream_id) {
+                    Some(stream) => {
+                        let stream_id = cell.stream_id;
+                        if stream.send(cell).await.is_err() {
+                            circuit.streams.remove(&stream_id);
+                        }
+                        Ok(())
+                    },
+                    None => Ok(()),
+                }
+            },
+            RelayCommand::Extend => match PhantomBandMessage::from_relay_cell(&cell)? {
+                PhantomBandMessage::Extend { address, link_key, handshake } => self.extend(circuit_id, ExtendRequest { address, link_key, handshake }),
+                other => Err(PhantomBandError::Protocol(format!("Expected Extend, got {:?}", other))),
+            },
+            RelayCommand::Begin => match PhantomBandMessage::from_relay_cell(&cell)? {
+                PhantomBandMessage::Begin { target } => self.begin(circuit_id, cell.stream_id, StreamRequest::Connect(target)).await,
+                other => Err(PhantomBandError::Protocol(format!("Expected Begin, got {:?}", other))),
+            },
+            RelayCommand::BeginDatagram => match PhantomBandMessage::from_relay_cell(&cell)? {
+                PhantomBandMessage::BeginDatagram => self.begin(circuit_id, cell.stream_id, StreamRequest::Datagrams).await,
+                other => Err(PhantomBandError::Protocol(format!("Expected BeginDatagram, got {:?}", other))),
+            },
+            RelayCommand::End => {
+                PhantomBandMessage::from_relay_cell(&cell)?;
+                circuit.streams.remove(&cell.stream_id);
+                Ok(())
+            },
+            RelayCommand::Extended | RelayCommand::Connected => {
+                Err(PhantomBandError::Protocol(format!("Client sent a {:?} cell", cell.command)))
+            },
+        }
+    }
+
+    /// Opens an exit stream. The exit task answers the client itself; a
+    /// stream we cannot take at all is refused here.
+    async fn begin(&mut self, circuit_id: u64, stream_id: u16, request: StreamRequest) -> Result<(), PhantomBandError> {
+        let circuit = self.circuits.get_mut(&circuit_id)
+            .ok_or_else(|| PhantomBandError::Internal(format!("Opening a stream on unknown circuit {}", circuit_id)))?;
+        if circuit.streams.contains_key(&stream_id) {
+            return Err(PhantomBandError::Protocol(format!("Stream {} is already open on circuit {}", stream_id, circuit_id)));
+        }
+        if circuit.streams.len() >= MAX_STREAMS_PER_CIRCUIT {
+            warn!("Refusing stream {} on circuit {}: {} streams open", stream_id, circuit_id, circuit.streams.len());
+            let end = PhantomBandMessage::End { reason: EndReason::Misc }.to_relay_cell(stream_id)?;
+            let payload = circuit.onion.originate(&end)?;
+            return self.session.send_message(&PhantomBandMessage::Relay { circuit_id, payload: payload.to_vec() }).await;
+        }
+        info!("Opening stream {} on circuit {} from {}", stream_id, circuit_id, self.addr);
+        debug!("Stream {} on circuit {} is for {}", stream_id, circuit_id, request);
+        let (incoming, queue) = mpsc::channel(exit::STREAM_QUEUE_LEN);
+        let stream = StreamCircuit { circuit_id, stream_id, backward: self.backward_sender.clone() };
+        tokio::spawn(exit::serve_stream(self.state.clock.clone(), self.state.exit_policy.clone(), request, stream, queue));
+        circuit.streams.insert(stream_id, incoming);
+        Ok(())
+    }
+
+    /// Starts extending a circuit for which we are the last hop. The next
+    /// hop's answer comes back through `handle_backward`.
+    fn extend(&mut self, circuit_id: u64, request: ExtendRequest) -> Result<(), PhantomBandError> {
+        let own = self.state.ntor.read().unwrap_or_else(|e| e.into_inner()).fingerprint();
+        let circuit = self.circuits.get_mut(&circuit_id)
+            .ok_or_else(|| PhantomBandError::Internal(format!("Extending unknown circuit {}", circuit_id)))?;
+        if circuit.next.is_some() {
+            return Err(PhantomBandError::Protocol(format!("Circuit {} is already extended", circuit_id)));
+        }
+        if request.handshake.relay == own {
+            return Err(PhantomBandError::Policy("Refusing to extend a circuit to ourselves".to_string()));
+        }
+        if !self.state.extend_policy.allows(request.address.ip().to_canonical(), request.address.port()) {
+            return Err(PhantomBandError::Policy(format!("Refusing to extend a circuit to {}", request.address)));
+        }
+        info!("Extending circuit {} from {} to {} at {}", circuit_id, self.addr, request.handshake.relay, request.address);
+
+        let link = self.state.next_hops.join(&self.state, &request, circuit_id, self.backward_sender.clone())?;
+        let (outgoing, queue) = mpsc::channel(NEXT_HOP_QUEUE_LEN);
+        circuit.next = Some(NextHop { circuit_id: link.next_id, outgoing, created: false });
+        tokio::spawn(serve_next_hop(Arc::clone(&self.state), link, request.handshake, queue));
+        Ok(())
+    }
+
+    /// Passes a message from the next hop or an exit stream of a circuit
+    /// back to the client.
+    async fn handle_backward(&mut self, backward: Backward) -> Result<(), PhantomBandError> {
+        let circuit_id = match &backward {
+            Backward::NextHop(circuit_id, _) | Backward::Stream(circuit_id, _) => *circuit_id,
+        };
+        // The circuit may have been destroyed while the message was queued.
+        let Some(circuit) = self.circuits.get_mut(&circuit_id) else {
+            return Ok(());
+        };
+        let payload = match backward {
+            Backward::NextHop(_, message) => {
+                if matches!(message, PhantomBandMessage::CircuitCreated { .. }) {
+                    info!("Extended circuit {} from {}", circuit_id, self.addr);
+                }
+                circuit.backward_payload(message)
+            },
+            Backward::Stream(_, cell) => {
+                // Whatever a stream sends after the client ended it is
+                // dropped.
+                if cell.command == RelayCommand::End {
+                    if circuit.streams.remove(&cell.stream_id).is_none() {
+                        return Ok(());
+                    }
+                } else if !circuit.streams.contains_key(&cell.stream_id) {
+                    return Ok(());
+                }
+                circuit.onion.originate(&cell)
+            },
+        };
+        match payload {
+            Ok(payload) => self.session.send_message(&PhantomBandMessage::Relay { circuit_id, payload: payload.to_vec() }).await,
+            Err(e) => self.destroy(circuit_id, &e).await,
+        }
+    }
+
+    /// Tears down a circuit, also at its next hop, and tells the client why.
+    async fn destroy(&mut self, circuit_id: u64, error: &PhantomBandError) -> Result<(), PhantomBandError> {
+        warn!("Destroying circuit {} from {}: {}", circuit_id, self.addr, error);
+        self.circuits.remove(&circuit_id);
+        self.session.send_message(&PhantomBandMessage::Destroy { circuit_id, reason: error.close_reason() }).await
+    }
+
+    /// Tells the peer why we are closing, when the link is still usable.
+    async fn close(&mut self, error: &PhantomBandError) {
+        if matches!(error, PhantomBandError::Transport(_) | PhantomBandError::Closed(_)) {
+            return;
+        }
+        let reason: CloseReason = error.close_reason();
+        if let Err(e) = self.session.send_message(&PhantomBandMessage::Disconnect { reason }).await {
+            error!("Failed to send Disconnect to {}: {}", self.addr, e);
+        }
+    }
+}
+
+impl RelayCircuit {
+    /// Turns a message from the next hop into the payload of the relay cell
+    /// we send back to the client.
+    fn backward_payload(&mut self, message: PhantomBandMessage) -> Result<OnionPayload, PhantomBandError> {
+        let next = self.next.as_mut()
+            .ok_or_else(|| PhantomBandError::Internal("Message from a next hop we do not have".to_string()))?;
+        match message {
+            PhantomBandMessage::CircuitCreated { handshake, .. } if !next.created => {
+                next.created = true;
+                self.onion.originate(&PhantomBandMessage::Extended { handshake }.to_relay_cell(0)?)
+            },
+            PhantomBandMessage::Relay { payload, .. } if next.created => {
+                let mut payload: OnionPayload = payload.as_slice().try_into()
+                    .map_err(|_| PhantomBandError::Protocol(format!("Relay payload is {} bytes", payload.len())))?;
+                self.onion.add_layer(&mut payload)?;
+                Ok(payload)
+            },
+            PhantomBandMessage::Destroy { reason, .. } => Err(PhantomBandError::Closed(reason)),
+            other => Err(PhantomBandError::Protocol(format!("Unexpected message from next hop: {:?}", other))),
+        }
+    }
+}
+
+impl NextHop {
+    async fn send(&self, message: PhantomBandMessage) -> Result<(), PhantomBandError> {
+        self.outgoing.send(message).await
+            .map_err(|_| PhantomBandError::Transport("Link to the next hop is closed".to_string()))
+    }
+}
+
+impl NextHopLinks {
+    /// Adds circuit `circuit_id` to our link to the relay `request` extends
+    /// to, opening the link if we have none. The next hop's messages for the
+    /// circuit go to `backward`.
+    fn join(&self, state: &Arc<RelayState>, request: &ExtendRequest, circuit_id: u64, backward: mpsc::Sender<Backward>) -> Result<LinkCircuit, PhantomBandError> {
+        let id = (request.handshake.relay, request.address);
+        let mut table = self.table.lock().unwrap_or_else(|e| e.into_inner());
+        if !table.links.contains_key(&id) {
+            if table.links.len() >= MAX_NEXT_HOP_LINKS {
+                return Err(PhantomBandError::Policy(format!("{} links to next hops are open", table.links.len())));
+            }
+            table.opened += 1;
+            let serial = table.opened;
+            let (outgoing, queue) = mpsc::channel(LINK_QUEUE_LEN);
+            table.links.insert(id, NextHopLink { serial, outgoing, circuits: HashMap::new() });
+            tokio::spawn(serve_link(Arc::clone(state), id, serial, request.link_key, queue));
+        }
+        let link = table.links.get_mut(&id).expect("Link was just added");
+        if link.circuits.len() >= MAX_CIRCUITS_PER_LINK {
+            return Err(PhantomBandError::Policy(format!("Link to {} carries {} circuits", request.handshake.relay, link.circuits.len())));
+        }
+        let next_id = loop {
+            let next_id = protocol::random_circuit_id();
+            if !link.circuits.contains_key(&next_id) {
+                break next_id;
+            }
+        };
+        link.circuits.insert(next_id, (circuit_id, backward));
+        Ok(LinkCircuit { link: id, serial: link.serial, next_id, outgoing: link.outgoing.clone() })
+    }
+
+    /// Takes a circuit off its link, which closes once its last circuit is
+    /// gone. Returns whether the circuit was still on the link.
+    fn leave(&self, circuit: &LinkCircuit) -> bool {
+        self.remove(circuit.link, circuit.serial, circuit.next_id).is_some()
+    }
+
+    /// Where the next hop's messages for circuit `next_id` on a link go.
+    /// Once the next hop destroyed the circuit it is taken off the link.
+    fn route(&self, link: LinkId, serial: u64, next_id: u64, destroyed: bool) -> Option<(u64, mpsc::Sender<Backward>)> {
+        if destroyed {
+            return self.remove(link, serial, next_id);
+        }
+        let table = self.table.lock().unwrap_or_else(|e| e.into_inner());
+        table.links.get(&link).filter(|entry| entry.serial == serial)?.circuits.get(&next_id).cloned()
+    }
+
+    fn remove(&self, link: LinkId, serial: u64, next_id: u64) -> Option<(u64, mpsc::Sender<Backward>)> {
+        let mut table = self.table.lock().unwrap_or_else(|e| e.into_inner());
+        let entry = table.links.get_mut(&link).filter(|entry| entry.serial == serial)?;
+        let circuit = entry.circuits.remove(&next_id);
+        if entry.circuits.is_empty() {
+            table.links.remove(&link);
+        }
+        circuit
+    }
+
+    /// Forgets a link that failed, returning the circuits it carried.
+    fn close(&self, link: LinkId, serial: u64) -> HashMap<u64, (u64, mpsc::Sender<Backward>)> {
+        let mut table = self.table.lock().unwrap_or_else(|e| e.into_inner());
+        match table.links.get(&link) {
+            Some(entry) if entry.serial == serial => table.links.remove(&link).map(|entry| entry.circuits).unwrap_or_default(),
+            _ => HashMap::new(),
+        }
+    }
+}
+
+/// Creates a circuit at its next hop and carries its messages there until
+/// the circuit is gone on the client's side, then takes it off the link.
+/// Messages from the next hop reach the client through `serve_link`.
+async fn serve_next_hop(state: Arc<RelayState>, circuit: LinkCircuit, handshake: NtorRequest, mut queue: mpsc::Receiver<PhantomBandMessage>) {
+    if circuit.outgoing.send(PhantomBandMessage::CircuitCreate { circuit_id: circuit.next_id, handshake }).await.is_ok() {
+        while let Some(message) = queue.recv().await {
+            // A failed link destroys its circuits itself.
+            if circuit.outgoing.send(message).await.is_err() {
+                return;
+            }
+        }
+    }
+    if state.next_hops.leave(&circuit) {
+        let _ = circuit.outgoing.send(PhantomBandMessage::Destroy { circuit_id: circuit.next_id, reason: CloseReason::Requested }).await;
+    }
+}
+
+/// Opens a link to a next hop and carries the messages of all circuits on
+/// it until the last circuit is gone. When the link fails, every circuit on
+/// it is destroyed back towards its client.
+async fn serve_link(state: Arc<RelayState>, link: LinkId, serial: u64, link_key: PublicKey, mut queue: mpsc::Receiver<PhantomBandMessage>) {
+    let (relay, address) = link;
+    let result = async {
+        let mut session = open_link(&state, address, &link_key, relay).await?;
+        loop {
+            tokio::select! {
+                message = queue.recv() => match message {
+                    Some(message) => session.send_message(&message).await?,
+                    None => return session.send_message(&PhantomBandMessage::Disconnect { reason: CloseReason::Requested }).await,
+                },
+                message = session.receive_message() => {
+                    let message = message?;
+                    if let PhantomBandMessage::Disconnect { reason } = message {
+                        return Err(PhantomBandError::Closed(reason));
+                    }
+                    let next_id = message.circuit_id()
+                        .ok_or_else(|| PhantomBandError::Protocol(format!("Unexpected message from next hop: {:?}", message)))?;
+                    let destroyed = matches!(message, PhantomBandMessage::Destroy { .. });
+                    // The circuit may have left the link while the message
+                    // was on its way.
+                    if let Some((circuit_id, backward)) = state.next_hops.route(link, serial, next_id, destroyed) {
+                        let _ = backward.send(Backward::NextHop(circuit_id, message)).await;
+                    }
+                },
+            }
+        }
+    }.await;
+
+    match result {
+        Ok(()) => info!("Closed link to next hop {} at {}", relay, address),
+        Err(e) => {
+            warn!("Link to next hop {} at {} failed: {}", relay, address, e);
+            for (next_id, (circuit_id, backward)) in state.next_hops.close(link, serial) {
+                let destroy = PhantomBandMessage::Destroy { circuit_id: next_id, reason: e.close_reason() };
+                let _ = backward.send(Backward::NextHop(circuit_id, destroy)).await;
+            }
+        },
+    }
+}
+
+/// Opens a link to `address` and checks that it reaches `relay`.
+async fn open_link(state: &RelayState, address: SocketAddr, link_key: &PublicKey, relay: Fingerprint) -> Result<LinkSession, PhantomBandError> {
+    let stream = match tokio::time::timeout(EXTEND_TIMEOUT, TcpStream::connect(address)).await {
+        Err(_) => return Err(PhantomBandError::Timeout(format!("Connecting to next hop {}", address))),
+        Ok(Err(e)) => return Err(PhantomBandError::from(e).context("Failed to connect to next hop")),
+        Ok(Ok(stream)) => stream,
+    };
+    let session = tokio::time::timeout(EXTEND_TIMEOUT, LinkSession::initiate(stream, &state.credentials, link_key)).await
+        .map_err(|_| PhantomBandError::Timeout(format!("Link handshake with next hop {}", address)))?
+        .map_err(|e| e.context("Link handshake with next hop failed"))?;
+    let identity = session.remote_identity()
+        .ok_or_else(|| PhantomBandError::Internal("Link established without relay identity".to_string()))?
+        .fingerprint();
+    if identity != relay {
+        return Err(PhantomBandError::Policy(format!("Next hop at {} is {}, not {}", address, identity, relay)));
+    }
+    Ok(session)
+}
@@ -1,141 +1,414 @@
 // relay/src/main.rs
 
-use common::crypto;
-use common::protocol::PhantomBandMessage;
+mod crypto;
+mod descriptor;
+mod exit;
+mod listener;
+
+use common::crypto::{PublicKey, SecretKey};
+use common::crypto::ntor::NtorRelay;
+use common::crypto::replay::ReplayCache;
+use common::error::PhantomBandError;
+use common::protocol::descriptor::{ExitPolicy, SignedRelayDescriptor};
+use common::utils::{SharedClock, SystemClock};
+use listener::{NextHopLinks, RelayState};
 use tokio::net::TcpListener;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use crate::transports::quic::QuicTransport;
-use crate::transports::r#trait::PluggableTransport;
-use crate::transports::tcp::{send_message, receive_message};
-use bincode;
+use transports::quic::QuicTransport;
+use transports::noise::LinkCredentials;
+use transports::r#trait::PluggableTransport;
 use log::{info, error};
-use env_logger;
-use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+/// Where we listen unless an address is given as the first argument.
+const LISTEN_ADDRESS: &str = "127.0.0.1:8080";
+/// Second argument that makes the relay an exit.
+const EXIT_ARGUMENT: &str = "exit";
+/// Length of a replay cache bucket in seconds.
+const REPLAY_BUCKET_LENGTH: u64 = 10 * 60;
+/// Circuit handshakes one replay cache bucket is sized for, about 14 a second
+/// over a bucket length. Busier relays fill more buckets.
+const REPLAY_BUCKET_CAPACITY: usize = 1 << 13;
+/// Most replay cache buckets, room for about 190 handshakes a second kept
+/// up for two onion key periods, in about 185 MiB. Past that the replay
+/// cache lets false positives rise rather than refuse circuits.
+const REPLAY_MAX_BUCKETS: usize = 1 << 12;
+/// How often the replay cache counters are logged.
+const REPLAY_STATS_INTERVAL: Duration = Duration::from_secs(15 * 60);
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     env_logger::init();
     info!("PhantomBand Relay starting...");
-    let relay_keypair = crypto::generate_keypair();
-    info!("Generated relay keypair: {:?}", relay_keypair);
+    let clock = SystemClock::shared();
+    let identity = crypto::load_or_create_identity(Path::new(crypto::IDENTITY_KEY_FILE))?;
+    info!("Relay fingerprint: {}", identity.public_key().fingerprint());
+    let link_key = crypto::load_or_create_link_key(Path::new(crypto::LINK_KEY_FILE))?;
+    let onion_key = SecretKey::generate();
+    let listen_address = std::env::args().nth(1).unwrap_or_else(|| LISTEN_ADDRESS.to_string());
+    let address: SocketAddr = listen_address.parse()?;
+    let exit_policy = match std::env::args().nth(2).as_deref() {
+        Some(EXIT_ARGUMENT) => descriptor::exit_policy(address, &descriptor::interface_addresses()?),
+        Some(other) => return Err(format!("Unknown argument {:?}, expected {:?}", other, EXIT_ARGUMENT).into()),
+        None => ExitPolicy::reject_all(),
+    };
+    info!("Relay {} an exit", if exit_policy.is_exit() { "is" } else { "is not" });
+    let link_public = link_key.public_key();
+    let credentials = LinkCredentials::relay(link_key, &identity);
+    let fingerprint = identity.public_key().fingerprint();
+    let descriptor_clock = clock.clone();
+    let descriptor_policy = exit_policy.clone();
+    let publish = move |onion_key| descriptor::publish(descriptor_clock.as_ref(), &identity, link_public, onion_key, address, descriptor_policy.clone(), Path::new(descriptor::DESCRIPTOR_FILE));
+    publish(onion_key.public_key())?;
+    let ntor = NtorRelay::new(fingerprint, onion_key);
+    let state = Arc::new(RelayState {
+        clock: clock.clone(),
+        credentials,
+        ntor: RwLock::new(ntor),
+        replays: Mutex::new(ReplayCache::new(clock.clone(), 2 * crypto::ONION_KEY_ROTATION, REPLAY_BUCKET_LENGTH, REPLAY_BUCKET_CAPACITY, REPLAY_MAX_BUCKETS)),
+        exit_policy,
+        extend_policy: descriptor::extend_policy(address),
+        next_hops: NextHopLinks::default(),
+    });
+    tokio::spawn(rotate_onion_keys(clock.clone(), Arc::clone(&state), publish));
+    tokio::spawn(log_replay_stats(Arc::clone(&state)));
 
     let quic_transport = QuicTransport;
-    quic_transport.listen("127.0.0.1:8080")?;
-    info!("Relay listening on 127.0.0.1:8080 using QUIC transport");
+    quic_transport.listen(&listen_address)?;
+    info!("Relay listening on {} using QUIC transport", listen_address);
 
-    let listener = TcpListener::bind("127.0.0.1:8080").await?;
-    info!("Relay also listening on 127.0.0.1:8080 (TCP fallback for demonstration)");
-
-    let client_keys: Arc<Mutex<HashMap<String, [u8; 32]>>> = Arc::new(Mutex::new(HashMap::new()));
+    let tcp_listener = TcpListener::bind(address).await?;
+    info!("Relay also listening on {} (TCP fallback for demonstration)", listen_address);
 
     loop {
-        let (mut socket, addr) = listener.accept().await?;
+        let (socket, addr) = tcp_listener.accept().await?;
         info!("Accepted connection from: {}", addr);
 
-        let relay_id = "test_relay_id".to_string();
-        let relay_public_key = relay_keypair;
-        let client_keys_clone = Arc::clone(&client_keys);
+        tokio::spawn(listener::serve_connection(socket, addr, Arc::clone(&state)));
+    }
+}
+
+/// Replaces the onion key at the start of every rotation period and
+/// publishes a descriptor with the new key.
+async fn rotate_onion_keys(clock: SharedClock, state: Arc<RelayState>, publish: impl Fn(PublicKey) -> Result<SignedRelayDescriptor, PhantomBandError>) {
+    loop {
+        let now = clock.unix_time();
+        let next = (now / crypto::ONION_KEY_ROTATION + 1) * crypto::ONION_KEY_ROTATION;
+        tokio::time::sleep(Duration::from_secs(next - now)).await;
+        if clock.unix_time() < next {
+            continue;
+        }
+        let onion_key = SecretKey::generate();
+        let public = onion_key.public_key();
+        state.ntor.write().unwrap_or_else(|e| e.into_inner()).rotate(onion_key);
+        info!("Rotated onion key to {}", public);
+        if let Err(e) = publish(public) {
+            error!("Failed to republish the relay descriptor: {}", e);
+        }
+    }
+}
+
+/// Logs the replay cache counters now and then, for monitoring.
+async fn log_replay_stats(state: Arc<RelayState>) {
+    let mut interval = tokio::time::interval(REPLAY_STATS_INTERVAL);
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        let replays = state.replays.lock().unwrap_or_else(|e| e.into_inner());
+        info!("Replay cache: {}; {} tags in {} of at most {} KiB", replays.stats(), replays.len(), replays.memory() / 1024, replays.max_memory() / 1024);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{descriptor, exit, listener};
+    use client::circuit::Circuit;
+    use client::socks::SocksProxy;
+    use client::stream::{CircuitHandle, CircuitRequest};
+    use common::crypto::{IdentityKeypair, SecretKey};
+    use common::crypto::ntor::NtorRelay;
+    use common::crypto::replay::ReplayCache;
+    use common::error::PhantomBandError;
+    use common::protocol::descriptor::{ExitAction, ExitPolicy, ExitRule, RelayDescriptor};
+    use common::protocol::{Datagram, DatagramAssembler, EndReason, PhantomBandMessage, RelayCell, RelayCommand, StreamTarget};
+    use common::utils::{ManualClock, SystemClock};
+    use listener::{NextHopLinks, RelayState};
+    use std::net::SocketAddr;
+    use std::sync::{Arc, Mutex, RwLock};
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream, UdpSocket};
+    use tokio::sync::mpsc;
+    use transports::noise::LinkCredentials;
 
+    /// Starts a relay on a free loopback port. The receiver yields the peer
+    /// of each of its connections once that connection is closed.
+    async fn spawn_relay(exit_policy: ExitPolicy) -> (RelayDescriptor, mpsc::UnboundedReceiver<SocketAddr>) {
+        let clock = SystemClock::shared();
+        let (identity, link_key, onion_key) = (IdentityKeypair::generate(), SecretKey::generate(), SecretKey::generate());
+        let tcp_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = tcp_listener.local_addr().unwrap();
+        let relay = descriptor::describe(clock.as_ref(), &identity, link_key.public_key(), onion_key.public_key(), address, exit_policy.clone());
+        let state = Arc::new(RelayState {
+            clock: clock.clone(),
+            credentials: LinkCredentials::relay(link_key, &identity),
+            ntor: RwLock::new(NtorRelay::new(relay.fingerprint(), onion_key)),
+            replays: Mutex::new(ReplayCache::new(clock, 3600, 600, 64, 16)),
+            exit_policy,
+            extend_policy: descriptor::extend_policy(address),
+            next_hops: NextHopLinks::default(),
+        });
+        let (closed, closed_receiver) = mpsc::unbounded_channel();
         tokio::spawn(async move {
-            let mut current_client_id: Option<String> = None;
             loop {
-                match receive_message(&mut socket).await {
-                    Ok(encrypted_data) => {
-                        let client_key = if let Some(client_id) = &current_client_id {
-                            client_keys_clone.lock().unwrap().get(client_id).cloned()
-                        } else {
-                            // For the first message (ConnectRequest), we don't have the client_id yet,
-                            // so we'll try to decrypt with the relay's own key for now.
-                            // In a real scenario, the initial handshake would establish a shared secret.
-                            Some(relay_public_key)
-                        };
-
-                        let decrypted_data = match client_key {
-                            Some(key) => match crypto::decrypt(&encrypted_data, &key) {
-                                Ok(data) => data,
-                                Err(e) => {
-                                    error!("Failed to decrypt message from {}: {}. (Attempted with key {:?})", addr, e, key);
-                                    return;
-                                }
-                            },
-                            None => {
-                                error!("No client key found for {}. Cannot decrypt.", addr);
-                                return;
-                            }
-                        };
-
-                        match bincode::deserialize::<PhantomBandMessage>(&decrypted_data) {
-                            Ok(message) => {
-                                match message {
-                                    PhantomBandMessage::ConnectRequest { client_id, public_key } => {
-                                        info!("Received ConnectRequest from client {}: {:?}", client_id, public_key);
-                                        client_keys_clone.lock().unwrap().insert(client_id.clone(), public_key);
-                                        current_client_id = Some(client_id);
-
-                                        let connect_response = PhantomBandMessage::ConnectResponse {
-                                            relay_id: relay_id.clone(),
-                                            public_key: relay_public_key,
-                                            success: true,
-                                            message: Some("Connection established.".to_string()),
-                                        };
-                                        let serialized_response = bincode::serialize(&connect_response).unwrap();
-                                        let encrypted_response = crypto::encrypt(&serialized_response, &relay_public_key).unwrap();
-                                        if let Err(e) = send_message(&mut socket, &encrypted_response).await {
-                                            error!("Failed to send ConnectResponse to {}: {}", addr, e);
-                                            return;
-                                        }
-                                        info!("Sent ConnectResponse to {}: {:?}", addr, connect_response);
-                                    },
-                                    PhantomBandMessage::CircuitCreate { circuit_id, public_key: client_pk } => {
-                                        info!("Received CircuitCreate for circuit {}: {:?}", circuit_id, client_pk);
-                                        // In a real scenario, the relay would store circuit state and potentially forward to next hop.
-                                        let circuit_created = PhantomBandMessage::CircuitCreated {
-                                            circuit_id,
-                                            success: true,
-                                            message: Some("Circuit created successfully.".to_string()),
-                                        };
-                                        let serialized_response = bincode::serialize(&circuit_created).unwrap();
-                                        let encrypted_response = crypto::encrypt(&serialized_response, &relay_public_key).unwrap(); // Use relay's key for now
-                                        if let Err(e) = send_message(&mut socket, &encrypted_response).await {
-                                            error!("Failed to send CircuitCreated to {}: {}", addr, e);
-                                            return;
-                                        }
-                                        info!("Sent CircuitCreated to {}: {:?}", addr, circuit_created);
-                                    },
-                                    PhantomBandMessage::Data { circuit_id, payload } => {
-                                        info!("Received Data for circuit {} from {}: {:?}", circuit_id, addr, payload);
-                                        // Echo the data back for now
-                                        let echoed_data = PhantomBandMessage::Data { circuit_id, payload: payload.clone() };
-                                        let serialized_echo = bincode::serialize(&echoed_data).unwrap();
-                                        let encrypted_echo = crypto::encrypt(&serialized_echo, &relay_public_key).unwrap(); // Use relay's key for now
-                                        if let Err(e) = send_message(&mut socket, &encrypted_echo).await {
-                                            error!("Failed to echo Data to {}: {}", addr, e);
-                                            return;
-                                        }
-                                        info!("Echoed Data to {}: {:?}", addr, echoed_data);
-                                    },
-                                    PhantomBandMessage::Disconnect => {
-                                        info!("Received Disconnect from {}. Closing connection.", addr);
-                                        if let Some(client_id) = &current_client_id {
-                                            client_keys_clone.lock().unwrap().remove(client_id);
-                                            info!("Removed client key for {}.", client_id);
-                                        }
-                                        return;
-                                    },
-                                }
-                            }
-                            Err(e) => {
-                                error!("Failed to deserialize message from {}: {}. Raw data: {:?}", addr, e, encrypted_data);
-                            }
-                        }
-                    }
-                    Err(e) => {
-                        error!("Failed to read from socket for {}: {}", addr, e);
-                        return;
-                    }
-                }
+                let (socket, addr) = tcp_listener.accept().await.unwrap();
+                let (state, closed) = (Arc::clone(&state), closed.clone());
+                tokio::spawn(async move {
+                    listener::serve_connection(socket, addr, state).await;
+                    let _ = closed.send(addr);
+                });
             }
         });
+        (relay, closed_receiver)
+    }
+
+    async fn assert_closed(closed: &mut mpsc::UnboundedReceiver<SocketAddr>) {
+        tokio::time::timeout(Duration::from_secs(5), closed.recv()).await
+            .expect("Relay kept its link open")
+            .expect("Relay stopped");
+    }
+
+    #[tokio::test]
+    async fn test_circuit_extends_through_relays_and_destroy_propagates() {
+        let accept_all = ExitPolicy { rules: vec![ExitRule { action: ExitAction::Accept, network: None, ports: (1, u16::MAX) }] };
+        let (guard, mut guard_closed) = spawn_relay(ExitPolicy::reject_all()).await;
+        let (middle, mut middle_closed) = spawn_relay(ExitPolicy::reject_all()).await;
+        let (exit, mut exit_closed) = spawn_relay(accept_all).await;
+        let echo = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let echo_address = echo.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut socket, _) = echo.accept().await.unwrap();
+            let mut buffer = [0u8; 64];
+            let read = socket.read(&mut buffer).await.unwrap();
+            socket.write_all(&buffer[..read]).await.unwrap();
+        });
+
+        let mut circuit = Circuit::new();
+        let path = [guard, middle, exit];
+        circuit.build(&path).await.expect("Circuit build failed");
+        assert_eq!(circuit.path, path.iter().map(RelayDescriptor::fingerprint).collect::<Vec<_>>());
+
+        // Relay cells pass both ways through the extended hops.
+        circuit.send(&PhantomBandMessage::Begin { target: StreamTarget::Address(echo_address) }.to_relay_cell(1).unwrap()).await.unwrap();
+        assert_eq!(circuit.receive().await.unwrap().command, RelayCommand::Connected);
+        circuit.send(&RelayCell::new(RelayCommand::Data, 1, b"through three hops").unwrap()).await.unwrap();
+        let echoed = circuit.receive().await.unwrap();
+        assert_eq!((echoed.command, echoed.data.as_slice()), (RelayCommand::Data, &b"through three hops"[..]));
+
+        // Destroying the circuit at the first hop closes every link after it.
+        circuit.close().await.unwrap();
+        assert_closed(&mut guard_closed).await;
+        assert_closed(&mut middle_closed).await;
+        assert_closed(&mut exit_closed).await;
+    }
+
+    #[tokio::test]
+    async fn test_circuits_to_the_same_next_hop_share_a_link() {
+        let (guard, mut guard_closed) = spawn_relay(ExitPolicy::reject_all()).await;
+        let (middle, mut middle_closed) = spawn_relay(ExitPolicy::reject_all()).await;
+        let (exit, mut exit_closed) = spawn_relay(ExitPolicy::reject_all()).await;
+        let path = [guard, middle, exit];
+        let (mut first, mut second) = (Circuit::new(), Circuit::new());
+        first.build(&path).await.expect("Circuit build failed");
+        second.build(&path).await.expect("Circuit build failed");
+
+        // The guard carries both circuits to the middle relay on one link,
+        // which stays open while either circuit does.
+        first.close().await.unwrap();
+        assert_closed(&mut guard_closed).await;
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(middle_closed.try_recv().is_err(), "Link closed with a circuit on it");
+        assert!(exit_closed.try_recv().is_err(), "Link closed with a circuit on it");
+
+        second.close().await.unwrap();
+        assert_closed(&mut guard_closed).await;
+        assert_closed(&mut middle_closed).await;
+        assert_closed(&mut exit_closed).await;
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(middle_closed.try_recv().is_err(), "Middle relay had more than one link");
+        assert!(exit_closed.try_recv().is_err(), "Exit had more than one link");
+    }
+
+    #[tokio::test]
+    async fn test_socks_retries_streams_the_exit_refuses_on_a_circuit_for_their_port() {
+        let accept_all = ExitPolicy { rules: vec![ExitRule { action: ExitAction::Accept, network: None, ports: (1, u16::MAX) }] };
+        let only_https = ExitPolicy { rules: vec![ExitRule { action: ExitAction::Accept, network: None, ports: (443, 443) }] };
+        let (guard, _) = spawn_relay(ExitPolicy::reject_all()).await;
+        let (refusing, _) = spawn_relay(only_https).await;
+        let (accepting, _) = spawn_relay(accept_all).await;
+        let echo = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let echo_address = echo.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut socket, _) = echo.accept().await.unwrap();
+            let mut buffer = [0u8; 64];
+            let read = socket.read(&mut buffer).await.unwrap();
+            socket.write_all(&buffer[..read]).await.unwrap();
+        });
+        let build = |path: Vec<RelayDescriptor>| async move {
+            let mut circuit = Circuit::new();
+            circuit.build(&path).await.expect("Circuit build failed");
+            CircuitHandle::spawn(circuit)
+        };
+
+        // The builder is asked for a circuit for the refused port.
+        let circuit = build(vec![guard.clone(), refusing]).await;
+        let (builder, mut requests) = mpsc::channel::<CircuitRequest>(1);
+        tokio::spawn(async move {
+            let request = requests.recv().await.unwrap();
+            assert_eq!(request.port, echo_address.port());
+            let _ = request.answer.send(Some(build(vec![guard, accepting]).await));
+        });
+        let proxy = SocksProxy::bind(0, None).await.unwrap();
+        let proxy_address = proxy.local_addr().unwrap();
+        tokio::spawn(async move { proxy.serve(&circuit, &builder).await });
+
+        let mut client = TcpStream::connect(proxy_address).await.unwrap();
+        let mut request = vec![5, 1, 0, 5, 1, 0, 1, 127, 0, 0, 1];
+        request.extend_from_slice(&echo_address.port().to_be_bytes());
+        client.write_all(&request).await.unwrap();
+        let mut answer = [0u8; 12];
+        client.read_exact(&mut answer).await.unwrap();
+        assert_eq!(answer[..4], [5, 0, 5, 0], "Stream was not opened");
+        client.write_all(b"through another exit").await.unwrap();
+        let mut echoed = [0u8; 20];
+        client.read_exact(&mut echoed).await.unwrap();
+        assert_eq!(&echoed, b"through another exit");
+    }
+
+    #[tokio::test]
+    async fn test_failed_extend_destroys_the_circuit_back_to_the_client() {
+        let (guard, mut guard_closed) = spawn_relay(ExitPolicy::reject_all()).await;
+        let (middle, mut middle_closed) = spawn_relay(ExitPolicy::reject_all()).await;
+        let unreachable = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+        let clock = SystemClock::shared();
+        let missing = descriptor::describe(clock.as_ref(), &IdentityKeypair::generate(), SecretKey::generate().public_key(), SecretKey::generate().public_key(), unreachable, ExitPolicy::reject_all());
+
+        let mut circuit = Circuit::new();
+        let result = circuit.build(&[guard.clone(), middle.clone(), missing]).await;
+        assert!(matches!(result, Err(PhantomBandError::Closed(_))), "Unexpected result {:?}", result);
+        assert_eq!(circuit.path, vec![guard.fingerprint(), middle.fingerprint()]);
+        assert_closed(&mut guard_closed).await;
+        assert_closed(&mut middle_closed).await;
+    }
+
+    #[test]
+    fn test_public_relays_only_extend_to_public_addresses() {
+        let public = descriptor::extend_policy("203.0.113.5:9001".parse().unwrap());
+        for private in ["127.0.0.1", "10.1.2.3", "192.168.0.1", "::1", "fd00::1"] {
+            assert!(!public.allows(private.parse().unwrap(), 9001), "Extends to {}", private);
+        }
+        assert!(public.allows("198.51.100.7".parse().unwrap(), 9001));
+
+        let local = descriptor::extend_policy("127.0.0.1:8080".parse().unwrap());
+        assert!(local.allows("127.0.0.1".parse().unwrap(), 8081));
+    }
+
+    #[test]
+    fn test_exit_policy_rejects_private_and_own_addresses() {
+        let policy = descriptor::exit_policy("0.0.0.0:9001".parse().unwrap(), &["203.0.113.9".parse().unwrap(), "10.0.0.5".parse().unwrap()]);
+        for rejected in ["203.0.113.9", "10.0.0.5", "127.0.0.1", "224.0.0.1", "240.0.0.1", "255.255.255.255", "ff02::1", "64:ff9b::7f00:1", "fe80::1"] {
+            assert!(!policy.allows(rejected.parse().unwrap(), 443), "Allows {}", rejected);
+        }
+        assert!(policy.allows("198.51.100.7".parse().unwrap(), 443));
+        assert!(policy.allows("2001:db8::1".parse().unwrap(), 443));
+        // Private interface addresses are covered by their networks and kept
+        // out of the published policy.
+        assert!(!policy.rules.iter().any(|rule| rule.network == Some(("10.0.0.5".parse().unwrap(), 32))));
+    }
+
+    #[tokio::test]
+    async fn test_exit_resolves_before_checking_its_policy() {
+        let policy = descriptor::exit_policy("203.0.113.5:9001".parse().unwrap(), &[]);
+        let localhost = StreamTarget::Hostname { host: "localhost".to_string(), port: 80 };
+        assert_eq!(exit::resolve(&policy, &localhost).await, Err(EndReason::ExitPolicy));
+        assert!(matches!(exit::connect(&policy, &localhost).await, Err(EndReason::ExitPolicy)));
+
+        // IPv4 addresses in IPv6 form meet the IPv4 rules.
+        let mapped = |address: &str| StreamTarget::Address(address.parse().unwrap());
+        assert_eq!(exit::resolve(&policy, &mapped("[::ffff:127.0.0.1]:80")).await, Err(EndReason::ExitPolicy));
+        assert!(matches!(exit::connect(&policy, &mapped("[::ffff:10.0.0.1]:80")).await, Err(EndReason::ExitPolicy)));
+        assert_eq!(exit::resolve(&policy, &mapped("[::ffff:198.51.100.7]:443")).await, Ok(vec!["198.51.100.7:443".parse().unwrap()]));
+
+        // Where the policy allows it, we connect.
+        let local = descriptor::extend_policy("127.0.0.1:8080".parse().unwrap());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = StreamTarget::Hostname { host: "localhost".to_string(), port: listener.local_addr().unwrap().port() };
+        let connection = exit::connect(&local, &target).await.expect("Connecting to an allowed address failed");
+        assert_eq!(connection.peer_addr().unwrap(), listener.local_addr().unwrap());
+    }
+
+    #[test]
+    fn test_udp_flows_expire_and_are_bounded() {
+        let clock = ManualClock::new(1000);
+        let mut flows = exit::Flows::new(Arc::new(clock.clone()));
+        let peer = |port: u16| SocketAddr::from(([198, 51, 100, 7], port));
+
+        // Only peers the client sent to may answer.
+        assert!(!flows.touch(peer(1)), "Unsolicited peer accepted");
+        assert!(flows.open(peer(1)));
+        assert!(flows.touch(peer(1)));
+
+        for port in 2..=exit::MAX_FLOWS_PER_STREAM as u16 {
+            assert!(flows.open(peer(port)));
+        }
+        assert!(!flows.open(peer(0)), "Flow opened beyond the limit");
+        assert!(flows.open(peer(1)), "Existing flow refused at the limit");
+
+        // Quiet flows expire, and so does the stream once it has none.
+        clock.advance(exit::UDP_FLOW_TIMEOUT);
+        assert!(!flows.expire());
+        assert!(!flows.touch(peer(1)));
+        assert!(flows.open(peer(0)));
+        clock.advance(exit::UDP_FLOW_TIMEOUT);
+        assert!(!flows.expire());
+        clock.advance(exit::DATAGRAM_STREAM_IDLE_TIMEOUT);
+        assert!(flows.expire());
+    }
+
+    #[tokio::test]
+    async fn test_datagram_stream_drops_unsolicited_peers() {
+        let accept_all = ExitPolicy { rules: vec![ExitRule { action: ExitAction::Accept, network: None, ports: (1, u16::MAX) }] };
+        let (backward_sender, mut backward) = mpsc::channel(64);
+        let (incoming, queue) = mpsc::channel(64);
+        let circuit = exit::StreamCircuit { circuit_id: 7, stream_id: 1, backward: backward_sender };
+        tokio::spawn(exit::serve_stream(SystemClock::shared(), accept_all, exit::StreamRequest::Datagrams, circuit, queue));
+        let mut next_cell = async || match tokio::time::timeout(Duration::from_secs(5), backward.recv()).await.unwrap().unwrap() {
+            listener::Backward::Stream(7, cell) => cell,
+            _ => panic!("Unexpected message from the exit stream"),
+        };
+        assert_eq!(next_cell().await.command, RelayCommand::Connected);
+
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let stranger = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let datagram = Datagram { peer: StreamTarget::Address(peer.local_addr().unwrap()), data: b"hello".to_vec() };
+        for cell in datagram.to_cells(1).unwrap() {
+            incoming.send(cell).await.unwrap();
+        }
+        let mut buffer = [0u8; 64];
+        let (n, exit_address) = peer.recv_from(&mut buffer).await.unwrap();
+        assert_eq!(&buffer[..n], b"hello");
+
+        // Someone the client never sent to cannot reach it through the flow.
+        stranger.send_to(b"unsolicited", exit_address).await.unwrap();
+        peer.send_to(b"answer", exit_address).await.unwrap();
+        let mut assembler = DatagramAssembler::default();
+        let answer = loop {
+            if let Some(datagram) = assembler.push(&next_cell().await).unwrap() {
+                break datagram;
+            }
+        };
+        assert_eq!(answer, Datagram { peer: StreamTarget::Address(peer.local_addr().unwrap()), data: b"answer".to_vec() });
     }
-}
\ No newline at end of file
+}
@@ -1,17 +1,18 @@
 // transports/src/doh.rs
 
 use super::r#trait::PluggableTransport;
+use common::error::PhantomBandError;
 
 pub struct DohTransport;
 
 impl PluggableTransport for DohTransport {
-    fn connect(&self, addr: &str) -> Result<(), String> {
+    fn connect(&self, addr: &str) -> Result<(), PhantomBandError> {
         println!("DoH Transport: Connecting to {}", addr);
         // Dummy implementation
         Ok(())
     }
 
-    fn listen(&self, addr: &str) -> Result<(), String> {
+    fn listen(&self, addr: &str) -> Result<(), PhantomBandError> {
         println!("DoH Transport: Listening on {}", addr);
         // Dummy implementation
         Ok(())
@@ -6,4 +6,147 @@ pub mod doh;
 pub mod websocket;
 pub mod obfs4;
 pub mod traffic_shaping;
-pub mod tcp;
\ No newline at end of file
+pub mod tcp;
+pub mod noise;
+
+#[cfg(test)]
+mod tests {
+    use super::noise::{LinkCredentials, LinkSession};
+    use super::tcp::{self, FrameCodec};
+    use bytes::BytesMut;
+    use common::crypto::{IdentityKeypair, PublicKey, SecretKey};
+    use common::error::PhantomBandError;
+    use common::protocol::{Capabilities, Versions};
+    use common::protocol::version::SUPPORTED_VERSIONS;
+    use tokio::io::{AsyncWriteExt, DuplexStream};
+    use tokio_util::codec::{Decoder, Encoder};
+
+    fn encode(codec: &mut FrameCodec, frames: &[&[u8]]) -> BytesMut {
+        let mut buffer = BytesMut::new();
+        for frame in frames {
+            codec.encode(*frame, &mut buffer).expect("Encoding failed");
+        }
+        buffer
+    }
+
+    #[test]
+    fn test_frame_codec_partial_reads() {
+        let mut codec = FrameCodec::default();
+        let encoded = encode(&mut codec, &[b"split across many reads"]);
+
+        let mut buffer = BytesMut::new();
+        for (i, byte) in encoded.iter().enumerate() {
+            assert!(codec.decode(&mut buffer).expect("Decoding failed").is_none(), "Frame completed early at byte {}", i);
+            buffer.extend_from_slice(&[*byte]);
+        }
+        assert_eq!(&codec.decode(&mut buffer).expect("Decoding failed").expect("Frame missing")[..], b"split across many reads");
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn test_frame_codec_back_to_back_frames() {
+        let mut codec = FrameCodec::default();
+        let mut buffer = encode(&mut codec, &[b"first", b"", b"third"]);
+        buffer.extend_from_slice(&[0, 0]);
+
+        assert_eq!(&codec.decode(&mut buffer).unwrap().unwrap()[..], b"first");
+        assert_eq!(&codec.decode(&mut buffer).unwrap().unwrap()[..], b"");
+        assert_eq!(&codec.decode(&mut buffer).unwrap().unwrap()[..], b"third");
+        assert!(codec.decode(&mut buffer).unwrap().is_none());
+        assert_eq!(buffer.len(), 2, "The start of the next frame must stay buffered");
+    }
+
+    #[test]
+    fn test_frame_codec_rejects_oversized_frames() {
+        let mut codec = FrameCodec::new(16);
+        assert!(matches!(codec.encode(&[0u8; 17][..], &mut BytesMut::new()), Err(PhantomBandError::Protocol(_))));
+
+        // The length alone is enough to refuse the frame.
+        let mut buffer = BytesMut::from(&17u32.to_be_bytes()[..]);
+        assert!(matches!(codec.decode(&mut buffer), Err(PhantomBandError::Protocol(_))));
+    }
+
+    #[tokio::test]
+    async fn test_framed_stream_round_trip() {
+        let (client, server) = tokio::io::duplex(1024);
+        let mut client = tcp::framed(client);
+        let mut server = tcp::framed(server);
+        let large = vec![0xA5u8; tcp::MAX_FRAME_LEN];
+
+        let sender = tokio::spawn(async move {
+            tcp::send_message(&mut client, &large).await.expect("Send failed");
+            tcp::send_message(&mut client, b"small").await.expect("Send failed");
+            client.into_inner().shutdown().await.expect("Shutdown failed");
+        });
+
+        assert_eq!(tcp::receive_message(&mut server).await.expect("Receive failed"), vec![0xA5u8; tcp::MAX_FRAME_LEN]);
+        assert_eq!(tcp::receive_message(&mut server).await.expect("Receive failed"), b"small");
+        assert!(matches!(tcp::receive_message(&mut server).await, Err(PhantomBandError::Transport(_))));
+        sender.await.unwrap();
+    }
+
+    type LinkResult = Result<LinkSession<DuplexStream>, PhantomBandError>;
+
+    /// Runs both ends of a link handshake against each other. The initiator
+    /// expects `responder_key`, which defaults to the responder's real key.
+    async fn handshake(relay: &LinkCredentials, responder_key: Option<PublicKey>, initiator_offers: Versions, responder_offers: Versions) -> (LinkResult, LinkResult) {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let responder_key = responder_key.unwrap_or_else(|| relay.static_key.public_key());
+        let client_credentials = LinkCredentials::ephemeral();
+        tokio::join!(
+            LinkSession::initiate_offering(client, &client_credentials, &responder_key, &initiator_offers),
+            LinkSession::accept_offering(server, relay, &responder_offers),
+        )
+    }
+
+    async fn assert_link_carries_data(initiator: &mut LinkSession<DuplexStream>, responder: &mut LinkSession<DuplexStream>) {
+        initiator.send(b"forward").await.expect("Send failed");
+        assert_eq!(responder.receive().await.expect("Receive failed"), b"forward");
+        responder.send(b"backward").await.expect("Send failed");
+        assert_eq!(initiator.receive().await.expect("Receive failed"), b"backward");
+    }
+
+    #[tokio::test]
+    async fn test_link_handshake_post_quantum() {
+        let identity = IdentityKeypair::generate();
+        let relay = LinkCredentials::relay(SecretKey::generate(), &identity);
+        let (initiator, responder) = handshake(&relay, None, Versions::ours(), Versions::ours()).await;
+        let (mut initiator, mut responder) = (initiator.expect("Initiator failed"), responder.expect("Responder failed"));
+
+        assert!(initiator.parameters().capabilities.contains(Capabilities::PQ_HANDSHAKE));
+        assert_eq!(initiator.parameters(), responder.parameters());
+        assert_eq!(initiator.remote_identity().expect("Relay identity missing").fingerprint(), identity.public_key().fingerprint());
+        assert!(responder.remote_identity().is_none(), "Clients stay anonymous");
+        assert_link_carries_data(&mut initiator, &mut responder).await;
+    }
+
+    #[tokio::test]
+    async fn test_link_handshake_without_post_quantum() {
+        let relay = LinkCredentials::relay(SecretKey::generate(), &IdentityKeypair::generate());
+        let classical = Versions { versions: SUPPORTED_VERSIONS.to_vec(), capabilities: Capabilities::FIXED_CELLS };
+        let (initiator, responder) = handshake(&relay, None, classical, Versions::ours()).await;
+        let (mut initiator, mut responder) = (initiator.expect("Initiator failed"), responder.expect("Responder failed"));
+
+        assert!(!initiator.parameters().capabilities.contains(Capabilities::PQ_HANDSHAKE));
+        assert!(!responder.parameters().capabilities.contains(Capabilities::PQ_HANDSHAKE));
+        assert_link_carries_data(&mut initiator, &mut responder).await;
+    }
+
+    #[tokio::test]
+    async fn test_link_handshake_rejects_wrong_responder_key() {
+        let relay = LinkCredentials::relay(SecretKey::generate(), &IdentityKeypair::generate());
+        let expected = SecretKey::generate().public_key();
+        let (initiator, responder) = handshake(&relay, Some(expected), Versions::ours(), Versions::ours()).await;
+        assert!(initiator.is_err());
+        assert!(matches!(responder, Err(PhantomBandError::Crypto(_))), "The responder cannot decrypt a handshake for another key");
+    }
+
+    #[tokio::test]
+    async fn test_link_handshake_version_mismatch() {
+        let relay = LinkCredentials::relay(SecretKey::generate(), &IdentityKeypair::generate());
+        let future = Versions { versions: vec![SUPPORTED_VERSIONS.iter().max().unwrap() + 1], capabilities: Capabilities::supported() };
+        let (initiator, responder) = handshake(&relay, None, future, Versions::ours()).await;
+        assert!(matches!(initiator, Err(PhantomBandError::Protocol(_))));
+        assert!(matches!(responder, Err(PhantomBandError::Protocol(_))));
+    }
+}
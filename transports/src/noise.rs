@@ -0,0 +1,233 @@
+// transports/src/noise.rs
+
+//! Noise-encrypted link sessions between clients and relays, and between
+//! relays.
+//!
+//! Both ends first exchange Versions cells in the clear, then run the Noise
+//! XK handshake described in `common::protocol::handshake`. The keys it
+//! produces drive a `CipherState` per direction, and every cell travels as
+//! one encrypted message. All messages on the wire, handshake or transport,
+//! are framed by `tcp::FrameCodec`.
+
+use common::crypto::{mlkem, CipherState, DeliveryMode, IdentityKeypair, IdentityPublicKey, LinkCertificate, PublicKey, SecretKey, SessionKey, KEY_LEN};
+use common::crypto::cipher::CIPHER_OVERHEAD;
+use common::crypto::kdf::NONCE_BASE_LEN;
+use common::error::PhantomBandError;
+use common::protocol::{self, handshake, version, wire, Capabilities, Cell, LinkParameters, PhantomBandMessage, Versions};
+use common::protocol::handshake::{HandshakeFinish, HandshakeInit, HandshakeReply};
+use crate::tcp::{self, FramedStream};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use log::info;
+
+/// Largest message on the wire, which is also the Noise message size limit.
+pub const MAX_FRAME_LEN: usize = tcp::MAX_FRAME_LEN;
+/// Largest payload a single transport message can carry.
+pub const MAX_PAYLOAD_LEN: usize = MAX_FRAME_LEN - CIPHER_OVERHEAD;
+
+/// Our side of a link: the static key the Noise handshake authenticates and,
+/// for relays, the certificate binding it to the relay identity.
+pub struct LinkCredentials {
+    pub static_key: SecretKey,
+    pub certificate: Option<LinkCertificate>,
+}
+
+impl LinkCredentials {
+    /// Throwaway credentials for a client, so that links cannot be linked to
+    /// each other by the client's static key.
+    pub fn ephemeral() -> Self {
+        LinkCredentials { static_key: SecretKey::generate(), certificate: None }
+    }
+
+    pub fn relay(static_key: SecretKey, identity: &IdentityKeypair) -> Self {
+        let certificate = LinkCertificate::sign(identity, &static_key.public_key());
+        LinkCredentials { static_key, certificate: Some(certificate) }
+    }
+}
+
+/// An established, encrypted link.
+pub struct LinkSession<S = TcpStream> {
+    stream: FramedStream<S>,
+    sending: CipherState,
+    receiving: CipherState,
+    parameters: LinkParameters,
+    remote_identity: Option<IdentityPublicKey>,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> LinkSession<S> {
+    /// Opens a link to a responder whose static link key we already know. The
+    /// responder must prove the identity certified for that key.
+    pub async fn initiate(stream: S, credentials: &LinkCredentials, responder_key: &PublicKey) -> Result<Self, PhantomBandError> {
+        LinkSession::initiate_offering(stream, credentials, responder_key, &Versions::ours()).await
+    }
+
+    /// Like `initiate`, but advertises `versions` rather than everything this
+    /// build supports.
+    pub async fn initiate_offering(stream: S, credentials: &LinkCredentials, responder_key: &PublicKey, versions: &Versions) -> Result<Self, PhantomBandError> {
+        let mut stream = tcp::framed(stream);
+        let our_versions = versions.to_cell()?.encode();
+        tcp::send_message(&mut stream, &our_versions).await?;
+        let their_versions = tcp::receive_message(&mut stream).await?;
+        let parameters = negotiate(versions, &their_versions)?;
+        let hybrid = parameters.capabilities.contains(Capabilities::PQ_HANDSHAKE);
+
+        let static_key = credentials.static_key.to_bytes();
+        let prologue = handshake::prologue(&our_versions, &their_versions);
+        let mut noise = snow::Builder::new(noise_params(hybrid))
+            .local_private_key(&*static_key)
+            .remote_public_key(responder_key.as_bytes())
+            .prologue(&prologue)
+            .build_initiator()
+            .map_err(noise_error)?;
+
+        let kem_keypair = hybrid.then(mlkem::generate_keypair);
+        let init = HandshakeInit { kem_public_key: kem_keypair.as_ref().map(|keypair| keypair.encapsulation_key.as_bytes().to_vec()) };
+        write_handshake(&mut stream, &mut noise, &wire::encode(&init)?).await?;
+
+        let reply: HandshakeReply = wire::decode(&read_handshake(&mut stream, &mut noise).await?)?;
+        let remote_identity = reply.certificate.verify(responder_key)?;
+        match (&kem_keypair, reply.kem_ciphertext) {
+            (Some(keypair), Some(ciphertext)) => {
+                let shared = keypair.decapsulation_key.decapsulate(&mlkem::Ciphertext::from_bytes(&ciphertext)?);
+                noise.set_psk(handshake::PQ_PSK_LOCATION, &*shared).map_err(noise_error)?;
+            },
+            (None, None) => {},
+            _ => return Err(PhantomBandError::Protocol("ML-KEM ciphertext does not match the negotiated handshake".to_string())),
+        }
+
+        let finish = HandshakeFinish { certificate: credentials.certificate.clone() };
+        write_handshake(&mut stream, &mut noise, &wire::encode(&finish)?).await?;
+        LinkSession::established(stream, noise, parameters, Some(remote_identity))
+    }
+
+    /// Accepts a link as the responder. `credentials` must carry a certificate.
+    pub async fn accept(stream: S, credentials: &LinkCredentials) -> Result<Self, PhantomBandError> {
+        LinkSession::accept_offering(stream, credentials, &Versions::ours()).await
+    }
+
+    /// Like `accept`, but advertises `versions` rather than everything this
+    /// build supports.
+    pub async fn accept_offering(stream: S, credentials: &LinkCredentials, versions: &Versions) -> Result<Self, PhantomBandError> {
+        let mut stream = tcp::framed(stream);
+        let certificate = credentials.certificate.clone()
+            .ok_or_else(|| PhantomBandError::Internal("Accepting links requires a link certificate".to_string()))?;
+        let their_versions = tcp::receive_message(&mut stream).await?;
+        let our_versions = versions.to_cell()?.encode();
+        // Answer even when there is no common version, so the initiator can report why.
+        tcp::send_message(&mut stream, &our_versions).await?;
+        let parameters = negotiate(versions, &their_versions)?;
+        let hybrid = parameters.capabilities.contains(Capabilities::PQ_HANDSHAKE);
+
+        let static_key = credentials.static_key.to_bytes();
+        let prologue = handshake::prologue(&their_versions, &our_versions);
+        let mut noise = snow::Builder::new(noise_params(hybrid))
+            .local_private_key(&*static_key)
+            .prologue(&prologue)
+            .build_responder()
+            .map_err(noise_error)?;
+
+        let init: HandshakeInit = wire::decode(&read_handshake(&mut stream, &mut noise).await?)?;
+        let (kem_ciphertext, kem_shared) = match (hybrid, init.kem_public_key) {
+            (true, Some(key)) => {
+                let (ciphertext, shared) = mlkem::EncapsulationKey::from_bytes(&key)?.encapsulate();
+                (Some(ciphertext.as_bytes().to_vec()), Some(shared))
+            },
+            (false, None) => (None, None),
+            (true, None) => return Err(PhantomBandError::Protocol("Missing ML-KEM key for negotiated post-quantum handshake".to_string())),
+            (false, Some(_)) => return Err(PhantomBandError::Protocol("Unexpected ML-KEM key without post-quantum handshake".to_string())),
+        };
+
+        let reply = HandshakeReply { certificate, kem_ciphertext };
+        write_handshake(&mut stream, &mut noise, &wire::encode(&reply)?).await?;
+        if let Some(shared) = &kem_shared {
+            noise.set_psk(handshake::PQ_PSK_LOCATION, &**shared).map_err(noise_error)?;
+        }
+
+        let finish: HandshakeFinish = wire::decode(&read_handshake(&mut stream, &mut noise).await?)?;
+        let remote_static: [u8; KEY_LEN] = noise.get_remote_static()
+            .and_then(|key| key.try_into().ok())
+            .ok_or_else(|| PhantomBandError::Crypto("Noise handshake finished without the initiator's static key".to_string()))?;
+        let remote_identity = finish.certificate
+            .map(|certificate| certificate.verify(&PublicKey::from_bytes(remote_static)))
+            .transpose()?;
+        LinkSession::established(stream, noise, parameters, remote_identity)
+    }
+
+    fn established(stream: FramedStream<S>, mut noise: snow::HandshakeState, parameters: LinkParameters, remote_identity: Option<IdentityPublicKey>) -> Result<Self, PhantomBandError> {
+        if !noise.is_handshake_finished() {
+            return Err(PhantomBandError::Internal("Noise handshake is not finished".to_string()));
+        }
+        let (initiator_key, responder_key) = noise.dangerously_get_raw_split();
+        let (initiator_key, responder_key) = (SessionKey::from_bytes(initiator_key), SessionKey::from_bytes(responder_key));
+        // Every link has fresh keys, so a zero nonce base is safe.
+        let cipher = |key| CipherState::new(key, [0u8; NONCE_BASE_LEN], DeliveryMode::InOrder);
+        let (sending, receiving) = if noise.is_initiator() {
+            (cipher(initiator_key), cipher(responder_key))
+        } else {
+            (cipher(responder_key), cipher(initiator_key))
+        };
+        info!("Link established (version {}, capabilities {})", parameters.version, parameters.capabilities);
+        Ok(LinkSession { stream, sending, receiving, parameters, remote_identity })
+    }
+
+    pub fn parameters(&self) -> LinkParameters {
+        self.parameters
+    }
+
+    /// The relay identity the peer proved. Always set on links we initiated;
+    /// `None` when a client connected to us.
+    pub fn remote_identity(&self) -> Option<IdentityPublicKey> {
+        self.remote_identity
+    }
+
+    pub async fn send(&mut self, payload: &[u8]) -> Result<(), PhantomBandError> {
+        if payload.len() > MAX_PAYLOAD_LEN {
+            return Err(PhantomBandError::Protocol(format!("Link payload too large: {} bytes (max {})", payload.len(), MAX_PAYLOAD_LEN)));
+        }
+        let message = self.sending.encrypt(payload)?;
+        tcp::send_message(&mut self.stream, &message).await
+    }
+
+    pub async fn receive(&mut self) -> Result<Vec<u8>, PhantomBandError> {
+        let message = tcp::receive_message(&mut self.stream).await?;
+        self.receiving.decrypt(&message)
+    }
+
+    pub async fn send_message(&mut self, message: &PhantomBandMessage) -> Result<(), PhantomBandError> {
+        self.send(&protocol::encode_message(message)?).await
+    }
+
+    pub async fn receive_message(&mut self) -> Result<PhantomBandMessage, PhantomBandError> {
+        protocol::decode_message(&self.receive().await?)
+    }
+}
+
+fn noise_params(hybrid: bool) -> snow::params::NoiseParams {
+    let params = if hybrid { handshake::NOISE_PARAMS_PQ } else { handshake::NOISE_PARAMS };
+    params.parse().expect("Noise parameters are valid")
+}
+
+fn noise_error(e: snow::Error) -> PhantomBandError {
+    PhantomBandError::Crypto(format!("Noise: {}", e))
+}
+
+fn negotiate(ours: &Versions, their_versions: &[u8]) -> Result<LinkParameters, PhantomBandError> {
+    let theirs = Cell::decode(their_versions)
+        .and_then(|cell| Versions::from_cell(&cell))
+        .map_err(|e| e.context("Failed to read Versions cell"))?;
+    version::negotiate(ours, &theirs)
+}
+
+async fn write_handshake<S: AsyncWrite + Unpin>(stream: &mut FramedStream<S>, noise: &mut snow::HandshakeState, payload: &[u8]) -> Result<(), PhantomBandError> {
+    let mut message = vec![0u8; MAX_FRAME_LEN];
+    let len = noise.write_message(payload, &mut message).map_err(noise_error)?;
+    tcp::send_message(stream, &message[..len]).await
+}
+
+async fn read_handshake<S: AsyncRead + Unpin>(stream: &mut FramedStream<S>, noise: &mut snow::HandshakeState) -> Result<Vec<u8>, PhantomBandError> {
+    let message = tcp::receive_message(stream).await?;
+    let mut payload = vec![0u8; message.len()];
+    let len = noise.read_message(&message, &mut payload).map_err(noise_error)?;
+    payload.truncate(len);
+    Ok(payload)
+}
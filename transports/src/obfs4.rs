@@ -1,17 +1,18 @@
 // transports/src/obfs4.rs
 
 use super::r#trait::PluggableTransport;
+use common::error::PhantomBandError;
 
 pub struct Obfs4Transport;
 
 impl PluggableTransport for Obfs4Transport {
-    fn connect(&self, addr: &str) -> Result<(), String> {
+    fn connect(&self, addr: &str) -> Result<(), PhantomBandError> {
         println!("Obfs4 Transport: Connecting to {}", addr);
         // Dummy implementation
         Ok(())
     }
 
-    fn listen(&self, addr: &str) -> Result<(), String> {
+    fn listen(&self, addr: &str) -> Result<(), PhantomBandError> {
         println!("Obfs4 Transport: Listening on {}", addr);
         // Dummy implementation
         Ok(())
@@ -1,6 +1,7 @@
 // transports/src/quic.rs
 
 use super::r#trait::PluggableTransport;
+use common::error::PhantomBandError;
 // use quinn::{Endpoint, ClientConfig, ServerConfig, TransportConfig, Certificate, PrivateKey};
 // use std::sync::Arc;
 // use tokio::io::{AsyncReadExt, AsyncWriteExt};
@@ -8,14 +9,14 @@ use super::r#trait::PluggableTransport;
 pub struct QuicTransport;
 
 impl PluggableTransport for QuicTransport {
-    fn connect(&self, addr: &str) -> Result<(), String> {
+    fn connect(&self, addr: &str) -> Result<(), PhantomBandError> {
         println!("QUIC Transport: Connecting to {}", addr);
         // Dummy implementation for now due to local compilation issues
         // In a real scenario, this would involve quinn::Endpoint::client and connecting
         Ok(())
     }
 
-    fn listen(&self, addr: &str) -> Result<(), String> {
+    fn listen(&self, addr: &str) -> Result<(), PhantomBandError> {
         println!("QUIC Transport: Listening on {}", addr);
         // Dummy implementation for now due to local compilation issues
         // In a real scenario, this would involve quinn::Endpoint::server and listening
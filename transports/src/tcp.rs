@@ -1,14 +1,23 @@
 // transports/src/tcp.rs
 
 use super::r#trait::PluggableTransport;
-use tokio::net::{TcpListener, TcpStream};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use log::{info, error};
+use bytes::{Buf, BufMut, BytesMut};
+use common::error::PhantomBandError;
+use futures::{SinkExt, StreamExt};
+use tokio::net::TcpStream;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_util::codec::{Decoder, Encoder, Framed};
+use log::info;
+
+/// Bytes in the big-endian length prefix of every frame.
+pub const LENGTH_PREFIX_LEN: usize = 4;
+/// Largest frame body accepted by default.
+pub const MAX_FRAME_LEN: usize = 65535;
 
 pub struct TcpTransport;
 
 impl PluggableTransport for TcpTransport {
-    fn connect(&self, addr: &str) -> Result<(), String> {
+    fn connect(&self, addr: &str) -> Result<(), PhantomBandError> {
         info!("TCP Transport: Attempting to connect to {}", addr);
         // This connect is synchronous for now, but in a real async context,
         // it would return a future.
@@ -16,7 +25,7 @@ impl PluggableTransport for TcpTransport {
         Ok(())
     }
 
-    fn listen(&self, addr: &str) -> Result<(), String> {
+    fn listen(&self, addr: &str) -> Result<(), PhantomBandError> {
         info!("TCP Transport: Attempting to listen on {}", addr);
         // This listen is synchronous for now, but in a real async context,
         // it would return a future.
@@ -25,15 +34,86 @@ impl PluggableTransport for TcpTransport {
     }
 }
 
-// Helper function for sending/receiving data over a TcpStream
-pub async fn send_message(stream: &mut TcpStream, message: &[u8]) -> Result<(), String> {
-    stream.write_all(message).await
-        .map_err(|e| format!("Failed to send message: {}", e))
+/// Splits a byte stream into messages, each sent as a big-endian u32 length
+/// followed by that many bytes. Frames longer than the limit are refused in
+/// both directions, before any of the body is buffered.
+#[derive(Debug, Clone, Copy)]
+pub struct FrameCodec {
+    max_frame_len: usize,
+}
+
+impl FrameCodec {
+    pub fn new(max_frame_len: usize) -> Self {
+        FrameCodec { max_frame_len }
+    }
+
+    pub fn max_frame_len(&self) -> usize {
+        self.max_frame_len
+    }
+
+    fn check_len(&self, len: usize) -> Result<(), PhantomBandError> {
+        if len > self.max_frame_len {
+            return Err(PhantomBandError::Protocol(format!("Frame too large: {} bytes (max {})", len, self.max_frame_len)));
+        }
+        Ok(())
+    }
+}
+
+impl Default for FrameCodec {
+    fn default() -> Self {
+        FrameCodec::new(MAX_FRAME_LEN)
+    }
 }
 
-pub async fn receive_message(stream: &mut TcpStream) -> Result<Vec<u8>, String> {
-    let mut buffer = vec![0; 4096]; // Increased buffer size
-    let n = stream.read(&mut buffer).await
-        .map_err(|e| format!("Failed to read message: {}", e))?;
-    Ok(buffer[..n].to_vec())
+impl Decoder for FrameCodec {
+    type Item = BytesMut;
+    type Error = PhantomBandError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<BytesMut>, PhantomBandError> {
+        if src.len() < LENGTH_PREFIX_LEN {
+            return Ok(None);
+        }
+        let len = u32::from_be_bytes(src[..LENGTH_PREFIX_LEN].try_into().unwrap()) as usize;
+        self.check_len(len)?;
+        if src.len() < LENGTH_PREFIX_LEN + len {
+            src.reserve(LENGTH_PREFIX_LEN + len - src.len());
+            return Ok(None);
+        }
+        src.advance(LENGTH_PREFIX_LEN);
+        Ok(Some(src.split_to(len)))
+    }
+}
+
+impl Encoder<&[u8]> for FrameCodec {
+    type Error = PhantomBandError;
+
+    fn encode(&mut self, frame: &[u8], dst: &mut BytesMut) -> Result<(), PhantomBandError> {
+        self.check_len(frame.len())?;
+        dst.reserve(LENGTH_PREFIX_LEN + frame.len());
+        dst.put_u32(frame.len() as u32);
+        dst.put_slice(frame);
+        Ok(())
+    }
+}
+
+/// A stream carrying length-prefixed frames.
+pub type FramedStream<S = TcpStream> = Framed<S, FrameCodec>;
+
+pub fn framed<S: AsyncRead + AsyncWrite>(stream: S) -> FramedStream<S> {
+    Framed::new(stream, FrameCodec::default())
+}
+
+// Helpers for sending/receiving whole messages over a framed stream
+pub async fn send_message<S: AsyncWrite + Unpin>(stream: &mut FramedStream<S>, message: &[u8]) -> Result<(), PhantomBandError> {
+    stream.send(message).await
+        .map_err(|e| e.context("Failed to send message"))
+}
+
+pub async fn receive_message<S: AsyncRead + Unpin>(stream: &mut FramedStream<S>) -> Result<Vec<u8>, PhantomBandError> {
+    match stream.next().await {
+        Some(frame) => frame
+            .map(|frame| frame.to_vec())
+            .map_err(|e| e.context("Failed to read message")),
+        None => Err(PhantomBandError::Transport("Connection closed by peer".to_string())),
+    }
 }
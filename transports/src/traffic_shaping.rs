@@ -1,17 +1,18 @@
 // transports/src/traffic_shaping.rs
 
 use super::r#trait::PluggableTransport;
+use common::error::PhantomBandError;
 
 pub struct TrafficShapingTransport;
 
 impl PluggableTransport for TrafficShapingTransport {
-    fn connect(&self, addr: &str) -> Result<(), String> {
+    fn connect(&self, addr: &str) -> Result<(), PhantomBandError> {
         println!("Traffic Shaping Transport: Connecting to {}", addr);
         // Dummy implementation with padding and delays
         Ok(())
     }
 
-    fn listen(&self, addr: &str) -> Result<(), String> {
+    fn listen(&self, addr: &str) -> Result<(), PhantomBandError> {
         println!("Traffic Shaping Transport: Listening on {}", addr);
         // Dummy implementation with padding and delays
         Ok(())
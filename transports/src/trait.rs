@@ -1,6 +1,8 @@
 // transports/src/trait.rs
 
+use common::error::PhantomBandError;
+
 pub trait PluggableTransport {
-    fn connect(&self, addr: &str) -> Result<(), String>;
-    fn listen(&self, addr: &str) -> Result<(), String>;
+    fn connect(&self, addr: &str) -> Result<(), PhantomBandError>;
+    fn listen(&self, addr: &str) -> Result<(), PhantomBandError>;
 }
@@ -1,17 +1,18 @@
 // transports/src/websocket.rs
 
 use super::r#trait::PluggableTransport;
+use common::error::PhantomBandError;
 
 pub struct WebSocketTransport;
 
 impl PluggableTransport for WebSocketTransport {
-    fn connect(&self, addr: &str) -> Result<(), String> {
+    fn connect(&self, addr: &str) -> Result<(), PhantomBandError> {
         println!("WebSocket Transport: Connecting to {}", addr);
         // Dummy implementation
         Ok(())
     }
 
-    fn listen(&self, addr: &str) -> Result<(), String> {
+    fn listen(&self, addr: &str) -> Result<(), PhantomBandError> {
         println!("WebSocket Transport: Listening on {}", addr);
         // Dummy implementation
         Ok(())